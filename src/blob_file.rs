@@ -0,0 +1,268 @@
+//! blob_file implements key-value separation: when blob files are enabled, values of at least
+//! `min_blob_size` bytes are moved into append-only blob files by flushes and compactions, and
+//! tables only store a BlobIndex pointing to the value.
+//!
+//! A blob file is a sequence of records [checksum: fixed32, value: [u8]], where checksum is the
+//! masked crc32c of the value. Blob files are never modified; they are deleted once no table of
+//! the current version refers to them anymore.
+#[cfg(feature = "mesalock_sgx")]
+use std::prelude::v1::*;
+
+use cache::Cache;
+use env::RandomAccess;
+use error::{err, Result, StatusCode};
+use key_types::{set_value_type, ValueType};
+use log::{mask_crc, unmask_crc};
+use options::Options;
+use rate_limiter::{IOPriority, RateLimitedWriter};
+use table_cache::filenum_to_key;
+use types::FileNum;
+
+use crc::crc32::{self, Hasher32};
+use integer_encoding::{FixedInt, FixedIntWriter, VarIntReader, VarIntWriter};
+
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const BLOB_CKSUM_LEN: usize = 4;
+
+pub fn blob_file_name<P: AsRef<Path>>(name: P, num: FileNum) -> PathBuf {
+    assert!(num > 0);
+    name.as_ref().join(format!("{:06}.blob", num))
+}
+
+fn blob_checksum(value: &[u8]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+    digest.write(value);
+    digest.sum32()
+}
+
+/// A BlobIndex is stored in place of a value that was moved to a blob file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlobIndex {
+    pub file_num: FileNum,
+    /// The offset of the record, i.e. of the checksum preceding the value.
+    pub offset: usize,
+    /// The length of the value.
+    pub size: usize,
+}
+
+impl BlobIndex {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16);
+        buf.write_varint(self.file_num).unwrap();
+        buf.write_varint(self.offset).unwrap();
+        buf.write_varint(self.size).unwrap();
+        buf
+    }
+
+    pub fn decode(mut src: &[u8]) -> Result<BlobIndex> {
+        if let (Ok(file_num), Ok(offset), Ok(size)) = (
+            src.read_varint::<FileNum>(),
+            src.read_varint::<usize>(),
+            src.read_varint::<usize>(),
+        ) {
+            Ok(BlobIndex {
+                file_num,
+                offset,
+                size,
+            })
+        } else {
+            err(StatusCode::Corruption, "Bad blob index encoding")
+        }
+    }
+}
+
+/// BlobFileWriter appends values to a new blob file.
+pub struct BlobFileWriter {
+    num: FileNum,
+    dst: BufWriter<RateLimitedWriter<Box<dyn Write>>>,
+    offset: usize,
+    count: usize,
+}
+
+impl BlobFileWriter {
+    /// Creates the blob file `num` in the database directory `db`. Writes are charged to the rate
+    /// limiter at priority `pri`.
+    pub fn new<P: AsRef<Path>>(
+        db: P,
+        opt: &Options,
+        num: FileNum,
+        pri: IOPriority,
+    ) -> Result<BlobFileWriter> {
+        let f = opt
+            .env
+            .open_writable_file(Path::new(&blob_file_name(db, num)))?;
+        Ok(BlobFileWriter {
+            num,
+            dst: BufWriter::new(RateLimitedWriter::new(f, opt.clone(), pri)),
+            offset: 0,
+            count: 0,
+        })
+    }
+
+    pub fn num(&self) -> FileNum {
+        self.num
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn size(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the number of values written so far.
+    pub fn entries(&self) -> usize {
+        self.count
+    }
+
+    /// Appends a value and returns its location.
+    pub fn add(&mut self, value: &[u8]) -> Result<BlobIndex> {
+        let index = BlobIndex {
+            file_num: self.num,
+            offset: self.offset,
+            size: value.len(),
+        };
+        self.dst.write_fixedint(mask_crc(blob_checksum(value)))?;
+        self.dst.write_all(value)?;
+        self.offset += BLOB_CKSUM_LEN + value.len();
+        self.count += 1;
+        Ok(index)
+    }
+
+    /// Flushes the file and returns its size.
+    pub fn finish(&mut self) -> Result<usize> {
+        self.dst.flush()?;
+        Ok(self.offset)
+    }
+}
+
+/// separate_value moves the value of an entry to `blobs` if it's large enough, and replaces it
+/// with a BlobIndex. `key` is in InternalKey format; its entry type is changed accordingly.
+/// Returns true if the value was moved.
+pub fn separate_value(
+    opt: &Options,
+    blobs: &mut BlobFileWriter,
+    key: &mut Vec<u8>,
+    val: &mut Vec<u8>,
+) -> Result<bool> {
+    if val.len() < opt.min_blob_size {
+        return Ok(false);
+    }
+    let index = blobs.add(val)?;
+    set_value_type(key, ValueType::TypeBlobIndex);
+    *val = index.encode();
+    Ok(true)
+}
+
+/// BlobFileCache reads values from blob files, keeping a limited number of them open.
+pub struct BlobFileCache {
+    dbname: PathBuf,
+    cache: Cache<Rc<Box<dyn RandomAccess>>>,
+    opts: Options,
+}
+
+impl BlobFileCache {
+    /// Create a new BlobFileCache for the database named `db`, keeping up to `entries` files open.
+    pub fn new<P: AsRef<Path>>(db: P, opt: Options, entries: usize) -> BlobFileCache {
+        BlobFileCache {
+            dbname: db.as_ref().to_owned(),
+            cache: Cache::new(entries),
+            opts: opt,
+        }
+    }
+
+    /// Reads the value referred to by `index` and verifies its checksum.
+    pub fn get(&mut self, index: &BlobIndex) -> Result<Vec<u8>> {
+        let file = self.get_file(index.file_num)?;
+        let mut buf = vec![0; BLOB_CKSUM_LEN + index.size];
+        let read = file.read_at(index.offset, &mut buf)?;
+        if read < buf.len() {
+            return err(
+                StatusCode::Corruption,
+                &format!("short read of blob in file {}", index.file_num),
+            );
+        }
+        let value = buf.split_off(BLOB_CKSUM_LEN);
+        if unmask_crc(u32::decode_fixed(&buf)) != blob_checksum(&value) {
+            return err(
+                StatusCode::Corruption,
+                &format!(
+                    "checksum verification failed for blob in file {} at {}",
+                    index.file_num, index.offset
+                ),
+            );
+        }
+        Ok(value)
+    }
+
+    /// Resolves a BlobIndex found as value of an entry.
+    pub fn get_encoded(&mut self, index: &[u8]) -> Result<Vec<u8>> {
+        self.get(&BlobIndex::decode(index)?)
+    }
+
+    fn get_file(&mut self, file_num: FileNum) -> Result<Rc<Box<dyn RandomAccess>>> {
+        let key = filenum_to_key(file_num);
+        if let Some(f) = self.cache.get(&key) {
+            return Ok(f.clone());
+        }
+        let name = blob_file_name(&self.dbname, file_num);
+        let file = Rc::new(self.opts.env.open_random_access_file(Path::new(&name))?);
+        self.cache.insert(&key, file.clone());
+        Ok(file)
+    }
+
+    pub fn evict(&mut self, file_num: FileNum) {
+        self.cache.remove(&filenum_to_key(file_num));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use options;
+
+    #[test]
+    fn test_blob_file_name() {
+        assert_eq!(Path::new("abc/000122.blob"), blob_file_name("abc", 122));
+    }
+
+    #[test]
+    fn test_blob_index_encoding() {
+        let index = BlobIndex {
+            file_num: 123,
+            offset: 456789,
+            size: 100000,
+        };
+        assert_eq!(index, BlobIndex::decode(&index.encode()).unwrap());
+        assert!(BlobIndex::decode(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn test_blob_file_write_read() {
+        let mut opt = options::for_test();
+        opt.min_blob_size = 10;
+        let mut w = BlobFileWriter::new("db", &opt, 5, IOPriority::Flush).unwrap();
+
+        let (mut key, mut val) = (vec![b'a'; 9], vec![b'x'; 9]);
+        assert!(!separate_value(&opt, &mut w, &mut key, &mut val).unwrap());
+        assert_eq!(vec![b'x'; 9], val);
+
+        let i1 = w.add(b"value1").unwrap();
+        val = vec![b'y'; 1000];
+        assert!(separate_value(&opt, &mut w, &mut key, &mut val).unwrap());
+        assert_eq!(ValueType::TypeBlobIndex as u8, key[1]);
+        assert_eq!(2, w.entries());
+        assert_eq!(1014, w.finish().unwrap());
+        assert_eq!(1014, opt.env.size_of(&blob_file_name("db", 5)).unwrap());
+
+        let mut cache = BlobFileCache::new("db", opt.clone(), 10);
+        assert_eq!(b"value1".to_vec(), cache.get(&i1).unwrap());
+        assert_eq!(vec![b'y'; 1000], cache.get_encoded(&val).unwrap());
+        let mut bad = BlobIndex::decode(&val).unwrap();
+        bad.offset += 1;
+        assert_eq!(StatusCode::Corruption, cache.get(&bad).err().unwrap().code);
+        bad.size += 10;
+        assert!(cache.get(&bad).is_err());
+    }
+}
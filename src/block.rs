@@ -4,6 +4,8 @@ use std::prelude::v1::*;
 use std::cmp::Ordering;
 use std::rc::Rc;
 
+use block_hash_index::{self, Lookup};
+use cache::Pinnable;
 use options::Options;
 use types::LdbIterator;
 
@@ -31,7 +33,8 @@ pub type BlockContents = Vec<u8>;
 ///
 /// A RESTART is a fixed u32 pointing to the beginning of an ENTRY.
 ///
-/// N_RESTARTS contains the number of restarts.
+/// N_RESTARTS contains the number of restarts. If its highest bit is set, a hash index is stored
+/// between RESTARTS and N_RESTARTS (see block_hash_index).
 #[derive(Clone)]
 pub struct Block {
     block: Rc<BlockContents>,
@@ -44,15 +47,18 @@ impl Block {
     /// refcounted block contents as this block, meaning that if the iterator isn't released,
     /// the memory occupied by the block isn't, either)
     pub fn iter(&self) -> BlockIter {
-        let restarts = u32::decode_fixed(&self.block[self.block.len() - 4..]);
-        let restart_offset = self.block.len() - 4 - 4 * restarts as usize;
+        let (restarts, restart_offset, buckets_off, num_buckets) =
+            block_hash_index::decode_trailer(&self.block);
 
         BlockIter {
             block: self.block.clone(),
             opt: self.opt.clone(),
 
             offset: 0,
+            restarts,
             restarts_off: restart_offset,
+            buckets_off,
+            num_buckets,
             current_entry_offset: 0,
             current_restart_ix: 0,
 
@@ -65,6 +71,11 @@ impl Block {
         self.block.clone()
     }
 
+    /// The size of the (uncompressed) block contents.
+    pub fn size(&self) -> usize {
+        self.block.len()
+    }
+
     pub fn new(opt: Options, contents: BlockContents) -> Block {
         assert!(contents.len() > 4);
         Block {
@@ -74,6 +85,13 @@ impl Block {
     }
 }
 
+/// A block is pinned while a copy of it or an iterator over it exists outside of the block cache.
+impl Pinnable for Block {
+    fn is_pinned(&self) -> bool {
+        Rc::strong_count(&self.block) > 1
+    }
+}
+
 /// BlockIter is an iterator over the entries in a block. It doesn't depend on the Block's
 /// lifetime, as it uses a refcounted block underneath.
 pub struct BlockIter {
@@ -81,8 +99,13 @@ pub struct BlockIter {
     /// TODO: Maybe (probably...) this needs an Arc.
     block: Rc<BlockContents>,
     opt: Options,
+    /// number of restarts.
+    restarts: usize,
     /// offset of restarts area within the block.
     restarts_off: usize,
+    /// offset and size of the hash index; num_buckets is 0 if there is none.
+    buckets_off: usize,
+    num_buckets: usize,
 
     /// start of next entry to be parsed.
     offset: usize,
@@ -100,7 +123,27 @@ pub struct BlockIter {
 impl BlockIter {
     /// Return the number of restarts in this block.
     fn number_restarts(&self) -> usize {
-        u32::decode_fixed(&self.block[self.block.len() - 4..]) as usize
+        self.restarts
+    }
+
+    /// Like seek(), but only for finding the entries of `user_key`, which is the user key of
+    /// `to`: if the block has a hash index, the binary search over the restart points is skipped,
+    /// and the iterator becomes invalid if the block contains no entry for `user_key`.
+    pub fn seek_for_get(&mut self, to: &[u8], user_key: &[u8]) {
+        match block_hash_index::lookup(&self.block, self.buckets_off, self.num_buckets, user_key) {
+            Lookup::Restart(ix) if ix < self.number_restarts() => {
+                self.reset();
+                self.current_restart_ix = ix;
+                self.offset = self.get_restart_point(ix);
+                while let Some((k, _)) = self.next() {
+                    if self.opt.cmp.cmp(k.as_slice(), to) >= Ordering::Equal {
+                        return;
+                    }
+                }
+            }
+            Lookup::NotFound => self.reset(),
+            _ => self.seek(to),
+        }
     }
 
     /// Seek to restart point `ix`. After the seek, current() will return the entry at that restart
@@ -162,24 +205,6 @@ impl BlockIter {
         self.key
             .extend_from_slice(&self.block[off..off + non_shared]);
     }
-
-    pub fn seek_to_last(&mut self) {
-        if self.number_restarts() > 0 {
-            let num_restarts = self.number_restarts();
-            self.seek_to_restart_point(num_restarts - 1);
-        } else {
-            self.reset();
-        }
-
-        // Stop at last entry, before the iterator becomes invalid.
-        //
-        // We're checking the position before calling advance; if a restart point points to the
-        // last entry, calling advance() will directly reset the iterator.
-        while self.offset < self.restarts_off {
-            self.advance();
-        }
-        assert!(self.valid());
-    }
 }
 
 impl LdbIterator for BlockIter {
@@ -285,6 +310,24 @@ impl LdbIterator for BlockIter {
         }
     }
 
+    fn seek_to_last(&mut self) {
+        if self.number_restarts() > 0 {
+            let num_restarts = self.number_restarts();
+            self.seek_to_restart_point(num_restarts - 1);
+        } else {
+            self.reset();
+        }
+
+        // Stop at last entry, before the iterator becomes invalid.
+        //
+        // We're checking the position before calling advance; if a restart point points to the
+        // last entry, calling advance() will directly reset the iterator.
+        while self.offset < self.restarts_off {
+            self.advance();
+        }
+        assert!(self.valid());
+    }
+
     fn valid(&self) -> bool {
         !self.key.is_empty() && self.val_offset > 0 && self.val_offset <= self.restarts_off
     }
@@ -483,6 +526,47 @@ mod tests {
         assert_eq!(current_key_val(&block), None);
     }
 
+    #[test]
+    fn test_block_hash_index_seek() {
+        let mut o = options::for_test();
+        o.block_restart_interval = 2;
+        let data = get_data();
+
+        for &hash_index in [false, true].iter() {
+            let mut builder = BlockBuilder::new(o.clone());
+            if hash_index {
+                builder = builder.with_hash_index(0);
+            }
+            for &(k, v) in data.iter() {
+                builder.add(k, v);
+            }
+            let size_estimate = builder.size_estimate();
+            let block_contents = builder.finish();
+            assert_eq!(size_estimate, block_contents.len());
+            let flagged = u32::decode_fixed(&block_contents[block_contents.len() - 4..]) >> 31;
+            assert_eq!(hash_index, flagged == 1);
+
+            let block = Block::new(o.clone(), block_contents);
+            // Iteration is not affected by the hash index.
+            let mut iter = block.iter();
+            let mut i = 0;
+            while let Some((k, v)) = iter.next() {
+                assert_eq!((data[i].0, data[i].1), (k.as_slice(), v.as_slice()));
+                i += 1;
+            }
+            assert_eq!(data.len(), i);
+            iter.seek_to_last();
+            assert_eq!(data[data.len() - 1].0, current_key_val(&iter).unwrap().0.as_slice());
+
+            for &(k, v) in data.iter() {
+                iter.seek_for_get(k, k);
+                assert_eq!(Some((k.to_vec(), v.to_vec())), current_key_val(&iter));
+            }
+            iter.seek_for_get(b"prefix_key8", b"prefix_key8");
+            assert!(!iter.valid());
+        }
+    }
+
     #[test]
     fn test_block_seek_to_last() {
         let mut o = options::for_test();
@@ -4,6 +4,7 @@ use std::prelude::v1::*;
 use std::cmp::Ordering;
 
 use block::BlockContents;
+use block_hash_index::{HashIndexBuilder, HASH_INDEX_FLAG, MAX_RESTARTS};
 use options::Options;
 
 use integer_encoding::{FixedIntWriter, VarIntWriter};
@@ -18,6 +19,8 @@ pub struct BlockBuilder {
     last_key: Vec<u8>,
     restart_counter: usize,
     counter: usize,
+
+    hash_index: Option<HashIndexBuilder>,
 }
 
 impl BlockBuilder {
@@ -32,9 +35,17 @@ impl BlockBuilder {
             last_key: Vec::new(),
             restart_counter: 0,
             counter: 0,
+            hash_index: None,
         }
     }
 
+    /// Appends a hash index for point lookups to the block (see block_hash_index). The keys are
+    /// indexed without their last `suffix_len` bytes.
+    pub fn with_hash_index(mut self, suffix_len: usize) -> BlockBuilder {
+        self.hash_index = Some(HashIndexBuilder::new(suffix_len));
+        self
+    }
+
     pub fn entries(&self) -> usize {
         self.counter
     }
@@ -44,7 +55,12 @@ impl BlockBuilder {
     }
 
     pub fn size_estimate(&self) -> usize {
-        self.buffer.len() + 4 * self.restarts.len() + 4
+        // finish() omits the hash index from blocks with too many restart points.
+        let hash_index_size = match self.hash_index {
+            Some(ref ix) if self.restarts.len() <= MAX_RESTARTS => ix.size_estimate(),
+            _ => 0,
+        };
+        self.buffer.len() + 4 * self.restarts.len() + 4 + hash_index_size
     }
 
     pub fn reset(&mut self) {
@@ -53,6 +69,9 @@ impl BlockBuilder {
         self.last_key.clear();
         self.restart_counter = 0;
         self.counter = 0;
+        if let Some(ref mut ix) = self.hash_index {
+            ix.reset();
+        }
     }
 
     // push a key which is Greater than last_key after last_key
@@ -81,6 +100,10 @@ impl BlockBuilder {
             self.restart_counter = 0;
         }
 
+        if let Some(ref mut ix) = self.hash_index {
+            ix.add(key, self.restarts.len() - 1);
+        }
+
         let non_shared = key.len() - shared;
 
         self.buffer
@@ -113,9 +136,18 @@ impl BlockBuilder {
                 .expect("write to buffer failed");
         }
 
-        // 2. Append N_RESTARTS
+        // 2. Append the hash index, if the block isn't too large for it.
+        let mut n_restarts = self.restarts.len() as u32;
+        if let Some(ref ix) = self.hash_index {
+            if self.restarts.len() <= MAX_RESTARTS {
+                ix.finish(&mut self.buffer);
+                n_restarts |= HASH_INDEX_FLAG;
+            }
+        }
+
+        // 3. Append N_RESTARTS
         self.buffer
-            .write_fixedint(self.restarts.len() as u32)
+            .write_fixedint(n_restarts)
             .expect("write to buffer failed");
 
         // done
@@ -199,5 +231,22 @@ mod tests {
             assert_eq!(k, builder.last_key());
         }
     }
+    #[test]
+    fn test_block_builder_size_estimate_hash_index() {
+        // With a restart point per entry, the hash index is dropped once there are more than
+        // MAX_RESTARTS restart points; the estimate has to follow finish() either way.
+        for &n in [10, MAX_RESTARTS, MAX_RESTARTS + 1, 2 * MAX_RESTARTS].iter() {
+            let mut o = options::for_test();
+            o.block_restart_interval = 1;
+            let mut builder = BlockBuilder::new(o).with_hash_index(0);
+            for i in 0..n {
+                let k = format!("key{:05}", i);
+                builder.add(k.as_bytes(), b"value");
+            }
+            let estimate = builder.size_estimate();
+            assert_eq!(estimate, builder.finish().len());
+        }
+    }
+
     // Additional test coverage is provided by tests in block.rs.
 }
@@ -0,0 +1,185 @@
+//! block_hash_index implements the optional hash index of data blocks. It maps the user keys of
+//! a block to the restart interval containing them, so that point lookups don't need to
+//! binary-search the restart points.
+//!
+//! The index is stored after the RESTARTS of a block as NUM_BUCKETS u8 BUCKETS followed by a
+//! fixed u16 NUM_BUCKETS; its presence is flagged by the highest bit of N_RESTARTS. A bucket
+//! contains the index of a restart point, NO_ENTRY if no user key hashes to it, or COLLISION if
+//! user keys of different restart intervals do. Therefore only blocks with at most MAX_RESTARTS
+//! restart points get a hash index.
+#[cfg(feature = "mesalock_sgx")]
+use std::prelude::v1::*;
+
+use filter::hash;
+
+use integer_encoding::{FixedInt, FixedIntWriter};
+
+pub const HASH_INDEX_FLAG: u32 = 1 << 31;
+pub const MAX_RESTARTS: usize = 253;
+
+const NO_ENTRY: u8 = 255;
+const COLLISION: u8 = 254;
+const HASH_SEED: u32 = 0x54a2f3e1;
+/// The number of user keys per bucket.
+const UTIL_RATIO: f64 = 0.75;
+
+/// The result of looking up a user key in a hash index.
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    /// The key can only be in the restart interval starting at this restart point.
+    Restart(usize),
+    /// The key is not in the block.
+    NotFound,
+    /// The key may be anywhere in the block.
+    Unknown,
+}
+
+/// Returns the number of bytes following the indexed part of a key: the tag of InternalKeys and
+/// a user timestamp of `ts_size` bytes, so that all versions of a key share a bucket.
+pub fn key_suffix_len(internal_keys: bool, ts_size: usize) -> usize {
+    if internal_keys {
+        8 + ts_size
+    } else {
+        ts_size
+    }
+}
+
+/// Returns the part of `key` that is indexed, i.e. `key` without its last `suffix_len` bytes.
+pub fn user_key(key: &[u8], suffix_len: usize) -> &[u8] {
+    &key[0..key.len() - suffix_len]
+}
+
+/// HashIndexBuilder collects the user keys of a block and their restart intervals.
+pub struct HashIndexBuilder {
+    suffix_len: usize,
+    // (hash of user key, restart index)
+    entries: Vec<(u32, usize)>,
+}
+
+impl HashIndexBuilder {
+    /// Creates a builder for a block whose keys are indexed without their last `suffix_len` bytes
+    /// (see key_suffix_len).
+    pub fn new(suffix_len: usize) -> HashIndexBuilder {
+        HashIndexBuilder {
+            suffix_len,
+            entries: vec![],
+        }
+    }
+
+    /// Records that `key` is stored in the restart interval of restart point `restart_ix`.
+    pub fn add(&mut self, key: &[u8], restart_ix: usize) {
+        let h = hash(user_key(key, self.suffix_len), HASH_SEED);
+        self.entries.push((h, restart_ix));
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    fn num_buckets(&self) -> usize {
+        // An odd number of buckets spreads the hashes better.
+        ((self.entries.len() as f64 / UTIL_RATIO) as usize).max(1) | 1
+    }
+
+    pub fn size_estimate(&self) -> usize {
+        self.num_buckets() + 2
+    }
+
+    /// Appends the hash index to `buf`.
+    pub fn finish(&self, buf: &mut Vec<u8>) {
+        let num_buckets = self.num_buckets().min(u16::max_value() as usize);
+        let mut buckets = vec![NO_ENTRY; num_buckets];
+
+        for &(h, restart_ix) in self.entries.iter() {
+            assert!(restart_ix < MAX_RESTARTS);
+            let bucket = &mut buckets[h as usize % num_buckets];
+            if *bucket == NO_ENTRY {
+                *bucket = restart_ix as u8;
+            } else if *bucket != restart_ix as u8 {
+                *bucket = COLLISION;
+            }
+        }
+        buf.extend_from_slice(&buckets);
+        buf.write_fixedint(num_buckets as u16)
+            .expect("write to buffer failed");
+    }
+}
+
+/// Looks up `user_key` in the hash index whose buckets start at `buckets_off` in `block`.
+pub fn lookup(block: &[u8], buckets_off: usize, num_buckets: usize, user_key: &[u8]) -> Lookup {
+    if num_buckets == 0 {
+        return Lookup::Unknown;
+    }
+    let h = hash(user_key, HASH_SEED);
+    match block[buckets_off + h as usize % num_buckets] {
+        NO_ENTRY => Lookup::NotFound,
+        COLLISION => Lookup::Unknown,
+        restart_ix => Lookup::Restart(restart_ix as usize),
+    }
+}
+
+/// Decodes the trailer of `block`, returning the number of restart points, the offset of the
+/// restart points, and the offset and number of buckets of the hash index (or 0 buckets if the
+/// block has no hash index).
+pub fn decode_trailer(block: &[u8]) -> (usize, usize, usize, usize) {
+    let n_restarts = u32::decode_fixed(&block[block.len() - 4..]);
+    if n_restarts & HASH_INDEX_FLAG == 0 {
+        let restarts = n_restarts as usize;
+        return (restarts, block.len() - 4 - 4 * restarts, 0, 0);
+    }
+    let restarts = (n_restarts & !HASH_INDEX_FLAG) as usize;
+    let num_buckets = u16::decode_fixed(&block[block.len() - 6..block.len() - 4]) as usize;
+    let buckets_off = block.len() - 6 - num_buckets;
+    (restarts, buckets_off - 4 * restarts, buckets_off, num_buckets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key_types::LookupKey;
+
+    #[test]
+    fn test_block_hash_index() {
+        let keys: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("key{:03}", i))
+            .map(|k| LookupKey::new(k.as_bytes(), 1).internal_key().to_vec())
+            .collect();
+        let mut b = HashIndexBuilder::new(8);
+        for (i, k) in keys.iter().enumerate() {
+            b.add(k, i / 16);
+        }
+        let mut block = vec![];
+        block.write_fixedint(0 as u32).unwrap();
+        b.finish(&mut block);
+        block.write_fixedint(1 | HASH_INDEX_FLAG).unwrap();
+
+        let (restarts, restarts_off, buckets_off, num_buckets) = decode_trailer(&block);
+        assert_eq!((1, 0, 4), (restarts, restarts_off, buckets_off));
+        assert_eq!(b.size_estimate(), num_buckets + 2);
+
+        let mut found = 0;
+        for (i, k) in keys.iter().enumerate() {
+            match lookup(&block, buckets_off, num_buckets, user_key(k, 8)) {
+                Lookup::Restart(ix) => {
+                    assert_eq!(i / 16, ix);
+                    found += 1;
+                }
+                Lookup::Unknown => (),
+                Lookup::NotFound => panic!("key {} not found", i),
+            }
+        }
+        assert!(found > 30);
+
+        let missing = (0..100)
+            .filter(|i| {
+                let key = format!("xyz{:03}", i);
+                lookup(&block, buckets_off, num_buckets, key.as_bytes()) == Lookup::NotFound
+            })
+            .count();
+        assert!(missing > 10);
+
+        block.truncate(4);
+        block.write_fixedint(1 as u32).unwrap();
+        assert_eq!((1, 0, 0, 0), decode_trailer(&block));
+    }
+}
@@ -1,6 +1,8 @@
 #[cfg(feature = "mesalock_sgx")]
 use std::prelude::v1::*;
 
+use filter::hash;
+
 use std::collections::HashMap;
 use std::mem::{replace, swap};
 
@@ -91,21 +93,21 @@ impl<T> LRUList<T> {
 
     fn remove(&mut self, node_handle: LRUHandle<T>) -> T {
         unsafe {
-            // If has next
-            if let Some(ref mut nextp) = (*node_handle).next {
-                swap(&mut (**nextp).prev, &mut (*node_handle).prev);
-            }
-            // If has prev
-            if let Some(ref mut prevp) = (*node_handle).prev {
-                // swap prev.next
-                // (node_handle will own itself now)
-                swap(&mut (**prevp).next, &mut (*node_handle).next);
+            let prevp = (*node_handle).prev.unwrap();
+            let mut next = (*node_handle).next.take();
+
+            if let Some(ref mut next) = next {
+                // If has next, link it to prev
+                next.prev = Some(prevp);
+            } else {
+                // If last node, prev becomes the last node
+                self.head.prev = Some(prevp);
             }
 
+            // prev.next owns this node; replacing it with next drops the node.
+            let mut node = replace(&mut (*prevp).next, next).unwrap();
             self.count -= 1;
-            // node_handle now only has references/objects that point to itself,
-            // so it's safe to drop
-            replace(&mut (*node_handle).data, None).unwrap()
+            node.data.take().unwrap()
         }
     }
 
@@ -157,31 +159,296 @@ impl<T> LRUList<T> {
 
 pub type CacheKey = [u8; 16];
 pub type CacheID = u64;
-type CacheEntry<T> = (T, LRUHandle<CacheKey>);
 
-/// Implementation of `ShardedLRUCache`.
-/// Based on a HashMap; the elements are linked in order to support the LRU ordering.
-pub struct Cache<T> {
+/// CachePolicy selects which elements a `Cache` evicts once it is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CachePolicy {
+    /// Evicts the least recently used elements. The fraction `high_pri_pool_ratio` of the
+    /// capacity is reserved for a high-priority pool, which holds high-priority elements and
+    /// elements that were hit after their insertion. Other elements are inserted at the midpoint,
+    /// i.e. at the head of the low-priority pool, and are evicted first; that way, elements that
+    /// are only used once, e.g. by a scan, don't flush the cache. With a ratio of 0, this is a
+    /// plain LRU cache.
+    LRU { high_pri_pool_ratio: f64 },
+    /// Sweeps over the elements like a clock hand, evicting the first element that wasn't used
+    /// since the hand passed it last. High-priority elements and elements that were hit survive
+    /// up to MAX_CLOCK_COUNTDOWN more sweeps.
+    Clock,
+}
+
+/// The priority of a cached element; see `CachePolicy`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    Low,
+    High,
+}
+
+const MAX_CLOCK_COUNTDOWN: u8 = 3;
+
+// The position of an element in the eviction order.
+enum Position {
+    High(LRUHandle<CacheKey>),
+    Low(LRUHandle<CacheKey>),
+    Clock(usize),
+}
+
+struct CacheEntry<T> {
+    elem: T,
+    pos: Position,
+    charge: usize,
+}
+
+struct ClockSlot {
+    key: CacheKey,
+    // The number of sweeps the element survives.
+    countdown: u8,
+}
+
+/// Pinnable is implemented by cached values that can still be in use after the cache handed
+/// them out, like refcounted blocks. Evicting a pinned value doesn't free its memory.
+pub trait Pinnable {
+    /// Returns whether the value is referenced outside of the cache.
+    fn is_pinned(&self) -> bool;
+}
+
+/// A shard of a `Cache`, based on a HashMap; the elements are additionally kept in the order
+/// of eviction, which depends on the policy.
+struct CacheShard<T> {
     // note: CacheKeys (Vec<u8>) are duplicated between list and map. If this turns out to be a
     // performance bottleneck, another layer of indirection™ can solve this by mapping the key
     // to a numeric handle that keys both list and map.
-    list: LRUList<CacheKey>,
     map: HashMap<CacheKey, CacheEntry<T>>,
+    policy: CachePolicy,
     cap: usize,
+    usage: usize,
+
+    // Used by CachePolicy::LRU.
+    high: LRUList<CacheKey>,
+    low: LRUList<CacheKey>,
+    high_cap: usize,
+    high_usage: usize,
+
+    // Used by CachePolicy::Clock.
+    slots: Vec<Option<ClockSlot>>,
+    free_slots: Vec<usize>,
+    hand: usize,
+}
+
+impl<T> CacheShard<T> {
+    fn new(cap: usize, policy: CachePolicy) -> CacheShard<T> {
+        let high_cap = match policy {
+            CachePolicy::LRU {
+                high_pri_pool_ratio,
+            } => (cap as f64 * high_pri_pool_ratio) as usize,
+            CachePolicy::Clock => 0,
+        };
+        CacheShard {
+            map: HashMap::with_capacity(1024),
+            policy,
+            cap,
+            usage: 0,
+            high: LRUList::new(),
+            low: LRUList::new(),
+            high_cap,
+            high_usage: 0,
+            slots: vec![],
+            free_slots: vec![],
+            hand: 0,
+        }
+    }
+
+    fn is_clock(&self) -> bool {
+        self.policy == CachePolicy::Clock
+    }
+
+    fn insert(&mut self, key: &CacheKey, elem: T, charge: usize, prio: Priority) {
+        self.remove(key);
+        // An element larger than the shard would evict everything only to be evicted next.
+        if charge > self.cap {
+            return;
+        }
+        while self.usage + charge > self.cap {
+            if !self.evict() {
+                panic!("could not evict(); bug!");
+            }
+        }
+
+        let pos = if self.is_clock() {
+            let slot = ClockSlot {
+                key: key.clone(),
+                countdown: if prio == Priority::High { 2 } else { 0 },
+            };
+            if let Some(i) = self.free_slots.pop() {
+                self.slots[i] = Some(slot);
+                Position::Clock(i)
+            } else {
+                self.slots.push(Some(slot));
+                Position::Clock(self.slots.len() - 1)
+            }
+        } else if prio == Priority::High {
+            self.high_usage += charge;
+            Position::High(self.high.insert(key.clone()))
+        } else {
+            Position::Low(self.low.insert(key.clone()))
+        };
+        self.usage += charge;
+        self.map.insert(key.clone(), CacheEntry { elem, pos, charge });
+        self.balance_pools();
+    }
+
+    /// Moves the least recently used elements of the high-priority pool to the head of the
+    /// low-priority pool while the former exceeds its capacity.
+    fn balance_pools(&mut self) {
+        while self.high_usage > self.high_cap {
+            if let Some(key) = self.high.remove_last() {
+                let handle = self.low.insert(key);
+                let entry = self.map.get_mut(&key).unwrap();
+                entry.pos = Position::Low(handle);
+                self.high_usage -= entry.charge;
+            } else {
+                panic!("could not remove_last(); bug!");
+            }
+        }
+    }
+
+    /// Evicts one element, returning false if the shard is empty.
+    fn evict(&mut self) -> bool {
+        if self.map.is_empty() {
+            return false;
+        }
+        let key = if self.is_clock() {
+            loop {
+                let i = self.hand;
+                self.hand = (self.hand + 1) % self.slots.len();
+                match self.slots[i] {
+                    Some(ref mut slot) if slot.countdown > 0 => slot.countdown -= 1,
+                    Some(ref slot) => break slot.key,
+                    None => (),
+                }
+            }
+        } else if let Some(key) = self.low.remove_last() {
+            key
+        } else if let Some(key) = self.high.remove_last() {
+            key
+        } else {
+            panic!("could not remove_last(); bug!");
+        };
+
+        let entry = self.map.remove(&key).unwrap();
+        self.usage -= entry.charge;
+        match entry.pos {
+            Position::High(_) => self.high_usage -= entry.charge,
+            Position::Clock(i) => {
+                self.slots[i] = None;
+                self.free_slots.push(i);
+            }
+            Position::Low(_) => (),
+        }
+        true
+    }
+
+    fn get<'a>(&'a mut self, key: &CacheKey) -> Option<&'a T> {
+        let mut promoted = false;
+        match self.map.get_mut(key) {
+            None => return None,
+            Some(entry) => match entry.pos {
+                Position::High(handle) => self.high.reinsert_front(handle),
+                Position::Low(handle) if self.high_cap > 0 => {
+                    self.low.remove(handle);
+                    entry.pos = Position::High(self.high.insert(key.clone()));
+                    self.high_usage += entry.charge;
+                    promoted = true;
+                }
+                Position::Low(handle) => self.low.reinsert_front(handle),
+                Position::Clock(i) => {
+                    let slot = self.slots[i].as_mut().unwrap();
+                    slot.countdown = MAX_CLOCK_COUNTDOWN.min(slot.countdown + 1);
+                }
+            },
+        }
+        if promoted {
+            self.balance_pools();
+        }
+        self.map.get(key).map(|entry| &entry.elem)
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> Option<T> {
+        match self.map.remove(key) {
+            None => None,
+            Some(entry) => {
+                match entry.pos {
+                    Position::High(handle) => {
+                        self.high.remove(handle);
+                        self.high_usage -= entry.charge;
+                    }
+                    Position::Low(handle) => {
+                        self.low.remove(handle);
+                    }
+                    Position::Clock(i) => {
+                        self.slots[i] = None;
+                        self.free_slots.push(i);
+                    }
+                }
+                self.usage -= entry.charge;
+                Some(entry.elem)
+            }
+        }
+    }
+}
+
+/// Implementation of `ShardedLRUCache`, with a configurable eviction policy.
+/// The capacity limits the total charge of the elements, which is e.g. their size in bytes, or
+/// 1 for elements inserted with `insert()`. The elements are distributed over shards by the hash
+/// of their key, and each shard evicts elements once its share of the capacity is exceeded.
+pub struct Cache<T> {
+    shards: Vec<CacheShard<T>>,
+    shard_bits: usize,
     id: u64,
+    hits: u64,
+    misses: u64,
 }
 
 impl<T> Cache<T> {
+    /// Returns an unsharded LRU cache.
     pub fn new(capacity: usize) -> Cache<T> {
+        Cache::new_sharded(capacity, 0)
+    }
+
+    /// Returns an LRU cache with 2^`shard_bits` shards.
+    pub fn new_sharded(capacity: usize, shard_bits: usize) -> Cache<T> {
+        Cache::new_with_policy(
+            capacity,
+            shard_bits,
+            CachePolicy::LRU {
+                high_pri_pool_ratio: 0.,
+            },
+        )
+    }
+
+    /// Returns a cache with 2^`shard_bits` shards that evicts elements according to `policy`.
+    pub fn new_with_policy(capacity: usize, shard_bits: usize, policy: CachePolicy) -> Cache<T> {
         assert!(capacity > 0);
+        assert!(shard_bits < 16);
+        let num_shards = 1 << shard_bits;
+        let shard_cap = (capacity + num_shards - 1) / num_shards;
         Cache {
-            list: LRUList::new(),
-            map: HashMap::with_capacity(1024),
-            cap: capacity,
+            shards: (0..num_shards)
+                .map(|_| CacheShard::new(shard_cap, policy))
+                .collect(),
+            shard_bits,
             id: 0,
+            hits: 0,
+            misses: 0,
         }
     }
 
+    fn shard(&self, key: &CacheKey) -> usize {
+        if self.shard_bits == 0 {
+            return 0;
+        }
+        (hash(key, 0) >> (32 - self.shard_bits)) as usize
+    }
+
     /// Returns an ID that is unique for this cache and that can be used to partition the cache
     /// among several users.
     pub fn new_cache_id(&mut self) -> CacheID {
@@ -191,52 +458,81 @@ impl<T> Cache<T> {
 
     /// How many the cache currently contains
     pub fn count(&self) -> usize {
-        return self.list.count();
+        self.shards.iter().map(|s| s.map.len()).sum()
     }
 
     /// The capacity of this cache
     pub fn cap(&self) -> usize {
-        return self.cap;
+        self.shards.iter().map(|s| s.cap).sum()
+    }
+
+    /// The total charge of the elements in the cache.
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|s| s.usage).sum()
     }
 
-    /// Insert a new element into the cache. The returned `CacheHandle` can be used for further
-    /// operations on that element.
+    /// The fraction of lookups with get() that found an element, or 0 if there were none.
+    pub fn hit_ratio(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            return 0.;
+        }
+        self.hits as f64 / (self.hits + self.misses) as f64
+    }
+
+    /// Insert a new element with a charge of 1 into the cache.
     /// If the capacity has been reached, the least recently used element is removed from the
     /// cache.
     pub fn insert(&mut self, key: &CacheKey, elem: T) {
-        if self.list.count() >= self.cap {
-            if let Some(removed_key) = self.list.remove_last() {
-                assert!(self.map.remove(&removed_key).is_some());
-            } else {
-                panic!("could not remove_last(); bug!");
-            }
-        }
+        self.insert_with_charge(key, elem, 1)
+    }
 
-        let lru_handle = self.list.insert(key.clone());
-        self.map.insert(key.clone(), (elem, lru_handle));
+    /// Insert a new element of low priority into the cache, evicting elements of its shard until
+    /// its `charge` fits. Elements with a charge larger than a shard aren't cached.
+    pub fn insert_with_charge(&mut self, key: &CacheKey, elem: T, charge: usize) {
+        self.insert_with_priority(key, elem, charge, Priority::Low)
+    }
+
+    /// Like insert_with_charge(), for an element of the given priority.
+    pub fn insert_with_priority(
+        &mut self,
+        key: &CacheKey,
+        elem: T,
+        charge: usize,
+        prio: Priority,
+    ) {
+        let shard = self.shard(key);
+        self.shards[shard].insert(key, elem, charge, prio)
     }
 
     /// Retrieve an element from the cache.
     /// If the element has been preempted from the cache in the meantime, this returns None.
     pub fn get<'a>(&'a mut self, key: &CacheKey) -> Option<&'a T> {
-        match self.map.get(key) {
-            None => None,
-            Some(&(ref elem, ref lru_handle)) => {
-                self.list.reinsert_front(*lru_handle);
-                Some(elem)
-            }
+        let shard = self.shard(key);
+        let elem = self.shards[shard].get(key);
+        if elem.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
         }
+        elem
     }
 
     /// Remove an element from the cache (for invalidation).
     pub fn remove(&mut self, key: &CacheKey) -> Option<T> {
-        match self.map.remove(key) {
-            None => None,
-            Some((elem, lru_handle)) => {
-                self.list.remove(lru_handle);
-                Some(elem)
-            }
-        }
+        let shard = self.shard(key);
+        self.shards[shard].remove(key)
+    }
+}
+
+impl<T: Pinnable> Cache<T> {
+    /// The total charge of the elements that are in use outside of the cache.
+    pub fn pinned_usage(&self) -> usize {
+        self.shards
+            .iter()
+            .flat_map(|s| s.map.values())
+            .filter(|e| e.elem.is_pinned())
+            .map(|e| e.charge)
+            .sum()
     }
 }
 
@@ -245,6 +541,8 @@ mod tests {
     use super::LRUList;
     use super::*;
 
+    use std::rc::Rc;
+
     fn make_key(a: u8, b: u8, c: u8) -> CacheKey {
         [a, b, c, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
     }
@@ -302,6 +600,180 @@ mod tests {
         assert_eq!(cache.get(&h_899), Some(&899));
     }
 
+    #[test]
+    fn test_blockcache_cache_charge() {
+        let mut cache = Cache::new(100);
+
+        cache.insert_with_charge(&make_key(1, 0, 0), 1, 40);
+        cache.insert_with_charge(&make_key(2, 0, 0), 2, 40);
+        assert_eq!(cache.usage(), 80);
+        cache.get(&make_key(1, 0, 0));
+        // Evicts the least recently used element.
+        cache.insert_with_charge(&make_key(3, 0, 0), 3, 30);
+        assert_eq!(cache.usage(), 70);
+        assert_eq!(cache.get(&make_key(2, 0, 0)), None);
+        // Replacing an element replaces its charge.
+        cache.insert_with_charge(&make_key(3, 0, 0), 4, 10);
+        assert_eq!(cache.usage(), 50);
+        assert_eq!(cache.get(&make_key(3, 0, 0)), Some(&4));
+        // Too large elements aren't cached.
+        cache.insert_with_charge(&make_key(5, 0, 0), 5, 101);
+        assert_eq!(cache.get(&make_key(5, 0, 0)), None);
+        assert_eq!(cache.usage(), 50);
+        assert_eq!(cache.remove(&make_key(1, 0, 0)), Some(1));
+        assert_eq!(cache.usage(), 10);
+
+        assert_eq!(cache.hit_ratio(), 2. / 4.);
+    }
+
+    #[test]
+    fn test_blockcache_cache_sharded() {
+        let mut cache = Cache::new_sharded(3200, 4);
+        assert_eq!(cache.cap(), 3200);
+        assert_eq!(cache.hit_ratio(), 0.);
+
+        for i in 0..100 {
+            cache.insert_with_charge(&make_key(i, i, i), i, 10);
+        }
+        assert_eq!(cache.count(), 100);
+        assert_eq!(cache.usage(), 1000);
+        assert!(cache.shards.iter().all(|s| s.usage < 1000));
+        for i in 0..100 {
+            assert_eq!(cache.get(&make_key(i, i, i)), Some(&i));
+        }
+        // Each shard holds at most 200.
+        for i in 100..255 {
+            cache.insert_with_charge(&make_key(i, i, i), i, 10);
+        }
+        assert!(cache.count() < 255);
+        assert!(cache.shards.iter().all(|s| s.usage <= 200));
+    }
+
+    // Inserts 20 hot elements which are hit `hits` times each, then scans over 100 other
+    // elements. Returns the number of hot elements that survived the scan.
+    fn scan(policy: CachePolicy, hits: usize) -> usize {
+        let mut cache = Cache::new_with_policy(100, 0, policy);
+        for i in 0..20 {
+            cache.insert(&make_key(0, i, 0), i);
+        }
+        for _ in 0..hits {
+            for i in 0..20 {
+                assert_eq!(cache.get(&make_key(0, i, 0)), Some(&i));
+            }
+        }
+        for i in 0..100 {
+            cache.insert(&make_key(1, i, 0), i);
+            assert!(cache.usage() <= 100);
+        }
+        assert_eq!(cache.count(), 100);
+        (0..20)
+            .filter(|&i| cache.get(&make_key(0, i, 0)).is_some())
+            .count()
+    }
+
+    #[test]
+    fn test_blockcache_cache_scan_resistance() {
+        let plain = CachePolicy::LRU {
+            high_pri_pool_ratio: 0.,
+        };
+        let midpoint = CachePolicy::LRU {
+            high_pri_pool_ratio: 0.5,
+        };
+        assert_eq!(scan(plain, 1), 0);
+        assert_eq!(scan(midpoint, 0), 0);
+        assert_eq!(scan(midpoint, 1), 20);
+        assert_eq!(scan(CachePolicy::Clock, 0), 0);
+        assert_eq!(scan(CachePolicy::Clock, 3), 20);
+    }
+
+    #[test]
+    fn test_blockcache_cache_priority() {
+        for &policy in [
+            CachePolicy::LRU {
+                high_pri_pool_ratio: 0.5,
+            },
+            CachePolicy::Clock,
+        ]
+        .iter()
+        {
+            let mut cache = Cache::new_with_policy(10, 0, policy);
+            cache.insert_with_priority(&make_key(0, 0, 0), 0, 2, Priority::High);
+            for i in 1..10 {
+                cache.insert_with_charge(&make_key(0, i, 0), i, 2);
+            }
+            assert_eq!(cache.usage(), 10);
+            assert_eq!(cache.get(&make_key(0, 0, 0)), Some(&0));
+            assert_eq!(cache.remove(&make_key(0, 0, 0)), Some(0));
+            assert_eq!(cache.usage(), 8);
+            assert_eq!(cache.get(&make_key(0, 0, 0)), None);
+        }
+
+        // The high-priority pool overflows into the low-priority pool.
+        let mut cache = Cache::new_with_policy(
+            10,
+            0,
+            CachePolicy::LRU {
+                high_pri_pool_ratio: 0.2,
+            },
+        );
+        for i in 0..5 {
+            cache.insert_with_priority(&make_key(0, i, 0), i, 1, Priority::High);
+        }
+        assert_eq!(cache.shards[0].high_usage, 2);
+        for i in 5..10 {
+            cache.insert(&make_key(0, i, 0), i);
+        }
+        // The oldest element moved out of the high-priority pool is evicted first.
+        cache.insert(&make_key(0, 10, 0), 10);
+        assert_eq!(cache.get(&make_key(0, 0, 0)), None);
+        assert_eq!(cache.get(&make_key(0, 1, 0)), Some(&1));
+        assert_eq!(cache.get(&make_key(0, 3, 0)), Some(&3));
+    }
+
+    #[test]
+    fn test_blockcache_cache_promote_and_evict() {
+        // Hits move elements from the low-priority to the high-priority pool, which removes them
+        // from anywhere in the low-priority list, including its tail.
+        let mut shard = CacheShard::new(
+            8,
+            CachePolicy::LRU {
+                high_pri_pool_ratio: 0.5,
+            },
+        );
+        for i in 0..64u8 {
+            shard.insert(&make_key(0, i, 0), i, 1, Priority::Low);
+            let oldest = i.saturating_sub(7);
+            for j in (oldest..=i).step_by(3) {
+                shard.get(&make_key(0, j, 0));
+            }
+            let len = (i as usize + 1).min(8);
+            assert_eq!(shard.map.len(), len);
+            assert_eq!(shard.usage, len);
+            assert_eq!(shard.high.count() + shard.low.count(), len);
+            assert_eq!(shard.high.count(), shard.high_usage);
+            assert!(shard.high_usage <= shard.high_cap);
+        }
+    }
+
+    #[test]
+    fn test_blockcache_cache_pinned_usage() {
+        struct Elem(Rc<usize>);
+        impl Pinnable for Elem {
+            fn is_pinned(&self) -> bool {
+                Rc::strong_count(&self.0) > 1
+            }
+        }
+
+        let mut cache = Cache::new(100);
+        let pinned = Rc::new(1);
+        cache.insert_with_charge(&make_key(1, 0, 0), Elem(pinned.clone()), 10);
+        cache.insert_with_charge(&make_key(2, 0, 0), Elem(Rc::new(2)), 20);
+        assert_eq!(cache.usage(), 30);
+        assert_eq!(cache.pinned_usage(), 10);
+        drop(pinned);
+        assert_eq!(cache.pinned_usage(), 0);
+    }
+
     #[test]
     fn test_blockcache_lru_remove() {
         let mut lru = LRUList::<usize>::new();
@@ -320,6 +792,14 @@ mod tests {
         assert_eq!(lru.count(), 4);
         assert_eq!(56, lru.remove(h_56));
         assert_eq!(lru.count(), 3);
+
+        // The remaining nodes are still linked in order.
+        assert_eq!(lru._testing_head_ref(), Some(&1111));
+        assert_eq!(lru.remove_last(), Some(22));
+        assert_eq!(lru.remove_last(), Some(223));
+        assert_eq!(lru.remove_last(), Some(1111));
+        assert_eq!(lru.remove_last(), None);
+        assert_eq!(lru.count(), 0);
     }
 
     #[test]
@@ -23,7 +23,18 @@ pub trait Cmp {
 
     /// A unique identifier for a comparator. A comparator wrapper (like InternalKeyCmp) may
     /// return the id of its inner comparator.
-    fn id(&self) -> &'static str;
+    fn id(&self) -> &str;
+
+    /// The length of the user timestamp at the end of every key, or 0 if keys don't carry
+    /// timestamps (see `TimestampCmp`).
+    fn timestamp_size(&self) -> usize {
+        0
+    }
+
+    /// Compare two keys without their timestamps, i.e. ignoring which version of a key they are.
+    fn cmp_without_ts(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.cmp(a, b)
+    }
 }
 
 /// The default byte-wise comparator.
@@ -64,6 +75,11 @@ impl Cmp for DefaultCmp {
             diff_at += 1;
         }
 
+        // The empty key is a prefix of b, and so a valid separator.
+        if a.is_empty() {
+            return a.to_vec();
+        }
+
         let mut sep = Vec::with_capacity(a.len() + 1);
         sep.extend_from_slice(a);
         // Try increasing a and check if it's still smaller than b. First find the last byte
@@ -86,9 +102,13 @@ impl Cmp for DefaultCmp {
         // characters apart.
         // The result is not necessarily short, but a good separator: e.g., "abc" vs "abd" ->
         // "abc\0", which is greater than abc and lesser than abd.
-        // Append a 0 byte; by making it longer than a, it will compare greater to it.
+        // Append a 0 byte; by making it longer than a, it will compare greater to it. If that is b
+        // (e.g. "a" vs "a\0"), there is no key between a and b; a itself is a valid separator.
         sep.extend_from_slice(&[0]);
-        return sep;
+        if self.cmp(&sep, b) == Ordering::Less {
+            return sep;
+        }
+        return a.to_vec();
     }
 
     fn find_short_succ(&self, a: &[u8]) -> Vec<u8> {
@@ -106,6 +126,215 @@ impl Cmp for DefaultCmp {
     }
 }
 
+/// A byte-wise comparator ordering keys in reverse, e.g. for newest-first time-series keys.
+#[derive(Clone)]
+pub struct ReverseBytewiseCmp;
+
+impl Cmp for ReverseBytewiseCmp {
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn id(&self) -> &'static str {
+        "leveldb.ReverseBytewiseComparator"
+    }
+
+    fn find_shortest_sep(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
+        // a is bytewise greater than b, and so are all prefixes of a that differ from b.
+        let mut diff_at = 0;
+        while diff_at < a.len() && diff_at < b.len() && a[diff_at] == b[diff_at] {
+            diff_at += 1;
+        }
+        if diff_at >= a.len() {
+            return a.to_vec();
+        }
+        a[0..diff_at + 1].to_vec()
+    }
+
+    fn find_short_succ(&self, _: &[u8]) -> Vec<u8> {
+        // The empty key is the greatest key.
+        vec![]
+    }
+}
+
+/// A comparator for keys that are fixed-width big-endian unsigned integers, like u64 ids. Keys are
+/// ordered by length first and then bytewise, which orders integers of the same width
+/// numerically.
+#[derive(Clone)]
+pub struct U64Cmp;
+
+impl Cmp for U64Cmp {
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+    }
+
+    fn id(&self) -> &'static str {
+        "leveldb.Uint64Comparator"
+    }
+
+    // There are no shorter keys between two integers of the same width.
+    fn find_shortest_sep(&self, a: &[u8], _: &[u8]) -> Vec<u8> {
+        a.to_vec()
+    }
+
+    fn find_short_succ(&self, a: &[u8]) -> Vec<u8> {
+        a.to_vec()
+    }
+}
+
+/// A comparator for keys consisting of a prefix of fixed length, ordered by one comparator, and
+/// a suffix ordered by another one. For example, time-series keys of a big-endian u64 series id
+/// and a timestamp can be ordered by id and newest-first by
+/// `CompositeCmp::new("ts", 8, Rc::new(Box::new(U64Cmp)), Rc::new(Box::new(ReverseBytewiseCmp)))`.
+/// Keys shorter than the prefix have an empty suffix.
+#[derive(Clone)]
+pub struct CompositeCmp {
+    name: &'static str,
+    prefix_len: usize,
+    prefix: WrappedCmp,
+    suffix: WrappedCmp,
+}
+
+impl CompositeCmp {
+    /// Returns a comparator with the id `name`, which must be unique for each combination of
+    /// prefix length and comparators.
+    pub fn new(
+        name: &'static str,
+        prefix_len: usize,
+        prefix: Rc<Box<dyn Cmp>>,
+        suffix: Rc<Box<dyn Cmp>>,
+    ) -> CompositeCmp {
+        CompositeCmp {
+            name,
+            prefix_len,
+            prefix,
+            suffix,
+        }
+    }
+
+    fn split<'a>(&self, key: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        key.split_at(self.prefix_len.min(key.len()))
+    }
+}
+
+impl Cmp for CompositeCmp {
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let ((pa, sa), (pb, sb)) = (self.split(a), self.split(b));
+        self.prefix
+            .cmp(pa, pb)
+            .then_with(|| self.suffix.cmp(sa, sb))
+    }
+
+    fn id(&self) -> &'static str {
+        self.name
+    }
+
+    fn find_shortest_sep(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
+        let ((pa, sa), (pb, sb)) = (self.split(a), self.split(b));
+        if self.prefix.cmp(pa, pb) == Ordering::Equal {
+            if pa.len() < self.prefix_len {
+                return a.to_vec();
+            }
+            let mut sep = pa.to_vec();
+            sep.extend_from_slice(&self.suffix.find_shortest_sep(sa, sb));
+            return sep;
+        }
+        // The separator of the prefixes has an empty suffix, so it has to be greater than pa. A
+        // separator longer than the prefix would be split differently.
+        let sep = self.prefix.find_shortest_sep(pa, pb);
+        if sep.len() <= self.prefix_len && self.prefix.cmp(&sep, pa) == Ordering::Greater {
+            return sep;
+        }
+        a.to_vec()
+    }
+
+    fn find_short_succ(&self, a: &[u8]) -> Vec<u8> {
+        let (pa, sa) = self.split(a);
+        // The successor of the prefix has an empty suffix, so it has to be greater than pa.
+        let succ = self.prefix.find_short_succ(pa);
+        if succ.len() <= self.prefix_len && self.prefix.cmp(&succ, pa) == Ordering::Greater {
+            return succ;
+        }
+        if pa.len() < self.prefix_len {
+            return a.to_vec();
+        }
+        let mut succ = pa.to_vec();
+        succ.extend_from_slice(&self.suffix.find_short_succ(sa));
+        succ
+    }
+}
+
+/// A comparator for keys ending in a fixed-size user timestamp, which is compared bytewise (e.g. a
+/// big-endian u64). Keys are ordered by the wrapped comparator first and then newest-first by
+/// timestamp, so that the first entry at or after `key || ts` is the newest version of `key` not
+/// newer than `ts`. The DB uses this comparator when `Options::timestamp_size` is set.
+#[derive(Clone)]
+pub struct TimestampCmp {
+    cmp: WrappedCmp,
+    ts_size: usize,
+    name: String,
+}
+
+impl TimestampCmp {
+    /// Returns a TimestampCmp for timestamps of `ts_size` bytes. Its id is the id of `cmp` with
+    /// the timestamp width appended (e.g. "leveldb.BytewiseComparator.u64ts"), so that a database
+    /// can't be opened with a different timestamp size.
+    pub fn new(cmp: Rc<Box<dyn Cmp>>, ts_size: usize) -> TimestampCmp {
+        let name = format!("{}.u{}ts", cmp.id(), ts_size * 8);
+        TimestampCmp { cmp, ts_size, name }
+    }
+
+    /// Splits a key into the user key and the timestamp. Keys shorter than a timestamp have an
+    /// empty user key.
+    pub fn split<'a>(&self, key: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        key.split_at(key.len().saturating_sub(self.ts_size))
+    }
+
+    /// Appends the greatest timestamp, which sorts first, to `key`.
+    fn with_max_ts(&self, mut key: Vec<u8>) -> Vec<u8> {
+        key.resize(key.len() + self.ts_size, 0xff);
+        key
+    }
+}
+
+impl Cmp for TimestampCmp {
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let ((ka, tsa), (kb, tsb)) = (self.split(a), self.split(b));
+        self.cmp.cmp(ka, kb).then_with(|| tsb.cmp(tsa))
+    }
+
+    fn id(&self) -> &str {
+        &self.name
+    }
+
+    fn timestamp_size(&self) -> usize {
+        self.ts_size
+    }
+
+    fn cmp_without_ts(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.cmp.cmp(self.split(a).0, self.split(b).0)
+    }
+
+    fn find_shortest_sep(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
+        let ((ka, _), (kb, _)) = (self.split(a), self.split(b));
+        let sep = self.cmp.find_shortest_sep(ka, kb);
+        // With the greatest timestamp, sep sorts before all versions of itself.
+        if self.cmp.cmp(ka, &sep) == Ordering::Less && self.cmp.cmp(&sep, kb) == Ordering::Less {
+            return self.with_max_ts(sep);
+        }
+        a.to_vec()
+    }
+
+    fn find_short_succ(&self, a: &[u8]) -> Vec<u8> {
+        let ka = self.split(a).0;
+        let succ = self.cmp.find_short_succ(ka);
+        if self.cmp.cmp(ka, &succ) == Ordering::Less {
+            return self.with_max_ts(succ);
+        }
+        a.to_vec()
+    }
+}
+
 /// Same as memtable_key_cmp, but for InternalKeys.
 #[derive(Clone)]
 pub struct InternalKeyCmp(pub Rc<Box<dyn Cmp>>);
@@ -115,10 +344,14 @@ impl Cmp for InternalKeyCmp {
         key_types::cmp_internal_key(self.0.as_ref().as_ref(), a, b)
     }
 
-    fn id(&self) -> &'static str {
+    fn id(&self) -> &str {
         self.0.id()
     }
 
+    fn timestamp_size(&self) -> usize {
+        self.0.timestamp_size()
+    }
+
     fn find_shortest_sep(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
         if a == b {
             return a.to_vec();
@@ -149,6 +382,11 @@ impl InternalKeyCmp {
     pub fn cmp_inner(&self, a: &[u8], b: &[u8]) -> Ordering {
         self.0.cmp(a, b)
     }
+
+    /// cmp_inner_without_ts compares the user keys a and b ignoring their timestamps.
+    pub fn cmp_inner_without_ts(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.0.cmp_without_ts(a, b)
+    }
 }
 
 /// An internal comparator wrapping a user-supplied comparator. This comparator is used to compare
@@ -164,10 +402,14 @@ impl Cmp for MemtableKeyCmp {
         key_types::cmp_memtable_key(self.0.as_ref().as_ref(), a, b)
     }
 
-    fn id(&self) -> &'static str {
+    fn id(&self) -> &str {
         self.0.id()
     }
 
+    fn timestamp_size(&self) -> usize {
+        self.0.timestamp_size()
+    }
+
     // The following two impls should not be used (by principle) although they should be correct.
     // They will crash the program.
     fn find_shortest_sep(&self, _: &[u8], _: &[u8]) -> Vec<u8> {
@@ -219,6 +461,19 @@ mod tests {
             DefaultCmp.find_shortest_sep("".as_bytes(), "".as_bytes()),
             "".as_bytes()
         );
+        // There is no key between a and a + "\0", and "" can't be incremented.
+        assert_eq!(
+            DefaultCmp.find_shortest_sep("a".as_bytes(), "a\0".as_bytes()),
+            "a".as_bytes()
+        );
+        assert_eq!(
+            DefaultCmp.find_shortest_sep("".as_bytes(), "a".as_bytes()),
+            "".as_bytes()
+        );
+        assert_eq!(
+            DefaultCmp.find_shortest_sep("ab".as_bytes(), "abc".as_bytes()),
+            "ab\0".as_bytes()
+        );
     }
 
     #[test]
@@ -310,6 +565,142 @@ mod tests {
         assert_eq!(Ordering::Greater, cmp.cmp_inner(e, d));
     }
 
+    /// Checks that find_shortest_sep() and find_short_succ() of `cmp` return valid keys for all
+    /// pairs of `keys`.
+    fn check_sep_and_succ(cmp: &dyn Cmp, mut keys: Vec<Vec<u8>>) {
+        keys.sort_by(|a, b| cmp.cmp(a, b));
+        keys.dedup();
+        for (i, a) in keys.iter().enumerate() {
+            let succ = cmp.find_short_succ(a);
+            assert!(cmp.cmp(a, &succ) != Ordering::Greater, "{:?} {:?}", a, succ);
+            for b in keys[i + 1..].iter() {
+                let sep = cmp.find_shortest_sep(a, b);
+                assert!(cmp.cmp(a, &sep) != Ordering::Greater, "{:?} {:?} {:?}", a, b, sep);
+                assert_eq!(Ordering::Less, cmp.cmp(&sep, b), "{:?} {:?} {:?}", a, b, sep);
+            }
+        }
+    }
+
+    fn u64_key(v: u64) -> Vec<u8> {
+        (0..8).rev().map(|i| (v >> (8 * i)) as u8).collect()
+    }
+
+    #[test]
+    fn test_cmp_reversebytewisecmp() {
+        let cmp = ReverseBytewiseCmp;
+        assert_eq!(Ordering::Greater, cmp.cmp(b"abc", b"abd"));
+        assert_eq!(Ordering::Less, cmp.cmp(b"abc", b"ab"));
+        assert_eq!(Ordering::Equal, cmp.cmp(b"abc", b"abc"));
+        assert_eq!(b"b".to_vec(), cmp.find_shortest_sep(b"bcd", b"abc"));
+        assert_eq!(b"abd".to_vec(), cmp.find_shortest_sep(b"abde", b"abc"));
+        assert_eq!(b"abcd".to_vec(), cmp.find_shortest_sep(b"abcd", b"abc"));
+        assert_eq!(b"abc".to_vec(), cmp.find_shortest_sep(b"abc", b"abc"));
+        assert!(cmp.find_short_succ(b"abc").is_empty());
+
+        let keys = ["", "a", "ab", "abc", "abd", "b", "\x7f", "\x7f\x7f", "zz"];
+        check_sep_and_succ(&cmp, keys.iter().map(|k| k.as_bytes().to_vec()).collect());
+    }
+
+    #[test]
+    fn test_cmp_u64cmp() {
+        let cmp = U64Cmp;
+        assert_eq!(Ordering::Less, cmp.cmp(&u64_key(1), &u64_key(256)));
+        assert_eq!(Ordering::Greater, cmp.cmp(&u64_key(1 << 63), &u64_key(1 << 62)));
+        assert_eq!(Ordering::Equal, cmp.cmp(&u64_key(7), &u64_key(7)));
+        // Keys of other widths sort by length.
+        assert_eq!(Ordering::Less, cmp.cmp(b"\xff", &u64_key(0)));
+
+        let keys = [0, 1, 2, 255, 256, 1 << 32, u64::max_value()];
+        check_sep_and_succ(&cmp, keys.iter().map(|&k| u64_key(k)).collect());
+    }
+
+    #[test]
+    fn test_cmp_compositecmp() {
+        let cmp = CompositeCmp::new(
+            "test.IdTimestampComparator",
+            8,
+            Rc::new(Box::new(U64Cmp)),
+            Rc::new(Box::new(ReverseBytewiseCmp)),
+        );
+        assert_eq!("test.IdTimestampComparator", cmp.id());
+        let key = |id: u64, ts: &str| {
+            let mut k = u64_key(id);
+            k.extend_from_slice(ts.as_bytes());
+            k
+        };
+        // Ordered by id, then newest-first.
+        assert_eq!(Ordering::Less, cmp.cmp(&key(1, "2021"), &key(2, "2020")));
+        assert_eq!(Ordering::Less, cmp.cmp(&key(1, "2021"), &key(1, "2020")));
+        assert_eq!(Ordering::Equal, cmp.cmp(&key(1, "2021"), &key(1, "2021")));
+        assert_eq!(key(1, "2021"), cmp.find_shortest_sep(&key(1, "2021"), &key(2, "2020")));
+        assert_eq!(key(1, "2021"), cmp.find_shortest_sep(&key(1, "20210"), &key(1, "2020")));
+
+        let mut keys = vec![];
+        for &id in [0, 1, 255, 256, u64::max_value()].iter() {
+            for ts in ["", "2020", "2021", "20210", "2022"].iter() {
+                keys.push(key(id, ts));
+            }
+        }
+        check_sep_and_succ(&cmp, keys.clone());
+
+        let cmp = CompositeCmp::new(
+            "test.PrefixComparator",
+            2,
+            Rc::new(Box::new(DefaultCmp)),
+            Rc::new(Box::new(DefaultCmp)),
+        );
+        assert_eq!(b"b".to_vec(), cmp.find_shortest_sep(b"abx", b"cd"));
+        assert_eq!(b"b".to_vec(), cmp.find_short_succ(b"abx"));
+        let keys = ["", "a", "ab", "abc", "abd", "ac", "b", "b\0", "\x7f\x7f", "\x7f\x7fa", "zz"];
+        check_sep_and_succ(&cmp, keys.iter().map(|k| k.as_bytes().to_vec()).collect());
+        check_sep_and_succ(&DefaultCmp, keys.iter().map(|k| k.as_bytes().to_vec()).collect());
+    }
+
+    #[test]
+    fn test_cmp_timestampcmp() {
+        let cmp = TimestampCmp::new(Rc::new(Box::new(DefaultCmp)), 8);
+        assert_eq!("leveldb.BytewiseComparator.u64ts", cmp.id());
+        assert_eq!(8, cmp.timestamp_size());
+        let key = |k: &str, ts: u64| {
+            let mut key = k.as_bytes().to_vec();
+            key.extend_from_slice(&u64_key(ts));
+            key
+        };
+        // Ordered by key, then newest-first.
+        assert_eq!(Ordering::Less, cmp.cmp(&key("abc", 1), &key("abd", 2)));
+        assert_eq!(Ordering::Less, cmp.cmp(&key("abc", 2), &key("abc", 1)));
+        assert_eq!(Ordering::Less, cmp.cmp(&key("ab", 1), &key("abc", 2)));
+        assert_eq!(Ordering::Equal, cmp.cmp_without_ts(&key("abc", 2), &key("abc", 1)));
+        assert_eq!(Ordering::Less, cmp.cmp_without_ts(&key("abc", 2), &key("abd", 1)));
+        assert_eq!((&b"abc"[..], &u64_key(7)[..]), cmp.split(&key("abc", 7)));
+        assert_eq!(
+            key("abd", u64::max_value()),
+            cmp.find_shortest_sep(&key("abcd", 1), &key("abf", 2))
+        );
+        assert_eq!(key("abc", 2), cmp.find_shortest_sep(&key("abc", 2), &key("abc", 1)));
+        assert_eq!(key("b", u64::max_value()), cmp.find_short_succ(&key("abc", 3)));
+
+        let mut keys = vec![];
+        for k in ["", "a", "ab", "abc", "abd", "b", "\x7f\x7f"].iter() {
+            for &ts in [0, 1, 2, 1 << 40, u64::max_value()].iter() {
+                keys.push(key(k, ts));
+            }
+        }
+        check_sep_and_succ(&cmp, keys.clone());
+
+        // InternalKeyCmp orders the versions of a key by timestamp before sequence numbers.
+        let icmp = InternalKeyCmp(Rc::new(Box::new(cmp)));
+        assert_eq!(8, icmp.timestamp_size());
+        assert_eq!(
+            Ordering::Less,
+            icmp.cmp(
+                LookupKey::new(&key("abc", 2), 1).internal_key(),
+                LookupKey::new(&key("abc", 1), 5).internal_key()
+            )
+        );
+        assert_eq!(Ordering::Equal, icmp.cmp_inner_without_ts(&key("abc", 2), &key("abc", 1)));
+    }
+
     #[test]
     #[should_panic]
     fn test_cmp_memtablekeycmp_panics() {
@@ -0,0 +1,230 @@
+//! compression implements the codecs table blocks can be compressed with.
+//!
+//! Snappy and Zstd blocks are stored as plain compressed buffers, as by LevelDB. LZ4 blocks are
+//! prefixed with the varint-encoded length of the uncompressed block, as by RocksDB, because
+//! the LZ4 block format doesn't record it.
+//!
+//! Zstd blocks may be compressed with a dictionary trained on the data blocks of their table;
+//! the dictionary is stored in the table's metaindex under DICT_META_KEY.
+//!
+//! Zstd and LZ4 are only available with the `zstd` and `lz4` features, as their implementations
+//! need std and C libraries. Without them, blocks compressed with these codecs can't be written
+//! or read (StatusCode::NotSupported).
+#[cfg(feature = "mesalock_sgx")]
+use std::prelude::v1::*;
+
+use error::{err, Result, StatusCode};
+use options::{CompressionType, Options};
+
+#[cfg(feature = "lz4")]
+use integer_encoding::{VarInt, VarIntReader, VarIntWriter};
+#[cfg(feature = "lz4")]
+use lz4::block::CompressionMode;
+use snap::{Decoder, Encoder};
+
+#[cfg(feature = "zstd")]
+use std::io::Read;
+
+pub const DICT_META_KEY: &str = "compression_dict";
+
+/// Returns whether blocks compressed with `ctype` can be written and read by this build.
+pub fn is_supported(ctype: CompressionType) -> bool {
+    match ctype {
+        CompressionType::CompressionNone | CompressionType::CompressionSnappy => true,
+        CompressionType::CompressionZstd => cfg!(feature = "zstd"),
+        CompressionType::CompressionLZ4 => cfg!(feature = "lz4"),
+    }
+}
+
+/// Returns a NotSupported error if `opt` selects a codec, for any level, that is not available in
+/// this build, so that this is noticed before the first table is written.
+pub fn check_options(opt: &Options) -> Result<()> {
+    let configured = opt
+        .compression_per_level
+        .iter()
+        .chain(opt.bottommost_compression.iter())
+        .chain(Some(&opt.compression_type));
+    for &ctype in configured {
+        if !is_supported(ctype) {
+            return err(
+                StatusCode::NotSupported,
+                &format!("{:?} is not enabled in this build", ctype),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Compresses `data` with `ctype`. `level` is only used by Zstd and LZ4; 0 selects the default
+/// level of the codec. `dict` is only used by Zstd.
+pub fn compress_block(
+    ctype: CompressionType,
+    level: i32,
+    dict: Option<&[u8]>,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    match ctype {
+        CompressionType::CompressionNone => Ok(data.to_vec()),
+        CompressionType::CompressionSnappy => Ok(Encoder::new().compress_vec(data)?),
+        CompressionType::CompressionZstd => zstd_compress(level, dict, data),
+        CompressionType::CompressionLZ4 => lz4_compress(level, data),
+    }
+}
+
+/// Decompresses a block that was compressed with `ctype` and `dict`.
+pub fn decompress_block(
+    ctype: CompressionType,
+    dict: Option<&[u8]>,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    match ctype {
+        CompressionType::CompressionNone => Ok(data.to_vec()),
+        CompressionType::CompressionSnappy => Ok(Decoder::new().decompress_vec(data)?),
+        CompressionType::CompressionZstd => zstd_decompress(dict, data),
+        CompressionType::CompressionLZ4 => lz4_decompress(data),
+    }
+}
+
+/// Trains a dictionary of at most `max_size` bytes on `samples`. This fails if there are too few
+/// samples, or if Zstd isn't available.
+#[cfg(feature = "zstd")]
+pub fn train_dictionary(samples: &[&[u8]], max_size: usize) -> Result<Vec<u8>> {
+    match zstd::dict::from_samples(samples, max_size) {
+        Ok(dict) => Ok(dict),
+        Err(e) => err(StatusCode::CompressionError, &e.to_string()),
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn train_dictionary(_: &[&[u8]], _: usize) -> Result<Vec<u8>> {
+    not_supported("Zstd")
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(level: i32, dict: Option<&[u8]>, data: &[u8]) -> Result<Vec<u8>> {
+    match dict {
+        Some(dict) => Ok(zstd::bulk::Compressor::with_dictionary(level, dict)?.compress(data)?),
+        None => Ok(zstd::bulk::compress(data, level)?),
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(dict: Option<&[u8]>, data: &[u8]) -> Result<Vec<u8>> {
+    match dict {
+        Some(dict) => {
+            let mut decoded = vec![];
+            zstd::stream::read::Decoder::with_dictionary(data, dict)?.read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+        None => Ok(zstd::stream::decode_all(data)?),
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_: i32, _: Option<&[u8]>, _: &[u8]) -> Result<Vec<u8>> {
+    not_supported("Zstd")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_: Option<&[u8]>, _: &[u8]) -> Result<Vec<u8>> {
+    not_supported("Zstd")
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_compress(level: i32, data: &[u8]) -> Result<Vec<u8>> {
+    let mode = if level > 0 {
+        Some(CompressionMode::HIGHCOMPRESSION(level))
+    } else {
+        None
+    };
+    let compressed = lz4::block::compress(data, mode, false)?;
+    let mut buf = Vec::with_capacity(data.len().required_space() + compressed.len());
+    buf.write_varint(data.len())?;
+    buf.extend_from_slice(&compressed);
+    Ok(buf)
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut src = data;
+    match src.read_varint::<usize>() {
+        Ok(len) if len <= i32::max_value() as usize => {
+            Ok(lz4::block::decompress(src, Some(len as i32))?)
+        }
+        _ => err(StatusCode::Corruption, "bad LZ4 block length"),
+    }
+}
+
+#[cfg(not(feature = "lz4"))]
+fn lz4_compress(_: i32, _: &[u8]) -> Result<Vec<u8>> {
+    not_supported("LZ4")
+}
+
+#[cfg(not(feature = "lz4"))]
+fn lz4_decompress(_: &[u8]) -> Result<Vec<u8>> {
+    not_supported("LZ4")
+}
+
+#[cfg(not(all(feature = "zstd", feature = "lz4")))]
+fn not_supported(codec: &str) -> Result<Vec<u8>> {
+    err(
+        StatusCode::NotSupported,
+        &format!("{} compression is not enabled in this build", codec),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_roundtrip() {
+        let data = b"abcdefghabcdefghabcdefghabcdefgh0123456789".repeat(20);
+        let types = [
+            CompressionType::CompressionNone,
+            CompressionType::CompressionSnappy,
+            CompressionType::CompressionZstd,
+            CompressionType::CompressionLZ4,
+        ];
+        for &ctype in types.iter() {
+            if !is_supported(ctype) {
+                let e = compress_block(ctype, 0, None, &data).unwrap_err();
+                assert_eq!(StatusCode::NotSupported, e.code);
+                assert!(decompress_block(ctype, None, &data).is_err());
+                continue;
+            }
+            for &level in [0, 1, 9].iter() {
+                let compressed = compress_block(ctype, level, None, &data).unwrap();
+                if ctype != CompressionType::CompressionNone {
+                    assert!(compressed.len() < data.len() / 4);
+                }
+                assert_eq!(data, decompress_block(ctype, None, &compressed).unwrap());
+            }
+        }
+        assert!(decompress_block(CompressionType::CompressionZstd, None, b"garbage").is_err());
+        assert!(decompress_block(CompressionType::CompressionLZ4, None, &[0xff]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_compression_dictionary() {
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("{{\"id\":{},\"name\":\"user{}\",\"active\":true}}", i, i * 7))
+            .map(|s| s.into_bytes())
+            .collect();
+        let refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
+        assert!(train_dictionary(&refs[..2], 1024).is_err());
+        let dict = train_dictionary(&refs, 1024).unwrap();
+        assert!(dict.len() <= 1024);
+
+        let ctype = CompressionType::CompressionZstd;
+        let plain = compress_block(ctype, 0, None, &samples[42]).unwrap();
+        let compressed = compress_block(ctype, 0, Some(&dict), &samples[42]).unwrap();
+        assert!(compressed.len() < plain.len());
+        assert_eq!(
+            samples[42],
+            decompress_block(ctype, Some(&dict), &compressed).unwrap()
+        );
+        assert!(decompress_block(ctype, None, &compressed).is_err());
+    }
+}
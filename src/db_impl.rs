@@ -8,24 +8,31 @@ use std::prelude::v1::*;
 #[cfg(feature = "mesalock_sgx")]
 use std::untrusted::path::PathEx;
 
-use db_iter::DBIterator;
+use db_iter::{DBIterator, RangeIter};
 
-use cmp::{Cmp, InternalKeyCmp};
+use blob_file::{blob_file_name, separate_value, BlobFileCache, BlobFileWriter, BlobIndex};
+use cmp::{Cmp, InternalKeyCmp, TimestampCmp};
+use compression;
 use env::{Env, FileLock};
 use error::{err, Result, StatusCode};
+use event_listener::{
+    CompactionJobInfo, EventListener, FlushJobInfo, TableFileCreationInfo, TableFileDeletionInfo,
+};
 use filter::{BoxedFilterPolicy, InternalFilterPolicy};
 use infolog::Logger;
-use key_types::{parse_internal_key, InternalKey, LookupKey, ValueType};
+use key_types::{parse_internal_key, set_value_type, InternalKey, LookupKey, ValueType};
 use log::{LogReader, LogWriter};
 use memtable::MemTable;
 use merging_iter::MergingIter;
-use options::Options;
+use options::{CompactionStyle, Options};
+use rate_limiter::{IOPriority, RateLimitedWriter};
 use snapshot::{Snapshot, SnapshotList};
+use statistics::{self, HistogramType, Ticker};
 use table_builder::TableBuilder;
 use table_cache::{table_file_name, TableCache};
 use types::{
     parse_file_name, share, FileMetaData, FileNum, FileType, LdbIterator, SequenceNumber, Shared,
-    MAX_SEQUENCE_NUMBER, NUM_LEVELS,
+    MAX_SEQUENCE_NUMBER,
 };
 use version::Version;
 use version_edit::VersionEdit;
@@ -37,7 +44,7 @@ use write_batch::WriteBatch;
 use std::cmp::Ordering;
 use std::io::{self, BufWriter, Write};
 use std::mem;
-use std::ops::Drop;
+use std::ops::{Bound, Drop, RangeBounds};
 use std::path::Path;
 use std::path::PathBuf;
 use std::rc::Rc;
@@ -59,10 +66,15 @@ pub struct DB {
     log: Option<LogWriter<BufWriter<Box<dyn Write>>>>,
     log_num: Option<FileNum>,
     cache: Shared<TableCache>,
+    blobs: Shared<BlobFileCache>,
     vset: Shared<VersionSet>,
     snaps: SnapshotList,
 
-    cstats: [CompactionStats; NUM_LEVELS],
+    cstats: Vec<CompactionStats>,
+    // Versions of a key hidden by a version at or below this user timestamp can be dropped.
+    full_history_ts_low: Vec<u8>,
+
+    read_only: bool,
 }
 
 // RECOVERY AND INITIALIZATION //
@@ -78,8 +90,14 @@ impl DB {
         // FIXME:: use std::untrusted::path::PathEx
         let path = name.canonicalize().unwrap_or(name.to_owned());
 
+        if opt.timestamp_size > 0 && opt.cmp.timestamp_size() == 0 {
+            opt.cmp = Rc::new(Box::new(TimestampCmp::new(opt.cmp.clone(), opt.timestamp_size)));
+        }
+
         let cache = share(TableCache::new(&name, opt.clone(), opt.max_open_files - 10));
         let vset = VersionSet::new(&name, opt.clone(), cache.clone());
+        let blobs = BlobFileCache::new(&name, opt.clone(), (opt.max_open_files / 4).max(1));
+        let cstats = vec![CompactionStats::default(); opt.num_levels];
 
         DB {
             name: name.to_owned(),
@@ -88,7 +106,7 @@ impl DB {
             internal_cmp: Rc::new(Box::new(InternalKeyCmp(opt.cmp.clone()))),
             fpol: InternalFilterPolicy::new(opt.filter_policy.clone()),
 
-            mem: MemTable::new(opt.cmp.clone()),
+            mem: MemTable::new_with_rep(opt.cmp.clone(), opt.memtable_rep),
             imm: None,
 
             opt,
@@ -96,10 +114,14 @@ impl DB {
             log: None,
             log_num: None,
             cache,
+            blobs: share(blobs),
             vset: share(vset),
             snaps: SnapshotList::new(),
 
-            cstats: Default::default(),
+            cstats,
+            full_history_ts_low: vec![],
+
+            read_only: false,
         }
     }
 
@@ -113,6 +135,7 @@ impl DB {
     /// Whether a new database is created and what happens if a database exists at the given path
     /// depends on the options set (`create_if_missing`, `error_if_exists`).
     pub fn open<P: AsRef<Path>>(name: P, opt: Options) -> Result<DB> {
+        compression::check_options(&opt)?;
         let name = name.as_ref();
         let mut db = DB::new(name, opt);
         let mut ve = VersionEdit::new();
@@ -126,7 +149,9 @@ impl DB {
                 .env
                 .open_writable_file(Path::new(&log_file_name(&db.name, lognum)))?;
             ve.set_log_num(lognum);
-            db.log = Some(LogWriter::new(BufWriter::new(logfile)));
+            db.log = Some(
+                LogWriter::new(BufWriter::new(logfile)).with_statistics(db.opt.statistics.clone()),
+            );
             db.log_num = Some(lognum);
         }
 
@@ -140,6 +165,27 @@ impl DB {
         Ok(db)
     }
 
+    /// Opens an existing database in read-only mode. Log files are replayed into the memtable
+    /// without being flushed, and no files in the database directory are created, modified or
+    /// deleted; the database lock is not acquired either. Write operations (`put()`, `delete()`,
+    /// `write()`) and compactions fail with `StatusCode::NotSupported`.
+    pub fn open_read_only<P: AsRef<Path>>(name: P, mut opt: Options) -> Result<DB> {
+        let name = name.as_ref();
+        // Reusing logs or manifests requires opening them for appending.
+        opt.reuse_logs = false;
+        opt.reuse_manifest = false;
+        if opt.log.is_none() {
+            // open_info_log() would rotate the info log.
+            opt.log = Some(share(Logger(Box::new(io::sink()))));
+        }
+
+        let mut db = DB::new(name, opt);
+        db.read_only = true;
+        let mut ve = VersionEdit::new();
+        db.recover(&mut ve)?;
+        Ok(db)
+    }
+
     /// initialize_db initializes a new database.
     fn initialize_db(&mut self) -> Result<()> {
         let mut ve = VersionEdit::new();
@@ -161,21 +207,36 @@ impl DB {
     /// recover recovers from the existing state on disk. If the wrapped result is `true`, then
     /// log_and_apply() should be called after recovery has finished.
     fn recover(&mut self, ve: &mut VersionEdit) -> Result<bool> {
-        if self.opt.error_if_exists && self.opt.env.exists(&self.path.as_ref()).unwrap_or(false) {
-            return err(StatusCode::AlreadyExists, "database already exists");
-        }
+        if self.opt.num_levels < 2 {
+            return err(StatusCode::InvalidArgument, "num_levels must be at least 2");
+        }
+        if self.read_only {
+            // Neither create nor lock anything; the database must already exist.
+            if let Err(e) = read_current_file(&self.opt.env, &self.path) {
+                if e.code == StatusCode::NotFound {
+                    return err(StatusCode::NotFound, "database does not exist");
+                }
+                return Err(e);
+            }
+        } else {
+            if self.opt.error_if_exists
+                && self.opt.env.exists(&self.path.as_ref()).unwrap_or(false)
+            {
+                return err(StatusCode::AlreadyExists, "database already exists");
+            }
 
-        let _ = self.opt.env.mkdir(Path::new(&self.path));
-        self.acquire_lock()?;
+            let _ = self.opt.env.mkdir(Path::new(&self.path));
+            self.acquire_lock()?;
 
-        if let Err(e) = read_current_file(&self.opt.env, &self.path) {
-            if e.code == StatusCode::NotFound && self.opt.create_if_missing {
-                self.initialize_db()?;
-            } else {
-                return err(
-                    StatusCode::InvalidArgument,
-                    "database does not exist and create_if_missing is false",
-                );
+            if let Err(e) = read_current_file(&self.opt.env, &self.path) {
+                if e.code == StatusCode::NotFound && self.opt.create_if_missing {
+                    self.initialize_db()?;
+                } else {
+                    return err(
+                        StatusCode::InvalidArgument,
+                        "database does not exist and create_if_missing is false",
+                    );
+                }
             }
         }
 
@@ -238,7 +299,16 @@ impl DB {
         let mut max_seq = 0;
         let mut save_manifest = false;
         let cmp: Rc<Box<dyn Cmp>> = self.opt.cmp.clone();
-        let mut mem = MemTable::new(cmp.clone());
+        // In read-only mode, all log files are replayed into the live memtable, which is never
+        // written out.
+        let mut mem = if self.read_only {
+            mem::replace(
+                &mut self.mem,
+                MemTable::new_with_rep(cmp.clone(), self.opt.memtable_rep),
+            )
+        } else {
+            MemTable::new_with_rep(cmp.clone(), self.opt.memtable_rep)
+        };
         {
             let logfile = self.opt.env.open_sequential_file(Path::new(&filename))?;
             // Use the user-supplied comparator; it will be wrapped inside a MemtableKeyCmp.
@@ -251,16 +321,31 @@ impl DB {
             let mut scratch = vec![];
             let mut batch = WriteBatch::new();
 
-            while let Ok(len) = logreader.read(&mut scratch) {
+            loop {
+                let len = match logreader.read(&mut scratch) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        let msg = format!("corruption in log file {:06}: {}", log_num, e);
+                        if self.opt.paranoid_checks {
+                            return err(StatusCode::Corruption, &msg);
+                        }
+                        // The rest of the log file is dropped.
+                        log!(self.opt.log, "{}", msg);
+                        break;
+                    }
+                };
                 if len == 0 {
                     break;
                 }
                 if len < 12 {
-                    log!(
-                        self.opt.log,
+                    let msg = format!(
                         "corruption in log file {:06}: record shorter than 12B",
                         log_num
                     );
+                    if self.opt.paranoid_checks {
+                        return err(StatusCode::Corruption, &msg);
+                    }
+                    log!(self.opt.log, "{}", msg);
                     continue;
                 }
 
@@ -271,23 +356,26 @@ impl DB {
                 if last_seq > max_seq {
                     max_seq = last_seq
                 }
-                if mem.approx_mem_usage() > self.opt.write_buffer_size {
+                if !self.read_only && mem.approx_mem_usage() > self.opt.write_buffer_size {
                     compactions += 1;
                     self.write_l0_table(&mem, ve, None)?;
                     save_manifest = true;
-                    mem = MemTable::new(cmp.clone());
+                    mem = MemTable::new_with_rep(cmp.clone(), self.opt.memtable_rep);
                 }
                 batch.clear();
             }
         }
 
         // Check if we can reuse the last log file.
-        if self.opt.reuse_logs && is_last && compactions == 0 {
+        if self.read_only {
+            self.mem = mem;
+        } else if self.opt.reuse_logs && is_last && compactions == 0 {
             assert!(self.log.is_none());
             log!(self.opt.log, "reusing log file {:?}", filename);
             let oldsize = self.opt.env.size_of(Path::new(&filename))?;
             let oldfile = self.opt.env.open_appendable_file(Path::new(&filename))?;
-            let lw = LogWriter::new_with_off(BufWriter::new(oldfile), oldsize);
+            let lw = LogWriter::new_with_off(BufWriter::new(oldfile), oldsize)
+                .with_statistics(self.opt.statistics.clone());
             self.log = Some(lw);
             self.log_num = Some(log_num);
             self.mem = mem;
@@ -317,7 +405,7 @@ impl DB {
                             continue;
                         }
                     }
-                    FileType::Table => {
+                    FileType::Table | FileType::Blob => {
                         if files.contains(&num) {
                             continue;
                         }
@@ -335,10 +423,15 @@ impl DB {
                 // If we're here, delete this file.
                 if typ == FileType::Table {
                     let _ = self.cache.borrow_mut().evict(num);
+                } else if typ == FileType::Blob {
+                    self.blobs.borrow_mut().evict(num);
                 }
                 log!(self.opt.log, "Deleting file type={:?} num={}", typ, num);
                 if let Err(e) = self.opt.env.delete(&self.path.join(&name)) {
                     log!(self.opt.log, "Deleting file num={} failed: {}", num, e);
+                } else if typ == FileType::Table {
+                    let info = TableFileDeletionInfo { file_num: num };
+                    self.notify(|l| l.on_table_file_deleted(&info));
                 }
             }
         }
@@ -389,11 +482,53 @@ impl DB {
         self.write(wb, false)
     }
 
+    /// Sets `k` to `new` (or deletes it, if `new` is `None`) if its current value is `expected`,
+    /// with `None` meaning that `k` doesn't exist. Returns whether the entry was updated.
+    pub fn compare_and_swap(
+        &mut self,
+        k: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut wb = WriteBatch::new();
+        wb.expect(k, expected);
+        match new {
+            Some(v) => wb.put(k, v),
+            None => wb.delete(k),
+        }
+        match self.write(wb, false) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.code == StatusCode::PreconditionFailed => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds an entry if no entry exists for `k` yet. Returns whether the entry was added.
+    pub fn put_if_absent(&mut self, k: &[u8], v: &[u8]) -> Result<bool> {
+        self.compare_and_swap(k, None, Some(v))
+    }
+
     /// Writes an entire WriteBatch. `sync` determines whether the write should be flushed to
-    /// disk.
+    /// disk. If the batch contains preconditions (see `WriteBatch::expect()`), they are checked
+    /// against the current state first; if any of them doesn't hold, nothing is written and
+    /// `StatusCode::PreconditionFailed` is returned.
     pub fn write(&mut self, batch: WriteBatch, sync: bool) -> Result<()> {
+        self.check_writable()?;
         assert!(self.log.is_some());
 
+        let ts_size = self.opt.timestamp_size;
+        if ts_size > 0 && batch.iter().any(|(k, _)| k.len() < ts_size) {
+            return err(StatusCode::InvalidArgument, "key is shorter than a timestamp");
+        }
+
+        if !self.check_preconditions(&batch)? {
+            return err(
+                StatusCode::PreconditionFailed,
+                "write batch precondition doesn't hold",
+            );
+        }
+
+        let start_ts = statistics::start_timer(&self.opt.statistics, &**self.opt.env);
         self.make_room_for_write(false)?;
 
         let entries = batch.count() as u64;
@@ -403,16 +538,84 @@ impl DB {
         batch.insert_into_memtable(next, &mut self.mem);
         log.add_record(&batch.encode(next))?;
         if sync {
-            log.flush()?;
+            self.sync_log()?;
         }
         self.vset.borrow_mut().last_seq += entries;
+        statistics::measure_since(
+            &self.opt.statistics,
+            &**self.opt.env,
+            HistogramType::Write,
+            start_ts,
+        );
         Ok(())
     }
 
-    /// flush makes sure that all pending changes (e.g. from put()) are stored on disk.
+    /// flush writes the buffered write-ahead log records to the log file, making sure that all
+    /// pending changes (e.g. from put()) can be recovered. It is equivalent to `flush_wal(true)`;
+    /// it does not write the memtable to a table file, which is what `flush_memtable()` does.
     pub fn flush(&mut self) -> Result<()> {
+        self.flush_wal(true)
+    }
+
+    /// flush_wal writes the buffered write-ahead log records to the log file. If `sync` is true,
+    /// the log file is also synced to disk using `Env::sync_data()`.
+    pub fn flush_wal(&mut self, sync: bool) -> Result<()> {
+        self.check_writable()?;
         assert!(self.log.is_some());
-        self.log.as_mut().unwrap().flush()
+        if sync {
+            self.sync_log()
+        } else {
+            self.log.as_mut().unwrap().flush()
+        }
+    }
+
+    /// sync_log flushes the write-ahead log and makes it durable.
+    fn sync_log(&mut self) -> Result<()> {
+        self.log.as_mut().unwrap().flush()?;
+        let logfile = log_file_name(&self.path, self.log_num.unwrap());
+        self.opt.env.sync_data(Path::new(&logfile))
+    }
+
+    /// flush_memtable writes the current memtable to a table file and starts a new memtable and
+    /// log file. Unlike `compact_range()`, this doesn't compact any table files.
+    ///
+    /// As this implementation has no background threads, the memtable is always written before
+    /// this returns. If `wait` is false, errors are only logged and reported to the event
+    /// listeners instead of being returned.
+    pub fn flush_memtable(&mut self, wait: bool) -> Result<()> {
+        self.check_writable()?;
+        // make_room_for_write() rotates the memtable and writes it using write_l0_table().
+        match self.make_room_for_write(true) {
+            Err(ref e) if !wait => {
+                log!(self.opt.log, "Memtable flush failed: {}", e);
+                self.notify(|l| l.on_background_error(e));
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
+    /// check_preconditions returns whether all preconditions of `batch` hold at the latest
+    /// sequence number. As writes are serialized by `&mut self`, nothing can change between the
+    /// check and the write.
+    fn check_preconditions(&mut self, batch: &WriteBatch) -> Result<bool> {
+        let seq = self.vset.borrow().last_seq;
+        for &(ref k, ref expected) in batch.preconditions() {
+            let current = self.get_internal(seq, k)?;
+            if current != *expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// check_writable returns an error if the database was opened with `open_read_only()`.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            err(StatusCode::NotSupported, "database is opened read-only")
+        } else {
+            Ok(())
+        }
     }
 }
 
@@ -424,10 +627,17 @@ impl DB {
         let lkey = LookupKey::new(key, seq);
 
         // 先从MemTable找
+        let stats = self.opt.statistics.clone();
         match self.mem.get(&lkey) {
-            (Some(v), _) => return Ok(Some(v)),
+            (Some(v), _) => {
+                statistics::record_tick(&stats, Ticker::MemtableHit, 1);
+                return Ok(Some(v));
+            }
             // deleted entry
-            (None, true) => return Ok(None),
+            (None, true) => {
+                statistics::record_tick(&stats, Ticker::MemtableHit, 1);
+                return Ok(None);
+            }
             // not found entry
             (None, false) => {}
         }
@@ -435,13 +645,20 @@ impl DB {
         // 再从不可变MemTable中找
         if let Some(imm) = self.imm.as_ref() {
             match imm.get(&lkey) {
-                (Some(v), _) => return Ok(Some(v)),
+                (Some(v), _) => {
+                    statistics::record_tick(&stats, Ticker::MemtableHit, 1);
+                    return Ok(Some(v));
+                }
                 // deleted entry
-                (None, true) => return Ok(None),
+                (None, true) => {
+                    statistics::record_tick(&stats, Ticker::MemtableHit, 1);
+                    return Ok(None);
+                }
                 // not found entry
                 (None, false) => {}
             }
         }
+        statistics::record_tick(&stats, Ticker::MemtableMiss, 1);
 
         // 最后到磁盘上的SSTable中找
         let mut do_compaction = false;
@@ -451,17 +668,26 @@ impl DB {
         {
             let current = self.current();
             let mut current = current.borrow_mut();
-            if let Ok(Some((v, st))) = current.get(lkey.internal_key()) {
+            if let Ok(Some((v, typ, st))) = current.get(lkey.internal_key()) {
                 if current.update_stats(st) {
                     do_compaction = true;
                 }
-                result = Some(v)
+                result = Some((v, typ))
             }
         }
+        // Read the blob before a compaction can delete its file.
+        let result = match result {
+            Some((index, ValueType::TypeBlobIndex)) => {
+                Some(self.blobs.borrow_mut().get_encoded(&index)?)
+            }
+            Some((v, _)) => Some(v),
+            None => None,
+        };
 
         if do_compaction {
             if let Err(e) = self.maybe_do_compaction() {
                 log!(self.opt.log, "error while doing compaction in get: {}", e);
+                self.notify(|l| l.on_background_error(&e));
             }
         }
         Ok(result)
@@ -470,18 +696,70 @@ impl DB {
     /// get_at reads the value for a given key at or before snapshot. It returns Ok(None) if the
     /// entry wasn't found, and Err(_) if an error occurred.
     pub fn get_at(&mut self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self.get_internal(snapshot.sequence(), key)
+        let start_ts = statistics::start_timer(&self.opt.statistics, &**self.opt.env);
+        let r = self.get_internal(snapshot.sequence(), key);
+        statistics::measure_since(
+            &self.opt.statistics,
+            &**self.opt.env,
+            HistogramType::Get,
+            start_ts,
+        );
+        r
+    }
+
+    /// get_at_timestamp reads the newest version of `key` (without a timestamp) that is not newer
+    /// than the user timestamp `ts`. Timestamps below the one set by set_full_history_ts_low()
+    /// can't be read anymore.
+    pub fn get_at_timestamp(&mut self, key: &[u8], ts: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_read_timestamp(ts)?;
+        let mut tskey = key.to_vec();
+        tskey.extend_from_slice(ts);
+        let seq = self.vset.borrow().last_seq;
+        let start_ts = statistics::start_timer(&self.opt.statistics, &**self.opt.env);
+        let r = self.get_internal(seq, &tskey);
+        statistics::measure_since(
+            &self.opt.statistics,
+            &**self.opt.env,
+            HistogramType::Get,
+            start_ts,
+        );
+        r
     }
 
     /// get is a simplified version of get_at(), translating errors to None.
     pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
         let seq = self.vset.borrow().last_seq;
-        if let Ok(v) = self.get_internal(seq, key) {
+        let start_ts = statistics::start_timer(&self.opt.statistics, &**self.opt.env);
+        let r = self.get_internal(seq, key);
+        statistics::measure_since(
+            &self.opt.statistics,
+            &**self.opt.env,
+            HistogramType::Get,
+            start_ts,
+        );
+        if let Ok(v) = r {
             v
         } else {
             None
         }
     }
+
+    /// check_read_timestamp returns an error if `ts` is not a user timestamp that can be read at.
+    fn check_read_timestamp(&self, ts: &[u8]) -> Result<()> {
+        if self.opt.timestamp_size == 0 || ts.len() != self.opt.timestamp_size {
+            err(
+                StatusCode::InvalidArgument,
+                "timestamp size doesn't match Options::timestamp_size",
+            )
+        } else if ts < self.full_history_ts_low.as_slice() {
+            err(
+                StatusCode::InvalidArgument,
+                "timestamp is older than full_history_ts_low",
+            )
+        } else {
+            Ok(())
+        }
+    }
 }
 
 // ITERATOR //
@@ -498,11 +776,51 @@ impl DB {
         Ok(DBIterator::new(
             self.opt.cmp.clone(),
             self.vset.clone(),
+            self.blobs.clone(),
             self.merge_iterators()?,
             ss,
+            self.opt.env.clone(),
+            self.opt.statistics.clone(),
         ))
     }
 
+    /// new_iter_at_timestamp returns a DBIterator over the current state of the database returning
+    /// the newest version of each key that is not newer than the user timestamp `ts`. The keys
+    /// returned by it, and the keys it seeks to, include timestamps.
+    pub fn new_iter_at_timestamp(&mut self, ts: &[u8]) -> Result<DBIterator> {
+        self.check_read_timestamp(ts)?;
+        Ok(self.new_iter()?.with_timestamp(ts.to_vec()))
+    }
+
+    /// range returns an iterator over the entries with keys in `range`, in the order defined by
+    /// the comparator. The returned iterator is double-ended, i.e. `range(..).rev()` iterates in
+    /// reverse order. Like `new_iter()`, it doesn't return entries added after its creation.
+    pub fn range<'a, R: RangeBounds<&'a [u8]>>(&mut self, range: R) -> RangeIter {
+        let start = owned_bound(range.start_bound());
+        let end = owned_bound(range.end_bound());
+        let ss = self.get_snapshot();
+        let iters = self
+            .new_iter_at(ss.clone())
+            .and_then(|front| Ok((front, self.new_iter_at(ss)?)));
+        RangeIter::new(self.opt.cmp.clone(), iters, start, end)
+    }
+
+    /// prefix returns an iterator over the entries with keys starting with `prefix`. This assumes
+    /// that keys with a common prefix are ordered consecutively, as is the case for the default
+    /// (bytewise) comparator.
+    pub fn prefix(&mut self, prefix: &[u8]) -> RangeIter {
+        match prefix_successor(prefix) {
+            Some(end) => self.range::<(Bound<&[u8]>, Bound<&[u8]>)>((
+                Bound::Included(prefix),
+                Bound::Excluded(&end),
+            )),
+            None => self.range::<(Bound<&[u8]>, Bound<&[u8]>)>((
+                Bound::Included(prefix),
+                Bound::Unbounded,
+            )),
+        }
+    }
+
     /// merge_iterators produces a MergingIter merging the entries in the memtable, the immutable
     /// memtable, and table files from all levels.
     fn merge_iterators(&mut self) -> Result<MergingIter> {
@@ -537,7 +855,7 @@ impl DB {
 // STATISTICS //
 impl DB { 
     fn add_stats(&mut self, level: usize, cs: CompactionStats) {
-        assert!(level < NUM_LEVELS);
+        assert!(level < self.cstats.len());
         self.cstats[level].add(cs);
     }
 
@@ -547,6 +865,7 @@ impl DB {
         if current.borrow_mut().record_read_sample(k) {
             if let Err(e) = self.maybe_do_compaction() {
                 log!(self.opt.log, "record_read_sample: compaction failed: {}", e);
+                self.notify(|l| l.on_background_error(&e));
             }
         }
     }
@@ -555,8 +874,11 @@ impl DB {
 // COMPACTIONS //
 impl DB {
     /// make_room_for_write checks if the memtable has become too large, and triggers a compaction
-    /// if it's the case.
+    /// if it's the case. Unless `force` is set, the write may be stalled first.
     fn make_room_for_write(&mut self, force: bool) -> Result<()> {
+        if !force {
+            self.maybe_stall_write()?;
+        }
         if !force && self.mem.approx_mem_usage() < self.opt.write_buffer_size {
             Ok(())
         } else if self.mem.len() == 0 {
@@ -572,21 +894,126 @@ impl DB {
                 self.vset.borrow_mut().reuse_file_number(logn);
                 Err(logf.err().unwrap())
             } else {
-                self.log = Some(LogWriter::new(BufWriter::new(logf.unwrap())));
+                self.log = Some(
+                    LogWriter::new(BufWriter::new(logf.unwrap()))
+                        .with_statistics(self.opt.statistics.clone()),
+                );
                 self.log_num = Some(logn);
 
-                let mut imm = MemTable::new(self.opt.cmp.clone());
+                let mut imm =
+                    MemTable::new_with_rep(self.opt.cmp.clone(), self.opt.memtable_rep);
                 mem::swap(&mut imm, &mut self.mem);
                 self.imm = Some(imm);
-                self.maybe_do_compaction()
+
+                // A write waiting for the memtable compaction is stalled.
+                let start_ts = statistics::start_timer(&self.opt.statistics, &**self.opt.env);
+                let r = self.maybe_do_compaction();
+                if !force {
+                    statistics::record_micros_since(
+                        &self.opt.statistics,
+                        &**self.opt.env,
+                        Ticker::StallMicros,
+                        start_ts,
+                    );
+                }
+                r
+            }
+        }
+    }
+
+    /// maybe_stall_write throttles writes while compactions are falling behind, so that level 0
+    /// doesn't grow without bounds. As there are no background threads, delayed writes run the
+    /// compactions themselves: once level 0 holds `level0_slowdown_writes_trigger` files or the
+    /// soft pending compaction bytes limit is reached, every write runs one compaction. Once the
+    /// stop trigger or the hard limit is reached, writes are stopped until compactions have caught
+    /// up.
+    fn maybe_stall_write(&mut self) -> Result<()> {
+        // FIFO compaction keeps an unbounded number of files in level 0.
+        if self.opt.compaction_style == CompactionStyle::Fifo {
+            return Ok(());
+        }
+
+        let (mut l0_files, mut pending) = self.compaction_backlog();
+        let exceeds = |n, limit| limit > 0 && n >= limit;
+        if exceeds(l0_files, self.opt.level0_stop_writes_trigger)
+            || exceeds(pending, self.opt.hard_pending_compaction_bytes_limit)
+        {
+            let start_ts = statistics::start_timer(&self.opt.statistics, &**self.opt.env);
+            log!(
+                self.opt.log,
+                "Stopping writes: {} L0 files, {} pending compaction bytes",
+                l0_files,
+                pending
+            );
+            while exceeds(l0_files, self.opt.level0_stop_writes_trigger)
+                || exceeds(pending, self.opt.hard_pending_compaction_bytes_limit)
+            {
+                let c = self.vset.borrow_mut().pick_compaction();
+                if let Some(c) = c {
+                    self.start_compaction(c)?;
+                } else {
+                    break;
+                }
+                let backlog = self.compaction_backlog();
+                if backlog.0 >= l0_files && backlog.1 >= pending {
+                    log!(self.opt.log, "Compaction made no progress; resuming writes");
+                    break;
+                }
+                l0_files = backlog.0;
+                pending = backlog.1;
+            }
+            statistics::record_tick(&self.opt.statistics, Ticker::WriteStops, 1);
+            statistics::record_micros_since(
+                &self.opt.statistics,
+                &**self.opt.env,
+                Ticker::WriteStopMicros,
+                start_ts,
+            );
+        } else if exceeds(l0_files, self.opt.level0_slowdown_writes_trigger)
+            || exceeds(pending, self.opt.soft_pending_compaction_bytes_limit)
+        {
+            // Without compaction work to catch up with, there's nothing to wait for.
+            let c = self.vset.borrow_mut().pick_compaction();
+            if let Some(c) = c {
+                let start_ts = statistics::start_timer(&self.opt.statistics, &**self.opt.env);
+                self.start_compaction(c)?;
+                statistics::record_tick(&self.opt.statistics, Ticker::WriteSlowdowns, 1);
+                statistics::record_micros_since(
+                    &self.opt.statistics,
+                    &**self.opt.env,
+                    Ticker::WriteSlowdownMicros,
+                    start_ts,
+                );
             }
         }
+        Ok(())
+    }
+
+    /// compaction_backlog returns the number of files in level 0 and the estimated number of
+    /// bytes waiting to be compacted.
+    fn compaction_backlog(&self) -> (usize, usize) {
+        let vset = self.vset.borrow();
+        let l0_files = vset.current().borrow().num_level_files(0);
+        (l0_files, vset.pending_compaction_bytes())
     }
 
     /// maybe_do_compaction starts a blocking compaction if it makes sense.
     fn maybe_do_compaction(&mut self) -> Result<()> {
-        if self.imm.is_some() {
-            self.compact_memtable()
+        if self.read_only {
+            Ok(())
+        } else if self.imm.is_some() {
+            self.compact_memtable()?;
+            // Dropping old tables is cheap, so do it right after the flush that made the database
+            // too large.
+            if self.opt.compaction_style == CompactionStyle::Fifo
+                && self.vset.borrow().needs_compaction()
+            {
+                let c = self.vset.borrow_mut().pick_compaction();
+                if let Some(c) = c {
+                    self.start_compaction(c)?;
+                }
+            }
+            Ok(())
         } else if self.vset.borrow().needs_compaction() {
             let c = self.vset.borrow_mut().pick_compaction();
             if let Some(c) = c {
@@ -599,18 +1026,29 @@ impl DB {
         }
     }
 
+    /// set_full_history_ts_low lets compactions drop the versions of a key that are hidden by a
+    /// newer version not newer than the user timestamp `ts`; afterwards, reads at timestamps
+    /// below `ts` fail. The watermark can't be lowered and is not persisted.
+    pub fn set_full_history_ts_low(&mut self, ts: &[u8]) -> Result<()> {
+        self.check_read_timestamp(ts)?;
+        self.full_history_ts_low = ts.to_vec();
+        Ok(())
+    }
+
     /// compact_range triggers an immediate compaction on the specified key range. Repeatedly
     /// calling this without actually adding new keys is not useful.
     ///
     /// Compactions in general will cause the database to find entries more quickly, and take up
     /// less space on disk.
     pub fn compact_range(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        self.check_writable()?;
+
         // 找到最大的包含key range的level
         let mut max_level = 1;
         {
             let v = self.vset.borrow().current();
             let v = v.borrow();
-            for l in 1..NUM_LEVELS - 1 {
+            for l in 1..v.num_levels() - 1 {
                 if v.overlap_in_level(l, from, to) {
                     max_level = l;
                 }
@@ -645,9 +1083,63 @@ impl DB {
         Ok(())
     }
 
+    /// verify_checksum reads every block of every live table file and every record of the current
+    /// MANIFEST, and verifies their checksums. Each corruption is logged; if there are any, a
+    /// Corruption error listing the affected files and offsets is returned.
+    pub fn verify_checksum(&mut self) -> Result<()> {
+        let mut corrupt = vec![];
+        let current = self.current();
+        for files in current.borrow().files.iter() {
+            for f in files.iter() {
+                let num = f.borrow().num;
+                let table = self.cache.borrow_mut().get_table(num);
+                match table {
+                    Ok(table) => {
+                        for (offset, e) in table.verify_checksums() {
+                            corrupt.push(format!("table {:06} at offset {}: {}", num, offset, e));
+                        }
+                    }
+                    Err(e) => corrupt.push(format!("table {:06}: {}", num, e)),
+                }
+            }
+        }
+
+        let manifest_num = self.vset.borrow().manifest_num;
+        let manifest = manifest_file_name(&self.path, manifest_num);
+        let file = self.opt.env.open_sequential_file(&manifest)?;
+        let mut reader = LogReader::new(file, true);
+        let mut buf = vec![];
+        loop {
+            let offset = reader.offset();
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    // Records following a corrupt one can't be located reliably.
+                    corrupt.push(format!(
+                        "MANIFEST-{:06} at offset {}: {}",
+                        manifest_num, offset, e
+                    ));
+                    break;
+                }
+            }
+        }
+
+        if corrupt.is_empty() {
+            return Ok(());
+        }
+        for c in corrupt.iter() {
+            log!(self.opt.log, "Checksum verification failed: {}", c);
+        }
+        err(StatusCode::Corruption, &corrupt.join("; "))
+    }
+
     /// start_compaction dispatches the different kinds of compactions depending on the current
     /// state of the database.
     fn start_compaction(&mut self, mut compaction: Compaction) -> Result<()> {
+        if compaction.is_deletion_only() {
+            return self.drop_compaction_inputs(compaction);
+        }
         // 只有一个文件需要compaction
         if compaction.is_trivial_move() {
             assert_eq!(1, compaction.num_inputs(0));
@@ -664,6 +1156,9 @@ impl DB {
                 log!(self.opt.log, "trivial move failed: {}", e);
                 Err(e)
             } else {
+                if level == 0 {
+                    self.cache.borrow_mut().unpin(num);
+                }
                 log!(
                     self.opt.log,
                     "Moved num={} bytes={} from L{} to L{}",
@@ -672,6 +1167,17 @@ impl DB {
                     level,
                     level + 1
                 );
+                let info = CompactionJobInfo {
+                    level,
+                    output_level: level + 1,
+                    inputs: vec![num],
+                    outputs: vec![num],
+                    bytes_read: 0,
+                    bytes_written: 0,
+                    micros: 0,
+                    trivial_move: true,
+                };
+                self.notify(|l| l.on_compaction_completed(&info));
                 log!(
                     self.opt.log,
                     "Summary: {}",
@@ -689,6 +1195,9 @@ impl DB {
             if let Err(e) = self.do_compaction_work(&mut state) {
                 state.cleanup(&self.opt.env, &self.path);
                 log!(self.opt.log, "Compaction work failed: {}", e);
+                self.notify(|l| l.on_background_error(&e));
+                // The inputs are still needed, as the outputs are incomplete.
+                return Err(e);
             }
             self.install_compaction_results(state)?;
             log!(
@@ -701,6 +1210,42 @@ impl DB {
         }
     }
 
+    /// drop_compaction_inputs deletes the input files of a compaction without rewriting them.
+    fn drop_compaction_inputs(&mut self, mut compaction: Compaction) -> Result<()> {
+        let level = compaction.level();
+        let mut info = CompactionJobInfo {
+            level,
+            output_level: compaction.output_level(),
+            inputs: vec![],
+            outputs: vec![],
+            bytes_read: 0,
+            bytes_written: 0,
+            micros: 0,
+            trivial_move: false,
+        };
+        let mut bytes = 0;
+        for i in 0..compaction.num_inputs(0) {
+            let f = compaction.input(0, i);
+            info.inputs.push(f.num);
+            bytes += f.size;
+        }
+
+        compaction.add_input_deletions();
+        if let Err(e) = self.vset.borrow_mut().log_and_apply(compaction.into_edit()) {
+            log!(self.opt.log, "dropping tables failed: {}", e);
+            return Err(e);
+        }
+        log!(
+            self.opt.log,
+            "Dropped {} tables with {} bytes from L{}",
+            info.inputs.len(),
+            bytes,
+            level
+        );
+        self.notify(|l| l.on_compaction_completed(&info));
+        self.delete_obsolete_files()
+    }
+
     /// 当不可变MemTable不为空时，进行compaction
     fn compact_memtable(&mut self) -> Result<()> {
         assert!(self.imm.is_some());
@@ -709,39 +1254,87 @@ impl DB {
         let base = self.current();
 
         let imm = self.imm.take().unwrap();
-        if let Err(e) = self.write_l0_table(&imm, &mut ve, Some(&base.borrow())) {
-            self.imm = Some(imm);
-            return Err(e);
-        }
+        let flushed = match self.write_l0_table(&imm, &mut ve, Some(&base.borrow())) {
+            Ok(flushed) => flushed,
+            Err(e) => {
+                self.imm = Some(imm);
+                return Err(e);
+            }
+        };
         ve.set_log_num(self.log_num.unwrap_or(0));
         self.vset.borrow_mut().log_and_apply(ve)?;
+        if let Some(info) = flushed {
+            let created = TableFileCreationInfo {
+                file_num: info.file_num,
+                level: info.level,
+                bytes: info.bytes,
+            };
+            self.notify(|l| l.on_table_file_created(&created));
+            self.notify(|l| l.on_flush_completed(&info));
+        }
         if let Err(e) = self.delete_obsolete_files() {
             log!(self.opt.log, "Error deleting obsolete files: {}", e);
         }
         Ok(())
     }
 
-    /// write_l0_table writes the given memtable to a table file.
+    /// write_l0_table writes the given memtable to a table file. It returns None if the memtable
+    /// was empty and no file was written.
     fn write_l0_table(
         &mut self,
         memt: &MemTable,
         ve: &mut VersionEdit,
         base: Option<&Version>,
-    ) -> Result<()> {
+    ) -> Result<Option<FlushJobInfo>> {
         let start_ts = self.opt.env.micros();
         let num = self.vset.borrow_mut().new_file_number();
+
+        // The output level is picked first, as it determines the compression of the table.
+        let mut level = 0;
+        if let (Some(b), CompactionStyle::Leveled) = (base, self.opt.compaction_style) {
+            let mut iter = memt.iter();
+            let (mut smallest, mut largest, mut val) = (vec![], vec![], vec![]);
+            iter.seek_to_first();
+            if iter.current(&mut smallest, &mut val) {
+                iter.seek_to_last();
+                assert!(iter.current(&mut largest, &mut val));
+                level = b.pick_memtable_output_level(
+                    parse_internal_key(&smallest).2,
+                    parse_internal_key(&largest).2,
+                );
+            }
+        }
+        let mut blobs = None;
+        if self.opt.enable_blob_files {
+            let blob_num = self.vset.borrow_mut().new_file_number();
+            let w = BlobFileWriter::new(&self.path, &self.opt, blob_num, IOPriority::Flush)?;
+            blobs = Some(w);
+        }
         log!(self.opt.log, "Start write of L0 table {:06}", num);
-        let fmd = build_table(&self.path, &self.opt, memt.iter(), num)?;
+        let mut fmd = build_table(
+            &self.path,
+            &self.opt,
+            memt.iter(),
+            num,
+            level,
+            blobs.as_mut(),
+        )?;
         log!(self.opt.log, "L0 table {:06} has {} bytes", num, fmd.size);
 
+        if let Some(ref b) = blobs {
+            if fmd.blob_files.is_empty() {
+                self.vset.borrow_mut().reuse_file_number(b.num());
+            } else {
+                log!(self.opt.log, "Blob file {:06} has {} bytes", b.num(), b.size());
+            }
+        }
         // Wrote empty table.
         if fmd.size == 0 {
             self.vset.borrow_mut().reuse_file_number(num);
-            return Ok(());
+            return Ok(None);
         }
 
-        let cache_result = self.cache.borrow_mut().get_table(num);
-        if let Err(e) = cache_result {
+        if let Err(e) = self.verify_new_table(num) {
             log!(
                 self.opt.log,
                 "L0 table {:06} not returned by cache: {}",
@@ -752,25 +1345,34 @@ impl DB {
                 .opt
                 .env
                 .delete(Path::new(&table_file_name(&self.path, num)));
+            for blob in fmd.blob_files.iter() {
+                let _ = self.opt.env.delete(&blob_file_name(&self.path, *blob));
+            }
             return Err(e);
         }
 
         let mut stats = CompactionStats::default();
         stats.micros = self.opt.env.micros() - start_ts;
-        stats.written = fmd.size;
-
-        let mut level = 0;
-        if let Some(b) = base {
-            level = b.pick_memtable_output_level(
-                parse_internal_key(&fmd.smallest).2,
-                parse_internal_key(&fmd.largest).2,
-            );
-        }
-
+        stats.written = fmd.size + blobs.map(|b| b.size()).unwrap_or(0);
+
+        if let Some(ref s) = self.opt.statistics {
+            let mut s = s.borrow_mut();
+            s.measure_time(HistogramType::Compaction, stats.micros);
+            s.record_level_io(level, 0, fmd.size);
+        }
+        let info = FlushJobInfo {
+            file_num: num,
+            level,
+            bytes: fmd.size,
+            micros: stats.micros,
+        };
         self.add_stats(level, stats);
+        if self.opt.compaction_style == CompactionStyle::Fifo {
+            fmd.creation_time = start_ts;
+        }
         ve.add_file(level, fmd);
 
-        Ok(())
+        Ok(Some(info))
     }
 
     fn do_compaction_work(&mut self, cs: &mut CompactionState) -> Result<()> {
@@ -786,9 +1388,23 @@ impl DB {
             cs.compaction.num_inputs(0),
             cs.compaction.level(),
             cs.compaction.num_inputs(1),
-            cs.compaction.level() + 1
+            cs.compaction.output_level()
         );
 
+        if self.opt.enable_blob_files {
+            // Values in the oldest blob files are moved to new ones.
+            let blobs = self.vset.borrow().live_blob_files();
+            let n = (blobs.len() as f64 * self.opt.blob_garbage_collection_age_cutoff) as usize;
+            if n > 0 {
+                cs.blob_gc_before = blobs.get(n).cloned().unwrap_or(FileNum::max_value());
+            }
+        }
+
+        let mut table_opt = self.opt.clone();
+        table_opt.compression_type = self
+            .opt
+            .compression_for_level(cs.compaction.output_level(), cs.compaction.is_bottommost());
+
         let mut input = self.vset.borrow().make_input_iterator(&cs.compaction);
         input.seek_to_first();
 
@@ -799,6 +1415,13 @@ impl DB {
         let mut have_ukey = false;
         // 记录当前的User Key
         let mut current_ukey = vec![];
+        // With user timestamps: the current user key without timestamp, and whether a version of
+        // it at or below full_history_ts_low is visible to all snapshots, hiding older versions.
+        let ts_size = self.opt.timestamp_size;
+        let ts_low = self.full_history_ts_low.clone();
+        let mut have_history_key = false;
+        let mut history_key = vec![];
+        let mut history_covered = false;
 
         while input.valid() {
             // TODO: Do we need to do a memtable compaction here? Probably not, in the sequential
@@ -818,6 +1441,7 @@ impl DB {
                 last_seq_for_key = MAX_SEQUENCE_NUMBER;
                 have_ukey = false;
                 current_ukey.clear();
+                have_history_key = false;
                 input.advance();
                 continue;
             }
@@ -830,6 +1454,24 @@ impl DB {
                 last_seq_for_key = MAX_SEQUENCE_NUMBER;
             }
 
+            if ts_size > 0 && !ts_low.is_empty() {
+                let (key_without_ts, ts) = ukey.split_at(ukey.len().saturating_sub(ts_size));
+                if !have_history_key || key_without_ts != history_key.as_slice() {
+                    history_key.clear();
+                    history_key.extend_from_slice(key_without_ts);
+                    have_history_key = true;
+                    history_covered = false;
+                } else if history_covered {
+                    // Versions are ordered newest-first, so this one can't be read anymore.
+                    last_seq_for_key = seq;
+                    input.advance();
+                    continue;
+                }
+                if seq <= cs.smallest_seq && ts <= ts_low.as_slice() {
+                    history_covered = true;
+                }
+            }
+
             // We can omit the key under the following conditions:
             // 如果上一个Key的SequenceNumber <= 最小的存活的Snapshot，那么
             // 这个Key的SequenceNumber一定 < 最小的存活的Snapshot，那么这个Key就可以被丢弃，
@@ -854,25 +1496,42 @@ impl DB {
             }
 
             last_seq_for_key = seq;
+            let blob_file = self.compaction_blob_value(cs, &mut key, &mut val)?;
 
             if cs.builder.is_none() {
                 let fnum = self.vset.borrow_mut().new_file_number();
                 let mut fmd = FileMetaData::default();
                 fmd.num = fnum;
+                if self.opt.compaction_style == CompactionStyle::Fifo {
+                    // A merged table expires together with the newest table merged into it.
+                    fmd.creation_time = (0..cs.compaction.num_inputs(0))
+                        .map(|i| cs.compaction.input(0, i).creation_time)
+                        .max()
+                        .unwrap_or(0);
+                }
 
                 let fname = table_file_name(&self.path, fnum);
                 let f = self.opt.env.open_writable_file(Path::new(&fname))?;
+                let f = RateLimitedWriter::new(f, self.opt.clone(), IOPriority::Compaction);
                 let f = Box::new(BufWriter::new(f));
-                cs.builder = Some(TableBuilder::new(self.opt.clone(), f));
+                cs.builder = Some(TableBuilder::new(table_opt.clone(), f));
                 cs.outputs.push(fmd);
             }
             if cs.builder.as_ref().unwrap().entries() == 0 {
                 cs.current_output().smallest = key.clone();
             }
             cs.builder.as_mut().unwrap().add(&key, &val)?;
+            if let Some(blob) = blob_file {
+                let output = cs.current_output();
+                if !output.blob_files.contains(&blob) {
+                    output.blob_files.push(blob);
+                }
+            }
             // NOTE: Adjust max file size based on level.
             // 达到文件大小，就写入文件，生成新文件
-            if cs.builder.as_ref().unwrap().size_estimate() > self.opt.max_file_size {
+            if cs.builder.as_ref().unwrap().size_estimate()
+                > cs.compaction.max_output_file_size()
+            {
                 self.finish_compaction_output(cs, key.clone())?;
             }
 
@@ -882,6 +1541,11 @@ impl DB {
         if cs.builder.is_some() {
             self.finish_compaction_output(cs, key)?;
         }
+        if let Some(mut b) = cs.blob.take() {
+            let size = b.finish()?;
+            cs.blob_bytes += size;
+            log!(self.opt.log, "New blob file num={}: size={}", b.num(), size);
+        }
 
         let mut stats = CompactionStats::default();
         stats.micros = self.opt.env.micros() - start_ts;
@@ -893,10 +1557,67 @@ impl DB {
         for output in &cs.outputs {
             stats.written += output.size;
         }
+        stats.written += cs.blob_bytes;
+        if let Some(ref s) = self.opt.statistics {
+            let mut s = s.borrow_mut();
+            let level = cs.compaction.level();
+            s.measure_time(HistogramType::Compaction, stats.micros);
+            for parent in 0..2 {
+                let read = (0..cs.compaction.num_inputs(parent))
+                    .map(|i| cs.compaction.input(parent, i).size)
+                    .sum();
+                s.record_level_io(level + parent, read, 0);
+            }
+            s.record_level_io(cs.compaction.output_level(), 0, stats.written);
+        }
+        cs.micros = stats.micros;
         self.cstats[cs.compaction.level()].add(stats);
         Ok(())
     }
 
+    /// compaction_blob_value prepares an entry for being written by a compaction: Large values are
+    /// moved to the compaction's blob file, as are values in blob files that are garbage collected.
+    /// It returns the blob file the entry refers to afterwards, if any.
+    fn compaction_blob_value(
+        &mut self,
+        cs: &mut CompactionState,
+        key: &mut Vec<u8>,
+        val: &mut Vec<u8>,
+    ) -> Result<Option<FileNum>> {
+        let typ = parse_internal_key(key).0;
+        if typ == ValueType::TypeBlobIndex {
+            let index = BlobIndex::decode(val)?;
+            if index.file_num >= cs.blob_gc_before {
+                return Ok(Some(index.file_num));
+            }
+            *val = self.blobs.borrow_mut().get(&index)?;
+            set_value_type(key, ValueType::TypeValue);
+        } else if typ != ValueType::TypeValue || !self.opt.enable_blob_files {
+            return Ok(None);
+        }
+        if val.len() < self.opt.min_blob_size {
+            return Ok(None);
+        }
+
+        if cs.blob.is_none() {
+            let num = self.vset.borrow_mut().new_file_number();
+            let w = BlobFileWriter::new(&self.path, &self.opt, num, IOPriority::Compaction)?;
+            cs.blob = Some(w);
+            cs.blob_outputs.push(num);
+        }
+        let mut b = cs.blob.take().unwrap();
+        separate_value(&self.opt, &mut b, key, val)?;
+        let num = b.num();
+        if b.size() >= self.opt.blob_file_size {
+            let size = b.finish()?;
+            cs.blob_bytes += size;
+            log!(self.opt.log, "New blob file num={}: size={}", num, size);
+        } else {
+            cs.blob = Some(b);
+        }
+        Ok(Some(num))
+    }
+
     fn finish_compaction_output(
         &mut self,
         cs: &mut CompactionState,
@@ -916,12 +1637,11 @@ impl DB {
 
         cs.current_output().largest = largest;
         cs.current_output().size = bytes;
+        cs.current_output().blob_files.sort();
 
         if entries > 0 {
-            // Verify that table can be used. (Separating get_table() because borrowing in an if
-            // let expression is dangerous).
-            let r = self.cache.borrow_mut().get_table(output_num);
-            if let Err(e) = r {
+            // Verify that table can be used.
+            if let Err(e) = self.verify_new_table(output_num) {
                 log!(self.opt.log, "New table can't be read: {}", e);
                 return Err(e);
             }
@@ -936,6 +1656,21 @@ impl DB {
         Ok(())
     }
 
+    /// verify_new_table opens a newly written table. With `paranoid_checks`, every block of the
+    /// table is read and verified as well.
+    fn verify_new_table(&self, num: FileNum) -> Result<()> {
+        let table = self.cache.borrow_mut().get_table(num)?;
+        if self.opt.paranoid_checks {
+            if let Some((offset, e)) = table.verify_checksums().into_iter().next() {
+                return err(
+                    StatusCode::Corruption,
+                    &format!("table {:06} is corrupt at offset {}: {}", num, offset, e),
+                );
+            }
+        }
+        Ok(())
+    }
+
     fn install_compaction_results(&mut self, mut cs: CompactionState) -> Result<()> {
         log!(
             self.opt.log,
@@ -943,17 +1678,54 @@ impl DB {
             cs.compaction.num_inputs(0),
             cs.compaction.level(),
             cs.compaction.num_inputs(1),
-            cs.compaction.level() + 1,
+            cs.compaction.output_level(),
             cs.total_bytes
         );
         cs.compaction.add_input_deletions();
         let level = cs.compaction.level();
+        let output_level = cs.compaction.output_level();
         for output in &cs.outputs {
-            cs.compaction.edit().add_file(level + 1, output.clone());
+            cs.compaction.edit().add_file(output_level, output.clone());
+        }
+
+        let mut info = CompactionJobInfo {
+            level,
+            output_level,
+            inputs: vec![],
+            outputs: cs.outputs.iter().map(|f| f.num).collect(),
+            bytes_read: 0,
+            bytes_written: cs.total_bytes,
+            micros: cs.micros,
+            trivial_move: false,
+        };
+        for parent in 0..2 {
+            for i in 0..cs.compaction.num_inputs(parent) {
+                let f = cs.compaction.input(parent, i);
+                info.inputs.push(f.num);
+                info.bytes_read += f.size;
+            }
         }
+
         self.vset
             .borrow_mut()
-            .log_and_apply(cs.compaction.into_edit())
+            .log_and_apply(cs.compaction.into_edit())?;
+        for output in &cs.outputs {
+            let created = TableFileCreationInfo {
+                file_num: output.num,
+                level: output_level,
+                bytes: output.size,
+            };
+            self.notify(|l| l.on_table_file_created(&created));
+        }
+        self.notify(|l| l.on_compaction_completed(&info));
+        Ok(())
+    }
+
+    /// notify calls `f` on all event listeners.
+    fn notify<F: Fn(&dyn EventListener)>(&self, f: F) {
+        for l in self.opt.listeners.iter() {
+            f(l.as_ref().as_ref());
+        }
     }
 }
 
@@ -969,6 +1741,14 @@ struct CompactionState {
     outputs: Vec<FileMetaData>,
     builder: Option<TableBuilder<Box<dyn Write>>>,
     total_bytes: usize,
+    micros: u64,
+
+    // The blob file currently written to, and all blob files written.
+    blob: Option<BlobFileWriter>,
+    blob_outputs: Vec<FileNum>,
+    blob_bytes: usize,
+    // References to blob files older than this are moved to new blob files.
+    blob_gc_before: FileNum,
 }
 
 impl CompactionState {
@@ -979,6 +1759,11 @@ impl CompactionState {
             outputs: vec![],
             builder: None,
             total_bytes: 0,
+            micros: 0,
+            blob: None,
+            blob_outputs: vec![],
+            blob_bytes: 0,
+            blob_gc_before: 0,
         }
     }
 
@@ -993,10 +1778,14 @@ impl CompactionState {
             let name = table_file_name(name.as_ref(), o.num);
             let _ = env.delete(&name);
         }
+        self.blob = None;
+        for num in self.blob_outputs.drain(..) {
+            let _ = env.delete(&blob_file_name(name.as_ref(), num));
+        }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 struct CompactionStats {
     micros: u64,
     read: usize,
@@ -1012,14 +1801,22 @@ impl CompactionStats {
 }
 
 // 构建一个新的level0的SSTable
+/// build_table writes the entries of `from` to the table `num`, which is to be added to `level`.
+/// If `blobs` is given, large values are moved to that blob file, which is finished (or deleted if
+/// it remains empty) as well.
 pub fn build_table<I: LdbIterator, P: AsRef<Path>>(
     dbname: P,
     opt: &Options,
     mut from: I,
     num: FileNum,
+    level: usize,
+    mut blobs: Option<&mut BlobFileWriter>,
 ) -> Result<FileMetaData> {
     from.reset();
     let filename = table_file_name(dbname.as_ref(), num);
+    let blob_filename = blobs
+        .as_ref()
+        .map(|b| blob_file_name(dbname.as_ref(), b.num()));
 
     let (mut kbuf, mut vbuf) = (vec![], vec![]);
     let mut firstkey = None;
@@ -1030,25 +1827,44 @@ pub fn build_table<I: LdbIterator, P: AsRef<Path>>(
     // TODO: Replace with catch {} when available.
     let r = (|| -> Result<()> {
         let f = opt.env.open_writable_file(Path::new(&filename))?;
-        let f = BufWriter::new(f);
-        let mut builder = TableBuilder::new(opt.clone(), f);
+        let f = BufWriter::new(RateLimitedWriter::new(f, opt.clone(), IOPriority::Flush));
+        let mut table_opt = opt.clone();
+        table_opt.compression_type = opt.compression_for_level(level, false);
+        // Compression dictionaries are only trained for compaction outputs.
+        table_opt.compression_max_dict_bytes = 0;
+        let mut builder = TableBuilder::new(table_opt, f);
         while from.advance() {
             assert!(from.current(&mut kbuf, &mut vbuf));
+            if let Some(ref mut b) = blobs {
+                if parse_internal_key(&kbuf).0 == ValueType::TypeValue {
+                    separate_value(opt, b, &mut kbuf, &mut vbuf)?;
+                }
+            }
             if firstkey.is_none() {
                 firstkey = Some(kbuf.clone());
             }
             builder.add(&kbuf, &vbuf)?;
         }
         builder.finish()?;
+        if let Some(ref mut b) = blobs {
+            b.finish()?;
+        }
         Ok(())
     })();
 
+    let mut md = FileMetaData::default();
+    if let Some(ref b) = blobs {
+        if r.is_err() || b.entries() == 0 {
+            let _ = opt.env.delete(blob_filename.as_ref().unwrap());
+        } else {
+            md.blob_files.push(b.num());
+        }
+    }
     if let Err(e) = r {
         let _ = opt.env.delete(Path::new(&filename));
         return Err(e);
     }
 
-    let mut md = FileMetaData::default();
     if firstkey.is_none() {
         let _ = opt.env.delete(Path::new(&filename));
     } else {
@@ -1060,6 +1876,27 @@ pub fn build_table<I: LdbIterator, P: AsRef<Path>>(
     Ok(md)
 }
 
+fn owned_bound(b: Bound<&&[u8]>) -> Bound<Vec<u8>> {
+    match b {
+        Bound::Included(k) => Bound::Included(k.to_vec()),
+        Bound::Excluded(k) => Bound::Excluded(k.to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// prefix_successor returns the smallest key (in bytewise order) that is greater than all keys
+/// starting with `prefix`, or None if there is no such key.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut succ = prefix.to_vec();
+    while let Some(last) = succ.pop() {
+        if last < 0xff {
+            succ.push(last + 1);
+            return Some(succ);
+        }
+    }
+    None
+}
+
 fn log_file_name(db: &Path, num: FileNum) -> PathBuf {
     db.join(format!("{:06}.log", num))
 }
@@ -1093,6 +1930,20 @@ pub mod testutil {
 
     use version::testutil::make_version;
 
+    /// list_files returns the sorted numbers of the files of type `typ` in the directory `dir`.
+    pub fn list_files(env: &dyn Env, dir: &str, typ: FileType) -> Vec<FileNum> {
+        let mut nums: Vec<FileNum> = env
+            .children(Path::new(dir))
+            .unwrap()
+            .iter()
+            .filter_map(|name| parse_file_name(name).ok())
+            .filter(|(_, t)| *t == typ)
+            .map(|(num, _)| num)
+            .collect();
+        nums.sort();
+        nums
+    }
+
     /// build_db creates a database filled with the tables created by make_version().
     pub fn build_db() -> (DB, Options) {
         let name = "db";
@@ -1107,7 +1958,7 @@ pub mod testutil {
         // 30 entries in these tables.
         ve.set_last_seq(30);
 
-        for l in 0..NUM_LEVELS {
+        for l in 0..v.num_levels() {
             for f in &v.files[l] {
                 ve.add_file(l, f.borrow().clone());
             }
@@ -1129,7 +1980,7 @@ pub mod testutil {
         let mut v = v.borrow_mut();
 
         let mut ftc = None;
-        for l in 0..NUM_LEVELS {
+        for l in 0..v.num_levels() {
             for f in &v.files[l] {
                 if f.borrow().num == num {
                     ftc = Some((f.clone(), l));
@@ -1147,15 +1998,26 @@ pub mod testutil {
 
 #[cfg(test)]
 mod tests {
-    use super::testutil::{build_db, set_file_to_compact};
+    use super::testutil::{build_db, list_files, set_file_to_compact};
     use super::*;
 
+    use cmp::{CompositeCmp, DefaultCmp, ReverseBytewiseCmp, U64Cmp};
+    use env::{Logger, RandomAccess};
     use error::Status;
+    use filter::RibbonPolicy;
     use key_types::LookupKey;
     use mem_env::MemEnv;
-    use options;
+    use memtable_rep::MemTableRepType;
+    use options::{self, CompressionType};
+    use rate_limiter::RateLimiter;
+    use statistics::Statistics;
     use test_util::LdbIteratorIter;
+    use types::{current_key_val, NUM_LEVELS};
     use version::testutil::make_version;
+    use version::FileMetaHandle;
+
+    use std::cell::Cell;
+    use std::io::Read;
 
     #[test]
     fn test_db_impl_open_info_log() {
@@ -1314,6 +2176,202 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_db_impl_open_read_only() {
+        let mut opt = options::for_test();
+        opt.reuse_logs = false;
+        opt.reuse_manifest = false;
+        let env = opt.env.clone();
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            db.put(b"abc", b"def").unwrap();
+            db.put(b"xyz", b"123").unwrap();
+            db.delete(b"abc").unwrap();
+            db.flush().unwrap();
+        }
+
+        let mut before = env.children(Path::new("db/")).unwrap();
+        before.sort();
+        {
+            let mut db = DB::open_read_only("db", opt.clone()).unwrap();
+            assert!(db.lock.is_none());
+            assert_eq!(3, db.mem.len());
+            assert_eq!(None, db.get(b"abc"));
+            assert_eq!(Some(b"123".to_vec()), db.get(b"xyz"));
+
+            let want_err = Status::new(StatusCode::NotSupported, "database is opened read-only");
+            assert_eq!(want_err, db.put(b"abc", b"ghi").err().unwrap());
+            assert_eq!(want_err, db.delete(b"xyz").err().unwrap());
+            assert_eq!(want_err, db.write(WriteBatch::new(), true).err().unwrap());
+            assert_eq!(want_err, db.compact_range(b"a", b"z").err().unwrap());
+
+            // The lock isn't taken, so several read-only instances can coexist.
+            let _ = DB::open_read_only("db", opt.clone()).unwrap();
+        }
+        let mut after = env.children(Path::new("db/")).unwrap();
+        after.sort();
+        assert_eq!(before, after);
+
+        assert_eq!(
+            StatusCode::NotFound,
+            DB::open_read_only("nodb", opt).err().unwrap().code
+        );
+    }
+
+    #[test]
+    fn test_db_impl_compare_and_swap() {
+        let mut db = build_db().0;
+
+        // Table entry.
+        assert!(!db.compare_and_swap(b"aaa", Some(b"val2"), Some(b"new1")).unwrap());
+        assert!(!db.compare_and_swap(b"aaa", None, Some(b"new1")).unwrap());
+        assert_eq!(Some(b"val1".to_vec()), db.get(b"aaa"));
+        assert!(db.compare_and_swap(b"aaa", Some(b"val1"), Some(b"new1")).unwrap());
+        assert_eq!(Some(b"new1".to_vec()), db.get(b"aaa"));
+
+        // Memtable entry, deleted by swapping in None.
+        assert!(db.compare_and_swap(b"aaa", Some(b"new1"), None).unwrap());
+        assert_eq!(None, db.get(b"aaa"));
+        assert!(!db.compare_and_swap(b"aaa", Some(b"new1"), None).unwrap());
+
+        assert!(db.put_if_absent(b"aaa", b"new2").unwrap());
+        assert!(!db.put_if_absent(b"aaa", b"new3").unwrap());
+        assert!(db.put_if_absent(b"xyz", b"new4").unwrap());
+        assert_eq!(Some(b"new2".to_vec()), db.get(b"aaa"));
+        assert_eq!(Some(b"new4".to_vec()), db.get(b"xyz"));
+
+        // Preconditions in a batch apply to all of its entries.
+        let last_seq = db.vset.borrow().last_seq;
+        let mut wb = WriteBatch::new();
+        wb.expect(b"aaa", Some(b"new2"));
+        wb.expect(b"xyz", None);
+        wb.put(b"aaa", b"new5");
+        wb.delete(b"xyz");
+        assert_eq!(
+            StatusCode::PreconditionFailed,
+            db.write(wb, false).err().unwrap().code
+        );
+        assert_eq!(last_seq, db.vset.borrow().last_seq);
+        assert_eq!(Some(b"new2".to_vec()), db.get(b"aaa"));
+
+        let mut wb = WriteBatch::new();
+        wb.expect(b"aaa", Some(b"new2"));
+        wb.expect(b"xyz", Some(b"new4"));
+        wb.put(b"aaa", b"new5");
+        wb.delete(b"xyz");
+        db.write(wb, false).unwrap();
+        assert_eq!(Some(b"new5".to_vec()), db.get(b"aaa"));
+        assert_eq!(None, db.get(b"xyz"));
+    }
+
+
+    #[test]
+    fn test_db_impl_statistics() {
+        let mut opt = options::for_test();
+        let stats = share(Statistics::new());
+        opt.statistics = Some(stats.clone());
+        let mut db = DB::open("db", opt).unwrap();
+
+        db.put(b"aaa", b"val1").unwrap();
+        db.put(b"aab", b"val2").unwrap();
+        assert_eq!(Some(b"val1".to_vec()), db.get(b"aaa"));
+        {
+            let s = stats.borrow();
+            assert_eq!(1, s.ticker(Ticker::MemtableHit));
+            assert_eq!(0, s.ticker(Ticker::MemtableMiss));
+            assert!(s.ticker(Ticker::WalBytes) > 0);
+            assert_eq!(2, s.histogram(HistogramType::Write).count());
+            assert_eq!(1, s.histogram(HistogramType::Get).count());
+        }
+
+        db.compact_range(b"aaa", b"aab").unwrap();
+        assert_eq!(Some(b"val2".to_vec()), db.get(b"aab"));
+        assert_eq!(Some(b"val2".to_vec()), db.get(b"aab"));
+        assert_eq!(None, db.get(b"aaa0"));
+        let mut iter = db.new_iter().unwrap();
+        iter.seek(b"aab");
+        assert!(iter.valid());
+
+        let snap = stats.borrow().snapshot();
+        stats.borrow_mut().reset();
+        assert_eq!(0, stats.borrow().ticker(Ticker::MemtableMiss));
+
+        assert_eq!(3, snap.ticker(Ticker::MemtableMiss));
+        assert_eq!(1, snap.ticker(Ticker::BlockCacheMiss));
+        assert!(snap.ticker(Ticker::BlockCacheHit) >= 1);
+        assert!(snap.ticker(Ticker::BlockReadBytes) > 0);
+        assert!(snap.ticker(Ticker::BloomFilterPositive) >= 2);
+        assert_eq!(1, snap.ticker(Ticker::BloomFilterUseful));
+        assert_eq!(4, snap.histogram(HistogramType::Get).count());
+        assert_eq!(1, snap.histogram(HistogramType::Seek).count());
+        assert!(snap.histogram(HistogramType::Compaction).count() >= 1);
+        assert!((0..NUM_LEVELS).map(|l| snap.level_bytes_written(l)).sum::<u64>() > 0);
+        assert!(snap.to_string().contains("memtable.miss COUNT : 3\n"));
+    }
+    #[test]
+    fn test_db_impl_range() {
+        let mut db = build_db().0;
+        db.put(b"aac", b"val6").unwrap();
+        db.put(b"aab", b"val7").unwrap();
+        db.delete(b"aax").unwrap();
+
+        let collect = |it: &mut dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>| {
+            it.map(|r| r.unwrap().0).collect::<Vec<Vec<u8>>>()
+        };
+        let keys = |ks: &[&[u8]]| ks.iter().map(|k| k.to_vec()).collect::<Vec<Vec<u8>>>();
+
+        assert_eq!(
+            keys(&[b"aab", b"aac", b"aba"]),
+            collect(&mut db.range(b"aab".as_ref()..b"bab".as_ref()))
+        );
+        assert_eq!(
+            keys(&[b"aba", b"aac", b"aab"]),
+            collect(&mut db.range(b"aab".as_ref()..b"bab".as_ref()).rev())
+        );
+        assert_eq!(
+            keys(&[b"aab", b"aac", b"aba", b"bab"]),
+            collect(&mut db.range(b"aaa".as_ref()..=b"bab".as_ref()).skip(1))
+        );
+        assert_eq!(
+            keys(&[b"aaa", b"aab"]),
+            collect(&mut db.range(..b"aac".as_ref()))
+        );
+        assert_eq!(
+            keys(&[b"iba", b"iaa", b"hba", b"haa", b"gda"]),
+            collect(&mut db.range(b"gca".as_ref()..).rev())
+        );
+        assert_eq!(
+            keys(&[b"aab", b"aac"]),
+            collect(&mut db.range((
+                Bound::Excluded(b"aaa".as_ref()),
+                Bound::Excluded(b"aba".as_ref())
+            )))
+        );
+        assert!(collect(&mut db.range(b"zzz".as_ref()..)).is_empty());
+        assert!(collect(&mut db.range(b"bab".as_ref()..b"bab".as_ref())).is_empty());
+
+        // Both ends meet in the middle.
+        let mut it = db.range(..);
+        let first = it.next().unwrap().unwrap();
+        let last = it.next_back().unwrap().unwrap();
+        assert_eq!((b"aaa".to_vec(), b"val1".to_vec()), first);
+        assert_eq!((b"iba".to_vec(), b"val2".to_vec()), last);
+        let rest = collect(&mut it);
+        assert!(!rest.is_empty());
+        assert!(!rest.contains(&first.0) && !rest.contains(&last.0));
+        let all = collect(&mut db.range(..));
+        assert_eq!(all.len(), rest.len() + 2);
+
+        assert_eq!(keys(&[b"aaa", b"aab", b"aac"]), collect(&mut db.prefix(b"aa")));
+        assert_eq!(keys(&[b"aac", b"aab", b"aaa"]), collect(&mut db.prefix(b"aa").rev()));
+        assert!(collect(&mut db.prefix(b"aax")).is_empty());
+        assert_eq!(all, collect(&mut db.prefix(b"")));
+
+        assert_eq!(Some(b"ab".to_vec()), prefix_successor(b"aa"));
+        assert_eq!(Some(b"b".to_vec()), prefix_successor(b"a\xff\xff"));
+        assert_eq!(None, prefix_successor(b"\xff"));
+    }
+
     #[test]
     fn test_db_impl_compact_range() {
         let (mut db, opt) = build_db();
@@ -1405,7 +2463,7 @@ mod tests {
         opt.block_size = 128;
         let mt = build_memtable();
 
-        let f = build_table("db", &opt, mt.iter(), 123).unwrap();
+        let f = build_table("db", &opt, mt.iter(), 123, 0, None).unwrap();
         let path = Path::new("db/000123.ldb");
 
         assert_eq!(
@@ -1444,13 +2502,16 @@ mod tests {
 
             let mut tc = TableCache::new("db", opt.clone(), 100);
             let tbl = tc.get_table(123).unwrap();
+            let mut iter = tbl.iter();
             // The last two entries are skipped due to the corruption above.
             assert_eq!(
                 5,
-                LdbIteratorIter::wrap(&mut tbl.iter())
+                LdbIteratorIter::wrap(&mut iter)
                     .map(|v| println!("{:?}", v))
                     .count()
             );
+            // ...but the error is reported.
+            assert_eq!(StatusCode::Corruption, iter.status().err().unwrap().code);
         }
     }
 
@@ -1625,6 +2686,999 @@ mod tests {
         assert_eq!(2, v.borrow().files[2].len());
     }
 
+    struct RecordingListener(Shared<Vec<String>>);
+
+    impl EventListener for RecordingListener {
+        fn on_flush_completed(&self, info: &FlushJobInfo) {
+            self.0
+                .borrow_mut()
+                .push(format!("flush {} L{}", info.file_num, info.level));
+        }
+        fn on_compaction_completed(&self, info: &CompactionJobInfo) {
+            self.0.borrow_mut().push(format!(
+                "compaction L{}->L{} {:?} => {:?}",
+                info.level, info.output_level, info.inputs, info.outputs
+            ));
+        }
+        fn on_table_file_created(&self, info: &TableFileCreationInfo) {
+            self.0
+                .borrow_mut()
+                .push(format!("created {} L{}", info.file_num, info.level));
+        }
+        fn on_table_file_deleted(&self, info: &TableFileDeletionInfo) {
+            self.0
+                .borrow_mut()
+                .push(format!("deleted {}", info.file_num));
+        }
+    }
+
+    #[test]
+    fn test_db_impl_event_listener() {
+        let mut db = build_db().0;
+        let events = share(vec![]);
+        db.opt
+            .listeners
+            .push(Rc::new(Box::new(RecordingListener(events.clone()))));
+
+        let v = db.current();
+        v.borrow_mut().compaction_score = Some(2.0);
+        v.borrow_mut().compaction_level = Some(1);
+        db.maybe_do_compaction().unwrap();
+
+        db.put(b"xxx", b"yyy").unwrap();
+        db.make_room_for_write(true).unwrap();
+
+        let events = events.borrow();
+        assert_eq!(8, events.len());
+        assert_eq!("created 13 L2", events[0]);
+        assert_eq!("compaction L1->L2 [3, 4, 5, 6] => [13]", events[1]);
+        // Obsolete files are deleted in directory order.
+        let mut deleted = events[2..6].to_vec();
+        deleted.sort();
+        assert_eq!(vec!["deleted 3", "deleted 4", "deleted 5", "deleted 6"], deleted);
+        assert_eq!("created 15 L2", events[6]);
+        assert_eq!("flush 15 L2", events[7]);
+    }
+
+    #[test]
+    fn test_db_impl_rate_limiter() {
+        let mut opt = options::for_test();
+        let rl = share(RateLimiter::new(opt.env.clone(), 10 << 20));
+        opt.rate_limiter = Some(rl.clone());
+        let mut db = DB::open("db", opt).unwrap();
+
+        for round in 0..2 {
+            for i in 0..100 {
+                let k = format!("key{:03}", i);
+                db.put(k.as_bytes(), format!("{}", round).as_bytes()).unwrap();
+            }
+            db.compact_range(b"key000", b"key999").unwrap();
+        }
+        assert_eq!(Some(b"1".to_vec()), db.get(b"key050"));
+
+        let rl = rl.borrow();
+        assert_eq!(2, rl.total_requests(IOPriority::Flush));
+        assert!(rl.total_bytes_through(IOPriority::Flush) > 2 * 100 * 6);
+        assert!(rl.total_requests(IOPriority::Compaction) > 0);
+        assert!(rl.total_bytes_through(IOPriority::Compaction) > 2 * 100 * 6);
+    }
+
+    #[test]
+    fn test_db_impl_flush_memtable_and_wal() {
+        let opt = options::for_test();
+        let env = opt.env.clone();
+        let mut db = DB::open("db", opt).unwrap();
+
+        db.put(b"abc", b"def").unwrap();
+        let log = log_file_name(&db.path, db.log_num.unwrap());
+        assert_eq!(0, env.size_of(&log).unwrap());
+        db.flush_wal(false).unwrap();
+        assert!(env.size_of(&log).unwrap() > 0);
+
+        db.flush_memtable(true).unwrap();
+        assert_eq!(0, db.mem.len());
+        assert!(db.imm.is_none());
+        assert_ne!(log, log_file_name(&db.path, db.log_num.unwrap()));
+        {
+            let v = db.current();
+            let v = v.borrow();
+            assert_eq!(1, (0..NUM_LEVELS).map(|l| v.num_level_files(l)).sum::<usize>());
+        }
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+
+        // An empty memtable isn't written.
+        db.flush_memtable(false).unwrap();
+        let v = db.current();
+        assert_eq!(1, (0..NUM_LEVELS).map(|l| v.borrow().num_level_files(l)).sum::<usize>());
+    }
+
+    /// TestEnv is a MemEnv that counts the calls to sync_data(), and that corrupts the first byte
+    /// of new table files while `corrupt_tables` is set.
+    struct TestEnv {
+        env: MemEnv,
+        syncs: Rc<Cell<usize>>,
+        corrupt_tables: Rc<Cell<bool>>,
+    }
+
+    impl TestEnv {
+        fn new() -> TestEnv {
+            TestEnv {
+                env: MemEnv::new(),
+                syncs: Rc::new(Cell::new(0)),
+                corrupt_tables: Rc::new(Cell::new(false)),
+            }
+        }
+    }
+
+    /// CorruptingWriter flips a bit of the first byte written to it.
+    struct CorruptingWriter(Box<dyn Write>, bool);
+
+    impl Write for CorruptingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.1 || buf.is_empty() {
+                return self.0.write(buf);
+            }
+            self.1 = false;
+            let mut corrupted = buf.to_vec();
+            corrupted[0] ^= 0x40;
+            self.0.write(&corrupted)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Env for TestEnv {
+        fn open_sequential_file(&self, p: &Path) -> Result<Box<dyn Read>> {
+            self.env.open_sequential_file(p)
+        }
+        fn open_random_access_file(&self, p: &Path) -> Result<Box<dyn RandomAccess>> {
+            self.env.open_random_access_file(p)
+        }
+        fn open_writable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
+            let f = self.env.open_writable_file(p)?;
+            let is_table = match p.file_name().and_then(|n| n.to_str()).map(parse_file_name) {
+                Some(Ok((_, FileType::Table))) => true,
+                _ => false,
+            };
+            if is_table && self.corrupt_tables.get() {
+                Ok(Box::new(CorruptingWriter(f, true)))
+            } else {
+                Ok(f)
+            }
+        }
+        fn open_appendable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
+            self.env.open_appendable_file(p)
+        }
+        fn sync_data(&self, p: &Path) -> Result<()> {
+            self.syncs.set(self.syncs.get() + 1);
+            self.env.sync_data(p)
+        }
+        fn exists(&self, p: &Path) -> Result<bool> {
+            self.env.exists(p)
+        }
+        fn children(&self, p: &Path) -> Result<Vec<PathBuf>> {
+            self.env.children(p)
+        }
+        fn size_of(&self, p: &Path) -> Result<usize> {
+            self.env.size_of(p)
+        }
+        fn delete(&self, p: &Path) -> Result<()> {
+            self.env.delete(p)
+        }
+        fn mkdir(&self, p: &Path) -> Result<()> {
+            self.env.mkdir(p)
+        }
+        fn rmdir(&self, p: &Path) -> Result<()> {
+            self.env.rmdir(p)
+        }
+        fn rename(&self, old: &Path, new: &Path) -> Result<()> {
+            self.env.rename(old, new)
+        }
+        fn lock(&self, p: &Path) -> Result<FileLock> {
+            self.env.lock(p)
+        }
+        fn unlock(&self, l: FileLock) -> Result<()> {
+            self.env.unlock(l)
+        }
+        fn new_logger(&self, p: &Path) -> Result<Logger> {
+            self.env.new_logger(p)
+        }
+        fn micros(&self) -> u64 {
+            self.env.micros()
+        }
+    }
+
+    #[test]
+    fn test_db_impl_flush_wal_sync() {
+        let env = TestEnv::new();
+        let syncs = env.syncs.clone();
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(env));
+        let mut db = DB::open("db", opt).unwrap();
+
+        db.put(b"abc", b"def").unwrap();
+        db.flush_wal(false).unwrap();
+        assert_eq!(0, syncs.get());
+        db.flush_wal(true).unwrap();
+        assert_eq!(1, syncs.get());
+        db.flush().unwrap();
+        assert_eq!(2, syncs.get());
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"xyz", b"123");
+        db.write(batch, true).unwrap();
+        assert_eq!(3, syncs.get());
+        db.put(b"xyz", b"456").unwrap();
+        assert_eq!(3, syncs.get());
+    }
+
+    #[test]
+    fn test_db_impl_failed_compaction_keeps_inputs() {
+        let env = TestEnv::new();
+        let corrupt_tables = env.corrupt_tables.clone();
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(env));
+        opt.paranoid_checks = true;
+        let mut db = DB::open("db", opt).unwrap();
+
+        for round in 0..2 {
+            for i in 0..100 {
+                let val = format!("val{}-{}", round, i);
+                db.put(format!("key{:03}", i).as_bytes(), val.as_bytes())
+                    .unwrap();
+            }
+            db.flush_memtable(true).unwrap();
+        }
+        let num_files = |db: &DB| {
+            let v = db.current();
+            let v = v.borrow();
+            (0..NUM_LEVELS).map(|l| v.num_level_files(l)).sum::<usize>()
+        };
+        assert_eq!(2, num_files(&db));
+
+        // The compaction output fails verification, so the inputs must stay in place.
+        corrupt_tables.set(true);
+        let e = db.compact_range(b"key000", b"key099").unwrap_err();
+        assert_eq!(StatusCode::Corruption, e.code);
+        corrupt_tables.set(false);
+        assert_eq!(2, num_files(&db));
+        for i in 0..100 {
+            let val = format!("val1-{}", i);
+            assert_eq!(Some(val.into_bytes()), db.get(format!("key{:03}", i).as_bytes()));
+        }
+
+        db.compact_range(b"key000", b"key099").unwrap();
+        assert_eq!(1, num_files(&db));
+        assert_eq!(Some(b"val1-42".to_vec()), db.get(b"key042"));
+    }
+
+    #[test]
+    fn test_db_impl_memtable_reps() {
+        for &rep in [MemTableRepType::HashIndex, MemTableRepType::Vector].iter() {
+            let mut opt = options::for_test();
+            opt.memtable_rep = rep;
+            let mut db = DB::open("db", opt).unwrap();
+
+            db.put(b"bbb", b"1").unwrap();
+            db.put(b"aaa", b"2").unwrap();
+            db.put(b"ccc", b"3").unwrap();
+            db.delete(b"bbb").unwrap();
+            assert_eq!(Some(b"2".to_vec()), db.get(b"aaa"));
+            assert_eq!(None, db.get(b"bbb"));
+
+            let mut iter = db.new_iter().unwrap();
+            let entries: Vec<Vec<u8>> = LdbIteratorIter::wrap(&mut iter).map(|(k, _)| k).collect();
+            assert_eq!(vec![b"aaa".to_vec(), b"ccc".to_vec()], entries, "{:?}", rep);
+
+            db.flush_memtable(true).unwrap();
+            assert_eq!(Some(b"3".to_vec()), db.get(b"ccc"));
+            assert_eq!(None, db.get(b"bbb"));
+        }
+    }
+
+    #[test]
+    fn test_db_impl_num_levels() {
+        let mut opt = options::for_test();
+        opt.num_levels = 1;
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            DB::open("db", opt.clone()).err().unwrap().code
+        );
+
+        // The first flush goes to the last level.
+        opt.num_levels = 3;
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"abc", b"def").unwrap();
+        db.flush_memtable(true).unwrap();
+        assert_eq!(3, db.current().borrow().num_levels());
+        assert_eq!(1, db.current().borrow().num_level_files(2));
+        drop(db);
+
+        opt.num_levels = 2;
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            DB::open("db", opt.clone()).err().unwrap().code
+        );
+
+        opt.num_levels = 5;
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(5, db.current().borrow().num_levels());
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+        drop(db);
+
+        opt.num_levels = 3;
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+    }
+
+    #[test]
+    fn test_db_impl_write_stalls() {
+        let mut opt = options::for_test();
+        let stats = share(Statistics::new());
+        opt.statistics = Some(stats.clone());
+        opt.level0_file_num_compaction_trigger = 2;
+        opt.level0_slowdown_writes_trigger = 0;
+        opt.level0_stop_writes_trigger = 4;
+        let mut db = DB::open("db", opt).unwrap();
+        let l0_files = |db: &DB| db.current().borrow().num_level_files(0);
+
+        // The first two flushes are placed in level 2 and 1, the following ones in level 0.
+        for i in 0..6 {
+            db.put(b"a", format!("val{}", i).as_bytes()).unwrap();
+            db.put(b"z", format!("val{}", i).as_bytes()).unwrap();
+            db.flush_memtable(true).unwrap();
+        }
+        assert_eq!(4, l0_files(&db));
+        assert_eq!(0, stats.borrow().ticker(Ticker::WriteStops));
+
+        // The stopped write compacts level 0 itself.
+        db.put(b"m", b"val").unwrap();
+        assert_eq!(0, l0_files(&db));
+        assert_eq!(1, stats.borrow().ticker(Ticker::WriteStops));
+        assert_eq!(0, stats.borrow().ticker(Ticker::WriteSlowdowns));
+        assert_eq!(Some(b"val5".to_vec()), db.get(b"a"));
+        assert_eq!(Some(b"val5".to_vec()), db.get(b"z"));
+        assert_eq!(Some(b"val".to_vec()), db.get(b"m"));
+    }
+
+    #[test]
+    fn test_db_impl_write_slowdown() {
+        // With a compaction trigger above the slowdown trigger, there's no compaction to run.
+        for &compaction_trigger in [2, 4].iter() {
+            let mut opt = options::for_test();
+            let stats = share(Statistics::new());
+            opt.statistics = Some(stats.clone());
+            opt.level0_file_num_compaction_trigger = compaction_trigger;
+            opt.level0_slowdown_writes_trigger = 3;
+            opt.level0_stop_writes_trigger = 5;
+            let mut db = DB::open("db", opt).unwrap();
+            let l0_files = |db: &DB| db.current().borrow().num_level_files(0);
+
+            for i in 0..5 {
+                db.put(b"a", format!("val{}", i).as_bytes()).unwrap();
+                db.put(b"z", format!("val{}", i).as_bytes()).unwrap();
+                db.flush_memtable(true).unwrap();
+            }
+            assert_eq!(3, l0_files(&db));
+            db.put(b"a", b"val5").unwrap();
+
+            let slowed_down = compaction_trigger <= 3;
+            assert_eq!(
+                slowed_down as u64,
+                stats.borrow().ticker(Ticker::WriteSlowdowns)
+            );
+            assert_eq!(if slowed_down { 0 } else { 3 }, l0_files(&db));
+            assert_eq!(0, stats.borrow().ticker(Ticker::WriteStops));
+            assert_eq!(Some(b"val5".to_vec()), db.get(b"a"));
+            assert_eq!(Some(b"val4".to_vec()), db.get(b"z"));
+        }
+    }
+
+    #[test]
+    fn test_db_impl_blob_files() {
+        let mut opt = options::for_test();
+        opt.enable_blob_files = true;
+        opt.min_blob_size = 100;
+        let blob_files = |opt: &Options| list_files(&**opt.env, "db", FileType::Blob);
+        let val = |c: u8| vec![c; 1000];
+        let keys: Vec<Vec<u8>> = (0..5).map(|i| format!("key{}", i).into_bytes()).collect();
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"abc", b"small").unwrap();
+        for k in keys.iter() {
+            db.put(k, &val(b'a')).unwrap();
+        }
+        db.flush_memtable(true).unwrap();
+        let first = blob_files(&opt);
+        assert_eq!(1, first.len());
+        {
+            let v = db.current();
+            let v = v.borrow();
+            let tables: Vec<&FileMetaHandle> = v.files.iter().flat_map(|fs| fs.iter()).collect();
+            assert_eq!(1, tables.len());
+            assert_eq!(first, tables[0].borrow().blob_files);
+            assert!(tables[0].borrow().size < 1000);
+        }
+        assert_eq!(Some(b"small".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(val(b'a')), db.get(b"key3"));
+
+        // Once all values in a blob file are overwritten, compactions delete it.
+        for k in keys.iter() {
+            db.put(k, &val(b'b')).unwrap();
+        }
+        db.flush_memtable(true).unwrap();
+        let second = blob_files(&opt)[1];
+        db.compact_range(b"abc", b"key4").unwrap();
+        assert_eq!(vec![second], blob_files(&opt));
+        assert_eq!(Some(val(b'b')), db.get(b"key0"));
+        drop(db);
+
+        // Values are moved out of old blob files.
+        opt.blob_garbage_collection_age_cutoff = 1.0;
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"key0", &val(b'c')).unwrap();
+        db.flush_memtable(true).unwrap();
+        assert_eq!(2, blob_files(&opt).len());
+        db.compact_range(b"abc", b"key4").unwrap();
+        let remaining = blob_files(&opt);
+        assert_eq!(1, remaining.len());
+        assert!(remaining[0] > second + 1);
+        drop(db);
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(Some(b"small".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(val(b'c')), db.get(b"key0"));
+        assert_eq!(Some(val(b'b')), db.get(b"key4"));
+
+        let mut iter = db.new_iter().unwrap();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = LdbIteratorIter::wrap(&mut iter).collect();
+        assert_eq!(6, entries.len());
+        assert_eq!((b"abc".to_vec(), b"small".to_vec()), entries[0]);
+        assert_eq!((b"key0".to_vec(), val(b'c')), entries[1]);
+        assert!(entries[2..].iter().all(|e| e.1 == val(b'b')));
+        iter.seek_to_last();
+        let (mut k, mut v) = (vec![], vec![]);
+        assert!(iter.current(&mut k, &mut v));
+        assert_eq!((b"key4".to_vec(), val(b'b')), (k.clone(), v.clone()));
+        while iter.prev() && iter.current(&mut k, &mut v) && k != b"key0" {}
+        assert_eq!((b"key0".to_vec(), val(b'c')), (k, v));
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_db_impl_paranoid_checks() {
+        let mut opt = options::for_test();
+        opt.paranoid_checks = true;
+        let files = |opt: &Options, typ: FileType| list_files(&**opt.env, "db", typ);
+        let corrupt = |opt: &Options, path: &Path, offset: usize| {
+            let mut buf = vec![];
+            opt.env
+                .open_sequential_file(path)
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            buf[offset] ^= 0x40;
+            opt.env
+                .open_writable_file(path)
+                .unwrap()
+                .write_all(&buf)
+                .unwrap();
+        };
+
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            db.put(b"abc", b"def").unwrap();
+            db.flush_memtable(true).unwrap();
+            db.put(b"xyz", b"123").unwrap();
+            db.flush().unwrap();
+            assert!(db.verify_checksum().is_ok());
+        }
+        let log = log_file_name(Path::new("db"), *files(&opt, FileType::Log).last().unwrap());
+        corrupt(&opt, &log, 10);
+        let r = DB::open("db", opt.clone());
+        assert_eq!(StatusCode::Corruption, r.err().unwrap().code);
+
+        // Without paranoid checks, the rest of the log is dropped.
+        opt.paranoid_checks = false;
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+            assert_eq!(None, db.get(b"xyz"));
+            assert!(db.verify_checksum().is_ok());
+        }
+
+        let table = files(&opt, FileType::Table)[0];
+        corrupt(&opt, &table_file_name("db", table), 0);
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        let e = db.verify_checksum().err().unwrap();
+        assert_eq!(StatusCode::Corruption, e.code);
+        assert!(e.err.contains(&format!("table {:06} at offset 0: ", table)));
+    }
+
+    #[test]
+    fn test_db_impl_partitioned_index_and_filters() {
+        let mut opt = options::for_test();
+        opt.partition_index_and_filters = true;
+        opt.metadata_block_size = 256;
+        opt.block_size = 256;
+
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            for i in 0..2000 {
+                db.put(format!("key{:05}", i * 2).as_bytes(), b"value")
+                    .unwrap();
+            }
+            db.flush_memtable(true).unwrap();
+            db.compact_range(b"key00000", b"key99999").unwrap();
+            assert!(db.verify_checksum().is_ok());
+        }
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for i in 0..2000 {
+            assert_eq!(
+                Some(b"value".to_vec()),
+                db.get(format!("key{:05}", i * 2).as_bytes())
+            );
+            assert_eq!(None, db.get(format!("key{:05}", i * 2 + 1).as_bytes()));
+        }
+        let mut iter = db.new_iter().unwrap();
+        assert_eq!(2000, LdbIteratorIter::wrap(&mut iter).count());
+    }
+
+    #[test]
+    fn test_db_impl_data_block_hash_index() {
+        let mut opt = options::for_test();
+        opt.data_block_hash_index = true;
+        opt.block_restart_interval = 4;
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for i in 0..500 {
+            db.put(format!("key{:03}", i).as_bytes(), b"old").unwrap();
+        }
+        db.flush_memtable(true).unwrap();
+        for i in 0..500 {
+            if i % 3 == 0 {
+                db.delete(format!("key{:03}", i).as_bytes()).unwrap();
+            } else {
+                db.put(format!("key{:03}", i).as_bytes(), b"new").unwrap();
+            }
+        }
+        db.flush_memtable(true).unwrap();
+
+        for i in 0..500 {
+            let expected = if i % 3 == 0 { None } else { Some(b"new".to_vec()) };
+            assert_eq!(expected, db.get(format!("key{:03}", i).as_bytes()));
+        }
+        assert_eq!(None, db.get(b"key"));
+        assert_eq!(None, db.get(b"key1000"));
+    }
+
+    #[test]
+    fn test_db_impl_full_filters() {
+        for &partitioned in [false, true].iter() {
+            let mut opt = options::for_test();
+            let stats = share(Statistics::new());
+            opt.statistics = Some(stats.clone());
+            opt.filter_policy = Rc::new(Box::new(RibbonPolicy::new(10)));
+            opt.full_filters = true;
+            opt.partition_index_and_filters = partitioned;
+
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            for i in 0..500 {
+                db.put(format!("key{:03}", i * 2).as_bytes(), b"val").unwrap();
+            }
+            db.flush_memtable(true).unwrap();
+
+            for i in 0..500 {
+                let val = db.get(format!("key{:03}", i * 2).as_bytes());
+                assert_eq!(Some(b"val".to_vec()), val);
+                assert_eq!(None, db.get(format!("key{:03}", i * 2 + 1).as_bytes()));
+            }
+            // Almost all missing keys are rejected by the filter.
+            assert!(stats.borrow().ticker(Ticker::BloomFilterUseful) > 480);
+        }
+    }
+
+    #[test]
+    fn test_db_impl_comparators() {
+        let mut opt = options::for_test();
+        opt.cmp = Rc::new(Box::new(CompositeCmp::new(
+            "test.SeriesComparator",
+            8,
+            Rc::new(Box::new(U64Cmp)),
+            Rc::new(Box::new(ReverseBytewiseCmp)),
+        )));
+        // A big-endian series id and timestamp.
+        let key = |id: u64, ts: u32| {
+            let mut k: Vec<u8> = (0..8).rev().map(|i| (id >> (8 * i)) as u8).collect();
+            k.extend((0..4).rev().map(|i| (ts >> (8 * i)) as u8));
+            k
+        };
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for &id in [300, 2, 1].iter() {
+            for ts in 0..100 {
+                db.put(&key(id, ts), &key(id, ts)).unwrap();
+            }
+            db.flush_memtable(true).unwrap();
+        }
+        // Series are ordered by id, and each series newest-first.
+        let found: Vec<Vec<u8>> = LdbIteratorIter::wrap(&mut db.new_iter().unwrap())
+            .map(|(k, _)| k)
+            .collect();
+        let mut expected = vec![];
+        for &id in [1, 2, 300].iter() {
+            expected.extend((0..100).rev().map(|ts| key(id, ts)));
+        }
+        assert_eq!(expected, found);
+        assert_eq!(Some(key(2, 42)), db.get(&key(2, 42)));
+        assert_eq!(None, db.get(&key(3, 42)));
+        drop(db);
+
+        let mut bytewise = opt.clone();
+        bytewise.cmp = Rc::new(Box::new(DefaultCmp));
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            DB::open("db", bytewise).err().unwrap().code
+        );
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(Some(key(300, 99)), db.get(&key(300, 99)));
+    }
+
+    #[test]
+    fn test_db_impl_compaction_after_released_snapshot() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        db.put(b"key", b"old").unwrap();
+        {
+            let ss = db.get_snapshot();
+            let _iter = db.new_iter().unwrap();
+            assert_eq!(Some(b"old".to_vec()), db.get_at(&ss, b"key").unwrap());
+        }
+        db.put(b"key", b"new").unwrap();
+        db.flush_memtable(true).unwrap();
+        db.compact_range(b"a", b"z").unwrap();
+        // With no live snapshots, the old version is dropped.
+        let versions = LdbIteratorIter::wrap(&mut db.merge_iterators().unwrap()).count();
+        assert_eq!(1, versions);
+        assert_eq!(Some(b"new".to_vec()), db.get(b"key"));
+    }
+
+    #[test]
+    fn test_db_impl_user_timestamps() {
+        let mut opt = options::for_test();
+        opt.timestamp_size = 8;
+        let ts = |t: u64| -> Vec<u8> { (0..8).rev().map(|i| (t >> (8 * i)) as u8).collect() };
+        let key = |k: &str, t: u64| {
+            let mut key = k.as_bytes().to_vec();
+            key.extend_from_slice(&ts(t));
+            key
+        };
+
+        let mut db = DB::open("db", opt).unwrap();
+        db.put(&key("a", 10), b"a10").unwrap();
+        db.put(&key("a", 20), b"a20").unwrap();
+        db.put(&key("b", 15), b"b15").unwrap();
+        db.delete(&key("b", 25)).unwrap();
+        db.put(&key("c", 5), b"c5").unwrap();
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.put(b"short", b"").err().unwrap().code
+        );
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.get_at_timestamp(b"a", b"1234").err().unwrap().code
+        );
+
+        let expected: Vec<(&str, u64, Option<&str>)> = vec![
+            ("a", 5, None),
+            ("a", 15, Some("a10")),
+            ("a", 25, Some("a20")),
+            ("b", 20, Some("b15")),
+            ("b", 30, None),
+            ("c", 100, Some("c5")),
+            ("d", 100, None),
+        ];
+        for &flush in [false, true].iter() {
+            if flush {
+                db.flush_memtable(true).unwrap();
+            }
+            for &(k, t, ref v) in expected.iter() {
+                let found = db.get_at_timestamp(k.as_bytes(), &ts(t)).unwrap();
+                assert_eq!(v.map(|v| v.as_bytes().to_vec()), found, "{} {} {}", k, t, flush);
+            }
+
+            let found: Vec<Vec<u8>> =
+                LdbIteratorIter::wrap(&mut db.new_iter_at_timestamp(&ts(20)).unwrap())
+                    .map(|(k, _)| k)
+                    .collect();
+            assert_eq!(vec![key("a", 20), key("b", 15), key("c", 5)], found);
+            let found: Vec<Vec<u8>> =
+                LdbIteratorIter::wrap(&mut db.new_iter_at_timestamp(&ts(30)).unwrap())
+                    .map(|(k, _)| k)
+                    .collect();
+            assert_eq!(vec![key("a", 20), key("c", 5)], found);
+
+            let mut iter = db.new_iter_at_timestamp(&ts(12)).unwrap();
+            iter.seek_to_last();
+            assert_eq!(Some((key("c", 5), b"c5".to_vec())), current_key_val(&iter));
+            iter.prev();
+            assert_eq!(Some((key("a", 10), b"a10".to_vec())), current_key_val(&iter));
+            iter.prev();
+            assert!(!iter.valid());
+            iter.seek(&key("a", 15));
+            assert_eq!(Some((key("a", 10), b"a10".to_vec())), current_key_val(&iter));
+            iter.advance();
+            assert_eq!(Some((key("c", 5), b"c5".to_vec())), current_key_val(&iter));
+        }
+
+        // Versions hidden by one at or below full_history_ts_low are dropped by compactions.
+        db.put(&key("a", 30), b"a30").unwrap();
+        db.set_full_history_ts_low(&ts(25)).unwrap();
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.set_full_history_ts_low(&ts(20)).err().unwrap().code
+        );
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.get_at_timestamp(b"a", &ts(15)).err().unwrap().code
+        );
+        db.compact_range(&key("a", u64::max_value()), &key("d", 0))
+            .unwrap();
+        let versions = |db: &mut DB, k: &[u8]| {
+            LdbIteratorIter::wrap(&mut db.merge_iterators().unwrap())
+                .filter(|(ikey, _)| parse_internal_key(ikey).2.starts_with(k))
+                .count()
+        };
+        assert_eq!(2, versions(&mut db, b"a"));
+        assert_eq!(0, versions(&mut db, b"b"));
+        assert_eq!(1, versions(&mut db, b"c"));
+        assert_eq!(Some(b"a20".to_vec()), db.get_at_timestamp(b"a", &ts(25)).unwrap());
+        assert_eq!(Some(b"a30".to_vec()), db.get_at_timestamp(b"a", &ts(30)).unwrap());
+        assert_eq!(None, db.get_at_timestamp(b"b", &ts(30)).unwrap());
+    }
+
+    #[test]
+    fn test_db_impl_timestamps_with_hash_index() {
+        let mut opt = options::for_test();
+        opt.timestamp_size = 8;
+        opt.data_block_hash_index = true;
+        let key = |k: &str, t: u8| {
+            let mut key = k.as_bytes().to_vec();
+            key.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, t]);
+            key
+        };
+
+        let mut db = DB::open("db", opt).unwrap();
+        for (i, k) in ["a", "b", "c", "d"].iter().enumerate() {
+            db.put(&key(k, 10 + i as u8), k.as_bytes()).unwrap();
+        }
+        db.flush_memtable(true).unwrap();
+
+        // The hash index of the data block is built from the keys without their timestamps.
+        for &k in ["a", "b", "c", "d"].iter() {
+            for &t in [13, 20, 255].iter() {
+                assert_eq!(
+                    Some(k.as_bytes().to_vec()),
+                    db.get_at_timestamp(k.as_bytes(), &key("", t)).unwrap(),
+                    "{} {}",
+                    k,
+                    t
+                );
+            }
+        }
+        assert_eq!(None, db.get_at_timestamp(b"e", &key("", 20)).unwrap());
+    }
+
+    #[test]
+    fn test_db_impl_timestamp_size_mismatch() {
+        let mut opt = options::for_test();
+        opt.timestamp_size = 8;
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            db.put(b"abc\0\0\0\0\0\0\0\x01", b"def").unwrap();
+            db.flush().unwrap();
+        }
+
+        for &ts_size in [0, 4].iter() {
+            let mut opt = opt.clone();
+            opt.timestamp_size = ts_size;
+            let e = DB::open("db", opt).err().unwrap();
+            assert_eq!(StatusCode::InvalidArgument, e.code, "{}", ts_size);
+        }
+
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(
+            Some(b"def".to_vec()),
+            db.get_at_timestamp(b"abc", &[0, 0, 0, 0, 0, 0, 0, 2]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_db_impl_open_checks_compression() {
+        let types = [
+            CompressionType::CompressionNone,
+            CompressionType::CompressionSnappy,
+            CompressionType::CompressionZstd,
+            CompressionType::CompressionLZ4,
+        ];
+        for &ctype in types.iter() {
+            for place in 0..3 {
+                let mut opt = options::for_test();
+                match place {
+                    0 => opt.compression_type = ctype,
+                    1 => opt.compression_per_level = vec![CompressionType::CompressionNone, ctype],
+                    _ => opt.bottommost_compression = Some(ctype),
+                }
+                match DB::open("db", opt) {
+                    Ok(_) => assert!(compression::is_supported(ctype)),
+                    Err(e) => {
+                        assert!(!compression::is_supported(ctype));
+                        assert_eq!(StatusCode::NotSupported, e.code);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_db_impl_compression_per_level() {
+        let mut opt = options::for_test();
+        opt.compression_per_level = vec![
+            CompressionType::CompressionNone,
+            CompressionType::CompressionNone,
+            CompressionType::CompressionNone,
+            CompressionType::CompressionSnappy,
+        ];
+        opt.bottommost_compression = Some(CompressionType::CompressionZstd);
+        let tables = |db: &DB| -> Vec<(usize, FileMetaData)> {
+            let v = db.current();
+            let v = v.borrow();
+            (0..v.num_levels())
+                .flat_map(|l| v.files[l].iter().map(move |f| (l, f.borrow().clone())))
+                .collect()
+        };
+        let is_zstd = |opt: &Options, num: FileNum| {
+            let f = opt
+                .env
+                .open_random_access_file(&table_file_name("db", num))
+                .unwrap();
+            let mut magic = [0; 4];
+            f.read_at(0, &mut magic).unwrap();
+            magic == [0x28, 0xb5, 0x2f, 0xfd]
+        };
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{:03}", i).as_bytes(), &[b'x'; 1000])
+                .unwrap();
+        }
+        db.flush_memtable(true).unwrap();
+        // The memtable is written to level 2, which is uncompressed.
+        let t = tables(&db);
+        assert_eq!(1, t.len());
+        assert_eq!(2, t[0].0);
+        assert!(t[0].1.size > 100000);
+        assert!(!is_zstd(&opt, t[0].1.num));
+
+        // Compacting it into level 3, which is the bottommost level, uses Zstd.
+        db.compact_range(b"key000", b"key099").unwrap();
+        let t = tables(&db);
+        assert_eq!(1, t.len());
+        assert_eq!(3, t[0].0);
+        assert!(t[0].1.size < 10000);
+        assert!(is_zstd(&opt, t[0].1.num));
+        assert_eq!(Some(vec![b'x'; 1000]), db.get(b"key042"));
+    }
+
+    #[test]
+    fn test_db_impl_universal_compaction() {
+        let mut opt = options::for_test();
+        opt.compaction_style = CompactionStyle::Universal;
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        let num_files = |db: &DB| {
+            let v = db.current();
+            let v = v.borrow();
+            assert_eq!(0, (1..NUM_LEVELS).map(|l| v.num_level_files(l)).sum::<usize>());
+            v.num_level_files(0)
+        };
+
+        // Flushes stay in level 0, even if they don't overlap with anything.
+        for i in 0..4 {
+            for k in 0..20 {
+                let (k, v) = (format!("key{:02}", k), format!("val{}", i));
+                db.put(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            db.delete(format!("key{:02}", i).as_bytes()).unwrap();
+            db.flush_memtable(true).unwrap();
+        }
+        assert_eq!(4, num_files(&db));
+
+        // The newer runs are about three times as large as the oldest one, so all are merged.
+        assert!(db.vset.borrow().needs_compaction());
+        db.maybe_do_compaction().unwrap();
+        assert_eq!(1, num_files(&db));
+        assert!(!db.vset.borrow().needs_compaction());
+        assert_eq!(None, db.get(b"key03"));
+        assert_eq!(Some(b"val3".to_vec()), db.get(b"key00"));
+        assert_eq!(Some(b"val3".to_vec()), db.get(b"key19"));
+
+        db.put(b"key03", b"val4").unwrap();
+        db.flush_memtable(true).unwrap();
+        assert_eq!(2, num_files(&db));
+        db.compact_range(b"key00", b"key19").unwrap();
+        assert_eq!(1, num_files(&db));
+
+        drop(db);
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(1, num_files(&db));
+        assert_eq!(Some(b"val4".to_vec()), db.get(b"key03"));
+        assert_eq!(Some(b"val3".to_vec()), db.get(b"key02"));
+    }
+
+    #[test]
+    fn test_db_impl_fifo_compaction() {
+        let mut opt = options::for_test();
+        opt.compaction_style = CompactionStyle::Fifo;
+        opt.fifo_compaction.max_table_files_size = 50000;
+        opt.fifo_compaction.ttl_seconds = 3600;
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        let val = vec![b'x'; 1000];
+        let files = |db: &DB| {
+            let v = db.current();
+            let v = v.borrow();
+            assert_eq!(0, (1..NUM_LEVELS).map(|l| v.num_level_files(l)).sum::<usize>());
+            let mut nums: Vec<FileNum> = v.files[0].iter().map(|f| f.borrow().num).collect();
+            nums.sort();
+            nums
+        };
+
+        // Every table is about 20 KB, so the oldest one is dropped after the third flush.
+        for i in 0..3 {
+            for k in 0..20 {
+                db.put(format!("{}-key{:02}", i, k).as_bytes(), &val).unwrap();
+            }
+            db.flush_memtable(true).unwrap();
+        }
+        let nums = files(&db);
+        assert_eq!(2, nums.len());
+        assert!(!opt.env.exists(&table_file_name("db", nums[0] - 2)).unwrap());
+        assert_eq!(None, db.get(b"0-key00"));
+        assert_eq!(Some(val.clone()), db.get(b"1-key00"));
+        assert_eq!(Some(val.clone()), db.get(b"2-key19"));
+
+        // Tables expire after the TTL.
+        assert!(!db.vset.borrow().needs_compaction());
+        db.current().borrow().files[0]
+            .iter()
+            .find(|f| f.borrow().num == nums[0])
+            .unwrap()
+            .borrow_mut()
+            .creation_time -= 3601 * 1000000;
+        assert!(db.vset.borrow().needs_compaction());
+        db.maybe_do_compaction().unwrap();
+        assert_eq!(vec![nums[1]], files(&db));
+        assert_eq!(None, db.get(b"1-key00"));
+
+        // Creation times survive reopening.
+        drop(db);
+        let db = DB::open("db", opt.clone()).unwrap();
+        assert!(db.current().borrow().files[0][0].borrow().creation_time > 0);
+        drop(db);
+
+        // Small tables are merged if allowed.
+        opt.fifo_compaction.allow_compaction = true;
+        let mut db = DB::open("db", opt).unwrap();
+        for i in 3..6 {
+            db.put(format!("{}-key00", i).as_bytes(), b"val").unwrap();
+            db.flush_memtable(true).unwrap();
+        }
+        assert_eq!(1, files(&db).len());
+        assert_eq!(Some(b"val".to_vec()), db.get(b"5-key00"));
+        assert_eq!(Some(val), db.get(b"2-key19"));
+    }
+
     #[test]
     fn test_db_impl_compaction_trivial() {
         let (mut v, opt) = make_version();
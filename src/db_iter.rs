@@ -1,15 +1,20 @@
 #[cfg(feature = "mesalock_sgx")]
 use std::prelude::v1::*;
 
+use blob_file::BlobFileCache;
 use cmp::Cmp;
+use env::Env;
+use error::{Result, Status};
 use key_types::{parse_internal_key, truncate_to_userkey, LookupKey, ValueType};
 use merging_iter::MergingIter;
 use snapshot::Snapshot;
-use types::{Direction, LdbIterator, Shared};
+use statistics::{self, HistogramType, Statistics};
+use types::{Direction, LdbIterator, SequenceNumber, Shared};
 use version_set::VersionSet;
 
 use std::cmp::Ordering;
 use std::mem;
+use std::ops::Bound;
 use std::rc::Rc;
 
 use rand;
@@ -21,6 +26,7 @@ pub struct DBIterator {
     // A user comparator.
     cmp: Rc<Box<dyn Cmp>>,
     vset: Shared<VersionSet>,
+    blobs: Shared<BlobFileCache>,
     iter: MergingIter,
     // By holding onto a snapshot, we make sure that the iterator iterates over the state at the
     // point of its creation.
@@ -35,20 +41,35 @@ pub struct DBIterator {
     keybuf: Vec<u8>,
     savedval: Vec<u8>,
     valbuf: Vec<u8>,
+    // The value of the current entry if it is stored in a blob file (only when moving forward).
+    blobval: Option<Vec<u8>>,
+    // An error encountered while reading a value from a blob file.
+    err: Option<Status>,
+    // If set, entries with a newer user timestamp are skipped.
+    ts: Option<Vec<u8>>,
+
+    env: Rc<Box<dyn Env>>,
+    stats: Option<Shared<Statistics>>,
 }
 
 impl DBIterator {
     pub fn new(
         cmp: Rc<Box<dyn Cmp>>,
         vset: Shared<VersionSet>,
+        blobs: Shared<BlobFileCache>,
         iter: MergingIter,
         ss: Snapshot,
+        env: Rc<Box<dyn Env>>,
+        stats: Option<Shared<Statistics>>,
     ) -> DBIterator {
         DBIterator {
             cmp,
             vset,
+            blobs,
             iter,
             ss,
+            env,
+            stats,
             dir: Direction::Forward,
             byte_count: random_period(),
 
@@ -57,6 +78,59 @@ impl DBIterator {
             keybuf: vec![],
             savedval: vec![],
             valbuf: vec![],
+            blobval: None,
+            err: None,
+            ts: None,
+        }
+    }
+
+    /// with_timestamp makes the iterator return the newest version of each key that is not newer
+    /// than the user timestamp `ts` (see Options::timestamp_size).
+    pub fn with_timestamp(mut self, ts: Vec<u8>) -> DBIterator {
+        self.ts = Some(ts);
+        self
+    }
+
+    /// visible returns whether an entry with the given sequence number and user key is in the
+    /// iterator's snapshot and not newer than its timestamp.
+    fn visible(&self, seq: SequenceNumber, ukey: &[u8]) -> bool {
+        if seq > self.ss.sequence() {
+            return false;
+        }
+        match self.ts {
+            Some(ref ts) => &ukey[ukey.len().saturating_sub(ts.len())..] <= ts.as_slice(),
+            None => true,
+        }
+    }
+
+    /// read_blob reads the value referred to by the BlobIndex `index`. On error, the iterator
+    /// becomes invalid and the error is returned by status().
+    fn read_blob(&mut self, index: &[u8]) -> Option<Vec<u8>> {
+        match self.blobs.borrow_mut().get_encoded(index) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.err = Some(e);
+                self.valid = false;
+                None
+            }
+        }
+    }
+
+    /// seek_internal implements seek(), which additionally measures its latency.
+    fn seek_internal(&mut self, to: &[u8]) {
+        self.dir = Direction::Forward;
+        self.savedkey.clear();
+        self.savedval.clear();
+        self.savedkey
+            .extend_from_slice(LookupKey::new(to, self.ss.sequence()).internal_key());
+        self.iter.seek(&self.savedkey);
+        if self.iter.valid() {
+            self.find_next_user_entry(
+                // skipping=
+                false,
+            );
+        } else {
+            self.valid = false;
         }
     }
 
@@ -85,20 +159,27 @@ impl DBIterator {
             let (typ, seq, ukey) = parse_internal_key(&self.keybuf);
 
             // Skip keys with a sequence number after our snapshot.
-            if seq <= self.ss.sequence() {
+            if self.visible(seq, ukey) {
                 if typ == ValueType::TypeDeletion {
                     // Mark current (deleted) key to be skipped.
                     self.savedkey.clear();
                     self.savedkey.extend_from_slice(ukey);
                     skipping = true;
-                } else if typ == ValueType::TypeValue {
-                    if skipping && self.cmp.cmp(ukey, &self.savedkey) <= Ordering::Equal {
-                        // Entry hidden, because it's smaller than the key to be skipped.
-                    } else {
-                        self.valid = true;
-                        self.savedkey.clear();
-                        return true;
+                } else if skipping
+                    && self.cmp.cmp_without_ts(ukey, &self.savedkey) <= Ordering::Equal
+                {
+                    // Entry hidden, because it's smaller than the key to be skipped (or an older
+                    // version of it).
+                } else {
+                    self.valid = true;
+                    self.savedkey.clear();
+                    self.blobval = None;
+                    if typ == ValueType::TypeBlobIndex {
+                        let index = self.savedval.clone();
+                        self.blobval = self.read_blob(&index);
+                        return self.valid;
                     }
+                    return true;
                 }
             }
             self.iter.advance();
@@ -132,9 +213,9 @@ impl DBIterator {
             self.record_read_sample(len);
             let (typ, seq, ukey) = parse_internal_key(&self.keybuf);
 
-            if seq > 0 && seq <= self.ss.sequence() {
+            if seq > 0 && self.visible(seq, ukey) {
                 if value_type != ValueType::TypeDeletion
-                    && self.cmp.cmp(ukey, &self.savedkey) == Ordering::Less
+                    && self.cmp.cmp_without_ts(ukey, &self.savedkey) == Ordering::Less
                 {
                     // We found a non-deleted entry for a previous key (in the previous iteration)
                     break;
@@ -160,6 +241,12 @@ impl DBIterator {
             self.dir = Direction::Forward;
         } else {
             self.valid = true;
+            if value_type == ValueType::TypeBlobIndex {
+                let index = mem::replace(&mut self.savedval, vec![]);
+                if let Some(v) = self.read_blob(&index) {
+                    self.savedval = v;
+                }
+            }
         }
         true
     }
@@ -202,6 +289,10 @@ impl LdbIterator for DBIterator {
         if self.dir == Direction::Forward {
             self.iter.current(key, val);
             truncate_to_userkey(key);
+            if let Some(ref v) = self.blobval {
+                val.clear();
+                val.extend_from_slice(v);
+            }
             true
         } else {
             key.clear();
@@ -234,7 +325,7 @@ impl LdbIterator for DBIterator {
                 // Scan until we hit the next-smaller key.
                 self.iter.current(&mut self.keybuf, &mut self.savedval);
                 truncate_to_userkey(&mut self.keybuf);
-                if self.cmp.cmp(&self.keybuf, &self.savedkey) == Ordering::Less {
+                if self.cmp.cmp_without_ts(&self.keybuf, &self.savedkey) == Ordering::Less {
                     break;
                 }
             }
@@ -246,12 +337,14 @@ impl LdbIterator for DBIterator {
         self.valid
     }
     fn seek(&mut self, to: &[u8]) {
+        let start_ts = statistics::start_timer(&self.stats, &**self.env);
+        self.seek_internal(to);
+        statistics::measure_since(&self.stats, &**self.env, HistogramType::Seek, start_ts);
+    }
+    fn seek_to_first(&mut self) {
         self.dir = Direction::Forward;
-        self.savedkey.clear();
         self.savedval.clear();
-        self.savedkey
-            .extend_from_slice(LookupKey::new(to, self.ss.sequence()).internal_key());
-        self.iter.seek(&self.savedkey);
+        self.iter.seek_to_first();
         if self.iter.valid() {
             self.find_next_user_entry(
                 // skipping=
@@ -261,17 +354,16 @@ impl LdbIterator for DBIterator {
             self.valid = false;
         }
     }
-    fn seek_to_first(&mut self) {
-        self.dir = Direction::Forward;
+    fn seek_to_last(&mut self) {
+        self.dir = Direction::Reverse;
+        self.savedkey.clear();
         self.savedval.clear();
-        self.iter.seek_to_first();
+        self.iter.seek_to_last();
         if self.iter.valid() {
-            self.find_next_user_entry(
-                // skipping=
-                false,
-            );
+            self.find_prev_user_entry();
         } else {
             self.valid = false;
+            self.dir = Direction::Forward;
         }
     }
     fn reset(&mut self) {
@@ -281,6 +373,174 @@ impl LdbIterator for DBIterator {
         self.savedval.clear();
         self.keybuf.clear();
     }
+    fn status(&self) -> Result<()> {
+        if let Some(ref e) = self.err {
+            return Err(e.clone());
+        }
+        self.iter.status()
+    }
+}
+
+/// RangeIter is a std::iter::Iterator over the entries of a database within a key range, as
+/// returned by `DB::range()` and `DB::prefix()`. It yields owned keys and values, and can be
+/// iterated from both ends (e.g. using `rev()`).
+///
+/// Errors (for example corrupted blocks) are yielded as an `Err` item, after which the iteration
+/// ends.
+pub struct RangeIter {
+    cmp: Rc<Box<dyn Cmp>>,
+    // front and back operate on the same snapshot.
+    front: Option<DBIterator>,
+    back: Option<DBIterator>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+
+    // The keys last returned from either end; iteration ends once they meet.
+    front_key: Option<Vec<u8>>,
+    back_key: Option<Vec<u8>>,
+    err: Option<Status>,
+    done: bool,
+}
+
+impl RangeIter {
+    pub fn new(
+        cmp: Rc<Box<dyn Cmp>>,
+        iters: Result<(DBIterator, DBIterator)>,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> RangeIter {
+        let (front, back, err) = match iters {
+            Ok((f, b)) => (Some(f), Some(b), None),
+            Err(e) => (None, None, Some(e)),
+        };
+        RangeIter {
+            cmp,
+            front,
+            back,
+            start,
+            end,
+            front_key: None,
+            back_key: None,
+            err,
+            done: false,
+        }
+    }
+
+    /// after_start returns true if key is within the lower bound of the range.
+    fn after_start(&self, key: &[u8]) -> bool {
+        match self.start {
+            Bound::Included(ref s) => self.cmp.cmp(key, s) != Ordering::Less,
+            Bound::Excluded(ref s) => self.cmp.cmp(key, s) == Ordering::Greater,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// before_end returns true if key is within the upper bound of the range.
+    fn before_end(&self, key: &[u8]) -> bool {
+        match self.end {
+            Bound::Included(ref e) => self.cmp.cmp(key, e) != Ordering::Greater,
+            Bound::Excluded(ref e) => self.cmp.cmp(key, e) == Ordering::Less,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// finish ends the iteration, returning the iterator's error if there is one.
+    fn finish(&mut self, status: Result<()>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        self.done = true;
+        match status {
+            Err(e) => Some(Err(e)),
+            Ok(()) => None,
+        }
+    }
+}
+
+impl Iterator for RangeIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if let Some(e) = self.err.take() {
+            return self.finish(Err(e));
+        }
+
+        let mut it = self.front.take().unwrap();
+        if self.front_key.is_none() {
+            match self.start {
+                Bound::Included(ref s) | Bound::Excluded(ref s) => it.seek(s),
+                Bound::Unbounded => it.seek_to_first(),
+            }
+        } else {
+            it.advance();
+        }
+        let (mut key, mut val) = (vec![], vec![]);
+        let mut found = it.current(&mut key, &mut val);
+        // An excluded start key is skipped.
+        if found && !self.after_start(&key) {
+            it.advance();
+            found = it.current(&mut key, &mut val);
+        }
+        let status = it.status();
+        self.front = Some(it);
+
+        if status.is_err() || !found || !self.before_end(&key) {
+            return self.finish(status);
+        }
+        if let Some(ref b) = self.back_key {
+            if self.cmp.cmp(&key, b) != Ordering::Less {
+                return self.finish(status);
+            }
+        }
+        self.front_key = Some(key.clone());
+        Some(Ok((key, val)))
+    }
+}
+
+impl DoubleEndedIterator for RangeIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if let Some(e) = self.err.take() {
+            return self.finish(Err(e));
+        }
+
+        let mut it = self.back.take().unwrap();
+        if self.back_key.is_none() {
+            match self.end {
+                Bound::Included(ref e) | Bound::Excluded(ref e) => {
+                    it.seek(e);
+                    if !it.valid() {
+                        it.seek_to_last();
+                    }
+                }
+                Bound::Unbounded => it.seek_to_last(),
+            }
+        } else {
+            it.prev();
+        }
+        let (mut key, mut val) = (vec![], vec![]);
+        let mut found = it.current(&mut key, &mut val);
+        // After a seek, the iterator is positioned at the first entry not before the end key.
+        if found && !self.before_end(&key) {
+            it.prev();
+            found = it.current(&mut key, &mut val);
+        }
+        let status = it.status();
+        self.back = Some(it);
+
+        if status.is_err() || !found || !self.after_start(&key) {
+            return self.finish(status);
+        }
+        if let Some(ref f) = self.front_key {
+            if self.cmp.cmp(&key, f) != Ordering::Greater {
+                return self.finish(status);
+            }
+        }
+        self.back_key = Some(key.clone());
+        Some(Ok((key, val)))
+    }
 }
 
 fn random_period() -> isize {
@@ -292,12 +552,17 @@ mod tests {
     use super::*;
     use db_impl::testutil::*;
     use db_impl::DB;
+    use error::StatusCode;
+    use options;
+    use table_cache::table_file_name;
     use test_util::LdbIteratorIter;
-    use types::{current_key_val, Direction};
+    use types::{current_key_val, Direction, FileType};
 
     use std::collections::HashMap;
     use std::collections::HashSet;
+    use std::io::{Read, Write};
     use std::iter::FromIterator;
+    use std::path::Path;
 
     #[test]
     fn db_iter_basic_test() {
@@ -414,6 +679,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn db_iter_range_reports_error() {
+        let opt = options::for_test();
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            for i in 0..100 {
+                db.put(format!("key{:03}", i).as_bytes(), b"val").unwrap();
+            }
+            db.flush_memtable(true).unwrap();
+        }
+
+        // Corrupt the first data block of the table.
+        let num = list_files(&**opt.env, "db", FileType::Table)[0];
+        let path = table_file_name(Path::new("db"), num);
+        let mut buf = vec![];
+        opt.env
+            .open_sequential_file(&path)
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        buf[10] ^= 0x40;
+        opt.env
+            .open_writable_file(&path)
+            .unwrap()
+            .write_all(&buf)
+            .unwrap();
+
+        let mut db = DB::open("db", opt).unwrap();
+        let mut iter = db.range(..);
+        assert_eq!(
+            StatusCode::Corruption,
+            iter.next().unwrap().err().unwrap().code
+        );
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+        // Iterating backwards reaches the corrupted block last.
+        let rev: Vec<Result<(Vec<u8>, Vec<u8>)>> = db.range(..).rev().collect();
+        assert!(rev[..rev.len() - 1].iter().all(|r| r.is_ok()));
+        assert_eq!(
+            StatusCode::Corruption,
+            rev.last().unwrap().as_ref().err().unwrap().code
+        );
+    }
+
+    #[test]
+    fn db_iter_seek_to_last() {
+        let mut db = build_db().0;
+        db.put(b"zzz", b"123").unwrap();
+        db.delete(b"zzz").unwrap();
+        let mut iter = db.new_iter().unwrap();
+
+        iter.seek_to_last();
+        assert_eq!(
+            (b"iba".to_vec(), b"val2".to_vec()),
+            current_key_val(&iter).unwrap()
+        );
+        assert!(iter.prev());
+        assert_eq!(
+            (b"iaa".to_vec(), b"val1".to_vec()),
+            current_key_val(&iter).unwrap()
+        );
+    }
+
     #[test]
     fn db_iter_deleted_entry_not_returned() {
         let mut db = build_db().0;
@@ -2,7 +2,7 @@
 use std::prelude::v1::*;
 
 use env::{path_to_str, Env, FileLock, Logger, RandomAccess};
-use env_common::micros;
+use env_common::{micros, sleep_for};
 use error::{err, Result, Status, StatusCode};
 
 use std::collections::HashMap;
@@ -155,6 +155,15 @@ impl Env for PosixDiskEnv {
             }
         }
     }
+    fn sync_data(&self, p: &Path) -> Result<()> {
+        // Syncing any descriptor of a file makes all of its written data durable. This applies to
+        // protected files too, once their buffers have been flushed.
+        let f = fs::OpenOptions::new()
+            .write(true)
+            .open(p)
+            .map_err(|e| map_err_with_name("sync_data (open)", p, e))?;
+        f.sync_data().map_err(|e| map_err_with_name("sync_data", p, e))
+    }
     fn exists(&self, p: &Path) -> Result<bool> {
         Ok(p.exists())
     }
@@ -293,6 +302,10 @@ impl Env for PosixDiskEnv {
     fn micros(&self) -> u64 {
         micros()
     }
+
+    fn sleep_for(&self, micros: u64) {
+        sleep_for(micros)
+    }
 }
 
 #[cfg(test)]
@@ -313,7 +326,9 @@ mod tests {
         assert!(env.open_appendable_file(name).is_ok());
         assert!(env.exists(name).unwrap_or(false));
         assert_eq!(env.size_of(name).unwrap_or(1), 0);
+        assert!(env.sync_data(name).is_ok());
         assert!(env.delete(name).is_ok());
+        assert!(env.sync_data(name).is_err());
 
         assert!(env.open_writable_file(name).is_ok());
         assert!(env.exists(name).unwrap_or(false));
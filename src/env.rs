@@ -3,6 +3,7 @@
 #[cfg(feature = "mesalock_sgx")]
 use std::prelude::v1::*;
 
+use env_common;
 use error::Result;
 
 use std::io::prelude::*;
@@ -48,6 +49,12 @@ pub trait Env {
     fn open_random_access_file(&self, p: &Path) -> Result<Box<dyn RandomAccess>>;
     fn open_writable_file(&self, p: &Path) -> Result<Box<dyn Write>>;
     fn open_appendable_file(&self, p: &Path) -> Result<Box<dyn Write>>;
+    /// Makes the data written to `p` durable, like fdatasync(2). Buffered data must have been
+    /// flushed through the handle it was written with first. The default relies on that flush,
+    /// which is enough for envs whose files are durable once flushed.
+    fn sync_data(&self, _: &Path) -> Result<()> {
+        Ok(())
+    }
 
     fn exists(&self, p: &Path) -> Result<bool>;
     fn children(&self, p: &Path) -> Result<Vec<PathBuf>>;
@@ -64,6 +71,11 @@ pub trait Env {
     fn new_logger(&self, p: &Path) -> Result<Logger>;
 
     fn micros(&self) -> u64;
+    /// Blocks the current thread for `micros` microseconds. The default implementation uses the
+    /// sleep of env_common.
+    fn sleep_for(&self, micros: u64) {
+        env_common::sleep_for(micros)
+    }
 }
 
 pub struct Logger {
@@ -15,3 +15,29 @@ pub fn micros() -> u64 {
         }
     }
 }
+
+cfg_if! {
+    if #[cfg(feature = "mesalock_sgx")] {
+        use libc;
+        use std::ptr;
+
+        /// Enclave threads can't sleep inside the enclave, so this sleeps in a single nanosleep
+        /// OCALL. Spinning on the clock would leave the enclave on every iteration.
+        pub fn sleep_for(micros: u64) {
+            let ts = libc::timespec {
+                tv_sec: (micros / 1000000) as libc::time_t,
+                tv_nsec: ((micros % 1000000) * 1000) as libc::c_long,
+            };
+            unsafe {
+                libc::ocall::nanosleep(&ts, ptr::null_mut());
+            }
+        }
+    } else {
+        use std::thread;
+        use std::time::Duration;
+
+        pub fn sleep_for(micros: u64) {
+            thread::sleep(Duration::from_micros(micros));
+        }
+    }
+}
@@ -27,6 +27,7 @@ pub enum StatusCode {
     NotFound,
     NotSupported,
     PermissionDenied,
+    PreconditionFailed,
     Unknown,
     Errno(c_int),
 }
@@ -0,0 +1,58 @@
+//! EventListener allows applications to react to flushes, compactions and table file changes
+//! without having to parse the info log.
+#[cfg(feature = "mesalock_sgx")]
+use std::prelude::v1::*;
+
+use error::Status;
+use types::FileNum;
+
+/// Describes a memtable that has been written to a table file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlushJobInfo {
+    pub file_num: FileNum,
+    /// The level the new table was placed at; not necessarily 0.
+    pub level: usize,
+    pub bytes: usize,
+    pub micros: u64,
+}
+
+/// Describes a finished compaction from `level` to `output_level`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionJobInfo {
+    pub level: usize,
+    pub output_level: usize,
+    pub inputs: Vec<FileNum>,
+    pub outputs: Vec<FileNum>,
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+    pub micros: u64,
+    /// A trivial move only moves a single file to the next level without rewriting it.
+    pub trivial_move: bool,
+}
+
+/// Describes a table file that has been added to the database.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableFileCreationInfo {
+    pub file_num: FileNum,
+    pub level: usize,
+    pub bytes: usize,
+}
+
+/// Describes an obsolete table file that has been deleted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableFileDeletionInfo {
+    pub file_num: FileNum,
+}
+
+/// An EventListener is notified about maintenance work done by the database. All methods have
+/// empty default implementations, so that implementors only need to override the events they are
+/// interested in. Callbacks are invoked synchronously and should return quickly.
+pub trait EventListener {
+    fn on_flush_completed(&self, _info: &FlushJobInfo) {}
+    fn on_compaction_completed(&self, _info: &CompactionJobInfo) {}
+    fn on_table_file_created(&self, _info: &TableFileCreationInfo) {}
+    fn on_table_file_deleted(&self, _info: &TableFileDeletionInfo) {}
+    /// Called when an error occurs during maintenance work that isn't returned to the caller
+    /// directly, e.g. a failed compaction.
+    fn on_background_error(&self, _err: &Status) {}
+}
@@ -3,6 +3,8 @@ use std::prelude::v1::*;
 
 use block::BlockContents;
 use filter::BoxedFilterPolicy;
+use statistics::{self, Statistics, Ticker};
+use types::Shared;
 
 use std::rc::Rc;
 
@@ -25,8 +27,12 @@ fn get_filter_index(offset: usize, base_lg2: u32) -> u32 {
 ///
 /// where offsets are 4 bytes, offset of offsets is 4 bytes, and log2 of FILTER_BASE is 1 byte.
 /// Two consecutive filter offsets may be the same.
+///
+/// A full Filter Block consists of a single filter over all keys added to the builder, without
+/// offsets. It is checked once per table (or partition) instead of once per data block.
 pub struct FilterBlockBuilder {
     policy: BoxedFilterPolicy,
+    full: bool,
     // filters, concatenated
     filters: Vec<u8>,
     filter_offsets: Vec<usize>,
@@ -40,6 +46,7 @@ impl FilterBlockBuilder {
     pub fn new(fp: BoxedFilterPolicy) -> FilterBlockBuilder {
         FilterBlockBuilder {
             policy: fp,
+            full: false,
             // some pre-allocation
             filters: Vec::with_capacity(1024),
             filter_offsets: Vec::with_capacity(1024),
@@ -48,7 +55,23 @@ impl FilterBlockBuilder {
         }
     }
 
+    /// Returns a builder for a full filter block.
+    pub fn new_full(fp: BoxedFilterPolicy) -> FilterBlockBuilder {
+        FilterBlockBuilder {
+            full: true,
+            ..FilterBlockBuilder::new(fp)
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
     pub fn size_estimate(&self) -> usize {
+        if self.full {
+            // Assume the usual 10 bits per key.
+            return (10 * self.key_offsets.len() + 7) / 8;
+        }
         self.filters.len() + 4 * self.filter_offsets.len() + 4 + 1
     }
 
@@ -62,6 +85,9 @@ impl FilterBlockBuilder {
     }
 
     pub fn start_block(&mut self, offset: usize) {
+        if self.full {
+            return;
+        }
         let filter_ix = get_filter_index(offset, FILTER_BASE_LOG2);
         assert!(filter_ix >= self.filter_offsets.len() as u32);
 
@@ -84,6 +110,9 @@ impl FilterBlockBuilder {
     }
 
     pub fn finish(mut self) -> Vec<u8> {
+        if self.full {
+            return self.policy.create_filter(&self.keys, &self.key_offsets);
+        }
         if !self.keys.is_empty() {
             self.generate_filter();
         }
@@ -111,9 +140,12 @@ impl FilterBlockBuilder {
 pub struct FilterBlockReader {
     policy: BoxedFilterPolicy,
     block: Rc<BlockContents>,
+    full: bool,
 
     offsets_offset: usize,
     filter_base_lg2: u32,
+
+    stats: Option<Shared<Statistics>>,
 }
 
 impl FilterBlockReader {
@@ -130,13 +162,36 @@ impl FilterBlockReader {
         FilterBlockReader {
             policy: pol,
             block: data,
+            full: false,
             filter_base_lg2: fbase,
             offsets_offset: offset,
+            stats: None,
+        }
+    }
+
+    /// Returns a reader for a full filter block.
+    pub fn new_full(pol: BoxedFilterPolicy, data: Rc<Vec<u8>>) -> FilterBlockReader {
+        FilterBlockReader {
+            policy: pol,
+            offsets_offset: data.len(),
+            block: data,
+            full: true,
+            filter_base_lg2: 0,
+            stats: None,
         }
     }
 
+    /// Makes key_may_match() count its results in `stats`.
+    pub fn with_statistics(mut self, stats: Option<Shared<Statistics>>) -> FilterBlockReader {
+        self.stats = stats;
+        self
+    }
+
     /// Returns number of filters
     pub fn num(&self) -> u32 {
+        if self.full {
+            return 1;
+        }
         ((self.block.len() - self.offsets_offset - 5) / 4) as u32
     }
 
@@ -147,20 +202,31 @@ impl FilterBlockReader {
     }
 
     /// blk_offset is the offset of the block containing key. Returns whether the key matches the
-    /// filter for the block at blk_offset.
+    /// filter for the block at blk_offset. Full filters ignore blk_offset.
     pub fn key_may_match(&self, blk_offset: usize, key: &[u8]) -> bool {
-        if get_filter_index(blk_offset, self.filter_base_lg2) > self.num() {
-            return true;
-        }
-
-        let filter_begin = self.offset_of(get_filter_index(blk_offset, self.filter_base_lg2));
-        let filter_end = self.offset_of(get_filter_index(blk_offset, self.filter_base_lg2) + 1);
+        let (filter_begin, filter_end) = if self.full {
+            (0, self.block.len())
+        } else {
+            if get_filter_index(blk_offset, self.filter_base_lg2) > self.num() {
+                return true;
+            }
+            let filter_ix = get_filter_index(blk_offset, self.filter_base_lg2);
+            (self.offset_of(filter_ix), self.offset_of(filter_ix + 1))
+        };
 
         assert!(filter_begin < filter_end);
         assert!(filter_end <= self.offsets_offset);
 
-        self.policy
-            .key_may_match(key, &self.block[filter_begin..filter_end])
+        let may_match = self
+            .policy
+            .key_may_match(key, &self.block[filter_begin..filter_end]);
+        let ticker = if may_match {
+            Ticker::BloomFilterPositive
+        } else {
+            Ticker::BloomFilterUseful
+        };
+        statistics::record_tick(&self.stats, ticker, 1);
+        may_match
     }
 }
 
@@ -222,6 +288,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_filter_block_full() {
+        let policy: BoxedFilterPolicy = Rc::new(Box::new(BloomPolicy::new(32)));
+        let mut bld = FilterBlockBuilder::new_full(policy.clone());
+        for (i, k) in get_keys().iter().enumerate() {
+            bld.start_block(i * 5000);
+            bld.add_key(k);
+        }
+        assert!(bld.is_full());
+        assert_eq!(bld.size_estimate(), 5);
+        let result = bld.finish();
+        // A single filter of 4 keys of 4 bytes plus 1B for `k`.
+        assert_eq!(result.len(), get_keys().len() * 4 + 1);
+
+        let reader = FilterBlockReader::new_full(policy, Rc::new(result));
+        assert_eq!(reader.num(), 1);
+        for &block_offset in [0, 5000, 1 << 30].iter() {
+            for key in get_keys().iter() {
+                assert!(reader.key_may_match(block_offset, key));
+            }
+            assert!(!reader.key_may_match(block_offset, b"xsb"));
+        }
+    }
+
     #[test]
     fn test_filter_block_build_read() {
         let result = produce_filter_block();
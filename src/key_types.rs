@@ -19,6 +19,8 @@ use integer_encoding::{FixedInt, FixedIntWriter, VarInt, VarIntWriter};
 pub enum ValueType {
     TypeDeletion = 0,
     TypeValue = 1,
+    /// The value is a BlobIndex referring to a value stored in a blob file. Only found in tables.
+    TypeBlobIndex = 2,
 }
 
 /// A MemtableKey consists of the following elements: [keylen, key, tag, (vallen, value)] where
@@ -102,6 +104,7 @@ pub fn parse_tag(tag: u64) -> (ValueType, u64) {
     match typ {
         0 => (ValueType::TypeDeletion, seq),
         1 => (ValueType::TypeValue, seq),
+        2 => (ValueType::TypeBlobIndex, seq),
         _ => (ValueType::TypeValue, seq),
     }
 }
@@ -214,6 +217,14 @@ pub fn cmp_internal_key<'a, 'b>(
     }
 }
 
+/// set_value_type changes the entry type of a key in InternalKey format in place.
+pub fn set_value_type(ikey: &mut [u8], t: ValueType) {
+    let len = ikey.len();
+    assert!(len >= 8);
+    // The tag is little-endian, so the type is its first byte.
+    ikey[len - 8] = t as u8;
+}
+
 /// truncate_to_userkey performs an in-place conversion from InternalKey to UserKey format.
 pub fn truncate_to_userkey(ikey: &mut Vec<u8>) {
     let len = ikey.len();
@@ -278,4 +289,14 @@ mod tests {
             vec![11, 97, 98, 99, 0, 231, 0, 0, 0, 0, 0, 0, 0]
         );
     }
+
+    #[test]
+    fn test_set_value_type() {
+        let mut ikey = LookupKey::new(b"abc", 231).internal_key().to_vec();
+        set_value_type(&mut ikey, ValueType::TypeBlobIndex);
+        assert_eq!(
+            (ValueType::TypeBlobIndex, 231, "abc".as_bytes()),
+            parse_internal_key(&ikey)
+        );
+    }
 }
@@ -44,22 +44,30 @@ cfg_if! {
 
 extern crate crc;
 extern crate integer_encoding;
+#[cfg(feature = "lz4")]
+extern crate lz4;
 extern crate rand;
 extern crate snap;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
 // #[cfg(test)]
 // #[macro_use]
 // extern crate time_test;
 
+mod blob_file;
 mod block;
 mod block_builder;
+mod block_hash_index;
 mod blockhandle;
 mod cache;
 mod cmp;
+mod compression;
 mod disk_env;
 mod env;
 mod env_common;
 mod error;
+mod event_listener;
 mod filter;
 mod filter_block;
 #[macro_use]
@@ -68,10 +76,13 @@ mod key_types;
 mod log;
 mod mem_env;
 mod memtable;
+mod memtable_rep;
 mod merging_iter;
 mod options;
+mod rate_limiter;
 mod skipmap;
 mod snapshot;
+mod statistics;
 mod table_block;
 mod table_builder;
 mod table_cache;
@@ -86,15 +97,25 @@ mod write_batch;
 mod db_impl;
 mod db_iter;
 
-pub use cmp::{Cmp, DefaultCmp};
+pub use cache::{Cache, CachePolicy};
+pub use cmp::{Cmp, CompositeCmp, DefaultCmp, ReverseBytewiseCmp, TimestampCmp, U64Cmp};
 pub use db_impl::DB;
-pub use db_iter::DBIterator;
+pub use db_iter::{DBIterator, RangeIter};
 pub use disk_env::PosixDiskEnv;
 pub use env::Env;
 pub use error::{Result, Status, StatusCode};
-pub use filter::{BloomPolicy, FilterPolicy};
+pub use event_listener::{
+    CompactionJobInfo, EventListener, FlushJobInfo, TableFileCreationInfo, TableFileDeletionInfo,
+};
+pub use filter::{BlockedBloomPolicy, BloomPolicy, FilterPolicy, RibbonPolicy};
 pub use mem_env::MemEnv;
-pub use options::{in_memory, CompressionType, Options};
+pub use memtable_rep::MemTableRepType;
+pub use options::{
+    in_memory, CompactionStyle, CompressionType, FifoCompactionOptions, Options,
+    UniversalCompactionOptions,
+};
+pub use rate_limiter::{IOPriority, RateLimiter};
 pub use skipmap::SkipMap;
+pub use statistics::{Histogram, HistogramType, Statistics, Ticker};
 pub use types::LdbIterator;
 pub use write_batch::WriteBatch;
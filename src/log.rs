@@ -6,6 +6,8 @@
 use std::prelude::v1::*;
 
 use error::{err, Result, StatusCode};
+use statistics::{self, Statistics, Ticker};
+use types::Shared;
 
 use std::io::{Read, Write};
 
@@ -30,6 +32,7 @@ pub struct LogWriter<W: Write> {
     digest: crc32::Digest,
     current_block_offset: usize,
     block_size: usize,
+    stats: Option<Shared<Statistics>>,
 }
 
 impl<W: Write> LogWriter<W> {
@@ -40,9 +43,17 @@ impl<W: Write> LogWriter<W> {
             current_block_offset: 0,
             block_size: BLOCK_SIZE,
             digest,
+            stats: None,
         }
     }
 
+    /// with_statistics makes the writer count the bytes it writes as write-ahead log bytes in
+    /// `stats`. It should therefore only be used for log files, not for the MANIFEST.
+    pub fn with_statistics(mut self, stats: Option<Shared<Statistics>>) -> LogWriter<W> {
+        self.stats = stats;
+        self
+    }
+
     /// new_with_off opens a writer starting at some offset of an existing log file. The file must
     /// have the default block size.
     pub fn new_with_off(writer: W, off: usize) -> LogWriter<W> {
@@ -109,6 +120,7 @@ impl<W: Write> LogWriter<W> {
         s += self.dst.write(&data[0..len])?;
 
         self.current_block_offset += s;
+        statistics::record_tick(&self.stats, Ticker::WalBytes, s as u64);
         Ok(s)
     }
 
@@ -123,6 +135,7 @@ pub struct LogReader<R: Read> {
     src: R,
     digest: crc32::Digest,
     blk_off: usize,
+    offset: usize,
     blocksize: usize,
     head_scratch: [u8; 7],
     checksums: bool,
@@ -133,6 +146,7 @@ impl<R: Read> LogReader<R> {
         LogReader {
             src,
             blk_off: 0,
+            offset: 0,
             blocksize: BLOCK_SIZE,
             checksums: chksum,
             head_scratch: [0; 7],
@@ -140,6 +154,11 @@ impl<R: Read> LogReader<R> {
         }
     }
 
+    /// Returns the number of bytes consumed so far, i.e. the offset of the next record.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
     /// EOF is signalled by Ok(0)
     pub fn read(&mut self, dst: &mut Vec<u8>) -> Result<usize> {
         let mut checksum: u32;
@@ -152,7 +171,7 @@ impl<R: Read> LogReader<R> {
         loop {
             if self.blocksize - self.blk_off < HEADER_SIZE {
                 // skip to next block
-                self
+                self.offset += self
                     .src
                     .read(&mut self.head_scratch[0..self.blocksize - self.blk_off])?;
                 self.blk_off = 0;
@@ -166,6 +185,7 @@ impl<R: Read> LogReader<R> {
             }
 
             self.blk_off += bytes_read;
+            self.offset += bytes_read;
 
             checksum = u32::decode_fixed(&self.head_scratch[0..4]);
             length = u16::decode_fixed(&self.head_scratch[4..6]);
@@ -176,6 +196,7 @@ impl<R: Read> LogReader<R> {
                 .src
                 .read(&mut dst[dst_offset..dst_offset + length as usize])?;
             self.blk_off += bytes_read;
+            self.offset += bytes_read;
 
             if self.checksums
                 && !self.check_integrity(typ, &dst[dst_offset..dst_offset + bytes_read], checksum)
@@ -311,6 +332,7 @@ mod tests {
             err(StatusCode::Corruption, "Invalid Checksum"),
             lr.read(&mut dst)
         );
+        assert_eq!(16, lr.offset());
 
         let mut i = 1;
         loop {
@@ -326,5 +348,6 @@ mod tests {
             i += 1;
         }
         assert_eq!(i, data.len());
+        assert_eq!(93, lr.offset());
     }
 }
@@ -3,7 +3,7 @@
 use std::prelude::v1::*;
 
 use env::{path_to_str, path_to_string, Env, FileLock, Logger, RandomAccess};
-use env_common::micros;
+use env_common::{micros, sleep_for};
 use error::{err, Result, StatusCode};
 
 use std::collections::hash_map::Entry;
@@ -305,6 +305,14 @@ impl Env for MemEnv {
     fn open_appendable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
         self.0.open_w(p, true, false)
     }
+    fn sync_data(&self, p: &Path) -> Result<()> {
+        // Memory files are never persisted; only check that the file exists.
+        if self.exists(p)? {
+            Ok(())
+        } else {
+            err(StatusCode::NotFound, "")
+        }
+    }
 
     fn exists(&self, p: &Path) -> Result<bool> {
         self.0.exists_(p)
@@ -348,6 +356,10 @@ impl Env for MemEnv {
         micros()
     }
 
+    fn sleep_for(&self, micros: u64) {
+        sleep_for(micros)
+    }
+
     fn new_logger(&self, p: &Path) -> Result<Logger> {
         self.open_appendable_file(p)
             .map(|dst| Logger::new(Box::new(dst)))
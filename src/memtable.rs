@@ -4,30 +4,35 @@ use std::prelude::v1::*;
 use cmp::{Cmp, MemtableKeyCmp};
 use key_types::{build_memtable_key, parse_internal_key, parse_memtable_key, ValueType};
 use key_types::{LookupKey, UserKey};
-use skipmap::{SkipMap, SkipMapIter};
-use types::{current_key_val, LdbIterator, SequenceNumber};
+use memtable_rep::{new_rep, MemTableRep, MemTableRepType};
+use types::{LdbIterator, SequenceNumber};
 
 use std::rc::Rc;
 
 use integer_encoding::FixedInt;
 
-/// Provides Insert/Get/Iterate, based on the SkipMap implementation.
-/// MemTable uses MemtableKeys internally, that is, it stores key and value in the [Skipmap] key.
+/// Provides Insert/Get/Iterate, based on a MemTableRep (by default a SkipMap).
+/// MemTable uses MemtableKeys internally, that is, it stores key and value in the rep's key.
 pub struct MemTable {
-    map: SkipMap,
+    map: Box<dyn MemTableRep>,
 }
 
 impl MemTable {
-    /// Returns a new MemTable.
+    /// Returns a new MemTable backed by a SkipMap.
     /// This wraps opt.cmp inside a MemtableKey-specific comparator.
     pub fn new(cmp: Rc<Box<dyn Cmp>>) -> MemTable {
-        MemTable::new_raw(Rc::new(Box::new(MemtableKeyCmp(cmp))))
+        MemTable::new_with_rep(cmp, MemTableRepType::SkipMap)
+    }
+
+    /// Returns a new MemTable backed by the given type of MemTableRep.
+    pub fn new_with_rep(cmp: Rc<Box<dyn Cmp>>, rep: MemTableRepType) -> MemTable {
+        MemTable::new_raw(Rc::new(Box::new(MemtableKeyCmp(cmp))), rep)
     }
 
     /// Doesn't wrap the comparator in a MemtableKeyCmp.
-    fn new_raw(cmp: Rc<Box<dyn Cmp>>) -> MemTable {
+    fn new_raw(cmp: Rc<Box<dyn Cmp>>, rep: MemTableRepType) -> MemTable {
         MemTable {
-            map: SkipMap::new(cmp),
+            map: new_rep(rep, cmp),
         }
     }
 
@@ -40,28 +45,21 @@ impl MemTable {
     }
 
     pub fn add<'a>(&mut self, seq: SequenceNumber, t: ValueType, key: UserKey<'a>, value: &[u8]) {
-        self.map
-            .insert(build_memtable_key(key, value, t, seq), Vec::new())
+        self.map.insert(build_memtable_key(key, value, t, seq))
     }
 
     /// get returns the value for the given entry and whether the entry is marked as deleted. This
     /// is to distinguish between not-found and found-deleted.
     #[allow(unused_variables)]
     pub fn get(&self, key: &LookupKey) -> (Option<Vec<u8>>, bool) {
-        let mut iter = self.map.iter();
-        iter.seek(key.memtable_key());
-
-        if let Some((foundkey, _)) = current_key_val(&iter) {
-            let (fkeylen, fkeyoff, tag, vallen, valoff) = parse_memtable_key(&foundkey);
-
-            // Compare user key -- if equal, proceed
-            // We only care about user key equality here
-            if key.user_key() == &foundkey[fkeyoff..fkeyoff + fkeylen] {
-                if tag & 0xff == ValueType::TypeValue as u64 {
-                    return (Some(foundkey[valoff..valoff + vallen].to_vec()), false);
-                } else {
-                    return (None, true);
-                }
+        // The rep only returns entries with the same user key (ignoring user timestamps).
+        if let Some(foundkey) = self.map.get(key.memtable_key()) {
+            let (_, _, tag, vallen, valoff) = parse_memtable_key(&foundkey);
+
+            if tag & 0xff == ValueType::TypeValue as u64 {
+                return (Some(foundkey[valoff..valoff + vallen].to_vec()), false);
+            } else {
+                return (None, true);
             }
         }
         (None, false)
@@ -69,7 +67,7 @@ impl MemTable {
 
     pub fn iter(&self) -> MemtableIterator {
         MemtableIterator {
-            skipmapiter: self.map.iter(),
+            iter: self.map.iter(),
         }
     }
 }
@@ -80,41 +78,26 @@ impl MemTable {
 ///
 /// This iterator does not skip deleted entries.
 pub struct MemtableIterator {
-    skipmapiter: SkipMapIter,
+    iter: Box<dyn LdbIterator>,
 }
 
 impl LdbIterator for MemtableIterator {
     fn advance(&mut self) -> bool {
-        if !self.skipmapiter.advance() {
+        if !self.iter.advance() {
             return false;
         }
-        self.skipmapiter.valid()
+        self.iter.valid()
     }
     fn reset(&mut self) {
-        self.skipmapiter.reset();
+        self.iter.reset();
     }
     fn prev(&mut self) -> bool {
-        // Make sure this is actually needed (skipping deleted values?).
-        let (mut key, mut val) = (vec![], vec![]);
-        loop {
-            if !self.skipmapiter.prev() {
-                return false;
-            }
-            if self.skipmapiter.current(&mut key, &mut val) {
-                let (_, _, tag, _, _) = parse_memtable_key(&key);
-
-                if tag & 0xff == ValueType::TypeValue as u64 {
-                    return true;
-                } else {
-                    continue;
-                }
-            } else {
-                return false;
-            }
-        }
+        // Like advance(), prev() doesn't skip deleted entries; DBIterator needs to see them in
+        // order to hide older versions of a deleted key.
+        self.iter.prev()
     }
     fn valid(&self) -> bool {
-        self.skipmapiter.valid()
+        self.iter.valid()
     }
     /// current places the current key (in InternalKey format) and value into the supplied vectors.
     fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
@@ -122,7 +105,7 @@ impl LdbIterator for MemtableIterator {
             return false;
         }
 
-        if self.skipmapiter.current(key, val) {
+        if self.iter.current(key, val) {
             let (keylen, keyoff, _, vallen, valoff) = parse_memtable_key(&key);
             val.clear();
             val.extend_from_slice(&key[valoff..valoff + vallen]);
@@ -139,9 +122,12 @@ impl LdbIterator for MemtableIterator {
     fn seek(&mut self, to: &[u8]) {
         // Assemble the correct memtable key from the supplied InternalKey.
         let (_, seq, ukey) = parse_internal_key(to);
-        self.skipmapiter
+        self.iter
             .seek(LookupKey::new(ukey, seq).memtable_key());
     }
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+    }
 }
 
 /// shift_left moves s[mid..] to s[0..s.len()-mid]. The new size is s.len()-mid.
@@ -160,6 +146,7 @@ mod tests {
     use key_types::*;
     use options;
     use test_util::{test_iterator_properties, LdbIteratorIter};
+    use types::current_key_val;
 
     #[test]
     fn test_shift_left() {
@@ -367,6 +354,31 @@ mod tests {
         assert!(!iter.valid());
     }
 
+    #[test]
+    fn test_memtable_iterator_reverse_deletion() {
+        let mt = get_memtable();
+        let mut iter = mt.iter();
+
+        // prev() stops at deleted entries, like advance().
+        iter.seek_to_last();
+        assert_eq!(
+            current_key_val(&iter).unwrap().0,
+            vec![97, 98, 102, 1, 123, 0, 0, 0, 0, 0, 0].as_slice()
+        );
+        iter.prev();
+        assert!(iter.valid());
+        assert_eq!(
+            current_key_val(&iter).unwrap().0,
+            vec![97, 98, 101, 0, 122, 0, 0, 0, 0, 0, 0].as_slice()
+        );
+        iter.prev();
+        assert!(iter.valid());
+        assert_eq!(
+            current_key_val(&iter).unwrap().0,
+            vec![97, 98, 100, 1, 121, 0, 0, 0, 0, 0, 0].as_slice()
+        );
+    }
+
     #[test]
     fn test_memtable_parse_key() {
         let key = vec![11, 1, 2, 3, 1, 123, 0, 0, 0, 0, 0, 0, 3, 4, 5, 6];
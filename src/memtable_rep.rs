@@ -0,0 +1,389 @@
+//! memtable_rep contains the data structures a MemTable can store its entries in. All of them
+//! store MemtableKeys (see key_types), which contain both key and value of an entry.
+#[cfg(feature = "mesalock_sgx")]
+use std::prelude::v1::*;
+
+use cmp::Cmp;
+use key_types::parse_memtable_key;
+use skipmap::SkipMap;
+use types::{current_key_val, LdbIterator};
+
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::rc::Rc;
+
+/// MemTableRepType selects the MemTableRep used by a database's memtables.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemTableRepType {
+    /// A skip map; good all-round performance.
+    SkipMap,
+    /// A hash table indexed by user key (without user timestamp). Point lookups are fast, but
+    /// every iterator has to sort all entries first.
+    HashIndex,
+    /// An unsorted vector that is sorted on first iteration, e.g. when the memtable is written to
+    /// a table file. Best for bulk loads that don't read from the memtable.
+    Vector,
+}
+
+/// A MemTableRep stores the MemtableKeys of a memtable.
+pub trait MemTableRep {
+    /// Inserts a MemtableKey. Keys are unique and not empty.
+    fn insert(&mut self, key: Vec<u8>);
+    fn len(&self) -> usize;
+    fn approx_memory(&self) -> usize;
+    /// Returns an iterator over all keys in the order of the comparator. Values are empty.
+    fn iter(&self) -> Box<dyn LdbIterator>;
+    /// Returns the size of the user timestamps at the end of user keys.
+    fn timestamp_size(&self) -> usize;
+
+    /// Returns the first key at or after the MemtableKey `key` if it has the same user key,
+    /// ignoring user timestamps. With timestamps, this is the newest version of the key that is
+    /// not newer than the timestamp of `key`.
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut iter = self.iter();
+        iter.seek(key);
+        current_key_val(&iter)
+            .map(|(found, _)| found)
+            .filter(|found| same_user_key(key, found, self.timestamp_size()))
+    }
+}
+
+/// Returns a new, empty MemTableRep. `cmp` must compare MemtableKeys.
+pub fn new_rep(typ: MemTableRepType, cmp: Rc<Box<dyn Cmp>>) -> Box<dyn MemTableRep> {
+    match typ {
+        MemTableRepType::SkipMap => Box::new(SkipMap::new(cmp)),
+        MemTableRepType::HashIndex => Box::new(HashRep::new(cmp)),
+        MemTableRepType::Vector => Box::new(VectorRep::new(cmp)),
+    }
+}
+
+/// Returns the user key of a MemtableKey without the trailing `ts_size` bytes of user timestamp.
+fn user_key(mkey: &[u8], ts_size: usize) -> &[u8] {
+    let (keylen, keyoff, _, _, _) = parse_memtable_key(mkey);
+    &mkey[keyoff..keyoff + keylen.saturating_sub(ts_size)]
+}
+
+fn same_user_key(a: &[u8], b: &[u8], ts_size: usize) -> bool {
+    user_key(a, ts_size) == user_key(b, ts_size)
+}
+
+/// Returns the index of the first entry in the sorted `entries` that is not less than `key`.
+fn lower_bound(cmp: &Rc<Box<dyn Cmp>>, entries: &[Vec<u8>], key: &[u8]) -> usize {
+    let (mut lo, mut hi) = (0, entries.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if cmp.cmp(&entries[mid], key) == Ordering::Less {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+impl MemTableRep for SkipMap {
+    fn insert(&mut self, key: Vec<u8>) {
+        SkipMap::insert(self, key, Vec::new())
+    }
+    fn len(&self) -> usize {
+        SkipMap::len(self)
+    }
+    fn approx_memory(&self) -> usize {
+        SkipMap::approx_memory(self)
+    }
+    fn iter(&self) -> Box<dyn LdbIterator> {
+        Box::new(SkipMap::iter(self))
+    }
+    fn timestamp_size(&self) -> usize {
+        SkipMap::timestamp_size(self)
+    }
+}
+
+/// HashRep keeps the entries of every user key in a sorted bucket of a hash table. All versions of
+/// a user key with different user timestamps share a bucket.
+pub struct HashRep {
+    buckets: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    cmp: Rc<Box<dyn Cmp>>,
+    ts_size: usize,
+    len: usize,
+    approx_mem: usize,
+}
+
+impl HashRep {
+    pub fn new(cmp: Rc<Box<dyn Cmp>>) -> HashRep {
+        HashRep {
+            buckets: HashMap::new(),
+            ts_size: cmp.timestamp_size(),
+            cmp,
+            len: 0,
+            approx_mem: size_of::<Self>(),
+        }
+    }
+}
+
+impl MemTableRep for HashRep {
+    fn insert(&mut self, key: Vec<u8>) {
+        assert!(!key.is_empty());
+        self.approx_mem += key.len() + size_of::<Vec<u8>>();
+        self.len += 1;
+
+        let ukey = user_key(&key, self.ts_size).to_vec();
+        let bucket = self.buckets.entry(ukey).or_insert_with(Vec::new);
+        let ix = lower_bound(&self.cmp, bucket, &key);
+        bucket.insert(ix, key);
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn approx_memory(&self) -> usize {
+        self.approx_mem
+    }
+    fn iter(&self) -> Box<dyn LdbIterator> {
+        let mut entries = Vec::with_capacity(self.len);
+        for bucket in self.buckets.values() {
+            entries.extend_from_slice(bucket);
+        }
+        let cmp = self.cmp.clone();
+        entries.sort_by(|a, b| cmp.cmp(a, b));
+        Box::new(SortedVecIter::new(self.cmp.clone(), Rc::new(entries)))
+    }
+    fn timestamp_size(&self) -> usize {
+        self.ts_size
+    }
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let bucket = self.buckets.get(user_key(key, self.ts_size))?;
+        bucket.get(lower_bound(&self.cmp, bucket, key)).cloned()
+    }
+}
+
+/// VectorRep appends entries to a vector, which is only sorted when it's read from.
+pub struct VectorRep {
+    entries: RefCell<Rc<Vec<Vec<u8>>>>,
+    sorted: Cell<bool>,
+    cmp: Rc<Box<dyn Cmp>>,
+    approx_mem: usize,
+}
+
+impl VectorRep {
+    pub fn new(cmp: Rc<Box<dyn Cmp>>) -> VectorRep {
+        VectorRep {
+            entries: RefCell::new(Rc::new(vec![])),
+            sorted: Cell::new(true),
+            cmp,
+            approx_mem: size_of::<Self>(),
+        }
+    }
+
+    /// Sorts the entries, if necessary, and returns them.
+    fn sorted_entries(&self) -> Rc<Vec<Vec<u8>>> {
+        if !self.sorted.get() {
+            let mut entries = self.entries.borrow_mut();
+            let cmp = self.cmp.clone();
+            // Clones the entries if an iterator still refers to the unsorted ones.
+            Rc::make_mut(&mut entries).sort_by(|a, b| cmp.cmp(a, b));
+            self.sorted.set(true);
+        }
+        self.entries.borrow().clone()
+    }
+}
+
+impl MemTableRep for VectorRep {
+    fn insert(&mut self, key: Vec<u8>) {
+        assert!(!key.is_empty());
+        self.approx_mem += key.len() + size_of::<Vec<u8>>();
+        Rc::make_mut(self.entries.get_mut()).push(key);
+        self.sorted.set(false);
+    }
+    fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+    fn approx_memory(&self) -> usize {
+        self.approx_mem
+    }
+    fn iter(&self) -> Box<dyn LdbIterator> {
+        Box::new(SortedVecIter::new(self.cmp.clone(), self.sorted_entries()))
+    }
+    fn timestamp_size(&self) -> usize {
+        self.cmp.timestamp_size()
+    }
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let entries = self.sorted_entries();
+        entries
+            .get(lower_bound(&self.cmp, &entries, key))
+            .filter(|found| same_user_key(key, found, self.timestamp_size()))
+            .cloned()
+    }
+}
+
+/// SortedVecIter iterates over a sorted snapshot of entries. Like SkipMapIter, it is positioned
+/// before the first entry after creation or reset().
+pub struct SortedVecIter {
+    cmp: Rc<Box<dyn Cmp>>,
+    entries: Rc<Vec<Vec<u8>>>,
+    // 0 is the invalid position before the first entry; i refers to entries[i-1].
+    current: usize,
+}
+
+impl SortedVecIter {
+    fn new(cmp: Rc<Box<dyn Cmp>>, entries: Rc<Vec<Vec<u8>>>) -> SortedVecIter {
+        SortedVecIter {
+            cmp,
+            entries,
+            current: 0,
+        }
+    }
+}
+
+impl LdbIterator for SortedVecIter {
+    fn advance(&mut self) -> bool {
+        if self.current < self.entries.len() {
+            self.current += 1;
+            true
+        } else {
+            self.reset();
+            false
+        }
+    }
+    fn reset(&mut self) {
+        self.current = 0;
+    }
+    fn seek(&mut self, key: &[u8]) {
+        let ix = lower_bound(&self.cmp, &self.entries, key);
+        self.current = if ix < self.entries.len() { ix + 1 } else { 0 };
+    }
+    fn seek_to_last(&mut self) {
+        self.current = self.entries.len();
+    }
+    fn valid(&self) -> bool {
+        self.current > 0
+    }
+    fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
+        if self.valid() {
+            key.clear();
+            val.clear();
+            key.extend_from_slice(&self.entries[self.current - 1]);
+            true
+        } else {
+            false
+        }
+    }
+    fn prev(&mut self) -> bool {
+        if self.current > 1 {
+            self.current -= 1;
+            true
+        } else {
+            self.reset();
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cmp::{DefaultCmp, MemtableKeyCmp, TimestampCmp};
+    use key_types::{build_memtable_key, LookupKey, ValueType};
+    use options;
+    use test_util::{test_iterator_properties, LdbIteratorIter};
+
+    const ALL_REPS: [MemTableRepType; 3] = [
+        MemTableRepType::SkipMap,
+        MemTableRepType::HashIndex,
+        MemTableRepType::Vector,
+    ];
+
+    fn make_rep(typ: MemTableRepType) -> Box<dyn MemTableRep> {
+        let cmp: Rc<Box<dyn Cmp>> = Rc::new(Box::new(MemtableKeyCmp(options::for_test().cmp)));
+        let mut rep = new_rep(typ, cmp);
+        // Insert out of order.
+        for &(k, seq) in [("abd", 3), ("abc", 1), ("abe", 4), ("abc", 2)].iter() {
+            rep.insert(build_memtable_key(
+                k.as_bytes(),
+                b"val",
+                ValueType::TypeValue,
+                seq,
+            ));
+        }
+        rep
+    }
+
+    #[test]
+    fn test_memtable_rep_iter() {
+        for &typ in ALL_REPS.iter() {
+            let rep = make_rep(typ);
+            assert_eq!(4, rep.len());
+            assert!(rep.approx_memory() > 0);
+
+            let keys: Vec<(Vec<u8>, u64)> = LdbIteratorIter::wrap(&mut rep.iter())
+                .map(|(k, _)| {
+                    let (_, _, tag, _, _) = parse_memtable_key(&k);
+                    (user_key(&k, 0).to_vec(), tag >> 8)
+                })
+                .collect();
+            assert_eq!(
+                vec![
+                    (b"abc".to_vec(), 2),
+                    (b"abc".to_vec(), 1),
+                    (b"abd".to_vec(), 3),
+                    (b"abe".to_vec(), 4)
+                ],
+                keys,
+                "{:?}",
+                typ
+            );
+            test_iterator_properties(rep.iter());
+        }
+    }
+
+    #[test]
+    fn test_memtable_rep_get() {
+        for &typ in ALL_REPS.iter() {
+            let rep = make_rep(typ);
+            let found = rep.get(LookupKey::new(b"abc", 5).memtable_key()).unwrap();
+            assert_eq!(2, parse_memtable_key(&found).2 >> 8, "{:?}", typ);
+            let found = rep.get(LookupKey::new(b"abc", 1).memtable_key()).unwrap();
+            assert_eq!(1, parse_memtable_key(&found).2 >> 8, "{:?}", typ);
+            assert!(rep.get(LookupKey::new(b"abd", 2).memtable_key()).is_none());
+            assert!(rep.get(LookupKey::new(b"abf", 5).memtable_key()).is_none());
+            assert!(rep.get(LookupKey::new(b"ab", 5).memtable_key()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_memtable_rep_get_with_timestamps() {
+        let ts_cmp = TimestampCmp::new(Rc::new(Box::new(DefaultCmp)), 1);
+        let cmp: Rc<Box<dyn Cmp>> = Rc::new(Box::new(MemtableKeyCmp(Rc::new(Box::new(ts_cmp)))));
+        for &typ in ALL_REPS.iter() {
+            let mut rep = new_rep(typ, cmp.clone());
+            assert_eq!(1, rep.timestamp_size());
+            for &(k, seq) in [(&b"abc\x05"[..], 1), (b"abd\x03", 3), (b"abc\x09", 2)].iter() {
+                rep.insert(build_memtable_key(k, b"val", ValueType::TypeValue, seq));
+            }
+
+            let seq_at = |key: &[u8]| {
+                rep.get(LookupKey::new(key, 10).memtable_key())
+                    .map(|found| parse_memtable_key(&found).2 >> 8)
+            };
+            assert_eq!(Some(1), seq_at(b"abc\x07"), "{:?}", typ);
+            assert_eq!(Some(2), seq_at(b"abc\x09"), "{:?}", typ);
+            assert_eq!(Some(2), seq_at(b"abc\xff"), "{:?}", typ);
+            assert_eq!(None, seq_at(b"abc\x04"), "{:?}", typ);
+            assert_eq!(Some(3), seq_at(b"abd\x03"), "{:?}", typ);
+            assert_eq!(None, seq_at(b"abe\xff"), "{:?}", typ);
+        }
+    }
+
+    #[test]
+    fn test_memtable_rep_vector_insert_after_iter() {
+        let mut rep = make_rep(MemTableRepType::Vector);
+        let mut iter = rep.iter();
+        rep.insert(build_memtable_key(b"abb", b"val", ValueType::TypeValue, 5));
+
+        // The existing iterator keeps its snapshot; new iterators see the new entry in order.
+        assert_eq!(4, LdbIteratorIter::wrap(&mut iter).count());
+        let first = rep.iter().next().unwrap().0;
+        assert_eq!(b"abb", user_key(&first, 0));
+    }
+}
@@ -2,6 +2,7 @@
 use std::prelude::v1::*;
 
 use cmp::Cmp;
+use error::Result;
 use types::{current_key_val, Direction, LdbIterator};
 
 use std::cmp::Ordering;
@@ -87,8 +88,7 @@ impl MergingIter {
                                 if self.iters[i].valid() {
                                     self.iters[i].prev();
                                 } else {
-                                    // seek to last.
-                                    while self.iters[i].advance() {}
+                                    self.iters[i].seek_to_last();
                                 }
                             }
                         }
@@ -168,12 +168,25 @@ impl LdbIterator for MergingIter {
         }
         self.find_smallest();
     }
+    fn seek_to_last(&mut self) {
+        for i in 0..self.iters.len() {
+            self.iters[i].seek_to_last();
+        }
+        self.direction = Direction::Reverse;
+        self.find_largest();
+    }
     fn reset(&mut self) {
         for i in 0..self.iters.len() {
             self.iters[i].reset();
         }
         self.current = None;
     }
+    fn status(&self) -> Result<()> {
+        for i in 0..self.iters.len() {
+            self.iters[i].status()?;
+        }
+        Ok(())
+    }
     fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
         if let Some(ix) = self.current {
             self.iters[ix].current(key, val)
@@ -2,15 +2,19 @@
 use std::prelude::v1::*;
 
 use block::Block;
-use cache::Cache;
+use cache::{Cache, CachePolicy};
 use cmp::{Cmp, DefaultCmp};
 use disk_env;
 
 use env::Env;
+use event_listener::EventListener;
 use filter;
 use infolog::{self, Logger};
 use mem_env::MemEnv;
-use types::{share, Shared};
+use memtable_rep::MemTableRepType;
+use rate_limiter::RateLimiter;
+use statistics::Statistics;
+use types::{share, Shared, NUM_LEVELS};
 
 use std::rc::Rc;
 
@@ -25,9 +29,14 @@ cfg_if! {
 
 const KB: usize = 1 << 10;
 const MB: usize = KB * KB;
+const GB: usize = KB * MB;
 
 const BLOCK_MAX_SIZE: usize = 4 * KB;
 const BLOCK_CACHE_CAPACITY: usize = 8 * MB;
+const BLOCK_CACHE_SHARD_BITS: usize = 4;
+const BLOCK_CACHE_POLICY: CachePolicy = CachePolicy::LRU {
+    high_pri_pool_ratio: 0.5,
+};
 const WRITE_BUFFER_SIZE: usize = 4 * MB;
 const DEFAULT_BITS_PER_KEY: u32 = 10; // NOTE: This may need to be optimized.
 
@@ -35,38 +44,197 @@ const DEFAULT_BITS_PER_KEY: u32 = 10; // NOTE: This may need to be optimized.
 pub enum CompressionType {
     CompressionNone = 0,
     CompressionSnappy = 1,
+    /// Needs the `zstd` feature.
+    CompressionZstd = 2,
+    /// Needs the `lz4` feature.
+    CompressionLZ4 = 4,
 }
 
 pub fn int_to_compressiontype(i: u32) -> Option<CompressionType> {
     match i {
         0 => Some(CompressionType::CompressionNone),
         1 => Some(CompressionType::CompressionSnappy),
+        2 => Some(CompressionType::CompressionZstd),
+        4 => Some(CompressionType::CompressionLZ4),
         _ => None,
     }
 }
 
+/// CompactionStyle selects how table files are organized and compacted.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompactionStyle {
+    /// Tables are compacted level by level, as in LevelDB. Reads are cheap, but every byte is
+    /// rewritten about ten times per level.
+    Leveled = 0,
+    /// All tables stay in level 0, each one forming a sorted run. Runs of similar size are merged,
+    /// which rewrites much less data at the cost of more runs to consult on reads.
+    Universal = 1,
+    /// All tables stay in level 0 and the oldest ones are deleted once the database grows too
+    /// large or old. Nothing is ever rewritten, but neither are overwritten or deleted keys
+    /// removed; only suitable for append-only data such as logs.
+    Fifo = 2,
+}
+
+pub fn int_to_compactionstyle(i: u32) -> Option<CompactionStyle> {
+    match i {
+        0 => Some(CompactionStyle::Leveled),
+        1 => Some(CompactionStyle::Universal),
+        2 => Some(CompactionStyle::Fifo),
+        _ => None,
+    }
+}
+
+/// UniversalCompactionOptions controls which sorted runs are merged by universal compaction.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct UniversalCompactionOptions {
+    /// A run is merged with the newer runs before it if it is at most this many percent larger
+    /// than their total size.
+    pub size_ratio: usize,
+    /// The minimum and maximum number of runs merged by a size ratio compaction.
+    pub min_merge_width: usize,
+    pub max_merge_width: usize,
+    /// All runs are merged if the newer runs' total size exceeds this many percent of the size of
+    /// the oldest run.
+    pub max_size_amplification_percent: usize,
+}
+
+impl Default for UniversalCompactionOptions {
+    fn default() -> UniversalCompactionOptions {
+        UniversalCompactionOptions {
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: usize::max_value(),
+            max_size_amplification_percent: 200,
+        }
+    }
+}
+
+/// FifoCompactionOptions controls when FIFO compaction deletes table files.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FifoCompactionOptions {
+    /// The oldest tables are deleted once all tables together are larger than this.
+    pub max_table_files_size: usize,
+    /// Tables older than this many seconds are deleted. 0 disables the limit.
+    pub ttl_seconds: u64,
+    /// If set, runs of small tables are merged into one, reducing the number of tables that reads
+    /// have to consult.
+    pub allow_compaction: bool,
+}
+
+impl Default for FifoCompactionOptions {
+    fn default() -> FifoCompactionOptions {
+        FifoCompactionOptions {
+            max_table_files_size: 1 << 30,
+            ttl_seconds: 0,
+            allow_compaction: false,
+        }
+    }
+}
+
 /// Options contains general parameters for a LevelDB instance. Most of the names are
 /// self-explanatory; the defaults are defined in the `Default` implementation.
-///
-/// Note: Compression is not yet implemented.
 #[derive(Clone)]
 pub struct Options {
     pub cmp: Rc<Box<dyn Cmp>>,
+    /// If not 0, every user key ends in a timestamp of this many bytes, which is compared
+    /// bytewise (see `TimestampCmp`). Keys can then be read as of a timestamp with
+    /// `DB::get_at_timestamp()`. The timestamps of a key must not decrease as it is overwritten.
+    /// The size is part of the comparator name recorded in the MANIFEST, so it can't be changed.
+    pub timestamp_size: usize,
     pub env: Rc<Box<dyn Env>>,
     pub log: Option<Shared<Logger>>,
     pub create_if_missing: bool,
     pub error_if_exists: bool,
+    /// If set, opening a database fails on any corruption in a log file or the MANIFEST, instead
+    /// of dropping the rest of the file; and every table written by a flush or compaction is read
+    /// back and verified completely.
     pub paranoid_checks: bool,
     pub write_buffer_size: usize,
+    pub memtable_rep: MemTableRepType,
     pub max_open_files: usize,
     pub max_file_size: usize,
+    /// The number of levels of the LSM tree; at least 2. Recorded in the manifest, and a database
+    /// can't be opened with fewer levels than it has files in.
+    pub num_levels: usize,
+    /// The maximum size of level 1; level L+1 may be `max_bytes_for_level_multiplier` times as
+    /// large as level L.
+    pub max_bytes_for_level_base: usize,
+    pub max_bytes_for_level_multiplier: f64,
+    /// Level 0 is compacted once it contains this many files.
+    pub level0_file_num_compaction_trigger: usize,
+    /// Writes are slowed down once level 0 contains this many files...
+    pub level0_slowdown_writes_trigger: usize,
+    /// ...and stopped until a compaction has finished once it contains this many.
+    pub level0_stop_writes_trigger: usize,
+    /// Like the level 0 triggers, but for the estimated number of bytes compactions need to
+    /// rewrite until all levels are within their limits. 0 disables a limit.
+    pub soft_pending_compaction_bytes_limit: usize,
+    pub hard_pending_compaction_bytes_limit: usize,
+    /// The cache of uncompressed blocks. Its capacity is in bytes; see set_block_cache().
     pub block_cache: Shared<Cache<Block>>,
     pub block_size: usize,
     pub block_restart_interval: usize,
+    /// If set, tables are written with partitioned index and filter blocks. Readers then only keep
+    /// the small top-level index and filter index in memory, and load partitions through the block
+    /// cache when needed.
+    pub partition_index_and_filters: bool,
+    /// The target size of index and filter partitions.
+    pub metadata_block_size: usize,
+    /// If set, tables are written with one filter for all keys of the table (or of an index
+    /// partition) instead of one filter per 2 KB of data. The filter is then checked before the
+    /// index is searched.
+    pub full_filters: bool,
+    /// If set, a hash index is appended to data blocks, which lets point lookups find the restart
+    /// interval of a key without a binary search.
+    pub data_block_hash_index: bool,
+    /// If set, the index and filter blocks of tables are read through the block cache with high
+    /// priority instead of being held by the open tables, so that their memory is bounded by the
+    /// block cache.
+    pub cache_index_and_filter_blocks: bool,
+    /// If set, the index and filter blocks of level-0 tables are held by their tables until the
+    /// files are deleted or moved to level 1, so that lookups in level 0 never read them again.
+    /// Only applies to leveled compaction, since the other styles keep all files in level 0.
+    pub pin_l0_filter_and_index_blocks_in_cache: bool,
     pub compression_type: CompressionType,
+    /// The level used by Zstd and LZ4 compression; 0 selects the default level of the codec.
+    pub compression_level: i32,
+    /// If not empty, tables written to level L are compressed with `compression_per_level[L]`
+    /// instead of `compression_type`; the last entry applies to all further levels.
+    pub compression_per_level: Vec<CompressionType>,
+    /// If set, overrides the compression of tables written by compactions into the bottommost
+    /// level, i.e. the level below which there's no older data.
+    pub bottommost_compression: Option<CompressionType>,
+    /// If not 0 and Zstd compression is used, compactions train a dictionary of at most this many
+    /// bytes on the data blocks of each output table, and compress its data blocks with it. This
+    /// helps a lot with small, similar values, which compress badly on their own.
+    pub compression_max_dict_bytes: usize,
+    /// The amount of data blocks the dictionary is trained on. These blocks are held in memory
+    /// until the dictionary has been trained.
+    pub compression_dict_buffer_bytes: usize,
     pub reuse_logs: bool,
     pub reuse_manifest: bool,
     pub filter_policy: filter::BoxedFilterPolicy,
+    pub compaction_style: CompactionStyle,
+    /// Only used if `compaction_style` is `Universal`.
+    pub universal_compaction: UniversalCompactionOptions,
+    /// Only used if `compaction_style` is `Fifo`.
+    pub fifo_compaction: FifoCompactionOptions,
+    /// If set, the database records tickers and latency histograms into this object.
+    pub statistics: Option<Shared<Statistics>>,
+    /// Listeners notified about flushes, compactions and table file changes.
+    pub listeners: Vec<Rc<Box<dyn EventListener>>>,
+    /// If set, flush and compaction I/O is throttled by this rate limiter.
+    pub rate_limiter: Option<Shared<RateLimiter>>,
+    /// If set, flushes and compactions move values of at least `min_blob_size` bytes to separate
+    /// blob files, and tables only store a reference to them. Large values then aren't rewritten
+    /// by every compaction and don't occupy the block cache.
+    pub enable_blob_files: bool,
+    pub min_blob_size: usize,
+    /// Compactions start a new blob file once the current one has reached this size.
+    pub blob_file_size: usize,
+    /// Compactions move values out of this fraction of the oldest blob files, so that those can
+    /// be deleted eventually. 0 disables garbage collection of blob files.
+    pub blob_garbage_collection_age_cutoff: f64,
 }
 
 
@@ -76,44 +244,114 @@ cfg_if! {
             pub fn new_disk_db_with(key: DBPersistKey) -> Options {
                 Options {
                     cmp: Rc::new(Box::new(DefaultCmp)),
+                    timestamp_size: 0,
                     env: Rc::new(Box::new(disk_env::PosixDiskEnv::new_with(key))),
                     log: None,
                     create_if_missing: true,
                     error_if_exists: false,
                     paranoid_checks: false,
                     write_buffer_size: WRITE_BUFFER_SIZE,
+                    memtable_rep: MemTableRepType::SkipMap,
                     max_open_files: 1 << 10,
                     max_file_size: 2 << 20,
-                    // 2000 elements by default
-                    block_cache: share(Cache::new(BLOCK_CACHE_CAPACITY / BLOCK_MAX_SIZE)),
+                    num_levels: NUM_LEVELS,
+                    max_bytes_for_level_base: 10 * MB,
+                    max_bytes_for_level_multiplier: 10.0,
+                    level0_file_num_compaction_trigger: 4,
+                    level0_slowdown_writes_trigger: 8,
+                    level0_stop_writes_trigger: 12,
+                    soft_pending_compaction_bytes_limit: 64 * GB,
+                    hard_pending_compaction_bytes_limit: 256 * GB,
+                    // 8 MB of uncompressed blocks in 16 shards by default
+                    block_cache: share(Cache::new_with_policy(
+                        BLOCK_CACHE_CAPACITY,
+                        BLOCK_CACHE_SHARD_BITS,
+                        BLOCK_CACHE_POLICY,
+                    )),
                     block_size: BLOCK_MAX_SIZE,
                     block_restart_interval: 16,
+                    partition_index_and_filters: false,
+                    metadata_block_size: 4 * KB,
+                    full_filters: false,
+                    data_block_hash_index: false,
+                    cache_index_and_filter_blocks: false,
+                    pin_l0_filter_and_index_blocks_in_cache: false,
                     reuse_logs: true,
                     reuse_manifest: true,
                     compression_type: CompressionType::CompressionNone,
+                    compression_level: 0,
+                    compression_per_level: vec![],
+                    bottommost_compression: None,
+                    compression_max_dict_bytes: 0,
+                    compression_dict_buffer_bytes: MB,
                     filter_policy: Rc::new(Box::new(filter::BloomPolicy::new(DEFAULT_BITS_PER_KEY))),
+                    compaction_style: CompactionStyle::Leveled,
+                    universal_compaction: UniversalCompactionOptions::default(),
+                    fifo_compaction: FifoCompactionOptions::default(),
+                    statistics: None,
+                    listeners: vec![],
+                    rate_limiter: None,
+                    enable_blob_files: false,
+                    min_blob_size: 4 * KB,
+                    blob_file_size: 256 * MB,
+                    blob_garbage_collection_age_cutoff: 0.25,
                 }
             }
 
             pub fn new_mem_db() -> Options {
                 Options {
                     cmp: Rc::new(Box::new(DefaultCmp)),
+                    timestamp_size: 0,
                     env: Rc::new(Box::new(MemEnv::new())),
                     log: None,
                     create_if_missing: true,
                     error_if_exists: false,
                     paranoid_checks: false,
                     write_buffer_size: WRITE_BUFFER_SIZE,
+                    memtable_rep: MemTableRepType::SkipMap,
                     max_open_files: 1 << 10,
                     max_file_size: 2 << 20,
-                    // 2000 elements by default
-                    block_cache: share(Cache::new(BLOCK_CACHE_CAPACITY / BLOCK_MAX_SIZE)),
+                    num_levels: NUM_LEVELS,
+                    max_bytes_for_level_base: 10 * MB,
+                    max_bytes_for_level_multiplier: 10.0,
+                    level0_file_num_compaction_trigger: 4,
+                    level0_slowdown_writes_trigger: 8,
+                    level0_stop_writes_trigger: 12,
+                    soft_pending_compaction_bytes_limit: 64 * GB,
+                    hard_pending_compaction_bytes_limit: 256 * GB,
+                    // 8 MB of uncompressed blocks in 16 shards by default
+                    block_cache: share(Cache::new_with_policy(
+                        BLOCK_CACHE_CAPACITY,
+                        BLOCK_CACHE_SHARD_BITS,
+                        BLOCK_CACHE_POLICY,
+                    )),
                     block_size: BLOCK_MAX_SIZE,
                     block_restart_interval: 16,
+                    partition_index_and_filters: false,
+                    metadata_block_size: 4 * KB,
+                    full_filters: false,
+                    data_block_hash_index: false,
+                    cache_index_and_filter_blocks: false,
+                    pin_l0_filter_and_index_blocks_in_cache: false,
                     reuse_logs: true,
                     reuse_manifest: true,
                     compression_type: CompressionType::CompressionNone,
+                    compression_level: 0,
+                    compression_per_level: vec![],
+                    bottommost_compression: None,
+                    compression_max_dict_bytes: 0,
+                    compression_dict_buffer_bytes: MB,
                     filter_policy: Rc::new(Box::new(filter::BloomPolicy::new(DEFAULT_BITS_PER_KEY))),
+                    compaction_style: CompactionStyle::Leveled,
+                    universal_compaction: UniversalCompactionOptions::default(),
+                    fifo_compaction: FifoCompactionOptions::default(),
+                    statistics: None,
+                    listeners: vec![],
+                    rate_limiter: None,
+                    enable_blob_files: false,
+                    min_blob_size: 4 * KB,
+                    blob_file_size: 256 * MB,
+                    blob_garbage_collection_age_cutoff: 0.25,
                 }
             }
         }
@@ -122,28 +360,88 @@ cfg_if! {
             fn default() -> Options {
                 Options {
                     cmp: Rc::new(Box::new(DefaultCmp)),
+                    timestamp_size: 0,
                     env: Rc::new(Box::new(MemEnv::new()));
                     log: None,
                     create_if_missing: true,
                     error_if_exists: false,
                     paranoid_checks: false,
                     write_buffer_size: WRITE_BUFFER_SIZE,
+                    memtable_rep: MemTableRepType::SkipMap,
                     max_open_files: 1 << 10,
                     max_file_size: 2 << 20,
-                    // 2000 elements by default
-                    block_cache: share(Cache::new(BLOCK_CACHE_CAPACITY / BLOCK_MAX_SIZE)),
+                    num_levels: NUM_LEVELS,
+                    max_bytes_for_level_base: 10 * MB,
+                    max_bytes_for_level_multiplier: 10.0,
+                    level0_file_num_compaction_trigger: 4,
+                    level0_slowdown_writes_trigger: 8,
+                    level0_stop_writes_trigger: 12,
+                    soft_pending_compaction_bytes_limit: 64 * GB,
+                    hard_pending_compaction_bytes_limit: 256 * GB,
+                    // 8 MB of uncompressed blocks in 16 shards by default
+                    block_cache: share(Cache::new_with_policy(
+                        BLOCK_CACHE_CAPACITY,
+                        BLOCK_CACHE_SHARD_BITS,
+                        BLOCK_CACHE_POLICY,
+                    )),
                     block_size: BLOCK_MAX_SIZE,
                     block_restart_interval: 16,
+                    partition_index_and_filters: false,
+                    metadata_block_size: 4 * KB,
+                    full_filters: false,
+                    data_block_hash_index: false,
+                    cache_index_and_filter_blocks: false,
+                    pin_l0_filter_and_index_blocks_in_cache: false,
                     reuse_logs: true,
                     reuse_manifest: true,
                     compression_type: CompressionType::CompressionNone,
+                    compression_level: 0,
+                    compression_per_level: vec![],
+                    bottommost_compression: None,
+                    compression_max_dict_bytes: 0,
+                    compression_dict_buffer_bytes: MB,
                     filter_policy: Rc::new(Box::new(filter::BloomPolicy::new(DEFAULT_BITS_PER_KEY))),
+                    compaction_style: CompactionStyle::Leveled,
+                    universal_compaction: UniversalCompactionOptions::default(),
+                    fifo_compaction: FifoCompactionOptions::default(),
+                    statistics: None,
+                    listeners: vec![],
+                    rate_limiter: None,
+                    enable_blob_files: false,
+                    min_blob_size: 4 * KB,
+                    blob_file_size: 256 * MB,
+                    blob_garbage_collection_age_cutoff: 0.25,
                 }
             }
         }
     }
 }
 
+impl Options {
+    /// Replaces the block cache with a new cache of `capacity` bytes that evicts blocks according
+    /// to `policy`. By default, the block cache is an LRU cache of 8 MB that reserves half of its
+    /// capacity for high-priority blocks and blocks that were hit after being cached.
+    pub fn set_block_cache(&mut self, capacity: usize, policy: CachePolicy) {
+        self.block_cache = share(Cache::new_with_policy(
+            capacity,
+            BLOCK_CACHE_SHARD_BITS,
+            policy,
+        ));
+    }
+
+    /// compression_for_level returns the compression type of tables written to `level`.
+    pub fn compression_for_level(&self, level: usize, bottommost: bool) -> CompressionType {
+        if let (true, Some(ctype)) = (bottommost, self.bottommost_compression) {
+            return ctype;
+        }
+        if self.compression_per_level.is_empty() {
+            return self.compression_type;
+        }
+        let ix = level.min(self.compression_per_level.len() - 1);
+        self.compression_per_level[ix]
+    }
+}
+
 /// Returns Options that will cause a database to exist purely in-memory instead of being stored on
 /// disk. This is useful for testing or ephemeral databases.
 pub fn in_memory() -> Options {
@@ -0,0 +1,260 @@
+//! A token-bucket rate limiter for the I/O done by flushes and compactions, so that background
+//! work doesn't starve foreground reads and writes.
+#[cfg(feature = "mesalock_sgx")]
+use std::prelude::v1::*;
+
+use env::Env;
+use error::Result;
+use options::Options;
+use statistics::{self, Ticker};
+use types::LdbIterator;
+
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Tokens accumulate for at most this long; this limits the size of bursts after idle periods.
+const REFILL_PERIOD_MICROS: u64 = 100 * 1000;
+/// Reads are charged in chunks of this size to avoid consulting the clock for every entry.
+const READ_CHARGE_CHUNK: usize = 32 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IOPriority {
+    /// Memtable flushes; writers may be waiting for them.
+    Flush = 0,
+    Compaction = 1,
+}
+
+/// RateLimiter limits the throughput of flush and compaction I/O to a configurable number of
+/// bytes per second.
+///
+/// Flushes have priority over compactions: a flush only waits until earlier requests have been
+/// paid for and then takes its tokens on credit, whereas a compaction waits until enough tokens
+/// for its request have accumulated.
+pub struct RateLimiter {
+    env: Rc<Box<dyn Env>>,
+    bytes_per_sec: usize,
+    // Negative if flushes have taken tokens on credit.
+    available: i64,
+    last_refill: u64,
+
+    throttled_micros: u64,
+    bytes_through: [u64; 2],
+    requests: [u64; 2],
+}
+
+impl RateLimiter {
+    pub fn new(env: Rc<Box<dyn Env>>, bytes_per_sec: usize) -> RateLimiter {
+        assert!(bytes_per_sec > 0);
+        let now = env.micros();
+        RateLimiter {
+            env,
+            bytes_per_sec,
+            available: 0,
+            last_refill: now,
+            throttled_micros: 0,
+            bytes_through: [0; 2],
+            requests: [0; 2],
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_sec
+    }
+
+    /// Changes the rate. Tokens accumulated so far are kept.
+    pub fn set_bytes_per_second(&mut self, bytes_per_sec: usize) {
+        assert!(bytes_per_sec > 0);
+        self.refill();
+        self.bytes_per_sec = bytes_per_sec;
+    }
+
+    /// Returns the total time requests have been delayed for, in microseconds.
+    pub fn throttled_micros(&self) -> u64 {
+        self.throttled_micros
+    }
+
+    pub fn total_bytes_through(&self, pri: IOPriority) -> u64 {
+        self.bytes_through[pri as usize]
+    }
+
+    pub fn total_requests(&self, pri: IOPriority) -> u64 {
+        self.requests[pri as usize]
+    }
+
+    fn burst(&self) -> i64 {
+        ((self.bytes_per_sec as u64 * REFILL_PERIOD_MICROS / 1000000) as i64).max(1)
+    }
+
+    fn refill(&mut self) {
+        let now = self.env.micros();
+        let elapsed = now.saturating_sub(self.last_refill);
+        self.last_refill = now;
+        let new = (elapsed as u128 * self.bytes_per_sec as u128 / 1000000) as i64;
+        self.available = (self.available + new).min(self.burst());
+    }
+
+    /// request blocks until `bytes` may be transferred at priority `pri`. It returns how long the
+    /// request was delayed, in microseconds.
+    pub fn request(&mut self, bytes: usize, pri: IOPriority) -> u64 {
+        self.refill();
+        self.requests[pri as usize] += 1;
+        self.bytes_through[pri as usize] += bytes as u64;
+
+        let bytes = bytes as i64;
+        let missing = match pri {
+            IOPriority::Flush => -self.available.min(0),
+            IOPriority::Compaction => (bytes - self.available).max(0),
+        };
+        let mut waited = 0;
+        if missing > 0 {
+            waited = (missing as u128 * 1000000 / self.bytes_per_sec as u128) as u64;
+            self.env.sleep_for(waited);
+            self.throttled_micros += waited;
+            // Account for exactly the tokens we waited for; the refill period restarts now.
+            self.available += missing;
+            self.last_refill = self.env.micros();
+        }
+        self.available -= bytes;
+        waited
+    }
+}
+
+/// Draws `bytes` from the rate limiter in `opt`, if there is one, and records the time spent
+/// throttled.
+pub fn request(opt: &Options, bytes: usize, pri: IOPriority) {
+    if let Some(ref rl) = opt.rate_limiter {
+        let waited = rl.borrow_mut().request(bytes, pri);
+        if waited > 0 {
+            statistics::record_tick(&opt.statistics, Ticker::RateLimitMicros, waited);
+        }
+    }
+}
+
+/// RateLimitedWriter charges everything written to it to the rate limiter in the options.
+pub struct RateLimitedWriter<W: Write> {
+    dst: W,
+    opt: Options,
+    pri: IOPriority,
+}
+
+impl<W: Write> RateLimitedWriter<W> {
+    pub fn new(dst: W, opt: Options, pri: IOPriority) -> RateLimitedWriter<W> {
+        RateLimitedWriter { dst, opt, pri }
+    }
+}
+
+impl<W: Write> Write for RateLimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        request(&self.opt, buf.len(), self.pri);
+        self.dst.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.dst.flush()
+    }
+}
+
+/// RateLimitedIter charges the keys and values read from an iterator to the rate limiter in the
+/// options.
+pub struct RateLimitedIter {
+    iter: Box<dyn LdbIterator>,
+    opt: Options,
+    pri: IOPriority,
+    pending: usize,
+    keybuf: Vec<u8>,
+    valbuf: Vec<u8>,
+}
+
+impl RateLimitedIter {
+    pub fn new(iter: Box<dyn LdbIterator>, opt: Options, pri: IOPriority) -> RateLimitedIter {
+        RateLimitedIter {
+            iter,
+            opt,
+            pri,
+            pending: 0,
+            keybuf: vec![],
+            valbuf: vec![],
+        }
+    }
+
+    fn charge_current(&mut self) {
+        if self.iter.current(&mut self.keybuf, &mut self.valbuf) {
+            self.pending += self.keybuf.len() + self.valbuf.len();
+        }
+        if self.pending >= READ_CHARGE_CHUNK || !self.iter.valid() {
+            self.charge_pending();
+        }
+    }
+
+    fn charge_pending(&mut self) {
+        if self.pending > 0 {
+            request(&self.opt, self.pending, self.pri);
+            self.pending = 0;
+        }
+    }
+}
+
+impl LdbIterator for RateLimitedIter {
+    fn advance(&mut self) -> bool {
+        let r = self.iter.advance();
+        self.charge_current();
+        r
+    }
+    fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
+        self.iter.current(key, val)
+    }
+    fn seek(&mut self, key: &[u8]) {
+        self.iter.seek(key);
+        self.charge_current();
+    }
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+        self.charge_current();
+    }
+    fn reset(&mut self) {
+        self.charge_pending();
+        self.iter.reset();
+    }
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+    fn prev(&mut self) -> bool {
+        let r = self.iter.prev();
+        self.charge_current();
+        r
+    }
+    fn status(&self) -> Result<()> {
+        self.iter.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem_env::MemEnv;
+
+    #[test]
+    fn test_rate_limiter_request() {
+        let env: Rc<Box<dyn Env>> = Rc::new(Box::new(MemEnv::new()));
+        // 1 MB/s: 100 KB take 100 ms.
+        let mut rl = RateLimiter::new(env, 1 << 20);
+
+        // Flushes take their tokens on credit...
+        assert_eq!(0, rl.request(100 << 10, IOPriority::Flush));
+        // ...which compactions have to pay for, in addition to their own request.
+        let waited = rl.request(50 << 10, IOPriority::Compaction);
+        assert!(waited >= 140000 && waited <= 150000, "{}", waited);
+        // A flush waits for the compaction's debt, but not for its own tokens.
+        assert!(rl.request(1 << 10, IOPriority::Flush) <= 1000);
+
+        assert!(rl.throttled_micros() >= waited);
+        assert_eq!(2, rl.total_requests(IOPriority::Flush));
+        assert_eq!(1, rl.total_requests(IOPriority::Compaction));
+        assert_eq!(101 << 10, rl.total_bytes_through(IOPriority::Flush));
+        assert_eq!(50 << 10, rl.total_bytes_through(IOPriority::Compaction));
+
+        rl.set_bytes_per_second(1 << 30);
+        assert_eq!(1 << 30, rl.bytes_per_second());
+        assert!(rl.request(1 << 20, IOPriority::Compaction) <= 2000);
+    }
+}
@@ -73,6 +73,10 @@ impl SkipMap {
     pub fn approx_memory(&self) -> usize {
         self.map.borrow().approx_mem
     }
+    /// Returns the size of user timestamps in the keys, as reported by the comparator.
+    pub fn timestamp_size(&self) -> usize {
+        self.map.borrow().cmp.timestamp_size()
+    }
     pub fn contains(&self, key: &[u8]) -> bool {
         self.map.borrow().contains(key)
     }
@@ -192,6 +196,32 @@ impl InnerSkipMap {
         }
     }
 
+    /// Returns the node with the greatest key, or None if the map is empty.
+    fn get_last<'a>(&'a self) -> Option<&'a Node> {
+        // Follow the highest skip links as far as possible, then work down from there.
+        let mut current = self.head.as_ref() as *const Node;
+        let mut level = self.head.skips.len() - 1;
+
+        loop {
+            unsafe {
+                if let Some(next) = (*current).skips[level] {
+                    current = next;
+                    continue;
+                }
+            }
+            if level == 0 {
+                break;
+            }
+            level -= 1;
+        }
+
+        if current == self.head.as_ref() {
+            None
+        } else {
+            unsafe { Some(&(*current)) }
+        }
+    }
+
     fn insert(&mut self, key: Vec<u8>, val: Vec<u8>) {
         assert!(!key.is_empty());
 
@@ -323,6 +353,13 @@ impl LdbIterator for SkipMapIter {
         }
         self.reset();
     }
+    fn seek_to_last(&mut self) {
+        if let Some(node) = self.map.borrow().get_last() {
+            self.current = node as *const Node;
+            return;
+        }
+        self.reset();
+    }
     fn valid(&self) -> bool {
         self.current != self.map.borrow().head.as_ref()
     }
@@ -473,6 +510,15 @@ pub mod tests {
                 .as_slice(),
             "abz".as_bytes()
         );
+        assert_eq!(
+            skm.map.borrow().get_last().unwrap().key.as_slice(),
+            "abz".as_bytes()
+        );
+        assert!(SkipMap::new(options::for_test().cmp)
+            .map
+            .borrow()
+            .get_last()
+            .is_none());
     }
 
     #[test]
@@ -36,7 +36,6 @@ impl Snapshot {
 struct InnerSnapshotList {
     map: HashMap<SnapshotHandle, SequenceNumber>,
     newest: SnapshotHandle,
-    oldest: SnapshotHandle,
 }
 
 pub struct SnapshotList {
@@ -49,7 +48,6 @@ impl SnapshotList {
             inner: share(InnerSnapshotList {
                 map: HashMap::new(),
                 newest: 0,
-                oldest: 0,
             }),
         }
     }
@@ -62,10 +60,6 @@ impl SnapshotList {
         let newest = sl.newest;
         sl.map.insert(newest, seq);
 
-        if sl.oldest == 0 {
-            sl.oldest = sl.newest;
-        }
-
         Snapshot {
             inner: Rc::new(InnerSnapshot {
                 id: sl.newest,
@@ -85,7 +79,7 @@ impl SnapshotList {
             .iter()
             .fold(
                 MAX_SEQUENCE_NUMBER,
-                |s, (seq, _)| if *seq < s { *seq } else { s },
+                |s, (_, seq)| if *seq < s { *seq } else { s },
             );
         if oldest == MAX_SEQUENCE_NUMBER {
             0
@@ -101,11 +95,11 @@ impl SnapshotList {
             .borrow()
             .map
             .iter()
-            .fold(0, |s, (seq, _)| if *seq > s { *seq } else { s })
+            .fold(0, |s, (_, seq)| if *seq > s { *seq } else { s })
     }
 
     pub fn empty(&self) -> bool {
-        self.inner.borrow().oldest == 0
+        self.inner.borrow().map.is_empty()
     }
 }
 
@@ -146,5 +140,16 @@ mod tests {
             assert_eq!(l.oldest(), 1);
         }
         assert_eq!(l.oldest(), 0);
+        assert!(l.empty());
+
+        // Snapshot handles are not sequence numbers.
+        let a = l.new_snapshot(20);
+        let b = l.new_snapshot(30);
+        assert_eq!(l.oldest(), 20);
+        assert_eq!(l.newest(), 30);
+        drop(a);
+        assert_eq!(l.oldest(), 30);
+        drop(b);
+        assert!(l.empty());
     }
 }
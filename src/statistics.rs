@@ -0,0 +1,447 @@
+//! Statistics collects counters and latency histograms about the operation of a database that
+//! can't be derived from the outside, e.g. block cache hit rates or time spent stalling writes.
+//!
+//! Collection is enabled by setting `Options::statistics` to a shared `Statistics` object.
+#[cfg(feature = "mesalock_sgx")]
+use std::prelude::v1::*;
+
+use env::Env;
+use types::{Shared, NUM_LEVELS};
+
+use std::fmt::{self, Display, Formatter};
+
+/// A Ticker is a monotonically increasing counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ticker {
+    /// Data blocks found in the block cache.
+    BlockCacheHit = 0,
+    /// Data blocks that had to be read from a table file.
+    BlockCacheMiss,
+    /// Bytes of data blocks read from table files.
+    BlockReadBytes,
+    /// Filter checks that ruled out a key (negative result), avoiding a block read.
+    BloomFilterUseful,
+    /// Filter checks that reported that a key may be present.
+    BloomFilterPositive,
+    /// Lookups answered by the memtable or the immutable memtable.
+    MemtableHit,
+    /// Lookups that had to consult table files.
+    MemtableMiss,
+    /// Bytes written to the write-ahead log, including record headers.
+    WalBytes,
+    /// Microseconds writes spent waiting for room in the memtable.
+    StallMicros,
+    /// Microseconds flushes and compactions were delayed by the rate limiter.
+    RateLimitMicros,
+    /// Writes that were slowed down because compactions were falling behind.
+    WriteSlowdowns,
+    WriteSlowdownMicros,
+    /// Writes that were stopped until compactions had caught up.
+    WriteStops,
+    WriteStopMicros,
+}
+
+const NUM_TICKERS: usize = 14;
+
+impl Ticker {
+    pub const ALL: [Ticker; NUM_TICKERS] = [
+        Ticker::BlockCacheHit,
+        Ticker::BlockCacheMiss,
+        Ticker::BlockReadBytes,
+        Ticker::BloomFilterUseful,
+        Ticker::BloomFilterPositive,
+        Ticker::MemtableHit,
+        Ticker::MemtableMiss,
+        Ticker::WalBytes,
+        Ticker::StallMicros,
+        Ticker::RateLimitMicros,
+        Ticker::WriteSlowdowns,
+        Ticker::WriteSlowdownMicros,
+        Ticker::WriteStops,
+        Ticker::WriteStopMicros,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Ticker::BlockCacheHit => "block.cache.hit",
+            Ticker::BlockCacheMiss => "block.cache.miss",
+            Ticker::BlockReadBytes => "block.read.bytes",
+            Ticker::BloomFilterUseful => "bloom.filter.useful",
+            Ticker::BloomFilterPositive => "bloom.filter.positive",
+            Ticker::MemtableHit => "memtable.hit",
+            Ticker::MemtableMiss => "memtable.miss",
+            Ticker::WalBytes => "wal.bytes",
+            Ticker::StallMicros => "stall.micros",
+            Ticker::RateLimitMicros => "rate.limit.micros",
+            Ticker::WriteSlowdowns => "write.slowdowns",
+            Ticker::WriteSlowdownMicros => "write.slowdown.micros",
+            Ticker::WriteStops => "write.stops",
+            Ticker::WriteStopMicros => "write.stop.micros",
+        }
+    }
+}
+
+/// A HistogramType names a latency distribution, measured in microseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramType {
+    /// DB::get() and DB::get_at().
+    Get = 0,
+    /// DB::write() and everything built on it, like DB::put().
+    Write,
+    /// DBIterator::seek().
+    Seek,
+    /// Memtable and table compactions.
+    Compaction,
+}
+
+const NUM_HISTOGRAMS: usize = 4;
+
+impl HistogramType {
+    pub const ALL: [HistogramType; NUM_HISTOGRAMS] = [
+        HistogramType::Get,
+        HistogramType::Write,
+        HistogramType::Seek,
+        HistogramType::Compaction,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HistogramType::Get => "db.get.micros",
+            HistogramType::Write => "db.write.micros",
+            HistogramType::Seek => "db.seek.micros",
+            HistogramType::Compaction => "compaction.micros",
+        }
+    }
+}
+
+/// Bucket i contains values v with 2^(i-1) <= v < 2^i; bucket 0 only contains 0.
+const NUM_BUCKETS: usize = 65;
+
+fn bucket_of(v: u64) -> usize {
+    64 - v.leading_zeros() as usize
+}
+
+fn bucket_limits(b: usize) -> (u64, u64) {
+    match b {
+        0 => (0, 0),
+        64 => (1 << 63, u64::max_value()),
+        _ => (1 << (b - 1), (1 << b) - 1),
+    }
+}
+
+/// A Histogram records a distribution of values in exponentially sized buckets.
+#[derive(Clone)]
+pub struct Histogram {
+    count: u64,
+    sum: u64,
+    sum_squares: f64,
+    min: u64,
+    max: u64,
+    buckets: [u64; NUM_BUCKETS],
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            count: 0,
+            sum: 0,
+            sum_squares: 0.0,
+            min: 0,
+            max: 0,
+            buckets: [0; NUM_BUCKETS],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Histogram::new();
+    }
+
+    pub fn add(&mut self, v: u64) {
+        if self.count == 0 || v < self.min {
+            self.min = v;
+        }
+        if v > self.max {
+            self.max = v;
+        }
+        self.count += 1;
+        self.sum = self.sum.saturating_add(v);
+        self.sum_squares += v as f64 * v as f64;
+        self.buckets[bucket_of(v)] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn average(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let n = self.count as f64;
+        let variance = (self.sum_squares * n - self.sum as f64 * self.sum as f64) / (n * n);
+        variance.max(0.0).sqrt()
+    }
+
+    /// Returns an estimate of the p-th percentile (0 <= p <= 100), interpolating linearly within
+    /// the bucket containing it.
+    pub fn percentile(&self, p: f64) -> f64 {
+        let threshold = self.count as f64 * (p / 100.0);
+        let mut cumulative = 0.0;
+        for (b, &n) in self.buckets.iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            cumulative += n as f64;
+            if cumulative >= threshold {
+                let (lo, hi) = bucket_limits(b);
+                let (lo, hi) = (lo.max(self.min) as f64, hi.min(self.max) as f64);
+                let pos = (threshold - (cumulative - n as f64)) / n as f64;
+                return lo + (hi - lo) * pos;
+            }
+        }
+        self.max as f64
+    }
+
+    pub fn median(&self) -> f64 {
+        self.percentile(50.0)
+    }
+}
+
+impl Display for Histogram {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Count: {} Average: {:.4} StdDev: {:.2}",
+            self.count,
+            self.average(),
+            self.std_dev()
+        )?;
+        writeln!(
+            f,
+            "Min: {} Median: {:.4} P95: {:.4} P99: {:.4} Max: {}",
+            self.min,
+            self.median(),
+            self.percentile(95.0),
+            self.percentile(99.0),
+            self.max
+        )?;
+        let mut cumulative = 0;
+        for (b, &n) in self.buckets.iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            cumulative += n;
+            let (lo, hi) = bucket_limits(b);
+            writeln!(
+                f,
+                "[ {:>10}, {:>10} ] {:>8} {:>7.3}% {:>7.3}%",
+                lo,
+                hi,
+                n,
+                100.0 * n as f64 / self.count as f64,
+                100.0 * cumulative as f64 / self.count as f64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Statistics holds all tickers and histograms of a database, as well as the number of bytes read
+/// and written by compactions on each level.
+#[derive(Clone)]
+pub struct Statistics {
+    tickers: [u64; NUM_TICKERS],
+    histograms: Vec<Histogram>,
+    // Grown as needed if there are more than NUM_LEVELS levels.
+    level_read: Vec<u64>,
+    level_written: Vec<u64>,
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics {
+            tickers: [0; NUM_TICKERS],
+            histograms: vec![Histogram::new(); NUM_HISTOGRAMS],
+            level_read: vec![0; NUM_LEVELS],
+            level_written: vec![0; NUM_LEVELS],
+        }
+    }
+
+    pub fn record_tick(&mut self, t: Ticker, n: u64) {
+        self.tickers[t as usize] += n;
+    }
+
+    pub fn ticker(&self, t: Ticker) -> u64 {
+        self.tickers[t as usize]
+    }
+
+    pub fn measure_time(&mut self, h: HistogramType, micros: u64) {
+        self.histograms[h as usize].add(micros);
+    }
+
+    pub fn histogram(&self, h: HistogramType) -> &Histogram {
+        &self.histograms[h as usize]
+    }
+
+    pub fn record_level_io(&mut self, level: usize, read: usize, written: usize) {
+        if level >= self.level_read.len() {
+            self.level_read.resize(level + 1, 0);
+            self.level_written.resize(level + 1, 0);
+        }
+        self.level_read[level] += read as u64;
+        self.level_written[level] += written as u64;
+    }
+
+    pub fn level_bytes_read(&self, level: usize) -> u64 {
+        self.level_read.get(level).cloned().unwrap_or(0)
+    }
+
+    pub fn level_bytes_written(&self, level: usize) -> u64 {
+        self.level_written.get(level).cloned().unwrap_or(0)
+    }
+
+    /// Returns a copy of the current state that isn't affected by later updates.
+    pub fn snapshot(&self) -> Statistics {
+        self.clone()
+    }
+
+    /// Resets all tickers and histograms to zero.
+    pub fn reset(&mut self) {
+        *self = Statistics::new();
+    }
+}
+
+impl Display for Statistics {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for t in Ticker::ALL.iter() {
+            writeln!(f, "{} COUNT : {}", t.name(), self.ticker(*t))?;
+        }
+        for l in 0..self.level_read.len() {
+            writeln!(
+                f,
+                "level{} READ : {} WRITE : {}",
+                l, self.level_read[l], self.level_written[l]
+            )?;
+        }
+        for h in HistogramType::ALL.iter() {
+            write!(f, "{}\n{}", h.name(), self.histogram(*h))?;
+        }
+        Ok(())
+    }
+}
+
+/// Increments ticker `t` by `n` if statistics are enabled.
+pub fn record_tick(stats: &Option<Shared<Statistics>>, t: Ticker, n: u64) {
+    if let Some(ref s) = *stats {
+        s.borrow_mut().record_tick(t, n);
+    }
+}
+
+/// Adds a latency sample to histogram `h` if statistics are enabled.
+pub fn measure_time(stats: &Option<Shared<Statistics>>, h: HistogramType, micros: u64) {
+    if let Some(ref s) = *stats {
+        s.borrow_mut().measure_time(h, micros);
+    }
+}
+
+/// Returns the current time if statistics are enabled, to be passed to `measure_since()` or
+/// `record_micros_since()`.
+/// Without statistics the clock isn't read at all, as that is an OCALL in SGX.
+pub fn start_timer(stats: &Option<Shared<Statistics>>, env: &dyn Env) -> Option<u64> {
+    stats.as_ref().map(|_| env.micros())
+}
+
+/// Adds the time elapsed since `start` (from `start_timer()`) to histogram `h`.
+pub fn measure_since(
+    stats: &Option<Shared<Statistics>>,
+    env: &dyn Env,
+    h: HistogramType,
+    start: Option<u64>,
+) {
+    if let Some(start) = start {
+        measure_time(stats, h, env.micros().saturating_sub(start));
+    }
+}
+
+/// Increments ticker `t` by the microseconds elapsed since `start` (from `start_timer()`).
+pub fn record_micros_since(
+    stats: &Option<Shared<Statistics>>,
+    env: &dyn Env,
+    t: Ticker,
+    start: Option<u64>,
+) {
+    if let Some(start) = start {
+        record_tick(stats, t, env.micros().saturating_sub(start));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statistics_histogram() {
+        let mut h = Histogram::new();
+        assert_eq!(h.percentile(50.0), 0.0);
+        for v in 1..101 {
+            h.add(v);
+        }
+        assert_eq!(h.count(), 100);
+        assert_eq!(h.sum(), 5050);
+        assert_eq!((h.min(), h.max()), (1, 100));
+        assert_eq!(h.average(), 50.5);
+        assert!((h.std_dev() - 28.866).abs() < 0.001);
+        let median = h.median();
+        assert!(median >= 32.0 && median <= 63.0, "{}", median);
+        assert!(h.percentile(99.0) <= 100.0);
+        assert_eq!(h.percentile(100.0), 100.0);
+        assert!(h.to_string().starts_with("Count: 100 Average: 50.5000"));
+
+        h.clear();
+        assert_eq!(h.count(), 0);
+    }
+
+    #[test]
+    fn test_statistics_snapshot_reset() {
+        let mut s = Statistics::new();
+        s.record_tick(Ticker::BlockCacheHit, 3);
+        s.record_tick(Ticker::BlockCacheHit, 1);
+        s.measure_time(HistogramType::Get, 12);
+        s.record_level_io(1, 100, 50);
+
+        let snap = s.snapshot();
+        s.reset();
+        assert_eq!(s.ticker(Ticker::BlockCacheHit), 0);
+        assert_eq!(s.histogram(HistogramType::Get).count(), 0);
+        assert_eq!(s.level_bytes_read(1), 0);
+
+        assert_eq!(snap.ticker(Ticker::BlockCacheHit), 4);
+        assert_eq!(snap.histogram(HistogramType::Get).count(), 1);
+        assert_eq!(snap.level_bytes_read(1), 100);
+        assert_eq!(snap.level_bytes_written(1), 50);
+
+        let dump = snap.to_string();
+        assert!(dump.contains("block.cache.hit COUNT : 4\n"));
+        assert!(dump.contains("level1 READ : 100 WRITE : 50\n"));
+        assert!(dump.contains("db.get.micros\nCount: 1"));
+    }
+}
@@ -3,17 +3,15 @@ use std::prelude::v1::*;
 
 use block::Block;
 use blockhandle::BlockHandle;
+use compression::decompress_block;
 use env::RandomAccess;
 use error::{err, Result, StatusCode};
-use filter;
-use filter_block::FilterBlockReader;
 use log::unmask_crc;
 use options::{self, CompressionType, Options};
 use table_builder;
 
 use crc::crc32::{self, Hasher32};
 use integer_encoding::FixedInt;
-use snap::Decoder;
 
 /// Reads the data for the specified block handle from a file.
 fn read_bytes(f: &dyn RandomAccess, location: &BlockHandle) -> Result<Vec<u8>> {
@@ -21,30 +19,25 @@ fn read_bytes(f: &dyn RandomAccess, location: &BlockHandle) -> Result<Vec<u8>> {
     f.read_at(location.offset(), &mut buf).map(|_| buf)
 }
 
-/// Reads a serialized filter block from a file and returns a FilterBlockReader.
-pub fn read_filter_block(
-    src: &dyn RandomAccess,
+/// Reads a table block from a random-access source. `dict` is the compression dictionary of the
+/// table, if any.
+pub fn read_table_block(
+    opt: Options,
+    f: &dyn RandomAccess,
     location: &BlockHandle,
-    policy: filter::BoxedFilterPolicy,
-) -> Result<FilterBlockReader> {
-    if location.size() == 0 {
-        return err(
-            StatusCode::InvalidArgument,
-            "no filter block in empty location",
-        );
-    }
-    let buf = read_bytes(src, location)?;
-    Ok(FilterBlockReader::new_owned(policy, buf))
+    dict: Option<&[u8]>,
+) -> Result<Block> {
+    Ok(Block::new(opt, read_block_contents(f, location, dict)?))
 }
 
-/// Reads a table block from a random-access source.
+/// Reads and decompresses the contents of a table block.
 /// A table block consists of [bytes..., compress (1B), checksum (4B)]; the handle only refers to
 /// the location and length of [bytes...].
-pub fn read_table_block(
-    opt: Options,
+pub fn read_block_contents(
     f: &dyn RandomAccess,
     location: &BlockHandle,
-) -> Result<Block> {
+    dict: Option<&[u8]>,
+) -> Result<Vec<u8>> {
     // The block is denoted by offset and length in BlockHandle. A block in an encoded
     // table is followed by 1B compression type and 4B checksum.
     // The checksum refers to the compressed contents.
@@ -76,11 +69,8 @@ pub fn read_table_block(
 
     if let Some(ctype) = options::int_to_compressiontype(compress[0] as u32) {
         match ctype {
-            CompressionType::CompressionNone => Ok(Block::new(opt, buf)),
-            CompressionType::CompressionSnappy => {
-                let decoded = Decoder::new().decompress_vec(&buf)?;
-                Ok(Block::new(opt, decoded))
-            }
+            CompressionType::CompressionNone => Ok(buf),
+            _ => decompress_block(ctype, dict, &buf),
         }
     } else {
         err(StatusCode::InvalidData, "invalid compression type")
@@ -3,8 +3,10 @@ use std::prelude::v1::*;
 
 use block::BlockContents;
 use block_builder::BlockBuilder;
+use block_hash_index;
 use blockhandle::BlockHandle;
-use cmp::InternalKeyCmp;
+use cmp::{DefaultCmp, InternalKeyCmp};
+use compression::{compress_block, train_dictionary, DICT_META_KEY};
 use error::Result;
 use filter::{InternalFilterPolicy, NoFilterPolicy};
 use filter_block::FilterBlockBuilder;
@@ -14,12 +16,12 @@ use options::{CompressionType, Options};
 
 use std::cmp::Ordering;
 use std::io::Write;
+use std::mem;
 use std::rc::Rc;
 
 use crc::crc32;
 use crc::Hasher32;
-use integer_encoding::FixedIntWriter;
-use snap::Encoder;
+use integer_encoding::{FixedIntWriter, VarIntWriter};
 
 pub const FOOTER_LENGTH: usize = 40;
 pub const FULL_FOOTER_LENGTH: usize = FOOTER_LENGTH + 8;
@@ -29,6 +31,18 @@ pub const MAGIC_FOOTER_ENCODED: [u8; 8] = [0x57, 0xfb, 0x80, 0x8b, 0x24, 0x75, 0
 pub const TABLE_BLOCK_COMPRESS_LEN: usize = 1;
 pub const TABLE_BLOCK_CKSUM_LEN: usize = 4;
 
+/// Metaindex key of the top-level index, if the index is partitioned.
+pub const PARTITIONED_INDEX_META_KEY: &str = "partitionedindex";
+/// Metaindex key prefix of the filter block (followed by the name of the filter policy).
+pub const FILTER_META_PREFIX: &str = "filter.";
+/// Metaindex key prefix of the full filter block.
+pub const FULL_FILTER_META_PREFIX: &str = "fullfilter.";
+/// Metaindex key prefix of the top-level filter index, if the filter is partitioned.
+pub const PARTITIONED_FILTER_META_PREFIX: &str = "partitionedfilter.";
+/// Metaindex key prefix of the top-level filter index, if the filter is partitioned into full
+/// filters.
+pub const PARTITIONED_FULL_FILTER_META_PREFIX: &str = "partitionedfullfilter.";
+
 /// Footer is a helper for encoding/decoding a table footer.
 #[derive(Debug, Clone)]
 pub struct Footer {
@@ -84,6 +98,18 @@ impl Footer {
 /// The FOOTER consists of a BlockHandle that points to the metaindex block, another pointing to
 /// the index block, padding to fill up to 40 B and at the end the 8B magic number
 /// 0xdb4775248b80fb57.
+///
+/// If a compression dictionary is used, it is stored in a META BLOCK as well.
+///
+/// With `partition_index_and_filters`, the index is split into partitions of about
+/// `metadata_block_size` bytes, which are indexed by a top-level index block; the footer and the
+/// metaindex entry PARTITIONED_INDEX_META_KEY point to the top-level index. The filter is split
+/// along the same keys; its top-level index maps each key to the filter partition and the offset
+/// of the partition's first data block, which the filter offsets are relative to.
+///
+/// With `full_filters`, the filter block (or each filter partition) contains a single filter
+/// over all of its keys, and is stored under FULL_FILTER_META_PREFIX (or
+/// PARTITIONED_FULL_FILTER_META_PREFIX) instead.
 
 pub struct TableBuilder<Dst: Write> {
     opt: Options,
@@ -96,6 +122,55 @@ pub struct TableBuilder<Dst: Write> {
     data_block: Option<BlockBuilder>,
     index_block: Option<BlockBuilder>,
     filter_block: Option<FilterBlockBuilder>,
+    // Whether the keys are InternalKeys; needed by the data block hash index.
+    internal_keys: bool,
+
+    // Data blocks held back until the compression dictionary has been trained on them. None if no
+    // dictionary is used or once it has been trained.
+    dict_buffer: Option<Vec<BufferedBlock>>,
+    dict_buffer_size: usize,
+    // Keys of the current data block, added to the filter block once it is written.
+    dict_filter_keys: Vec<Vec<u8>>,
+    dict: Option<Vec<u8>>,
+
+    // Finished index and filter partitions, written by finish().
+    partitions: Vec<Partition>,
+    partitions_size: usize,
+    // Offset of the first data block of the current partition.
+    partition_base: usize,
+}
+
+struct BufferedBlock {
+    contents: BlockContents,
+    index_key: Vec<u8>,
+    filter_keys: Vec<Vec<u8>>,
+}
+
+struct Partition {
+    last_key: Vec<u8>,
+    index: BlockContents,
+    filter: Option<BlockContents>,
+    base: usize,
+}
+
+/// Returns a builder for a data block, with a hash index if enabled in `opt`.
+fn new_data_block(opt: &Options, internal_keys: bool) -> BlockBuilder {
+    let block = BlockBuilder::new(opt.clone());
+    if opt.data_block_hash_index {
+        let ts_size = opt.cmp.timestamp_size();
+        block.with_hash_index(block_hash_index::key_suffix_len(internal_keys, ts_size))
+    } else {
+        block
+    }
+}
+
+/// Returns a builder for a filter block, which is a full filter block if enabled in `opt`.
+fn new_filter_block(opt: &Options) -> FilterBlockBuilder {
+    if opt.full_filters {
+        FilterBlockBuilder::new_full(opt.filter_policy.clone())
+    } else {
+        FilterBlockBuilder::new(opt.filter_policy.clone())
+    }
 }
 
 impl<Dst: Write> TableBuilder<Dst> {
@@ -113,21 +188,36 @@ impl<Dst: Write> TableBuilder<Dst> {
     /// in an InternalFilterPolicy.
     pub fn new(mut opt: Options, dst: Dst) -> TableBuilder<Dst> {
         opt.cmp = Rc::new(Box::new(InternalKeyCmp(opt.cmp.clone())));
-        opt.filter_policy = Rc::new(Box::new(InternalFilterPolicy::new(opt.filter_policy)));
-        TableBuilder::new_raw(opt, dst)
+        let fpol = InternalFilterPolicy::new(opt.filter_policy);
+        opt.filter_policy = Rc::new(Box::new(fpol.with_timestamp_size(opt.cmp.timestamp_size())));
+        TableBuilder::new_with(opt, dst, true)
     }
 
     /// Like new(), but doesn't wrap the comparator in an InternalKeyCmp (for testing)
     pub fn new_raw(opt: Options, dst: Dst) -> TableBuilder<Dst> {
+        TableBuilder::new_with(opt, dst, false)
+    }
+
+    fn new_with(opt: Options, dst: Dst, internal_keys: bool) -> TableBuilder<Dst> {
+        let use_dict = opt.compression_max_dict_bytes > 0
+            && opt.compression_type == CompressionType::CompressionZstd;
         TableBuilder {
             opt: opt.clone(),
             dst,
             offset: 0,
             prev_block_last_key: vec![],
             num_entries: 0,
-            data_block: Some(BlockBuilder::new(opt.clone())),
-            filter_block: Some(FilterBlockBuilder::new(opt.filter_policy.clone())),
+            data_block: Some(new_data_block(&opt, internal_keys)),
+            internal_keys,
+            filter_block: Some(new_filter_block(&opt)),
             index_block: Some(BlockBuilder::new(opt)),
+            dict_buffer: if use_dict { Some(vec![]) } else { None },
+            dict_buffer_size: 0,
+            dict_filter_keys: vec![],
+            dict: None,
+            partitions: vec![],
+            partitions_size: 0,
+            partition_base: 0,
         }
     }
 
@@ -146,7 +236,10 @@ impl<Dst: Write> TableBuilder<Dst> {
         if let Some(ref b) = self.filter_block {
             size += b.size_estimate();
         }
-        size + self.offset + FULL_FOOTER_LENGTH
+        if self.dict_buffer.is_some() {
+            size += self.dict_buffer_size;
+        }
+        size + self.partitions_size + self.offset + FULL_FOOTER_LENGTH
     }
 
     /// Add a key to the table. The key as to be lexically greater or equal to the last one added.
@@ -165,7 +258,11 @@ impl<Dst: Write> TableBuilder<Dst> {
         let dblock = &mut self.data_block.as_mut().unwrap();
 
         if let Some(ref mut fblock) = self.filter_block {
-            fblock.add_key(key);
+            if self.dict_buffer.is_some() {
+                self.dict_filter_keys.push(key.to_vec());
+            } else {
+                fblock.add_key(key);
+            }
         }
 
         self.num_entries += 1;
@@ -175,7 +272,8 @@ impl<Dst: Write> TableBuilder<Dst> {
 
     /// Writes an index entry for the current data_block where `next_key` is the first key of the
     /// next block.
-    /// Calls write_block() for writing the block to disk.
+    /// Calls add_data_block() for writing the block to disk, unless it is held back for training
+    /// the compression dictionary.
     fn write_data_block<'b>(&mut self, next_key: InternalKey<'b>) -> Result<()> {
         assert!(self.data_block.is_some());
 
@@ -183,9 +281,58 @@ impl<Dst: Write> TableBuilder<Dst> {
         let sep = self.opt.cmp.find_shortest_sep(&block.last_key(), next_key);
         self.prev_block_last_key = Vec::from(block.last_key());
         let contents = block.finish();
+        self.data_block = Some(new_data_block(&self.opt, self.internal_keys));
+
+        if let Some(ref mut buffer) = self.dict_buffer {
+            self.dict_buffer_size += contents.len();
+            buffer.push(BufferedBlock {
+                contents,
+                index_key: sep,
+                filter_keys: mem::replace(&mut self.dict_filter_keys, vec![]),
+            });
+            if self.dict_buffer_size >= self.opt.compression_dict_buffer_bytes {
+                self.flush_dict_buffer()?;
+            }
+            return Ok(());
+        }
+        self.add_data_block(contents, &sep)
+    }
 
+    /// Trains the compression dictionary on the held back data blocks, and writes them.
+    fn flush_dict_buffer(&mut self) -> Result<()> {
+        let blocks = match self.dict_buffer.take() {
+            Some(blocks) => blocks,
+            None => return Ok(()),
+        };
+        let samples: Vec<&[u8]> = blocks.iter().map(|b| b.contents.as_slice()).collect();
+        match train_dictionary(&samples, self.opt.compression_max_dict_bytes) {
+            Ok(dict) => self.dict = Some(dict),
+            Err(e) => {
+                log!(self.opt.log, "Not using a compression dictionary: {}", e);
+            }
+        }
+
+        for block in blocks {
+            if let Some(ref mut fblock) = self.filter_block {
+                for key in block.filter_keys.iter() {
+                    fblock.add_key(key);
+                }
+            }
+            self.add_data_block(block.contents, &block.index_key)?;
+        }
+        Ok(())
+    }
+
+    /// Compresses and writes a data block, and adds it to the index and filter blocks.
+    fn add_data_block(&mut self, contents: BlockContents, index_key: &[u8]) -> Result<()> {
         let ctype = self.opt.compression_type;
-        let handle = self.write_block(contents, ctype)?;
+        let data = if ctype == CompressionType::CompressionNone {
+            contents
+        } else {
+            let dict = self.dict.as_ref().map(|d| d.as_slice());
+            compress_block(ctype, self.opt.compression_level, dict, &contents)?
+        };
+        let handle = self.write_raw_block(data, ctype)?;
 
         let mut handle_enc = [0 as u8; 16];
         let enc_len = handle.encode_to(&mut handle_enc);
@@ -193,23 +340,88 @@ impl<Dst: Write> TableBuilder<Dst> {
         self.index_block
             .as_mut()
             .unwrap()
-            .add(&sep, &handle_enc[0..enc_len]);
-        self.data_block = Some(BlockBuilder::new(self.opt.clone()));
+            .add(index_key, &handle_enc[0..enc_len]);
 
         if let Some(ref mut fblock) = self.filter_block {
-            fblock.start_block(self.offset);
+            fblock.start_block(self.offset - self.partition_base);
+        }
+
+        if self.opt.partition_index_and_filters
+            && self.index_block.as_ref().unwrap().size_estimate() >= self.opt.metadata_block_size
+        {
+            self.finish_partition();
         }
 
         Ok(())
     }
 
-    /// Calculates the checksum, writes the block to disk and updates the offset.
-    fn write_block(&mut self, block: BlockContents, ctype: CompressionType) -> Result<BlockHandle> {
-        let mut data = block;
-        if ctype == CompressionType::CompressionSnappy {
-            let mut encoder = Encoder::new();
-            data = encoder.compress_vec(&data)?;
+    /// Finishes the current index and filter partitions, which end with the last data block.
+    fn finish_partition(&mut self) {
+        let index = self.index_block.take().unwrap();
+        self.index_block = Some(BlockBuilder::new(self.opt.clone()));
+        let last_key = index.last_key().to_vec();
+        let index = index.finish();
+
+        let filter = self.filter_block.take().map(|fblock| {
+            self.filter_block = Some(new_filter_block(&self.opt));
+            fblock.finish()
+        });
+
+        self.partitions_size += index.len() + filter.as_ref().map(|f| f.len()).unwrap_or(0);
+        self.partitions.push(Partition {
+            last_key,
+            index,
+            filter,
+            base: self.partition_base,
+        });
+        self.partition_base = self.offset;
+    }
+
+    /// Writes the index and filter partitions, and returns the handles of the top-level index
+    /// and, if there is a filter, the top-level filter index.
+    fn write_partitions(&mut self) -> Result<(BlockHandle, Option<BlockHandle>)> {
+        if self.index_block.as_ref().unwrap().entries() > 0 {
+            self.finish_partition();
         }
+        let ctype = self.opt.compression_type;
+        let mut top_index = BlockBuilder::new(self.opt.clone());
+        let mut top_filter = BlockBuilder::new(self.opt.clone());
+        let mut handle_enc = [0 as u8; 16];
+
+        for p in mem::replace(&mut self.partitions, vec![]) {
+            if let Some(filter) = p.filter {
+                let handle = self.write_block(filter, CompressionType::CompressionNone)?;
+                let enc_len = handle.encode_to(&mut handle_enc);
+                let mut val = handle_enc[0..enc_len].to_vec();
+                val.write_varint(p.base)?;
+                top_filter.add(&p.last_key, &val);
+            }
+            let handle = self.write_block(p.index, ctype)?;
+            let enc_len = handle.encode_to(&mut handle_enc);
+            top_index.add(&p.last_key, &handle_enc[0..enc_len]);
+        }
+
+        let mut filter_handle = None;
+        if self.filter_block.take().is_some() {
+            filter_handle = Some(self.write_block(top_filter.finish(), ctype)?);
+        }
+        let index_handle = self.write_block(top_index.finish(), ctype)?;
+        Ok((index_handle, filter_handle))
+    }
+
+    /// Compresses the block (without dictionary) and writes it using write_raw_block().
+    fn write_block(&mut self, block: BlockContents, ctype: CompressionType) -> Result<BlockHandle> {
+        let data = if ctype == CompressionType::CompressionNone {
+            block
+        } else {
+            compress_block(ctype, self.opt.compression_level, None, &block)?
+        };
+        self.write_raw_block(data, ctype)
+    }
+
+    /// Calculates the checksum, writes the already compressed block to disk and updates the
+    /// offset.
+    fn write_raw_block(&mut self, data: Vec<u8>, ctype: CompressionType) -> Result<BlockHandle> {
 
         let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
 
@@ -239,23 +451,64 @@ impl<Dst: Write> TableBuilder<Dst> {
                 .find_short_succ(self.data_block.as_ref().unwrap().last_key());
             self.write_data_block(&key_past_last)?;
         }
+        self.flush_dict_buffer()?;
+
+        let mut partitions = None;
+        if self.opt.partition_index_and_filters {
+            partitions = Some(self.write_partitions()?);
+        }
+
+        // Create metaindex block. Like in LevelDB, its keys are ordered bytewise.
+        let mut meta_opt = self.opt.clone();
+        meta_opt.cmp = Rc::new(Box::new(DefaultCmp));
+        let mut meta_ix_block = BlockBuilder::new(meta_opt);
+        let mut handle_enc = [0 as u8; 16];
 
-        // Create metaindex block
-        let mut meta_ix_block = BlockBuilder::new(self.opt.clone());
+        if let Some(dict) = self.dict.take() {
+            // The dictionary is stored uncompressed; its key sorts before the filter key.
+            let dict_handle = self.write_block(dict, CompressionType::CompressionNone)?;
+            let enc_len = dict_handle.encode_to(&mut handle_enc);
+
+            meta_ix_block.add(DICT_META_KEY.as_bytes(), &handle_enc[0..enc_len]);
+        }
 
         if self.filter_block.is_some() {
             // if there's a filter block, write the filter block and add it to the metaindex block.
             let fblock = self.filter_block.take().unwrap();
-            let filter_key = format!("filter.{}", fblock.filter_name());
+            let prefix = if fblock.is_full() {
+                FULL_FILTER_META_PREFIX
+            } else {
+                FILTER_META_PREFIX
+            };
+            let filter_key = format!("{}{}", prefix, fblock.filter_name());
             let fblock_data = fblock.finish();
             let fblock_handle = self.write_block(fblock_data, CompressionType::CompressionNone)?;
-
-            let mut handle_enc = [0 as u8; 16];
             let enc_len = fblock_handle.encode_to(&mut handle_enc);
 
             meta_ix_block.add(filter_key.as_bytes(), &handle_enc[0..enc_len]);
         }
 
+        if let Some((index_handle, filter_handle)) = partitions {
+            if let Some(filter_handle) = filter_handle {
+                let prefix = if self.opt.full_filters {
+                    PARTITIONED_FULL_FILTER_META_PREFIX
+                } else {
+                    PARTITIONED_FILTER_META_PREFIX
+                };
+                let filter_key = format!("{}{}", prefix, self.opt.filter_policy.name());
+                let enc_len = filter_handle.encode_to(&mut handle_enc);
+                meta_ix_block.add(filter_key.as_bytes(), &handle_enc[0..enc_len]);
+            }
+            let enc_len = index_handle.encode_to(&mut handle_enc);
+            meta_ix_block.add(
+                PARTITIONED_INDEX_META_KEY.as_bytes(),
+                &handle_enc[0..enc_len],
+            );
+
+            let meta_ix_handle = self.write_block(meta_ix_block.finish(), ctype)?;
+            return self.write_footer(meta_ix_handle, index_handle);
+        }
+
         // write metaindex block
         let meta_ix = meta_ix_block.finish();
         let meta_ix_handle = self.write_block(meta_ix, ctype)?;
@@ -263,7 +516,14 @@ impl<Dst: Write> TableBuilder<Dst> {
         // write index block
         let index_cont = self.index_block.take().unwrap().finish();
         let ix_handle = self.write_block(index_cont, ctype)?;
+        self.write_footer(meta_ix_handle, ix_handle)
+    }
 
+    fn write_footer(
+        mut self,
+        meta_ix_handle: BlockHandle,
+        ix_handle: BlockHandle,
+    ) -> Result<usize> {
         // write footer.
         let footer = Footer::new(meta_ix_handle, ix_handle);
         let mut buf = [0; FULL_FOOTER_LENGTH];
@@ -7,12 +7,13 @@ use std::prelude::v1::*;
 use cache::{self, Cache};
 use error::{err, Result, StatusCode};
 use key_types::InternalKey;
-use options::Options;
+use options::{CompactionStyle, Options};
 use table_reader::Table;
 use types::FileNum;
 
 use integer_encoding::FixedIntWriter;
 
+use std::collections::HashMap;
 use std::convert::AsRef;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
@@ -22,7 +23,7 @@ pub fn table_file_name<P: AsRef<Path>>(name: P, num: FileNum) -> PathBuf {
     name.as_ref().join(format!("{:06}.ldb", num))
 }
 
-fn filenum_to_key(num: FileNum) -> cache::CacheKey {
+pub fn filenum_to_key(num: FileNum) -> cache::CacheKey {
     let mut buf = [0; 16];
     (&mut buf[..]).write_fixedint(num).unwrap();
     buf
@@ -31,6 +32,12 @@ fn filenum_to_key(num: FileNum) -> cache::CacheKey {
 pub struct TableCache {
     dbname: PathBuf,
     cache: Cache<Table>,
+    // Level-0 tables whose index and filter blocks are pinned; they are kept until they are evicted
+    // or moved out of level 0.
+    pinned: HashMap<FileNum, Table>,
+    // The block cache ids of the tables that have been opened. A table keeps its id until it is
+    // evicted, so that its blocks can be found again after the table is reopened.
+    block_cache_ids: HashMap<FileNum, cache::CacheID>,
     opts: Options,
 }
 
@@ -42,21 +49,39 @@ impl TableCache {
         TableCache {
             dbname: db.as_ref().to_owned(),
             cache: Cache::new(entries),
+            pinned: HashMap::new(),
+            block_cache_ids: HashMap::new(),
             opts: opt,
         }
     }
 
+    /// Looks up `key` in the table `file_num` of `level`. The index and filter blocks of level-0
+    /// tables are pinned if opts.pin_l0_filter_and_index_blocks_in_cache is set and level 0 is
+    /// bounded, i.e. under leveled compaction.
     pub fn get<'a>(
         &mut self,
         file_num: FileNum,
+        level: usize,
         key: InternalKey<'a>,
     ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
-        let tbl = self.get_table(file_num)?;
+        // Pinned tables don't count against the table cache's capacity, and universal and FIFO
+        // compaction keep every table in level 0.
+        let pin = level == 0
+            && self.opts.pin_l0_filter_and_index_blocks_in_cache
+            && self.opts.compaction_style == CompactionStyle::Leveled;
+        let tbl = if pin {
+            self.get_pinned_table(file_num)?
+        } else {
+            self.get_table(file_num)?
+        };
         tbl.get(key)
     }
 
     /// Return a table from cache, or open the backing file, then cache and return it.
     pub fn get_table(&mut self, file_num: FileNum) -> Result<Table> {
+        if let Some(t) = self.pinned.get(&file_num) {
+            return Ok(t.clone());
+        }
         let key = filenum_to_key(file_num);
         if let Some(t) = self.cache.get(&key) {
             return Ok(t.clone());
@@ -64,6 +89,19 @@ impl TableCache {
         self.open_table(file_num)
     }
 
+    /// Returns a table with pinned index and filter blocks, which is kept outside of the table
+    /// cache so that it is not reopened.
+    fn get_pinned_table(&mut self, file_num: FileNum) -> Result<Table> {
+        if let Some(t) = self.pinned.get(&file_num) {
+            return Ok(t.clone());
+        }
+        let mut table = self.get_table(file_num)?;
+        table.pin_meta_blocks()?;
+        self.cache.remove(&filenum_to_key(file_num));
+        self.pinned.insert(file_num, table.clone());
+        Ok(table)
+    }
+
     /// Open a table on the file system and read it.
     fn open_table(&mut self, file_num: FileNum) -> Result<Table> {
         let name = table_file_name(&self.dbname, file_num);
@@ -74,13 +112,26 @@ impl TableCache {
         }
         let file = Rc::new(self.opts.env.open_random_access_file(&path)?);
         // No SSTable file name compatibility.
-        let table = Table::new(self.opts.clone(), file, file_size)?;
+        let block_cache = &self.opts.block_cache;
+        let cache_id = *self
+            .block_cache_ids
+            .entry(file_num)
+            .or_insert_with(|| block_cache.borrow_mut().new_cache_id());
+        let table = Table::new(self.opts.clone(), file, file_size)?.with_cache_id(cache_id);
         self.cache.insert(&filenum_to_key(file_num), table.clone());
         Ok(table)
     }
 
+    /// Releases the pinned index and filter blocks of a table that has left level 0. The table is
+    /// reopened through the table cache when it's accessed again.
+    pub fn unpin(&mut self, file_num: FileNum) {
+        self.pinned.remove(&file_num);
+    }
+
     pub fn evict(&mut self, file_num: FileNum) -> Result<()> {
-        if self.cache.remove(&filenum_to_key(file_num)).is_some() {
+        self.block_cache_ids.remove(&file_num);
+        let pinned = self.pinned.remove(&file_num).is_some();
+        if self.cache.remove(&filenum_to_key(file_num)).is_some() || pinned {
             Ok(())
         } else {
             err(StatusCode::NotFound, "table not present in cache")
@@ -92,6 +143,7 @@ impl TableCache {
 mod tests {
     use super::*;
     use cache;
+    use key_types::LookupKey;
     use mem_env::MemEnv;
     use options;
     use table_builder::TableBuilder;
@@ -165,4 +217,92 @@ mod tests {
         assert!(cache.evict(123).is_err());
         assert!(cache.cache.get(&filenum_to_key(123)).is_none());
     }
+
+    #[test]
+    fn test_table_cache_pin_l0() {
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(MemEnv::new()));
+        opt.cache_index_and_filter_blocks = true;
+        opt.pin_l0_filter_and_index_blocks_in_cache = true;
+        let dbname = Path::new("testdb1");
+        for num in 1..3 {
+            let tablename = table_file_name(dbname, num);
+            let w = opt.env.open_writable_file(Path::new(&tablename)).unwrap();
+            let mut b = TableBuilder::new(opt.clone(), w);
+            b.add(LookupKey::new(b"abc", 1).internal_key(), b"def").unwrap();
+            b.finish().unwrap();
+        }
+
+        let mut cache = TableCache::new(dbname, opt.clone(), 1);
+        let key = LookupKey::new(b"abc", 2);
+        for &(num, level) in [(1, 0), (2, 1), (1, 0)].iter() {
+            let (_, v) = cache.get(num, level, key.internal_key()).unwrap().unwrap();
+            assert_eq!(b"def", v.as_slice());
+        }
+        // The level-0 table is kept with its index and filter blocks outside of the table cache.
+        assert!(cache.pinned.contains_key(&1));
+        assert!(cache.cache.get(&filenum_to_key(1)).is_none());
+        assert!(cache.cache.get(&filenum_to_key(2)).is_some());
+        assert!(opt.block_cache.borrow().pinned_usage() > 0);
+
+        assert!(cache.evict(1).is_ok());
+        assert!(!cache.pinned.contains_key(&1));
+        assert_eq!(opt.block_cache.borrow().pinned_usage(), 0);
+
+        // A table moved out of level 0 is unpinned and read through the table cache again.
+        cache.get(2, 0, key.internal_key()).unwrap().unwrap();
+        assert!(cache.pinned.contains_key(&2));
+        cache.unpin(2);
+        assert!(!cache.pinned.contains_key(&2));
+        assert_eq!(opt.block_cache.borrow().pinned_usage(), 0);
+        cache.get(2, 1, key.internal_key()).unwrap().unwrap();
+        assert!(cache.pinned.is_empty());
+    }
+
+    #[test]
+    fn test_table_cache_pin_l0_leveled_only() {
+        for &style in [CompactionStyle::Universal, CompactionStyle::Fifo].iter() {
+            let mut opt = options::for_test();
+            opt.env = Rc::new(Box::new(MemEnv::new()));
+            opt.compaction_style = style;
+            opt.pin_l0_filter_and_index_blocks_in_cache = true;
+            let dbname = Path::new("testdb1");
+            let tablename = table_file_name(dbname, 1);
+            let w = opt.env.open_writable_file(Path::new(&tablename)).unwrap();
+            let mut b = TableBuilder::new(opt.clone(), w);
+            b.add(LookupKey::new(b"abc", 1).internal_key(), b"def").unwrap();
+            b.finish().unwrap();
+
+            let mut cache = TableCache::new(dbname, opt, 1);
+            let key = LookupKey::new(b"abc", 2);
+            cache.get(1, 0, key.internal_key()).unwrap().unwrap();
+            assert!(cache.pinned.is_empty());
+            assert!(cache.cache.get(&filenum_to_key(1)).is_some());
+        }
+    }
+
+    #[test]
+    fn test_table_cache_block_cache_ids() {
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(MemEnv::new()));
+        let dbname = Path::new("testdb1");
+        let nums = [1, (1 << 32) + 1, 2];
+        for &num in nums.iter() {
+            write_table_to(opt.clone(), Path::new(&table_file_name(dbname, num)));
+        }
+
+        // File numbers beyond 32 bits don't share block cache ids with other tables.
+        let mut cache = TableCache::new(dbname, opt.clone(), 1);
+        let mut ids = vec![];
+        for &num in nums.iter() {
+            cache.get_table(num).unwrap();
+            ids.push(cache.block_cache_ids[&num]);
+        }
+        assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+        // A reopened table keeps its id until it is evicted.
+        cache.get_table(nums[0]).unwrap();
+        assert_eq!(ids[0], cache.block_cache_ids[&nums[0]]);
+        cache.evict(nums[0]).unwrap();
+        assert!(!cache.block_cache_ids.contains_key(&nums[0]));
+    }
 }
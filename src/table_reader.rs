@@ -2,31 +2,48 @@
 use std::prelude::v1::*;
 
 use block::{Block, BlockIter};
+use block_hash_index;
 use blockhandle::BlockHandle;
-use cache;
-use cmp::InternalKeyCmp;
+use cache::{self, Priority};
+use cmp::{DefaultCmp, InternalKeyCmp};
+use compression::DICT_META_KEY;
 use env::RandomAccess;
-use error::Result;
+use error::{err, Result, Status, StatusCode};
 use filter;
 use filter_block::FilterBlockReader;
 use key_types::InternalKey;
 use options::Options;
+use statistics::{self, Ticker};
 use table_block;
-use table_builder::{self, Footer};
+use table_builder::{self, Footer, PARTITIONED_INDEX_META_KEY};
 use types::{current_key_val, LdbIterator};
 
 use std::cmp::Ordering;
 use std::rc::Rc;
 
-use integer_encoding::FixedIntWriter;
+use integer_encoding::{FixedIntWriter, VarInt};
 
 /// Reads the table footer.
 fn read_footer(f: &dyn RandomAccess, size: usize) -> Result<Footer> {
+    if size < table_builder::FULL_FOOTER_LENGTH {
+        return err(StatusCode::Corruption, "file too short to be a table");
+    }
     let mut buf = vec![0; table_builder::FULL_FOOTER_LENGTH];
     f.read_at(size - table_builder::FULL_FOOTER_LENGTH, &mut buf)?;
+    if buf[table_builder::FOOTER_LENGTH..] != table_builder::MAGIC_FOOTER_ENCODED {
+        return err(StatusCode::Corruption, "bad magic number in table footer");
+    }
     Ok(Footer::decode(&buf))
 }
 
+/// MetaBlock is an index or filter block of a table. It is either held by the table, or read
+/// through the block cache (see Options::cache_index_and_filter_blocks).
+#[derive(Clone)]
+enum MetaBlock {
+    Resident(Block),
+    Cached(BlockHandle),
+}
+
 #[derive(Clone)]
 pub struct Table {
     file: Rc<Box<dyn RandomAccess>>,
@@ -36,27 +53,50 @@ pub struct Table {
     opt: Options,
 
     footer: Footer,
-    indexblock: Block,
-    filters: Option<FilterBlockReader>,
+    // Whether the keys are InternalKeys (see new()).
+    internal_keys: bool,
+    // The top-level index if the index is partitioned.
+    indexblock: MetaBlock,
+    partitioned_index: bool,
+    filters: Option<MetaBlock>,
+    // The top-level filter index if the filter is partitioned.
+    filter_index: Option<MetaBlock>,
+    // Whether the filter (or the filter partitions) are full filters.
+    full_filter: bool,
+    dict: Option<Rc<Vec<u8>>>,
 }
 
 impl Table {
     /// Creates a new table reader operating on unformatted keys (i.e., UserKey).
     fn new_raw(opt: Options, file: Rc<Box<dyn RandomAccess>>, size: usize) -> Result<Table> {
         let footer = read_footer(file.as_ref().as_ref(), size)?;
-        let indexblock = table_block::read_table_block(
-            opt.clone(),
-            file.as_ref().as_ref(),
-            &footer.index
-        )?;
         let metaindexblock = table_block::read_table_block(
-            opt.clone(),
+            meta_index_options(&opt),
             file.as_ref().as_ref(),
-            &footer.meta_index
+            &footer.meta_index,
+            None
         )?;
 
-        let filter_block_reader =
-            Table::read_filter_block(&metaindexblock, file.as_ref().as_ref(), &opt)?;
+        let f = file.as_ref().as_ref();
+        let indexblock = Table::read_meta_block(footer.index.clone(), f, &opt)?;
+        let mut full_filter = false;
+        let filters = match Table::find_filter(&metaindexblock, &opt, false) {
+            Some((location, full)) => {
+                full_filter = full;
+                Some(Table::read_meta_block(location, f, &opt)?)
+            }
+            None => None,
+        };
+        let filter_index = match Table::find_filter(&metaindexblock, &opt, true) {
+            Some((location, full)) => {
+                full_filter = full;
+                Some(Table::read_meta_block(location, f, &opt)?)
+            }
+            None => None,
+        };
+        let partitioned_index =
+            find_meta_block(&metaindexblock, PARTITIONED_INDEX_META_KEY).is_some();
+        let dict = Table::read_dict_block(&metaindexblock, file.as_ref().as_ref())?;
         let cache_id = opt.block_cache.borrow_mut().new_cache_id();
 
         Ok(Table {
@@ -65,35 +105,65 @@ impl Table {
             cache_id,
             opt,
             footer,
-            filters: filter_block_reader,
+            internal_keys: false,
+            filters,
+            filter_index,
+            full_filter,
             indexblock,
+            partitioned_index,
+            dict: dict.map(Rc::new),
         })
     }
 
-    fn read_filter_block(
-        metaix: &Block,
+    /// Reads the index or filter block at `location`, unless it is to be read through the block
+    /// cache.
+    fn read_meta_block(
+        location: BlockHandle,
         file: &dyn RandomAccess,
         options: &Options,
-    ) -> Result<Option<FilterBlockReader>> {
-        // Open filter block for reading
-        let filter_name = format!("filter.{}", options.filter_policy.name())
-            .as_bytes()
-            .to_vec();
+    ) -> Result<MetaBlock> {
+        if options.cache_index_and_filter_blocks {
+            return Ok(MetaBlock::Cached(location));
+        }
+        let block = table_block::read_table_block(options.clone(), file, &location, None)?;
+        Ok(MetaBlock::Resident(block))
+    }
 
-        let mut metaindexiter = metaix.iter();
-        metaindexiter.seek(&filter_name);
+    /// Reads the compression dictionary, if the table has one.
+    fn read_dict_block(metaix: &Block, file: &dyn RandomAccess) -> Result<Option<Vec<u8>>> {
+        if let Some(location) = find_meta_block(metaix, DICT_META_KEY) {
+            return Ok(Some(table_block::read_block_contents(file, &location, None)?));
+        }
+        Ok(None)
+    }
 
-        if let Some((_key, val)) = current_key_val(&metaindexiter) {
-            let filter_block_location = BlockHandle::decode(&val).0;
-            if filter_block_location.size() > 0 {
-                return Ok(Some(table_block::read_filter_block(
-                    file,
-                    &filter_block_location,
-                    options.filter_policy.clone(),
-                )?));
+    /// Returns the location of the filter block (or of the top-level filter index if
+    /// `partitioned`), and whether it contains full filters.
+    fn find_filter(
+        metaix: &Block,
+        options: &Options,
+        partitioned: bool,
+    ) -> Option<(BlockHandle, bool)> {
+        let prefixes = if partitioned {
+            [
+                (table_builder::PARTITIONED_FILTER_META_PREFIX, false),
+                (table_builder::PARTITIONED_FULL_FILTER_META_PREFIX, true),
+            ]
+        } else {
+            [
+                (table_builder::FILTER_META_PREFIX, false),
+                (table_builder::FULL_FILTER_META_PREFIX, true),
+            ]
+        };
+        for &(prefix, full) in prefixes.iter() {
+            let key = format!("{}{}", prefix, options.filter_policy.name());
+            if let Some(location) = find_meta_block(metaix, &key) {
+                if location.size() > 0 {
+                    return Some((location, full));
+                }
             }
         }
-        Ok(None)
+        None
     }
 
     /// Creates a new table reader operating on internal keys (i.e., InternalKey). This means that
@@ -101,10 +171,104 @@ impl Table {
     /// (InternalFilterPolicy) are used.
     pub fn new(mut opt: Options, file: Rc<Box<dyn RandomAccess>>, size: usize) -> Result<Table> {
         opt.cmp = Rc::new(Box::new(InternalKeyCmp(opt.cmp.clone())));
-        opt.filter_policy = Rc::new(Box::new(filter::InternalFilterPolicy::new(
-            opt.filter_policy,
-        )));
-        Table::new_raw(opt, file, size)
+        let fpol = filter::InternalFilterPolicy::new(opt.filter_policy);
+        opt.filter_policy = Rc::new(Box::new(fpol.with_timestamp_size(opt.cmp.timestamp_size())));
+        let mut table = Table::new_raw(opt, file, size)?;
+        table.internal_keys = true;
+        Ok(table)
+    }
+
+    /// Sets the id under which the blocks of this table are stored in the block cache. Tables
+    /// opened with the same id share their cached blocks; the id must identify the file.
+    pub fn with_cache_id(mut self, id: cache::CacheID) -> Table {
+        self.cache_id = id;
+        self
+    }
+
+    /// Makes the table hold its index and filter blocks, so that they are neither evicted from
+    /// the block cache nor read again while the table is alive.
+    pub fn pin_meta_blocks(&mut self) -> Result<()> {
+        self.indexblock = MetaBlock::Resident(self.meta_block(&self.indexblock)?);
+        if let Some(ref filters) = self.filters {
+            self.filters = Some(MetaBlock::Resident(self.meta_block(filters)?));
+        }
+        if let Some(ref filter_index) = self.filter_index {
+            self.filter_index = Some(MetaBlock::Resident(self.meta_block(filter_index)?));
+        }
+        Ok(())
+    }
+
+    /// Returns an index or filter block, reading it through the block cache if necessary.
+    fn meta_block(&self, block: &MetaBlock) -> Result<Block> {
+        match *block {
+            MetaBlock::Resident(ref block) => Ok(block.clone()),
+            MetaBlock::Cached(ref location) => {
+                self.read_cached_block(location, None, Priority::High)
+            }
+        }
+    }
+
+    /// verify_checksums reads every block of the table, bypassing the block cache, and returns
+    /// the offset and error of each block that is corrupt.
+    pub fn verify_checksums(&self) -> Vec<(usize, Status)> {
+        let file = self.file.as_ref().as_ref();
+        let mut corrupt = vec![];
+        let mut meta_handles = vec![self.footer.meta_index.clone(), self.footer.index.clone()];
+        let meta_opt = meta_index_options(&self.opt);
+        if let Ok(metaix) =
+            table_block::read_table_block(meta_opt, file, &self.footer.meta_index, None)
+        {
+            let mut iter = metaix.iter();
+            while let Some((_, val)) = iter.next() {
+                meta_handles.push(BlockHandle::decode(&val).0);
+            }
+        }
+        let uncached = |block: &MetaBlock| match *block {
+            MetaBlock::Resident(ref block) => Ok(block.clone()),
+            MetaBlock::Cached(ref location) => {
+                table_block::read_table_block(self.opt.clone(), file, location, None)
+            }
+        };
+        if let Some(Ok(filter_index)) = self.filter_index.as_ref().map(&uncached) {
+            let mut iter = filter_index.iter();
+            while let Some((_, val)) = iter.next() {
+                meta_handles.push(BlockHandle::decode(&val).0);
+            }
+        }
+
+        let mut data_handles = vec![];
+        // A corrupt index is reported with the meta blocks.
+        if let Ok(index) = uncached(&self.indexblock) {
+            let mut iter = index.iter();
+            while let Some((_, val)) = iter.next() {
+                let handle = BlockHandle::decode(&val).0;
+                if !self.partitioned_index {
+                    data_handles.push(handle);
+                    continue;
+                }
+                match table_block::read_table_block(self.opt.clone(), file, &handle, None) {
+                    Ok(partition) => {
+                        let mut iter = partition.iter();
+                        while let Some((_, val)) = iter.next() {
+                            data_handles.push(BlockHandle::decode(&val).0);
+                        }
+                    }
+                    Err(e) => corrupt.push((handle.offset(), e)),
+                }
+            }
+        }
+
+        let dict = self.dict.as_ref().map(|d| d.as_slice());
+        let blocks = meta_handles
+            .into_iter()
+            .map(|h| (h, None))
+            .chain(data_handles.into_iter().map(|h| (h, dict)));
+        for (handle, dict) in blocks {
+            if let Err(e) = table_block::read_block_contents(file, &handle, dict) {
+                corrupt.push((handle.offset(), e));
+            }
+        }
+        corrupt
     }
 
     /// block_cache_handle creates a CacheKey for a block with a given offset to be used in the
@@ -120,33 +284,53 @@ impl Table {
         dst
     }
 
-    /// Read a block from the current table at `location`, and cache it in the options' block
+    /// Read a data block from the current table at `location`, and cache it in the options' block
     /// cache.
     fn read_block(&self, location: &BlockHandle) -> Result<Block> {
+        let dict = self.dict.as_ref().map(|d| d.as_slice());
+        self.read_cached_block(location, dict, Priority::Low)
+    }
+
+    /// Like read_block(), but for blocks compressed with the given dictionary (or none), which
+    /// are cached with priority `prio`.
+    fn read_cached_block(
+        &self,
+        location: &BlockHandle,
+        dict: Option<&[u8]>,
+        prio: Priority,
+    ) -> Result<Block> {
         let cachekey = self.block_cache_handle(location.offset());
         if let Some(block) = self.opt.block_cache.borrow_mut().get(&cachekey) {
+            statistics::record_tick(&self.opt.statistics, Ticker::BlockCacheHit, 1);
             return Ok(block.clone());
         }
+        statistics::record_tick(&self.opt.statistics, Ticker::BlockCacheMiss, 1);
+        statistics::record_tick(
+            &self.opt.statistics,
+            Ticker::BlockReadBytes,
+            location.size() as u64,
+        );
 
         // Two times as_ref(): First time to get a ref from Rc<>, then one from Box<>.
         let b = table_block::read_table_block(
             self.opt.clone(),
             self.file.as_ref().as_ref(),
-            location
+            location,
+            dict
         )?;
 
-        // insert a cheap copy (Rc).
+        // insert a cheap copy (Rc), charged with the size of the uncompressed block.
         self.opt
             .block_cache
             .borrow_mut()
-            .insert(&cachekey, b.clone());
+            .insert_with_priority(&cachekey, b.clone(), b.size(), prio);
 
         Ok(b)
     }
 
     /// Returns the offset of the block that contains `key`.
     pub fn approx_offset_of(&self, key: &[u8]) -> usize {
-        let mut iter = self.indexblock.iter();
+        let mut iter = self.index_iter();
 
         iter.seek(key);
 
@@ -163,12 +347,66 @@ impl Table {
         let iter = TableIterator {
             current_block: None,
             current_block_off: 0,
-            index_block: self.indexblock.iter(),
+            index_block: self.index_iter(),
             table: self.clone(),
+            err: None,
         };
         iter
     }
 
+    /// Returns an iterator over the index entries, i.e. the handles of the data blocks.
+    fn index_iter(&self) -> IndexIter {
+        let (top, err) = match self.meta_block(&self.indexblock) {
+            Ok(index) => (Some(index.iter()), None),
+            Err(e) => (None, Some(e)),
+        };
+        IndexIter {
+            table: self.clone(),
+            top,
+            partition: None,
+            err,
+        }
+    }
+
+    /// Returns a reader for the filter in `block`.
+    fn filter_reader(&self, block: &Block) -> FilterBlockReader {
+        let policy = self.opt.filter_policy.clone();
+        let reader = if self.full_filter {
+            FilterBlockReader::new_full(policy, block.contents())
+        } else {
+            FilterBlockReader::new(policy, block.contents())
+        };
+        reader.with_statistics(self.opt.statistics.clone())
+    }
+
+    /// Checks the filter for the data block at `blk_offset`, which is the block that may contain
+    /// `key`; full filters ignore `blk_offset`. If the filter can't be read, the data block has
+    /// to be read anyway.
+    fn key_may_match(&self, key: &[u8], blk_offset: usize) -> bool {
+        if let Some(ref filters) = self.filters {
+            if let Ok(filters) = self.meta_block(filters) {
+                return self.filter_reader(&filters).key_may_match(blk_offset, key);
+            }
+        }
+        if let Some(ref filter_index) = self.filter_index {
+            let filter_index = match self.meta_block(filter_index) {
+                Ok(filter_index) => filter_index,
+                Err(_) => return true,
+            };
+            let mut iter = filter_index.iter();
+            iter.seek(key);
+            if let Some((_, val)) = current_key_val(&iter) {
+                let (location, n) = BlockHandle::decode(&val);
+                let (base, _) = usize::decode_var(&val[n..]);
+                if let Ok(partition) = self.read_cached_block(&location, None, Priority::High) {
+                    let offset = blk_offset.saturating_sub(base);
+                    return self.filter_reader(&partition).key_may_match(offset, key);
+                }
+            }
+        }
+        true
+    }
+
     /// Retrieve next-biggest entry for key from table. This function uses the attached filters, so
     /// is better suited if you frequently look for non-existing values (as it will detect the
     /// non-existence of an entry in a block without having to load the block).
@@ -181,8 +419,13 @@ impl Table {
     /// exact match; it depends on other comparators than the one that the table reader knows
     /// whether a match is acceptable.
     pub fn get<'a>(&self, key: InternalKey<'a>) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
-        let mut index_iter = self.indexblock.iter();
+        // A full filter doesn't depend on the data block, and is checked before the index.
+        if self.full_filter && !self.key_may_match(key, 0) {
+            return Ok(None);
+        }
+        let mut index_iter = self.index_iter();
         index_iter.seek(key);
+        index_iter.status()?;
 
         let handle;
         if let Some((last_in_block, h)) = current_key_val(&index_iter) {
@@ -198,10 +441,8 @@ impl Table {
         // found correct block.
 
         // Check bloom (or whatever) filter
-        if let Some(ref filters) = self.filters {
-            if !filters.key_may_match(handle.offset(), key) {
-                return Ok(None);
-            }
+        if !self.full_filter && !self.key_may_match(key, handle.offset()) {
+            return Ok(None);
         }
 
         // Read block (potentially from cache)
@@ -209,7 +450,9 @@ impl Table {
         let mut iter = tb.iter();
 
         // Go to entry and check if it's the wanted entry.
-        iter.seek(key);
+        let suffix_len =
+            block_hash_index::key_suffix_len(self.internal_keys, self.opt.cmp.timestamp_size());
+        iter.seek_for_get(key, block_hash_index::user_key(key, suffix_len));
         if let Some((k, v)) = current_key_val(&iter) {
             if self.opt.cmp.cmp(&k, key) >= Ordering::Equal {
                 return Ok(Some((k, v)));
@@ -219,6 +462,161 @@ impl Table {
     }
 }
 
+/// Returns the options to read a metaindex block with; its keys are ordered bytewise.
+fn meta_index_options(opt: &Options) -> Options {
+    let mut meta_opt = opt.clone();
+    meta_opt.cmp = Rc::new(Box::new(DefaultCmp));
+    meta_opt
+}
+
+/// Returns the location of the meta block stored under `key` in the metaindex block.
+fn find_meta_block(metaix: &Block, key: &str) -> Option<BlockHandle> {
+    let mut iter = metaix.iter();
+    iter.seek(key.as_bytes());
+    match current_key_val(&iter) {
+        Some((k, val)) if k == key.as_bytes() => Some(BlockHandle::decode(&val).0),
+        _ => None,
+    }
+}
+
+/// IndexIter iterates over the index of a table, i.e. over the handles of its data blocks. If the
+/// index is partitioned, it iterates over the top-level index and loads the partitions through
+/// the block cache as needed.
+pub struct IndexIter {
+    table: Table,
+    // None if the index couldn't be read.
+    top: Option<BlockIter>,
+    partition: Option<BlockIter>,
+    // The first error encountered while loading the index or a partition; the affected
+    // partitions are skipped.
+    err: Option<Status>,
+}
+
+impl IndexIter {
+    // Loads the partition referenced by the current entry of the top-level index.
+    fn load_partition(&mut self) -> bool {
+        self.partition = None;
+        if let Some((_, val)) = self.top.as_ref().and_then(current_key_val) {
+            let location = BlockHandle::decode(&val).0;
+            match self.table.read_cached_block(&location, None, Priority::High) {
+                Ok(partition) => {
+                    self.partition = Some(partition.iter());
+                    return true;
+                }
+                Err(e) => {
+                    if self.err.is_none() {
+                        self.err = Some(e);
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    fn advance_top(&mut self) -> bool {
+        self.top.as_mut().map(|top| top.advance()).unwrap_or(false)
+    }
+
+    fn prev_top(&mut self) -> bool {
+        self.top.as_mut().map(|top| top.prev()).unwrap_or(false)
+    }
+}
+
+impl LdbIterator for IndexIter {
+    fn advance(&mut self) -> bool {
+        if !self.table.partitioned_index {
+            return self.advance_top();
+        }
+        loop {
+            if let Some(ref mut partition) = self.partition {
+                if partition.advance() {
+                    return true;
+                }
+            }
+            if !self.advance_top() {
+                self.reset();
+                return false;
+            }
+            self.load_partition();
+        }
+    }
+
+    fn seek(&mut self, to: &[u8]) {
+        if let Some(ref mut top) = self.top {
+            top.seek(to);
+        }
+        if self.table.partitioned_index && self.load_partition() {
+            self.partition.as_mut().unwrap().seek(to);
+        }
+    }
+
+    fn prev(&mut self) -> bool {
+        if !self.table.partitioned_index {
+            return self.prev_top();
+        }
+        if let Some(ref mut partition) = self.partition {
+            if partition.prev() {
+                return true;
+            }
+        }
+        loop {
+            if !self.prev_top() {
+                self.reset();
+                return false;
+            }
+            if self.load_partition() {
+                let partition = self.partition.as_mut().unwrap();
+                partition.seek_to_last();
+                return partition.valid();
+            }
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        if let Some(ref mut top) = self.top {
+            top.seek_to_last();
+        }
+        if self.table.partitioned_index && self.load_partition() {
+            self.partition.as_mut().unwrap().seek_to_last();
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(ref mut top) = self.top {
+            top.reset();
+        }
+        self.partition = None;
+    }
+
+    fn status(&self) -> Result<()> {
+        match self.err {
+            Some(ref e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn valid(&self) -> bool {
+        let iter = if self.table.partitioned_index {
+            &self.partition
+        } else {
+            &self.top
+        };
+        iter.as_ref().map(|it| it.valid()).unwrap_or(false)
+    }
+
+    fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
+        let iter = if self.table.partitioned_index {
+            &self.partition
+        } else {
+            &self.top
+        };
+        match *iter {
+            Some(ref it) => it.current(key, val),
+            None => false,
+        }
+    }
+}
+
 /// This iterator is a "TwoLevelIterator"; it uses an index block in order to get an offset hint
 /// into the data blocks.
 pub struct TableIterator {
@@ -231,7 +629,9 @@ pub struct TableIterator {
     table: Table,
     current_block: Option<BlockIter>,
     current_block_off: usize,
-    index_block: BlockIter,
+    index_block: IndexIter,
+    // The first error encountered while loading a block; the affected blocks are skipped.
+    err: Option<Status>,
 }
 
 impl TableIterator {
@@ -250,7 +650,15 @@ impl TableIterator {
     // Load the block at `handle` into `self.current_block`
     fn load_block(&mut self, handle: &[u8]) -> Result<()> {
         let (new_block_handle, _) = BlockHandle::decode(handle);
-        let block = self.table.read_block(&new_block_handle)?;
+        let block = match self.table.read_block(&new_block_handle) {
+            Ok(b) => b,
+            Err(e) => {
+                if self.err.is_none() {
+                    self.err = Some(e.clone());
+                }
+                return Err(e);
+            }
+        };
 
         self.current_block = Some(block.iter());
         self.current_block_off = new_block_handle.offset();
@@ -342,11 +750,29 @@ impl LdbIterator for TableIterator {
         }
     }
 
+    fn seek_to_last(&mut self) {
+        self.index_block.seek_to_last();
+        if let Some((_, handle)) = current_key_val(&self.index_block) {
+            if self.load_block(&handle).is_ok() {
+                self.current_block.as_mut().unwrap().seek_to_last();
+                return;
+            }
+        }
+        self.reset();
+    }
+
     fn reset(&mut self) {
         self.index_block.reset();
         self.current_block = None;
     }
 
+    fn status(&self) -> Result<()> {
+        match self.err {
+            Some(ref e) => Err(e.clone()),
+            None => self.index_block.status(),
+        }
+    }
+
     // This iterator is special in that it's valid even before the first call to advance(). It
     // behaves correctly, though.
     fn valid(&self) -> bool {
@@ -364,8 +790,10 @@ impl LdbIterator for TableIterator {
 
 #[cfg(test)]
 mod tests {
-    use filter::BloomPolicy;
+    use filter::{BlockedBloomPolicy, BloomPolicy, RibbonPolicy};
     use key_types::LookupKey;
+    use cache::CachePolicy;
+    use compression;
     use options::{self, CompressionType};
     use table_builder::TableBuilder;
     use test_util::{test_iterator_properties, LdbIteratorIter};
@@ -449,6 +877,11 @@ mod tests {
         Rc::new(Box::new(src))
     }
 
+    fn full_filter(table: &Table) -> FilterBlockReader {
+        let filters = table.meta_block(table.filters.as_ref().unwrap()).unwrap();
+        table.filter_reader(&filters)
+    }
+
     #[test]
     fn test_table_approximate_offset() {
         let (src, size) = build_table(build_data());
@@ -468,6 +901,225 @@ mod tests {
         assert_eq!(137, table.approx_offset_of("{aa".as_bytes()));
     }
 
+    #[test]
+    fn test_table_compression() {
+        let types = [
+            CompressionType::CompressionNone,
+            CompressionType::CompressionSnappy,
+            CompressionType::CompressionZstd,
+            CompressionType::CompressionLZ4,
+        ];
+        let mut sizes = vec![];
+        for &ctype in types.iter().filter(|&&t| compression::is_supported(t)) {
+            let mut d = Vec::with_capacity(4096);
+            let mut opt = options::for_test();
+            opt.compression_type = ctype;
+            opt.compression_level = 3;
+            {
+                let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+                for i in 0..100 {
+                    b.add(format!("key{:03}", i).as_bytes(), &[b'v'; 100]).unwrap();
+                }
+                b.finish().unwrap();
+            }
+            sizes.push(d.len());
+
+            let size = d.len();
+            let table = Table::new_raw(opt, wrap_buffer(d), size).unwrap();
+            let mut iter = table.iter();
+            let mut i = 0;
+            for (k, v) in LdbIteratorIter::wrap(&mut iter) {
+                assert_eq!(format!("key{:03}", i).as_bytes(), k.as_slice());
+                assert_eq!(vec![b'v'; 100], v);
+                i += 1;
+            }
+            assert_eq!(100, i);
+        }
+        for s in &sizes[1..] {
+            assert!(*s < sizes[0] / 4);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_table_compression_dictionary() {
+        // Small JSON values that have little in common but their structure.
+        let value = |i: u64| {
+            let h = i.wrapping_mul(0x9e3779b97f4a7c15);
+            format!(
+                "{{\"user_id\":{},\"email\":\"u{:x}@example.com\",\"score\":{}}}",
+                h % 1000003,
+                h >> 40,
+                (h >> 20) % 997
+            )
+        };
+        let build = |max_dict_bytes: usize| {
+            let mut d = Vec::with_capacity(65536);
+            let mut opt = options::for_test();
+            opt.block_size = 1024;
+            opt.compression_type = CompressionType::CompressionZstd;
+            opt.compression_max_dict_bytes = max_dict_bytes;
+            opt.compression_dict_buffer_bytes = 65536;
+            {
+                let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+                for i in 0..2000 {
+                    b.add(format!("key{:04}", i).as_bytes(), value(i).as_bytes())
+                        .unwrap();
+                }
+                b.finish().unwrap();
+            }
+            let size = d.len();
+            (Table::new_raw(opt, wrap_buffer(d), size).unwrap(), size)
+        };
+
+        let (plain, plain_size) = build(0);
+        let (table, size) = build(2048);
+        assert!(plain.dict.is_none());
+        assert!(table.dict.is_some());
+        assert!(size < plain_size * 3 / 4);
+
+        let mut iter = table.iter();
+        let mut i = 0;
+        for (k, v) in LdbIteratorIter::wrap(&mut iter) {
+            assert_eq!(format!("key{:04}", i).as_bytes(), k.as_slice());
+            assert_eq!(value(i).as_bytes(), v.as_slice());
+            i += 1;
+        }
+        assert_eq!(2000, i);
+        assert!(iter.status().is_ok());
+        // Blocks that were held back and those written after training are found by the filter.
+        for i in &[0, 1999] {
+            let key = format!("key{:04}", i);
+            assert!(table.get(key.as_bytes()).unwrap().is_some());
+        }
+        assert!(table.get(b"key00001").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_table_partitioned_index_and_filter() {
+        let mut opt = options::for_test();
+        opt.block_size = 64;
+        opt.partition_index_and_filters = true;
+        opt.metadata_block_size = 128;
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{:04}", i * 2)).collect();
+
+        let mut d = vec![];
+        {
+            let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+            for k in keys.iter() {
+                b.add(k.as_bytes(), b"value").unwrap();
+            }
+            b.finish().unwrap();
+        }
+        let size = d.len();
+
+        let table = Table::new_raw(opt.clone(), wrap_buffer(d), size).unwrap();
+        assert!(table.partitioned_index);
+        assert!(table.filters.is_none());
+        assert!(table.filter_index.is_some());
+        // Only the top-level blocks are resident.
+        let index = table.meta_block(&table.indexblock).unwrap();
+        let partitions = LdbIteratorIter::wrap(&mut index.iter()).count();
+        assert!(partitions > 10);
+        let filter_index = table.meta_block(table.filter_index.as_ref().unwrap()).unwrap();
+        assert_eq!(partitions, LdbIteratorIter::wrap(&mut filter_index.iter()).count());
+        assert_eq!(opt.block_cache.borrow().count(), 0);
+        assert!(table.verify_checksums().is_empty());
+
+        let mut iter = table.iter();
+        let found: Vec<Vec<u8>> = LdbIteratorIter::wrap(&mut iter).map(|(k, _)| k).collect();
+        assert_eq!(keys.len(), found.len());
+        assert!(keys.iter().zip(found.iter()).all(|(a, b)| a.as_bytes() == b.as_slice()));
+
+        iter.seek_to_last();
+        let mut n = 1;
+        while iter.prev() {
+            n += 1;
+        }
+        assert_eq!(keys.len(), n);
+
+        iter.seek(b"key0999");
+        assert_eq!(b"key1000", current_key_val(&iter).unwrap().0.as_slice());
+        iter.seek(b"key1997");
+        assert_eq!(b"key1998", current_key_val(&iter).unwrap().0.as_slice());
+        assert!(iter.next().is_none());
+
+        for k in keys.iter() {
+            assert!(table.get(k.as_bytes()).unwrap().is_some());
+        }
+        // Keys missing from the table are (almost always) rejected by the filter partitions.
+        let misses = (0..1000)
+            .filter(|i| {
+                let key = format!("key{:04}", i * 2 + 1);
+                let mut ix = table.index_iter();
+                ix.seek(key.as_bytes());
+                let handle = BlockHandle::decode(&current_key_val(&ix).unwrap().1).0;
+                table.key_may_match(key.as_bytes(), handle.offset())
+            })
+            .count();
+        assert!(misses < 50);
+        assert!(table.get(b"key0001").unwrap().is_none());
+        assert!(table.get(b"zzz").unwrap().is_none());
+        assert!(table.approx_offset_of(b"key1000") > table.approx_offset_of(b"key0100"));
+    }
+
+    #[test]
+    fn test_table_full_filters() {
+        let policies: Vec<filter::BoxedFilterPolicy> = vec![
+            Rc::new(Box::new(BloomPolicy::new(10))),
+            Rc::new(Box::new(BlockedBloomPolicy::new(10))),
+            Rc::new(Box::new(RibbonPolicy::new(10))),
+        ];
+        for policy in policies.into_iter() {
+            for &partitioned in [false, true].iter() {
+                let mut opt = options::for_test();
+                opt.filter_policy = policy.clone();
+                opt.full_filters = true;
+                opt.partition_index_and_filters = partitioned;
+                opt.cache_index_and_filter_blocks = true;
+                opt.block_size = 64;
+                opt.metadata_block_size = 128;
+                let keys: Vec<String> = (0..500).map(|i| format!("key{:04}", i * 2)).collect();
+
+                let mut d = vec![];
+                {
+                    let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+                    for k in keys.iter() {
+                        b.add(k.as_bytes(), b"value").unwrap();
+                    }
+                    b.finish().unwrap();
+                }
+                let size = d.len();
+                let table = Table::new_raw(opt.clone(), wrap_buffer(d), size).unwrap();
+                assert!(table.full_filter);
+                assert_eq!(partitioned, table.filter_index.is_some());
+                assert!(table.verify_checksums().is_empty());
+
+                // Missing keys are rejected before the index is read.
+                let missing: Vec<String> =
+                    (0..500).map(|i| format!("key{:04}", i * 2 + 1)).collect();
+                let rejected = missing
+                    .iter()
+                    .find(|k| !table.key_may_match(k.as_bytes(), 0))
+                    .unwrap();
+                assert!(table.get(rejected.as_bytes()).unwrap().is_none());
+                let index_key = table.block_cache_handle(table.footer.index.offset());
+                assert!(opt.block_cache.borrow_mut().get(&index_key).is_none());
+                // get() returns the next key for false positives.
+                let false_positives = missing
+                    .iter()
+                    .filter(|k| table.get(k.as_bytes()).unwrap().is_some())
+                    .count();
+                assert!(false_positives < 25, "{}", false_positives);
+
+                for k in keys.iter() {
+                    assert_eq!(b"value", table.get(k.as_bytes()).unwrap().unwrap().1.as_slice());
+                }
+                assert_eq!(keys.len(), LdbIteratorIter::wrap(&mut table.iter()).count());
+            }
+        }
+    }
+
     #[test]
     fn test_table_block_cache_use() {
         let (src, size) = build_table(build_data());
@@ -487,6 +1139,82 @@ mod tests {
         iter.next();
         iter.next();
         assert_eq!(opt.block_cache.borrow().count(), 2);
+
+        // The blocks are charged with their size, and the current one is pinned by the iterator.
+        let cache = opt.block_cache.borrow();
+        assert!(cache.usage() > 2 * 32);
+        assert!(cache.pinned_usage() > 0 && cache.pinned_usage() < cache.usage());
+        drop(cache);
+        drop(iter);
+        assert_eq!(opt.block_cache.borrow().pinned_usage(), 0);
+    }
+
+    #[test]
+    fn test_table_block_cache_policies() {
+        let policies = [
+            CachePolicy::LRU {
+                high_pri_pool_ratio: 0.,
+            },
+            CachePolicy::LRU {
+                high_pri_pool_ratio: 0.5,
+            },
+            CachePolicy::Clock,
+        ];
+        for &policy in policies.iter() {
+            let (src, size) = build_table(build_data());
+            let mut opt = options::for_test();
+            opt.set_block_cache(1 << 20, policy);
+            let table = Table::new_raw(opt.clone(), wrap_buffer(src), size).unwrap();
+
+            for _ in 0..2 {
+                for (k, v) in build_data() {
+                    let r = table.get(k.as_bytes()).unwrap();
+                    assert_eq!(Some((k.as_bytes().to_vec(), v.as_bytes().to_vec())), r);
+                }
+            }
+            let cache = opt.block_cache.borrow();
+            assert_eq!(cache.count(), 3);
+            assert!(cache.usage() > 0);
+            // Only the first lookup of each block misses.
+            assert_eq!(cache.hit_ratio(), 11. / 14.);
+        }
+    }
+
+    #[test]
+    fn test_table_cache_index_and_filter_blocks() {
+        let (src, size) = build_table(build_data());
+        let mut opt = options::for_test();
+        opt.cache_index_and_filter_blocks = true;
+        let src = wrap_buffer(src);
+
+        let table = Table::new_raw(opt.clone(), src.clone(), size).unwrap();
+        assert!(table.filters.is_some());
+        assert_eq!(opt.block_cache.borrow().count(), 0);
+        let (k, v) = build_data()[0];
+        let expected = Some((k.as_bytes().to_vec(), v.as_bytes().to_vec()));
+        assert_eq!(expected, table.get(k.as_bytes()).unwrap());
+        // The index, the filter and one data block.
+        assert_eq!(opt.block_cache.borrow().count(), 3);
+        assert_eq!(opt.block_cache.borrow().pinned_usage(), 0);
+
+        // A table reopened with the same cache id finds all blocks in the cache.
+        let mut table = Table::new_raw(opt.clone(), src, size)
+            .unwrap()
+            .with_cache_id(table.cache_id);
+        assert_eq!(expected, table.get(k.as_bytes()).unwrap());
+        assert_eq!(opt.block_cache.borrow().hit_ratio(), 0.5);
+
+        table.pin_meta_blocks().unwrap();
+        assert!(opt.block_cache.borrow().pinned_usage() > 0);
+        // Pinned blocks are not read again after they are evicted from the block cache.
+        let index_key = table.block_cache_handle(table.footer.index.offset());
+        assert!(opt.block_cache.borrow_mut().remove(&index_key).is_some());
+        for (k, _) in build_data() {
+            assert!(table.get(k.as_bytes()).unwrap().is_some());
+        }
+        assert!(opt.block_cache.borrow_mut().get(&index_key).is_none());
+        drop(table);
+        assert_eq!(opt.block_cache.borrow().pinned_usage(), 0);
     }
 
     #[test]
@@ -546,7 +1274,7 @@ mod tests {
 
         let table = Table::new_raw(options::for_test(), wrap_buffer(src), size).unwrap();
         assert!(table.filters.is_some());
-        let filter_reader = table.filters.clone().unwrap();
+        let filter_reader = full_filter(&table);
         let mut iter = table.iter();
 
         loop {
@@ -699,7 +1427,7 @@ mod tests {
         let (src, size) = build_internal_table();
 
         let table = Table::new(options::for_test(), wrap_buffer(src), size).unwrap();
-        let filter_reader = table.filters.clone().unwrap();
+        let filter_reader = full_filter(&table);
 
         // Check that we're actually using internal keys
         let mut _iter = table.iter();
@@ -728,6 +1456,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_table_reader_bad_footer() {
+        let (src, size) = build_table(build_data());
+        let table = Table::new_raw(options::for_test(), wrap_buffer(src.clone()), size).unwrap();
+        assert!(table.verify_checksums().is_empty());
+
+        let mut bad = src.clone();
+        bad[size - 1] += 1;
+        let r = Table::new_raw(options::for_test(), wrap_buffer(bad), size);
+        assert_eq!(StatusCode::Corruption, r.err().unwrap().code);
+        let r = Table::new_raw(options::for_test(), wrap_buffer(src), 20);
+        assert_eq!(StatusCode::Corruption, r.err().unwrap().code);
+    }
+
     #[test]
     fn test_table_reader_checksum() {
         let (mut src, size) = build_table(build_data());
@@ -737,7 +1479,12 @@ mod tests {
         let table = Table::new_raw(options::for_test(), wrap_buffer(src), size).unwrap();
 
         assert!(table.filters.is_some());
-        assert_eq!(table.filters.as_ref().unwrap().num(), 1);
+        assert_eq!(full_filter(&table).num(), 1);
+
+        let corrupt = table.verify_checksums();
+        assert_eq!(1, corrupt.len());
+        assert_eq!(0, corrupt[0].0);
+        assert_eq!(StatusCode::Corruption, corrupt[0].1.code);
 
         {
             let mut _iter = table.iter();
@@ -117,6 +117,12 @@ pub fn test_iterator_properties<It: LdbIterator>(mut it: It) {
     it.prev();
     assert_eq!(first, current_key_val(&it));
 
+    it.seek_to_last();
+    assert!(it.valid());
+    assert_eq!(fourth, current_key_val(&it));
+    assert!(it.prev());
+    assert_eq!(third, current_key_val(&it));
+
     it.reset();
     assert!(!it.valid());
     assert!(it.advance());
@@ -5,9 +5,11 @@ use std::prelude::v1::*;
 use error::{err, Result, StatusCode};
 
 use std::cell::RefCell;
+use std::mem;
 use std::path::Path;
 use std::rc::Rc;
 
+/// The default number of levels; see `Options::num_levels`.
 pub const NUM_LEVELS: usize = 7;
 
 /// Represents a sequence number of a single entry.
@@ -82,6 +84,30 @@ pub trait LdbIterator {
         self.reset();
         self.advance();
     }
+
+    /// seek_to_last seeks to the last element. The default implementation scans the whole
+    /// iterator and should be overridden by iterators that can do better.
+    fn seek_to_last(&mut self) {
+        let (mut key, mut last, mut val) = (vec![], vec![], vec![]);
+        self.reset();
+        while self.advance() {
+            if self.current(&mut key, &mut val) {
+                mem::swap(&mut key, &mut last);
+            }
+        }
+        if last.is_empty() {
+            self.reset();
+        } else {
+            self.seek(&last);
+        }
+    }
+
+    /// status returns the first error encountered by the iterator, e.g. while reading a table
+    /// file. An iterator that has hit an error may either become invalid or skip the affected
+    /// entries.
+    fn status(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// current_key_val is a helper allocating two vectors and filling them with the current key/value
@@ -114,6 +140,12 @@ impl LdbIterator for Box<dyn LdbIterator> {
     fn prev(&mut self) -> bool {
         self.as_mut().prev()
     }
+    fn seek_to_last(&mut self) {
+        self.as_mut().seek_to_last()
+    }
+    fn status(&self) -> Result<()> {
+        self.as_ref().status()
+    }
 }
 
 /// The unique (sequential) number of a file.
@@ -129,6 +161,11 @@ pub struct FileMetaData {
     // these are in InternalKey format:
     pub smallest: Vec<u8>,
     pub largest: Vec<u8>,
+    // Microseconds since the epoch at which the file's data was flushed; 0 if unknown. Only
+    // recorded for FIFO compaction.
+    pub creation_time: u64,
+    // The blob files this table refers to, in ascending order.
+    pub blob_files: Vec<FileNum>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -140,6 +177,7 @@ pub enum FileType {
     Current,
     Temp,
     InfoLog,
+    Blob,
 }
 
 pub fn parse_file_name<P: AsRef<Path>>(ff: P) -> Result<(FileNum, FileType)> {
@@ -168,6 +206,7 @@ pub fn parse_file_name<P: AsRef<Path>>(ff: P) -> Result<(FileNum, FileType)> {
                 "log" => FileType::Log,
                 "sst" | "ldb" => FileType::Table,
                 "dbtmp" => FileType::Temp,
+                "blob" => FileType::Blob,
                 _ => {
                     return err(
                         StatusCode::InvalidArgument,
@@ -200,6 +239,7 @@ mod tests {
             ("001122.sst", (1122, FileType::Table)),
             ("001122.ldb", (1122, FileType::Table)),
             ("001122.dbtmp", (1122, FileType::Temp)),
+            ("001122.blob", (1122, FileType::Blob)),
         ] {
             assert_eq!(parse_file_name(c.0).unwrap(), c.1);
         }
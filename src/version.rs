@@ -2,14 +2,13 @@
 use std::prelude::v1::*;
 
 use cmp::{Cmp, InternalKeyCmp};
-use error::Result;
+use error::{Result, Status};
 use key_types::{parse_internal_key, InternalKey, LookupKey, UserKey, ValueType};
 use table_cache::TableCache;
 use table_reader::TableIterator;
-use types::{FileMetaData, FileNum, LdbIterator, Shared, MAX_SEQUENCE_NUMBER, NUM_LEVELS};
+use types::{FileMetaData, FileNum, LdbIterator, Shared, MAX_SEQUENCE_NUMBER};
 
 use std::cmp::Ordering;
-use std::default::Default;
 use std::rc::Rc;
 
 /// FileMetaHandle is a reference-counted FileMetaData object with interior mutability. This is
@@ -26,7 +25,8 @@ pub struct GetStats {
 pub struct Version {
     table_cache: Shared<TableCache>,
     user_cmp: Rc<Box<dyn Cmp>>,
-    pub files: [Vec<FileMetaHandle>; NUM_LEVELS],
+    // One list of files per level.
+    pub files: Vec<Vec<FileMetaHandle>>,
 
     pub file_to_compact: Option<FileMetaHandle>,
     pub file_to_compact_lvl: usize,
@@ -35,11 +35,11 @@ pub struct Version {
 }
 
 impl Version {
-    pub fn new(cache: Shared<TableCache>, ucmp: Rc<Box<dyn Cmp>>) -> Version {
+    pub fn new(cache: Shared<TableCache>, ucmp: Rc<Box<dyn Cmp>>, num_levels: usize) -> Version {
         Version {
             table_cache: cache,
             user_cmp: ucmp,
-            files: Default::default(),
+            files: vec![vec![]; num_levels],
             file_to_compact: None,
             file_to_compact_lvl: 0,
             compaction_score: None,
@@ -47,20 +47,27 @@ impl Version {
         }
     }
 
+    pub fn num_levels(&self) -> usize {
+        self.files.len()
+    }
+
     pub fn num_level_bytes(&self, l: usize) -> usize {
-        assert!(l < NUM_LEVELS);
+        assert!(l < self.num_levels());
         total_size(self.files[l].iter())
     }
 
     pub fn num_level_files(&self, l: usize) -> usize {
-        assert!(l < NUM_LEVELS);
+        assert!(l < self.num_levels());
         self.files[l].len()
     }
 
     /// get returns the value for the specified key using the persistent tables contained in this
-    /// Version.
+    /// Version. The returned type is TypeValue, or TypeBlobIndex if the value is a BlobIndex.
     #[allow(unused_assignments)]
-    pub fn get<'a>(&self, key: InternalKey<'a>) -> Result<Option<(Vec<u8>, GetStats)>> {
+    pub fn get<'a>(
+        &self,
+        key: InternalKey<'a>,
+    ) -> Result<Option<(Vec<u8>, ValueType, GetStats)>> {
         let levels = self.get_overlapping(key);
         let ikey = key;
         let ukey = parse_internal_key(ikey).2;
@@ -85,17 +92,18 @@ impl Version {
                 // We receive both key and value from the table. Because we're using InternalKey
                 // keys, we now need to check whether the found entry's user key is equal to the
                 // one we're looking for (get() just returns the next-bigger key).
-                if let Ok(Some((k, v))) = self.table_cache.borrow_mut().get(f.borrow().num, ikey) {
+                let found = self.table_cache.borrow_mut().get(f.borrow().num, level, ikey);
+                if let Ok(Some((k, v))) = found {
                     // We don't need to check the sequence number; get() will not return an entry
                     // with a higher sequence number than the one in the supplied key.
+                    // With user timestamps, the found entry may be an older version of ukey.
                     let (typ, _, foundkey) = parse_internal_key(&k);
-                    if typ == ValueType::TypeValue
-                        && self.user_cmp.cmp(foundkey, ukey) == Ordering::Equal
-                    {
-                        return Ok(Some((v, stats)));
-                    } else if typ == ValueType::TypeDeletion {
-                        // Skip looking once we have found a deletion.
-                        return Ok(None);
+                    if self.user_cmp.cmp_without_ts(foundkey, ukey) == Ordering::Equal {
+                        if typ == ValueType::TypeDeletion {
+                            // Skip looking once we have found a deletion.
+                            return Ok(None);
+                        }
+                        return Ok(Some((v, typ, stats)));
                     }
                 }
             }
@@ -104,8 +112,8 @@ impl Version {
     }
 
     /// get_overlapping returns the files overlapping key in each level.
-    fn get_overlapping<'a>(&self, key: InternalKey<'a>) -> [Vec<FileMetaHandle>; NUM_LEVELS] {
-        let mut levels: [Vec<FileMetaHandle>; NUM_LEVELS] = Default::default();
+    fn get_overlapping<'a>(&self, key: InternalKey<'a>) -> Vec<Vec<FileMetaHandle>> {
+        let mut levels = vec![vec![]; self.num_levels()];
         let ikey = key;
         let ukey = parse_internal_key(key).2;
 
@@ -117,8 +125,9 @@ impl Version {
                 parse_internal_key(&f.smallest).2,
                 parse_internal_key(&f.largest).2,
             );
-            if self.user_cmp.cmp(ukey, fsmallest) >= Ordering::Equal
-                && self.user_cmp.cmp(ukey, flargest) <= Ordering::Equal
+            // A file may contain an older version of ukey even if it starts after ukey.
+            if self.user_cmp.cmp_without_ts(ukey, fsmallest) >= Ordering::Equal
+                && self.user_cmp.cmp_without_ts(ukey, flargest) <= Ordering::Equal
             {
                 levels[0].push(f_.clone());
             }
@@ -127,12 +136,12 @@ impl Version {
         levels[0].sort_by(|a, b| b.borrow().num.cmp(&a.borrow().num));
 
         let icmp = InternalKeyCmp(self.user_cmp.clone());
-        for level in 1..NUM_LEVELS {
+        for level in 1..self.num_levels() {
             let files = &self.files[level];
             if let Some(ix) = find_file(&icmp, files, ikey) {
                 let f = files[ix].borrow();
                 let fsmallest = parse_internal_key(&f.smallest).2;
-                if self.user_cmp.cmp(ukey, fsmallest) >= Ordering::Equal {
+                if self.user_cmp.cmp_without_ts(ukey, fsmallest) >= Ordering::Equal {
                     levels[level].push(files[ix].clone());
                 }
             }
@@ -144,7 +153,7 @@ impl Version {
     /// level_summary returns a summary of the distribution of tables and bytes in this version.
     pub fn level_summary(&self) -> String {
         let mut acc = String::with_capacity(256);
-        for level in 0..NUM_LEVELS {
+        for level in 0..self.num_levels() {
             let fs = &self.files[level];
             if fs.is_empty() {
                 continue;
@@ -174,11 +183,11 @@ impl Version {
             let limit = LookupKey::new_full(max, 0, ValueType::TypeDeletion);
 
             const MAX_MEM_COMPACT_LEVEL: usize = 2;
-            while level < MAX_MEM_COMPACT_LEVEL {
+            while level < MAX_MEM_COMPACT_LEVEL && level + 1 < self.num_levels() {
                 if self.overlap_in_level(level + 1, min, max) {
                     break;
                 }
-                if level + 2 < NUM_LEVELS {
+                if level + 2 < self.num_levels() {
                     let overlaps = self.overlapping_inputs(
                         level + 2,
                         start.internal_key(),
@@ -245,7 +254,7 @@ impl Version {
     /// tables in l, for the maximum case.
     fn max_next_level_overlapping_bytes(&self) -> usize {
         let mut max = 0;
-        for lvl in 1..NUM_LEVELS - 1 {
+        for lvl in 1..self.num_levels() - 1 {
             for f in &self.files[lvl] {
                 let f = f.borrow();
                 let ols = self.overlapping_inputs(lvl + 1, &f.smallest, &f.largest);
@@ -266,7 +275,7 @@ impl Version {
         smallest: UserKey<'a>,
         largest: UserKey<'a>,
     ) -> bool {
-        assert!(level < NUM_LEVELS);
+        assert!(level < self.num_levels());
         if level != 0 {
             some_file_overlaps_range_disjoint(
                 &InternalKeyCmp(self.user_cmp.clone()),
@@ -291,7 +300,7 @@ impl Version {
         begin: InternalKey<'a>,
         end: InternalKey<'b>,
     ) -> Vec<FileMetaHandle> {
-        assert!(level < NUM_LEVELS);
+        assert!(level < self.num_levels());
         let (mut ubegin, mut uend) = (
             parse_internal_key(begin).2.to_vec(),
             parse_internal_key(end).2.to_vec(),
@@ -369,7 +378,7 @@ impl Version {
             ));
         }
 
-        for l in 1..NUM_LEVELS {
+        for l in 1..self.num_levels() {
             if !self.files[l].is_empty() {
                 iters.push(Box::new(self.new_concat_iter(l)));
             }
@@ -392,6 +401,7 @@ pub fn new_version_iter(
         cmp: InternalKeyCmp(ucmp),
         current: None,
         current_ix: 0,
+        err: None,
     }
 }
 
@@ -408,11 +418,43 @@ pub struct VersionIter {
 
     current: Option<TableIterator>,
     current_ix: usize,
+    // The first error encountered while opening a table or reading from a previous table.
+    err: Option<Status>,
+}
+
+impl VersionIter {
+    /// open_table returns an iterator over the table at index `ix`, recording any error.
+    fn open_table(&mut self, ix: usize) -> Option<TableIterator> {
+        // Keep errors of the table iterator that is about to be replaced.
+        if let Some(Err(e)) = self.current.as_ref().map(|t| t.status()) {
+            self.record_err(e);
+        }
+        let table = self
+            .cache
+            .borrow_mut()
+            .get_table(self.files[ix].borrow().num);
+        match table {
+            Ok(tbl) => Some(tbl.iter()),
+            Err(e) => {
+                self.record_err(e);
+                None
+            }
+        }
+    }
+
+    /// record_err keeps the first error encountered by the iterator.
+    fn record_err(&mut self, e: Status) {
+        if self.err.is_none() {
+            self.err = Some(e);
+        }
+    }
 }
 
 impl LdbIterator for VersionIter {
     fn advance(&mut self) -> bool {
-        assert!(!self.files.is_empty());
+        if self.files.is_empty() {
+            return false;
+        }
 
         if let Some(ref mut t) = self.current {
             if t.advance() {
@@ -427,12 +469,9 @@ impl LdbIterator for VersionIter {
         }
 
         // Initialize iterator or load next table.
-        if let Ok(tbl) = self
-            .cache
-            .borrow_mut()
-            .get_table(self.files[self.current_ix].borrow().num)
-        {
-            self.current = Some(tbl.iter());
+        let ix = self.current_ix;
+        if let Some(iter) = self.open_table(ix) {
+            self.current = Some(iter);
         } else {
             return false;
         }
@@ -447,45 +486,77 @@ impl LdbIterator for VersionIter {
     }
     fn seek(&mut self, key: &[u8]) {
         if let Some(ix) = find_file(&self.cmp, &self.files, key) {
-            if let Ok(tbl) = self
-                .cache
-                .borrow_mut()
-                .get_table(self.files[ix].borrow().num)
-            {
-                let mut iter = tbl.iter();
+            if let Some(mut iter) = self.open_table(ix) {
                 iter.seek(key);
                 if iter.valid() {
                     self.current_ix = ix;
                     self.current = Some(iter);
                     return;
                 }
+                if let Err(e) = iter.status() {
+                    self.record_err(e);
+                }
+            }
+        }
+        self.reset();
+    }
+    fn seek_to_last(&mut self) {
+        if self.files.is_empty() {
+            self.reset();
+            return;
+        }
+        let ix = self.files.len() - 1;
+        if let Some(mut iter) = self.open_table(ix) {
+            iter.seek_to_last();
+            if iter.valid() {
+                self.current_ix = ix;
+                self.current = Some(iter);
+                return;
+            }
+            if let Err(e) = iter.status() {
+                self.record_err(e);
             }
         }
         self.reset();
     }
     fn reset(&mut self) {
-        self.current = None;
+        // Like a TableIterator, keep errors across resets.
+        if let Some(Err(e)) = self.current.take().map(|t| t.status()) {
+            self.record_err(e);
+        }
         self.current_ix = 0;
     }
     fn valid(&self) -> bool {
         self.current.as_ref().map(|t| t.valid()).unwrap_or(false)
     }
+    fn status(&self) -> Result<()> {
+        if let Some(ref e) = self.err {
+            return Err(e.clone());
+        }
+        self.current.as_ref().map(|t| t.status()).unwrap_or(Ok(()))
+    }
     fn prev(&mut self) -> bool {
-        if let Some(ref mut t) = self.current {
-            if t.prev() {
-                return true;
-            } else if self.current_ix > 0 {
-                let f = &self.files[self.current_ix - 1];
-                // Find previous table, seek to last entry.
-                if let Ok(tbl) = self.cache.borrow_mut().get_table(f.borrow().num) {
-                    let mut iter = tbl.iter();
-                    iter.seek(&f.borrow().largest);
-                    // The saved largest key must be in the table.
-                    assert!(iter.valid());
-                    self.current_ix -= 1;
-                    *t = iter;
+        let at_first_entry = match self.current {
+            Some(ref mut t) => !t.prev(),
+            None => return false,
+        };
+        if !at_first_entry {
+            return true;
+        }
+        if self.current_ix > 0 {
+            // Find previous table, seek to last entry.
+            let ix = self.current_ix - 1;
+            if let Some(mut iter) = self.open_table(ix) {
+                // The saved largest key is in the table, unless it can't be read.
+                iter.seek(&self.files[ix].borrow().largest);
+                if iter.valid() {
+                    self.current_ix = ix;
+                    self.current = Some(iter);
                     return true;
                 }
+                if let Err(e) = iter.status() {
+                    self.record_err(e);
+                }
             }
         }
         self.reset();
@@ -594,6 +665,8 @@ pub mod testutil {
             num: num,
             smallest: LookupKey::new(smallest, smallestix).internal_key().to_vec(),
             largest: LookupKey::new(largest, largestix).internal_key().to_vec(),
+            creation_time: 0,
+            blob_files: vec![],
         })
     }
 
@@ -706,7 +779,7 @@ pub mod testutil {
         let t9 = write_table(&env, f9, 1, 9);
 
         let cache = TableCache::new("db", opts.clone(), 100);
-        let mut v = Version::new(share(cache), Rc::new(Box::new(DefaultCmp)));
+        let mut v = Version::new(share(cache), Rc::new(Box::new(DefaultCmp)), opts.num_levels);
         v.files[0] = vec![t1, t2];
         v.files[1] = vec![t3, t4, t5];
         v.files[2] = vec![t6, t7];
@@ -738,6 +811,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_version_concat_iter_empty() {
+        let v = make_version().0;
+        let level = v.num_levels() - 1;
+        assert!(v.files[level].is_empty());
+
+        let mut iter = v.new_concat_iter(level);
+        assert!(!iter.advance());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        assert!(!iter.prev());
+        iter.seek(LookupKey::new(b"abc", MAX_SEQUENCE_NUMBER).internal_key());
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+    }
+
     #[test]
     fn test_version_concat_iter_properties() {
         let v = make_version().0;
@@ -803,7 +892,7 @@ mod tests {
 
         for ref c in cases {
             match v.get(LookupKey::new(c.0, c.1).internal_key()) {
-                Ok(Some((val, _))) => assert_eq!(c.2.as_ref().unwrap().as_ref().unwrap(), &val),
+                Ok(Some((val, _, _))) => assert_eq!(c.2.as_ref().unwrap().as_ref().unwrap(), &val),
                 Ok(None) => assert!(c.2.as_ref().unwrap().as_ref().is_none()),
                 Err(_) => assert!(c.2.is_err()),
             }
@@ -826,7 +915,7 @@ mod tests {
         assert_eq!(6, ol[2][0].borrow().num);
 
         let ol = v.get_overlapping(LookupKey::new(b"x", 50).internal_key());
-        for i in 0..NUM_LEVELS {
+        for i in 0..v.num_levels() {
             assert!(ol[i].is_empty());
         }
     }
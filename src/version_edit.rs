@@ -3,6 +3,7 @@ use std::prelude::v1::*;
 
 use error::{err, Result, StatusCode};
 use key_types::InternalKey;
+use options::{int_to_compactionstyle, CompactionStyle};
 use types::{FileMetaData, FileNum, SequenceNumber};
 
 use integer_encoding::{VarIntReader, VarIntWriter};
@@ -26,6 +27,10 @@ enum EditTag {
     DeletedFile = 6,
     NewFile = 7,
     PrevLogNumber = 9, // sic!
+    CompactionStyle = 10,
+    FileCreationTime = 11,
+    NumLevels = 12,
+    BlobFileRefs = 13,
 }
 
 fn tag_to_enum(t: u32) -> Option<EditTag> {
@@ -38,6 +43,10 @@ fn tag_to_enum(t: u32) -> Option<EditTag> {
         6 => Some(EditTag::DeletedFile),
         7 => Some(EditTag::NewFile),
         9 => Some(EditTag::PrevLogNumber),
+        10 => Some(EditTag::CompactionStyle),
+        11 => Some(EditTag::FileCreationTime),
+        12 => Some(EditTag::NumLevels),
+        13 => Some(EditTag::BlobFileRefs),
         _ => None,
     }
 }
@@ -62,11 +71,13 @@ fn read_length_prefixed<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
 
 /// Manages changes to the set of managed SSTables and logfiles.
 pub struct VersionEdit {
-    comparator: Option<String>,
+    pub comparator: Option<String>,
     pub log_number: Option<FileNum>,
     pub prev_log_number: Option<FileNum>,
     pub next_file_number: Option<FileNum>,
     pub last_seq: Option<SequenceNumber>,
+    pub compaction_style: Option<CompactionStyle>,
+    pub num_levels: Option<usize>,
 
     pub compaction_ptrs: Vec<CompactionPointer>,
     pub deleted: HashSet<(usize, FileNum)>,
@@ -81,6 +92,8 @@ impl VersionEdit {
             prev_log_number: None,
             next_file_number: None,
             last_seq: None,
+            compaction_style: None,
+            num_levels: None,
             compaction_ptrs: Vec::with_capacity(8),
             deleted: HashSet::with_capacity(8),
             new_files: Vec::with_capacity(8),
@@ -119,6 +132,14 @@ impl VersionEdit {
         self.next_file_number = Some(num)
     }
 
+    pub fn set_compaction_style(&mut self, style: CompactionStyle) {
+        self.compaction_style = Some(style)
+    }
+
+    pub fn set_num_levels(&mut self, num_levels: usize) {
+        self.num_levels = Some(num_levels)
+    }
+
     pub fn set_compact_pointer(&mut self, level: usize, key: InternalKey) {
         self.compaction_ptrs.push(CompactionPointer {
             level,
@@ -158,6 +179,16 @@ impl VersionEdit {
             buf.write_varint(ls).unwrap();
         }
 
+        if let Some(style) = self.compaction_style {
+            buf.write_varint(EditTag::CompactionStyle as u32).unwrap();
+            buf.write_varint(style as u32).unwrap();
+        }
+
+        if let Some(num_levels) = self.num_levels {
+            buf.write_varint(EditTag::NumLevels as u32).unwrap();
+            buf.write_varint(num_levels).unwrap();
+        }
+
         for cptr in self.compaction_ptrs.iter() {
             buf.write_varint(EditTag::CompactPointer as u32).unwrap();
             buf.write_varint(cptr.level).unwrap();
@@ -183,6 +214,24 @@ impl VersionEdit {
             buf.write(nf.1.largest.as_ref()).unwrap();
         }
 
+        // Creation times are stored in separate records so that the NewFile encoding is unchanged.
+        // LevelDB rejects the unknown tag though, so a MANIFEST with creation times can't be read
+        // by LevelDB anymore.
+        for nf in self.new_files.iter().filter(|nf| nf.1.creation_time > 0) {
+            buf.write_varint(EditTag::FileCreationTime as u32).unwrap();
+            buf.write_varint(nf.1.num).unwrap();
+            buf.write_varint(nf.1.creation_time).unwrap();
+        }
+
+        for nf in self.new_files.iter().filter(|nf| !nf.1.blob_files.is_empty()) {
+            buf.write_varint(EditTag::BlobFileRefs as u32).unwrap();
+            buf.write_varint(nf.1.num).unwrap();
+            buf.write_varint(nf.1.blob_files.len()).unwrap();
+            for blob in nf.1.blob_files.iter() {
+                buf.write_varint(*blob).unwrap();
+            }
+        }
+
         buf
     }
 
@@ -234,6 +283,69 @@ impl VersionEdit {
                         }
                     }
 
+                    EditTag::CompactionStyle => {
+                        if let Ok(style) = reader.read_varint() {
+                            if let Some(style) = int_to_compactionstyle(style) {
+                                ve.compaction_style = Some(style);
+                            } else {
+                                return err(StatusCode::Corruption, "Unknown compaction style");
+                            }
+                        } else {
+                            return err(StatusCode::IOError, "Couldn't read compaction style");
+                        }
+                    }
+
+                    EditTag::NumLevels => {
+                        if let Ok(num_levels) = reader.read_varint() {
+                            ve.num_levels = Some(num_levels);
+                        } else {
+                            return err(StatusCode::IOError, "Couldn't read num_levels");
+                        }
+                    }
+
+                    EditTag::FileCreationTime => {
+                        if let (Ok(num), Ok(time)) = (
+                            reader.read_varint::<FileNum>(),
+                            reader.read_varint::<u64>(),
+                        ) {
+                            if let Some(nf) = ve.new_files.iter_mut().find(|nf| nf.1.num == num) {
+                                nf.1.creation_time = time;
+                            } else {
+                                return err(
+                                    StatusCode::Corruption,
+                                    "Creation time for unknown file",
+                                );
+                            }
+                        } else {
+                            return err(StatusCode::IOError, "Couldn't read file creation time");
+                        }
+                    }
+
+                    EditTag::BlobFileRefs => {
+                        let num = reader.read_varint::<FileNum>();
+                        let count = reader.read_varint::<usize>();
+                        if let (Ok(num), Ok(count)) = (num, count) {
+                            let mut blob_files = Vec::with_capacity(count);
+                            for _ in 0..count {
+                                if let Ok(blob) = reader.read_varint() {
+                                    blob_files.push(blob);
+                                } else {
+                                    return err(StatusCode::IOError, "Couldn't read blob file");
+                                }
+                            }
+                            if let Some(nf) = ve.new_files.iter_mut().find(|nf| nf.1.num == num) {
+                                nf.1.blob_files = blob_files;
+                            } else {
+                                return err(
+                                    StatusCode::Corruption,
+                                    "Blob file references for unknown file",
+                                );
+                            }
+                        } else {
+                            return err(StatusCode::IOError, "Couldn't read blob file references");
+                        }
+                    }
+
                     EditTag::CompactPointer => {
                         // Monads by indentation...
                         if let Ok(lvl) = reader.read_varint() {
@@ -274,6 +386,8 @@ impl VersionEdit {
                                             smallest,
                                             largest,
                                             allowed_seeks: 0,
+                                            creation_time: 0,
+                                            blob_files: vec![],
                                         },
                                     ))
                                 } else {
@@ -305,6 +419,7 @@ mod tests {
     use super::VersionEdit;
 
     use cmp::{Cmp, DefaultCmp};
+    use options::CompactionStyle;
     use types::FileMetaData;
 
     #[test]
@@ -314,6 +429,8 @@ mod tests {
         ve.set_comparator_name(DefaultCmp.id());
         ve.set_log_num(123);
         ve.set_next_file(456);
+        ve.set_compaction_style(CompactionStyle::Universal);
+        ve.set_num_levels(5);
         ve.set_compact_pointer(0, &[0, 1, 2]);
         ve.set_compact_pointer(1, &[3, 4, 5]);
         ve.set_compact_pointer(2, &[6, 7, 8]);
@@ -325,6 +442,20 @@ mod tests {
                 size: 234,
                 smallest: vec![5, 6, 7],
                 largest: vec![8, 9, 0],
+                creation_time: 0,
+                blob_files: vec![],
+            },
+        );
+        ve.add_file(
+            0,
+            FileMetaData {
+                allowed_seeks: 0,
+                num: 902,
+                size: 345,
+                smallest: vec![1],
+                largest: vec![2],
+                creation_time: 1234567,
+                blob_files: vec![12, 34],
             },
         );
         ve.delete_file(1, 132);
@@ -336,6 +467,8 @@ mod tests {
         assert_eq!(decoded.comparator, Some(DefaultCmp.id().to_string()));
         assert_eq!(decoded.log_number, Some(123));
         assert_eq!(decoded.next_file_number, Some(456));
+        assert_eq!(decoded.compaction_style, Some(CompactionStyle::Universal));
+        assert_eq!(decoded.num_levels, Some(5));
         assert_eq!(decoded.compaction_ptrs.len(), 3);
         assert_eq!(
             decoded.compaction_ptrs[0],
@@ -358,7 +491,7 @@ mod tests {
                 key: vec![6, 7, 8],
             }
         );
-        assert_eq!(decoded.new_files.len(), 1);
+        assert_eq!(decoded.new_files.len(), 2);
         assert_eq!(
             decoded.new_files[0],
             (
@@ -369,9 +502,13 @@ mod tests {
                     size: 234,
                     smallest: vec![5, 6, 7],
                     largest: vec![8, 9, 0],
+                    creation_time: 0,
+                    blob_files: vec![],
                 }
             )
         );
+        assert_eq!(decoded.new_files[1].1.creation_time, 1234567);
+        assert_eq!(decoded.new_files[1].1.blob_files, vec![12, 34]);
         assert_eq!(decoded.deleted.len(), 1);
         assert!(decoded.deleted.contains(&(1, 132)));
     }
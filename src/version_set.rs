@@ -7,7 +7,8 @@ use error::{err, Result, Status, StatusCode};
 use key_types::{parse_internal_key, InternalKey, UserKey};
 use log::{LogReader, LogWriter};
 use merging_iter::MergingIter;
-use options::Options;
+use options::{CompactionStyle, Options, UniversalCompactionOptions};
+use rate_limiter::{IOPriority, RateLimitedIter};
 use table_cache::TableCache;
 use types::{
     parse_file_name, share, FileMetaData, FileNum, FileType, LdbIterator, Shared, NUM_LEVELS,
@@ -25,18 +26,24 @@ use std::os::unix::ffi::OsStrExt;
 
 pub struct Compaction {
     level: usize,
+    output_level: usize,
     max_file_size: usize,
+    max_output_file_size: usize,
     input_version: Option<Shared<Version>>,
-    level_ixs: [usize; NUM_LEVELS],
+    level_ixs: Vec<usize>,
     cmp: Rc<Box<dyn Cmp>>,
     icmp: InternalKeyCmp,
 
     manual: bool,
+    // If set, the inputs are deleted without being rewritten.
+    deletion_only: bool,
+    // If set, overrides the result of is_base_level_for().
+    bottommost: Option<bool>,
 
     // "parent" inputs from level and level+1.
     inputs: [Vec<FileMetaHandle>; 2],
     grandparent_ix: usize,
-    // remaining inputs from level+2..NUM_LEVELS
+    // remaining inputs from level+2..num_levels
     grandparents: Option<Vec<FileMetaHandle>>,
     overlapped_bytes: usize,
     seen_key: bool,
@@ -48,12 +55,16 @@ impl Compaction {
     pub fn new(opt: &Options, level: usize, input: Option<Shared<Version>>) -> Compaction {
         Compaction {
             level,
+            output_level: level + 1,
             max_file_size: opt.max_file_size,
+            max_output_file_size: opt.max_file_size,
             input_version: input,
-            level_ixs: Default::default(),
+            level_ixs: vec![0; opt.num_levels],
             cmp: opt.cmp.clone(),
             icmp: InternalKeyCmp(opt.cmp.clone()),
             manual: false,
+            deletion_only: false,
+            bottommost: None,
 
             inputs: Default::default(),
             grandparent_ix: 0,
@@ -73,6 +84,21 @@ impl Compaction {
         self.level
     }
 
+    /// output_level is the level the compaction's output files are added to.
+    pub fn output_level(&self) -> usize {
+        self.output_level
+    }
+
+    /// is_deletion_only returns true if the compaction's inputs are dropped without any output.
+    pub fn is_deletion_only(&self) -> bool {
+        self.deletion_only
+    }
+
+    /// max_output_file_size is the size after which a new output file is started.
+    pub fn max_output_file_size(&self) -> usize {
+        self.max_output_file_size
+    }
+
     pub fn input(&self, parent: usize, ix: usize) -> FileMetaData {
         assert!(parent < 2);
         assert!(ix < self.inputs[parent].len());
@@ -106,13 +132,21 @@ impl Compaction {
     /// to contain the key.
     pub fn is_base_level_for<'a>(&mut self, k: UserKey<'a>) -> bool {
         assert!(self.input_version.is_some());
+        if let Some(bottommost) = self.bottommost {
+            return bottommost;
+        }
         let inp_version = self.input_version.as_ref().unwrap();
-        for level in self.level + 2..NUM_LEVELS {
+        for level in self.level + 2..self.level_ixs.len() {
             let files = &inp_version.borrow().files[level];
             while self.level_ixs[level] < files.len() {
                 let f = files[self.level_ixs[level]].borrow();
-                if self.cmp.cmp(k, parse_internal_key(&f.largest).2) <= Ordering::Equal {
-                    if self.cmp.cmp(k, parse_internal_key(&f.smallest).2) >= Ordering::Equal {
+                let (fsmallest, flargest) = (
+                    parse_internal_key(&f.smallest).2,
+                    parse_internal_key(&f.largest).2,
+                );
+                // Any version of k in a deeper level is visible at some timestamp.
+                if self.cmp.cmp_without_ts(k, flargest) <= Ordering::Equal {
+                    if self.cmp.cmp_without_ts(k, fsmallest) >= Ordering::Equal {
                         // key is in this file's range, so this is not the base level.
                         return false;
                     }
@@ -125,8 +159,20 @@ impl Compaction {
         true
     }
 
+    /// is_bottommost returns true if no level below the output level contains any data, i.e. the
+    /// output files contain the oldest version of every key they include.
+    pub fn is_bottommost(&self) -> bool {
+        assert!(self.input_version.is_some());
+        if let Some(bottommost) = self.bottommost {
+            return bottommost;
+        }
+        let inp_version = self.input_version.as_ref().unwrap();
+        let files = &inp_version.borrow().files;
+        files[self.output_level + 1..].iter().all(|f| f.is_empty())
+    }
+
     pub fn is_trivial_move(&self) -> bool {
-        if self.manual {
+        if self.manual || self.output_level != self.level + 1 {
             return false;
         }
 
@@ -182,7 +228,12 @@ pub struct VersionSet {
     pub prev_log_num: u64,
 
     current: Option<Shared<Version>>,
-    compaction_ptrs: [Vec<u8>; NUM_LEVELS],
+    compaction_ptrs: Vec<Vec<u8>>,
+    // The number of levels recorded in the manifest; see compaction_style.
+    num_levels: usize,
+    // The compaction style recorded in the manifest. Manifests only record styles other than the
+    // default, so that they stay readable by LevelDB.
+    compaction_style: CompactionStyle,
 
     descriptor_log: Option<LogWriter<Box<dyn Write>>>,
 }
@@ -191,7 +242,8 @@ impl VersionSet {
     // Note: opt.cmp should not contain an InternalKeyCmp at this point, but instead the default or
     // user-supplied one.
     pub fn new<P: AsRef<Path>>(db: P, opt: Options, cache: Shared<TableCache>) -> VersionSet {
-        let v = share(Version::new(cache.clone(), opt.cmp.clone()));
+        let num_levels = opt.num_levels;
+        let v = share(Version::new(cache.clone(), opt.cmp.clone(), num_levels));
         VersionSet {
             dbname: db.as_ref().to_owned(),
             cmp: InternalKeyCmp(opt.cmp.clone()),
@@ -205,7 +257,9 @@ impl VersionSet {
             prev_log_num: 0,
 
             current: Some(v),
-            compaction_ptrs: Default::default(),
+            compaction_ptrs: vec![vec![]; num_levels],
+            num_levels: NUM_LEVELS,
+            compaction_style: CompactionStyle::Leveled,
             descriptor_log: None,
         }
     }
@@ -214,19 +268,36 @@ impl VersionSet {
         self.current.as_ref().unwrap().borrow().level_summary()
     }
 
-    /// live_files returns the files that are currently active.
+    /// live_files returns the files that are currently active, including the blob files tables
+    /// refer to.
     pub fn live_files(&self) -> HashSet<FileNum> {
         let mut files = HashSet::new();
         if let Some(ref version) = self.current {
-            for level in 0..NUM_LEVELS {
-                for file in &version.borrow().files[level] {
+            for level in version.borrow().files.iter() {
+                for file in level {
                     files.insert(file.borrow().num);
                 }
             }
         }
+        files.extend(self.live_blob_files());
         files
     }
 
+    /// live_blob_files returns the blob files referred to by the current version, oldest first.
+    pub fn live_blob_files(&self) -> Vec<FileNum> {
+        let mut blobs = vec![];
+        if let Some(ref version) = self.current {
+            for level in version.borrow().files.iter() {
+                for file in level {
+                    blobs.extend_from_slice(&file.borrow().blob_files);
+                }
+            }
+        }
+        blobs.sort();
+        blobs.dedup();
+        blobs
+    }
+
     /// current returns a reference to the current version. It panics if there is no current
     /// version.
     pub fn current(&self) -> Shared<Version> {
@@ -261,12 +332,53 @@ impl VersionSet {
         assert!(self.current.is_some());
         let v = self.current.as_ref().unwrap();
         let v = v.borrow();
+        match self.opt.compaction_style {
+            CompactionStyle::Leveled => (),
+            // Seek compactions only exist in leveled compaction.
+            CompactionStyle::Universal => return v.compaction_score.unwrap_or(0.0) >= 1.0,
+            CompactionStyle::Fifo => {
+                let now = self.opt.env.micros();
+                return v.compaction_score.unwrap_or(0.0) >= 1.0
+                    || v.files[0].iter().any(|f| self.is_expired(&f.borrow(), now));
+            }
+        }
         v.compaction_score.unwrap_or(0.0) >= 1.0 || v.file_to_compact.is_some()
     }
 
+    /// pending_compaction_bytes estimates how many bytes have to be compacted until no level
+    /// needs a compaction anymore. FIFO compactions never rewrite anything.
+    pub fn pending_compaction_bytes(&self) -> usize {
+        let current = self.current();
+        let v = current.borrow();
+        let mut pending = 0;
+        if self.opt.compaction_style != CompactionStyle::Fifo
+            && v.num_level_files(0) >= self.opt.level0_file_num_compaction_trigger
+        {
+            pending += v.num_level_bytes(0);
+        }
+        if self.opt.compaction_style == CompactionStyle::Leveled {
+            for l in 1..v.num_levels() - 1 {
+                let max_bytes = self.max_bytes_for_level(l) as usize;
+                pending += v.num_level_bytes(l).saturating_sub(max_bytes);
+            }
+        }
+        pending
+    }
+
+    /// max_bytes_for_level returns the size a level (except level 0) may grow to before it needs
+    /// to be compacted.
+    fn max_bytes_for_level(&self, level: usize) -> f64 {
+        assert!(level > 0);
+        let mut max_bytes = self.opt.max_bytes_for_level_base as f64;
+        for _ in 0..level - 1 {
+            max_bytes *= self.opt.max_bytes_for_level_multiplier;
+        }
+        max_bytes
+    }
+
     fn approximate_offset<'a>(&self, v: &Shared<Version>, key: InternalKey<'a>) -> usize {
         let mut offset = 0;
-        for level in 0..NUM_LEVELS {
+        for level in 0..v.borrow().num_levels() {
             for f in &v.borrow().files[level] {
                 if self.opt.cmp.cmp(&f.borrow().largest, key) <= Ordering::Equal {
                     offset += f.borrow().size;
@@ -288,6 +400,11 @@ impl VersionSet {
     /// 选定一个compaction，优先考虑Size compaction
     pub fn pick_compaction(&mut self) -> Option<Compaction> {
         assert!(self.current.is_some());
+        match self.opt.compaction_style {
+            CompactionStyle::Leveled => (),
+            CompactionStyle::Universal => return self.pick_universal_compaction(),
+            CompactionStyle::Fifo => return self.pick_fifo_compaction(),
+        }
         let current = self.current();
         let current = current.borrow();
 
@@ -297,7 +414,7 @@ impl VersionSet {
         // Size compaction?
         if current.compaction_score.unwrap_or(0.0) >= 1.0 {
             level = current.compaction_level.unwrap();
-            assert!(level < NUM_LEVELS - 1);
+            assert!(level < current.num_levels() - 1);
 
             for f in &current.files[level] {
                 if self.compaction_ptrs[level].is_empty()
@@ -324,6 +441,7 @@ impl VersionSet {
         }
 
         c.level = level;
+        c.output_level = level + 1;
         c.input_version = self.current.clone();
 
         if level == 0 {
@@ -337,6 +455,110 @@ impl VersionSet {
         Some(c)
     }
 
+    /// pick_universal_compaction picks a compaction of sorted runs in level 0, which always
+    /// contains the newest runs. The output is a single new run in level 0.
+    fn pick_universal_compaction(&mut self) -> Option<Compaction> {
+        let current = self.current();
+        let current = current.borrow();
+
+        // Newer runs have higher file numbers.
+        let mut runs = current.files[0].clone();
+        runs.sort_by(|a, b| b.borrow().num.cmp(&a.borrow().num));
+        let sizes: Vec<usize> = runs.iter().map(|f| f.borrow().size).collect();
+        let (n, reason) = pick_universal_runs(
+            &sizes,
+            &self.opt.universal_compaction,
+            self.opt.level0_file_num_compaction_trigger,
+        )?;
+        runs.truncate(n);
+        log!(
+            self.opt.log,
+            "Universal compaction ({}) of {} of {} runs, {} bytes",
+            reason,
+            n,
+            sizes.len(),
+            total_size(runs.iter())
+        );
+        Some(self.merge_l0_compaction(runs))
+    }
+
+    /// pick_fifo_compaction picks the oldest tables in level 0 for deletion if the database is too
+    /// large or they are too old. Otherwise, it may pick a merge of the newest tables if they are
+    /// small.
+    fn pick_fifo_compaction(&mut self) -> Option<Compaction> {
+        let current = self.current();
+        let current = current.borrow();
+        let fopt = self.opt.fifo_compaction;
+
+        // Older tables have lower file numbers.
+        let mut files = current.files[0].clone();
+        files.sort_by_key(|f| f.borrow().num);
+
+        let now = self.opt.env.micros();
+        let mut total = total_size(files.iter());
+        let mut expired = 0;
+        for f in &files {
+            let f = f.borrow();
+            if total <= fopt.max_table_files_size && !self.is_expired(&f, now) {
+                break;
+            }
+            total -= f.size;
+            expired += 1;
+        }
+
+        if expired > 0 {
+            files.truncate(expired);
+            log!(
+                self.opt.log,
+                "FIFO compaction: deleting {} tables, {} bytes",
+                expired,
+                total_size(files.iter())
+            );
+            let mut c = Compaction::new(&self.opt, 0, self.current.clone());
+            c.output_level = 0;
+            c.deletion_only = true;
+            c.inputs[0] = files;
+            return Some(c);
+        }
+
+        if fopt.allow_compaction {
+            let small: Vec<FileMetaHandle> = files
+                .iter()
+                .rev()
+                .take_while(|f| f.borrow().size <= self.opt.write_buffer_size)
+                .cloned()
+                .collect();
+            if small.len() >= self.opt.level0_file_num_compaction_trigger {
+                log!(self.opt.log, "FIFO compaction: merging {} tables", small.len());
+                return Some(self.merge_l0_compaction(small));
+            }
+        }
+        None
+    }
+
+    /// is_expired returns true if f is older than the TTL of FIFO compaction.
+    fn is_expired(&self, f: &FileMetaData, now: u64) -> bool {
+        let ttl = self.opt.fifo_compaction.ttl_seconds;
+        ttl > 0 && f.creation_time > 0 && now.saturating_sub(f.creation_time) > ttl * 1000000
+    }
+
+    /// merge_l0_compaction returns a compaction merging the given files, which must be the newest
+    /// files in level 0, into one new file in level 0.
+    fn merge_l0_compaction(&self, runs: Vec<FileMetaHandle>) -> Compaction {
+        let current = self.current();
+        let current = current.borrow();
+        let mut c = Compaction::new(&self.opt, 0, self.current.clone());
+        c.output_level = 0;
+        c.max_output_file_size = usize::max_value();
+        // Deletions can only be dropped if no older data exists.
+        c.bottommost = Some(
+            runs.len() == current.files[0].len()
+                && current.files[1..].iter().all(|files| files.is_empty()),
+        );
+        c.inputs[0] = runs;
+        c
+    }
+
     pub fn compact_range<'a, 'b>(
         &mut self,
         level: usize,
@@ -354,6 +576,22 @@ impl VersionSet {
             return None;
         }
 
+        if self.opt.compaction_style == CompactionStyle::Fifo {
+            // Tables are never rewritten.
+            return None;
+        }
+        if level == 0 && self.opt.compaction_style == CompactionStyle::Universal {
+            // Runs overlap in arbitrary ways; merge all of them.
+            let current = self.current();
+            let runs = current.borrow().files[0].clone();
+            if runs.len() < 2 {
+                return None;
+            }
+            let mut c = self.merge_l0_compaction(runs);
+            c.manual = true;
+            return Some(c);
+        }
+
         if level > 0 {
             let mut total = 0;
             for i in 0..inputs.len() {
@@ -436,7 +674,7 @@ impl VersionSet {
 
         // Set the list of grandparent (l+2) inputs to the files overlapped by the current overall
         // range.
-        if level + 2 < NUM_LEVELS {
+        if level + 2 < current.num_levels() {
             let grandparents = self.current.as_ref().unwrap().borrow().overlapping_inputs(
                 level + 2,
                 &allstart,
@@ -463,9 +701,15 @@ impl VersionSet {
 
         let mut edit = VersionEdit::new();
         edit.set_comparator_name(self.opt.cmp.id());
+        if self.opt.compaction_style != CompactionStyle::Leveled {
+            edit.set_compaction_style(self.opt.compaction_style);
+        }
+        if self.opt.num_levels != NUM_LEVELS {
+            edit.set_num_levels(self.opt.num_levels);
+        }
 
         // Save compaction pointers.
-        for level in 0..NUM_LEVELS {
+        for level in 0..self.compaction_ptrs.len() {
             if !self.compaction_ptrs[level].is_empty() {
                 edit.set_compact_pointer(level, &self.compaction_ptrs[level]);
             }
@@ -473,7 +717,7 @@ impl VersionSet {
 
         let current = self.current.as_ref().unwrap().borrow();
         // Save files.
-        for level in 0..NUM_LEVELS {
+        for level in 0..current.num_levels() {
             let fs = &current.files[level];
             for f in fs {
                 edit.add_file(level, f.borrow().clone());
@@ -501,10 +745,16 @@ impl VersionSet {
         }
         edit.set_next_file(self.next_file_num);
         edit.set_last_seq(self.last_seq);
+        if self.compaction_style != self.opt.compaction_style {
+            edit.set_compaction_style(self.opt.compaction_style);
+        }
+        if self.num_levels != self.opt.num_levels {
+            edit.set_num_levels(self.opt.num_levels);
+        }
 
-        let mut v = Version::new(self.cache.clone(), self.opt.cmp.clone());
+        let mut v = Version::new(self.cache.clone(), self.opt.cmp.clone(), self.opt.num_levels);
         {
-            let mut builder = Builder::new();
+            let mut builder = Builder::new(self.opt.num_levels);
             builder.apply(&edit, &mut self.compaction_ptrs);
             builder.save_to(&self.cmp, self.current.as_ref().unwrap(), &mut v);
         }
@@ -531,6 +781,8 @@ impl VersionSet {
         self.add_version(v);
         // log_number was set above.
         self.log_num = edit.log_number.unwrap();
+        self.compaction_style = self.opt.compaction_style;
+        self.num_levels = self.opt.num_levels;
 
         // TODO: Roll back written files if something went wrong.
         Ok(())
@@ -539,19 +791,37 @@ impl VersionSet {
     /// 在版本变更完成时，计算每一Level实际大小相对于最大大小的比率，找到比率最大的Level，用于判断是否需要size compaction
     /// level0根据文件数量计算，其他level根据文件大小计算
     fn finalize(&self, v: &mut Version) {
+        let l0_trigger = self.opt.level0_file_num_compaction_trigger as f64;
+        match self.opt.compaction_style {
+            CompactionStyle::Leveled => (),
+            CompactionStyle::Universal => {
+                // Every file in level 0 is a sorted run.
+                v.compaction_score = Some(v.files[0].len() as f64 / l0_trigger);
+                v.compaction_level = Some(0);
+                return;
+            }
+            CompactionStyle::Fifo => {
+                let fopt = &self.opt.fifo_compaction;
+                let mut score =
+                    total_size(v.files[0].iter()) as f64 / fopt.max_table_files_size as f64;
+                if fopt.allow_compaction {
+                    score = score.max(v.files[0].len() as f64 / l0_trigger);
+                }
+                v.compaction_score = Some(score);
+                v.compaction_level = Some(0);
+                return;
+            }
+        }
+
         let mut best_lvl = None;
         let mut best_score = None;
 
-        for l in 0..NUM_LEVELS - 1 {
+        for l in 0..v.num_levels() - 1 {
             let score: f64;
             if l == 0 {
-                score = v.files[l].len() as f64 / 4.0;
+                score = v.files[l].len() as f64 / l0_trigger;
             } else {
-                let mut max_bytes = 10.0 * f64::from(1 << 20);
-                for _ in 0..l - 1 {
-                    max_bytes *= 10.0;
-                }
-                score = total_size(v.files[l].iter()) as f64 / max_bytes;
+                score = total_size(v.files[l].iter()) as f64 / self.max_bytes_for_level(l);
             }
             if let Some(ref mut b) = best_score {
                 if *b < score {
@@ -578,7 +848,7 @@ impl VersionSet {
         let current = Path::new(&current);
 
         let descfilename = self.dbname.join(current);
-        let mut builder = Builder::new();
+        let mut builder = Builder::new(self.opt.num_levels);
         {
             let mut descfile = self
                 .opt
@@ -596,11 +866,33 @@ impl VersionSet {
             let mut last_seq = None;
 
             let mut buf = Vec::new();
-            while let Ok(size) = logreader.read(&mut buf) {
+            loop {
+                let size = match logreader.read(&mut buf) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        if self.opt.paranoid_checks {
+                            return Err(e);
+                        }
+                        log!(self.opt.log, "Ignoring rest of corrupt descriptor: {}", e);
+                        break;
+                    }
+                };
                 if size == 0 {
                     break;
                 }
                 let edit = VersionEdit::decode_from(&buf)?;
+                if let Some(ref cmp) = edit.comparator {
+                    if cmp.as_str() != self.opt.cmp.id() {
+                        return err(
+                            StatusCode::InvalidArgument,
+                            &format!(
+                                "comparator {} does not match existing comparator {}",
+                                self.opt.cmp.id(),
+                                cmp
+                            ),
+                        );
+                    }
+                }
                 builder.apply(&edit, &mut self.compaction_ptrs);
                 if let Some(ln) = edit.log_number {
                     log_number = Some(ln);
@@ -614,6 +906,12 @@ impl VersionSet {
                 if let Some(pln) = edit.prev_log_number {
                     prev_log_number = Some(pln);
                 }
+                if let Some(style) = edit.compaction_style {
+                    self.compaction_style = style;
+                }
+                if let Some(num_levels) = edit.num_levels {
+                    self.num_levels = num_levels;
+                }
             }
 
             if let Some(ln) = log_number {
@@ -649,8 +947,19 @@ impl VersionSet {
             }
         }
 
-        let mut v = Version::new(self.cache.clone(), self.opt.cmp.clone());
+        let num_levels = self.opt.num_levels.max(builder.num_levels());
+        let mut v = Version::new(self.cache.clone(), self.opt.cmp.clone(), num_levels);
         builder.save_to(&self.cmp, self.current.as_ref().unwrap(), &mut v);
+        if let Some(l) = (self.opt.num_levels..num_levels).find(|&l| !v.files[l].is_empty()) {
+            return err(
+                StatusCode::InvalidArgument,
+                &format!(
+                    "database with {} levels has files in level {}, but num_levels is {}",
+                    self.num_levels, l, self.opt.num_levels
+                ),
+            );
+        }
+        v.files.truncate(self.opt.num_levels);
         self.finalize(&mut v);
         self.add_version(v);
         self.manifest_num = self.next_file_num - 1;
@@ -665,6 +974,23 @@ impl VersionSet {
             self.last_seq
         );
 
+        if self.compaction_style != self.opt.compaction_style {
+            log!(
+                self.opt.log,
+                "Changing compaction style from {:?} to {:?}",
+                self.compaction_style,
+                self.opt.compaction_style
+            );
+        }
+        if self.num_levels != self.opt.num_levels {
+            log!(
+                self.opt.log,
+                "Changing number of levels from {} to {}",
+                self.num_levels,
+                self.opt.num_levels
+            );
+        }
+
         // A new manifest needs to be written only if we don't reuse the existing one.
         Ok(!self.reuse_manifest(&descfilename, &current))
     }
@@ -750,37 +1076,63 @@ impl VersionSet {
         }
         assert!(iters.len() <= cap);
         let cmp: Rc<Box<dyn Cmp>> = Rc::new(Box::new(self.cmp.clone()));
-        Box::new(MergingIter::new(cmp, iters))
+        let iter = Box::new(MergingIter::new(cmp, iters));
+        if self.opt.rate_limiter.is_some() {
+            Box::new(RateLimitedIter::new(
+                iter,
+                self.opt.clone(),
+                IOPriority::Compaction,
+            ))
+        } else {
+            iter
+        }
     }
 }
 
 struct Builder {
     // (added, deleted) files per level.
-    deleted: [Vec<FileNum>; NUM_LEVELS],
-    added: [Vec<FileMetaHandle>; NUM_LEVELS],
+    deleted: Vec<Vec<FileNum>>,
+    added: Vec<Vec<FileMetaHandle>>,
 }
 
 impl Builder {
-    fn new() -> Builder {
+    fn new(num_levels: usize) -> Builder {
         Builder {
-            deleted: Default::default(),
-            added: Default::default(),
+            deleted: vec![vec![]; num_levels],
+            added: vec![vec![]; num_levels],
+        }
+    }
+
+    /// num_levels returns the number of levels the builder has seen edits for, or the number it
+    /// was created with, if larger.
+    fn num_levels(&self) -> usize {
+        self.added.len()
+    }
+
+    fn ensure_levels(&mut self, num_levels: usize) {
+        if num_levels > self.num_levels() {
+            self.deleted.resize(num_levels, vec![]);
+            self.added.resize(num_levels, vec![]);
         }
     }
 
     /// apply applies the edits recorded in edit to the builder state. compaction pointers are
-    /// copied to the supplied compaction_ptrs array.
-    fn apply(&mut self, edit: &VersionEdit, compaction_ptrs: &mut [Vec<u8>; NUM_LEVELS]) {
+    /// copied to the supplied compaction_ptrs array, unless their level doesn't exist.
+    fn apply(&mut self, edit: &VersionEdit, compaction_ptrs: &mut [Vec<u8>]) {
         // 首先更新VersionSet中的compaction_ptrs
         for c in edit.compaction_ptrs.iter() {
-            compaction_ptrs[c.level] = c.key.clone();
+            if let Some(ptr) = compaction_ptrs.get_mut(c.level) {
+                *ptr = c.key.clone();
+            }
         }
         // 把VersionEdit里删除的文件插入到self.deleted相应Level里面去
         for &(level, num) in edit.deleted.iter() {
+            self.ensure_levels(level + 1);
             self.deleted[level].push(num);
         }
         // 把VersionEdit里添加的文件插入到self.added相应的Level里去
         for &(level, ref f) in edit.new_files.iter() {
+            self.ensure_levels(level + 1);
             let mut f = f.clone();
             f.allowed_seeks = f.size / 16384;
             if f.allowed_seeks < 100 {
@@ -825,16 +1177,19 @@ impl Builder {
     }
 
     /// save_to saves the edits applied to the builder to v, adding all non-deleted files from
-    /// Version base to v.
+    /// Version base to v. Files in levels that v doesn't have are ignored.
     fn save_to(&mut self, cmp: &InternalKeyCmp, base: &Shared<Version>, v: &mut Version) {
-        for level in 0..NUM_LEVELS {
+        self.ensure_levels(v.num_levels());
+        for level in 0..v.num_levels() {
             sort_files_by_smallest(cmp, &mut self.added[level]);
             // The base version should already have sorted files.
-            sort_files_by_smallest(cmp, &mut base.borrow_mut().files[level]);
+            if level < base.borrow().num_levels() {
+                sort_files_by_smallest(cmp, &mut base.borrow_mut().files[level]);
+            }
 
             // 拿出原本Version里的文件，以及Builder里累积的，添加的文件
             let added = self.added[level].clone();
-            let basefiles = base.borrow().files[level].clone();
+            let basefiles = base.borrow().files.get(level).cloned().unwrap_or_default();
             v.files[level].reserve(basefiles.len() + self.added[level].len());
 
             let iadded = added.into_iter();
@@ -916,6 +1271,48 @@ pub fn set_current_file<P: AsRef<Path>>(
     Ok(())
 }
 
+/// pick_universal_runs decides which of the sorted runs with the given sizes, ordered from newest
+/// to oldest, are merged by a universal compaction. It returns the number of runs to merge and the
+/// reason for the compaction.
+///
+/// Only the newest runs are merged, so that the file number of the output run still orders it
+/// correctly relative to the remaining, older runs.
+fn pick_universal_runs(
+    sizes: &[usize],
+    uopt: &UniversalCompactionOptions,
+    trigger: usize,
+) -> Option<(usize, &'static str)> {
+    let n = sizes.len();
+    if n < 2 {
+        return None;
+    }
+
+    // Space amplification: how much larger than the oldest run is the whole database?
+    let oldest = sizes[n - 1];
+    let newer: usize = sizes[..n - 1].iter().sum();
+    if newer * 100 > uopt.max_size_amplification_percent * oldest {
+        return Some((n, "space amplification"));
+    }
+
+    // Size ratio: merge runs as long as the next one isn't much larger than the ones before it.
+    let max_width = uopt.max_merge_width.min(n);
+    let mut total = sizes[0];
+    let mut width = 1;
+    while width < max_width && total * (100 + uopt.size_ratio) / 100 >= sizes[width] {
+        total += sizes[width];
+        width += 1;
+    }
+    if width >= uopt.min_merge_width.max(2) {
+        return Some((width, "size ratio"));
+    }
+
+    // Too many runs: merge just enough of them to get below the trigger.
+    if n >= trigger {
+        return Some(((n + 2 - trigger).min(n), "run count"));
+    }
+    None
+}
+
 /// sort_files_by_smallest sorts the list of files by the smallest keys of the files.
 fn sort_files_by_smallest<C: Cmp>(cmp: &C, files: &mut Vec<FileMetaHandle>) {
     files.sort_by(|a, b| cmp.cmp(&a.borrow().smallest, &b.borrow().smallest))
@@ -993,10 +1390,10 @@ fn get_range<'a, C: Cmp, I: Iterator<Item = &'a FileMetaHandle>>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use cmp::DefaultCmp;
+    use cmp::{DefaultCmp, ReverseBytewiseCmp};
     use key_types::LookupKey;
     use test_util::LdbIteratorIter;
-    use types::FileMetaData;
+    use types::{FileMetaData, MAX_SEQUENCE_NUMBER};
     use version::testutil::make_version;
 
     fn example_files() -> Vec<FileMetaHandle> {
@@ -1070,8 +1467,8 @@ mod tests {
         ve.delete_file(0, 2);
         ve.set_compact_pointer(2, LookupKey::new("xxx".as_bytes(), 123).internal_key());
 
-        let mut b = Builder::new();
-        let mut ptrs: [Vec<u8>; NUM_LEVELS] = Default::default();
+        let mut b = Builder::new(NUM_LEVELS);
+        let mut ptrs = vec![vec![]; NUM_LEVELS];
         b.apply(&ve, &mut ptrs);
 
         assert_eq!(
@@ -1084,6 +1481,7 @@ mod tests {
         let mut v2 = Version::new(
             share(TableCache::new("db", opt.clone(), 100)),
             opt.cmp.clone(),
+            NUM_LEVELS,
         );
         b.save_to(&InternalKeyCmp(opt.cmp.clone()), &v, &mut v2);
         // Second file in L0 was removed.
@@ -1162,6 +1560,17 @@ mod tests {
             assert_eq!(1, vs.current.as_ref().unwrap().borrow().files[1].len());
             assert_eq!(63, vs.write_snapshot().unwrap());
         }
+
+        // A database can't be opened with a different comparator.
+        {
+            let mut opt = opt.clone();
+            opt.cmp = Rc::new(Box::new(ReverseBytewiseCmp));
+            let cache = share(TableCache::new("db", opt.clone(), 100));
+            let mut vs = VersionSet::new("db", opt, cache);
+            let e = vs.recover().err().unwrap();
+            assert_eq!(StatusCode::InvalidArgument, e.code);
+            assert!(e.err.contains("leveldb.BytewiseComparator"));
+        }
     }
 
     #[test]
@@ -1223,6 +1632,118 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_version_set_finalize_lsm_shape() {
+        let (mut v, mut opt) = make_version();
+        opt.level0_file_num_compaction_trigger = 2;
+        let vs = VersionSet::new("db", opt.clone(), share(TableCache::new("db", opt, 100)));
+        vs.finalize(&mut v);
+        assert_eq!(Some(0), v.compaction_level);
+        assert_eq!(Some(1.0), v.compaction_score);
+
+        let (mut v, mut opt) = make_version();
+        opt.max_bytes_for_level_base = 1;
+        opt.max_bytes_for_level_multiplier = 1e9;
+        let vs = VersionSet::new("db", opt.clone(), share(TableCache::new("db", opt, 100)));
+        vs.finalize(&mut v);
+        assert_eq!(Some(1), v.compaction_level);
+        assert_eq!(Some(v.num_level_bytes(1) as f64), v.compaction_score);
+    }
+
+    #[test]
+    fn test_version_set_pending_compaction_bytes() {
+        let (v, mut opt) = make_version();
+        let mut vs =
+            VersionSet::new("db", opt.clone(), share(TableCache::new("db", opt.clone(), 100)));
+        let l1_bytes = v.num_level_bytes(1);
+        let l0_bytes = v.num_level_bytes(0);
+        vs.add_version(v);
+        assert_eq!(0, vs.pending_compaction_bytes());
+
+        opt.max_bytes_for_level_base = 100;
+        opt.max_bytes_for_level_multiplier = 1e9;
+        opt.level0_file_num_compaction_trigger = 2;
+        let mut vs = VersionSet::new("db", opt.clone(), share(TableCache::new("db", opt, 100)));
+        vs.add_version(make_version().0);
+        assert_eq!(l0_bytes + l1_bytes - 100, vs.pending_compaction_bytes());
+    }
+
+    #[test]
+    fn test_version_set_pick_universal_runs() {
+        let uopt = UniversalCompactionOptions::default();
+        let pick = |sizes: &[usize]| pick_universal_runs(sizes, &uopt, 4).map(|(n, _)| n);
+
+        assert_eq!(None, pick(&[10]));
+        assert_eq!(None, pick(&[10, 100]));
+        // The newer runs are three times as large as the oldest one.
+        assert_eq!(Some(2), pick(&[300, 100]));
+        // Runs of similar size are merged.
+        assert_eq!(Some(2), pick(&[10, 10, 100]));
+        assert_eq!(Some(3), pick(&[10, 10, 20, 1000]));
+        // No runs of similar size, but too many runs.
+        assert_eq!(Some(2), pick(&[1, 10, 100, 1000]));
+        assert_eq!(Some(3), pick(&[1, 10, 100, 1000, 10000]));
+
+        let uopt = UniversalCompactionOptions {
+            max_merge_width: 2,
+            ..uopt
+        };
+        assert_eq!(
+            Some(2),
+            pick_universal_runs(&[10, 10, 10, 1000], &uopt, 4).map(|(n, _)| n)
+        );
+    }
+
+    #[test]
+    fn test_version_set_universal_compaction() {
+        let (v, mut opt) = make_version();
+        opt.compaction_style = CompactionStyle::Universal;
+        let mut vs = VersionSet::new(
+            "db",
+            opt.clone(),
+            share(TableCache::new("db", opt.clone(), 100)),
+        );
+        vs.add_version(v);
+
+        // Both L0 files are merged into a new L0 file. As there are files in other levels,
+        // deletions have to be kept.
+        let from = LookupKey::new(b"aaa", MAX_SEQUENCE_NUMBER);
+        let to = LookupKey::new(b"zzz", 0);
+        let mut c = vs
+            .compact_range(0, from.internal_key(), to.internal_key())
+            .unwrap();
+        assert_eq!(2, c.num_inputs(0));
+        assert_eq!(0, c.num_inputs(1));
+        assert_eq!(0, c.output_level());
+        assert!(!c.is_trivial_move());
+        assert!(!c.is_bottommost());
+        assert!(!c.is_base_level_for(b"aaa"));
+
+        // The compaction style is recorded in the manifest.
+        {
+            let mut ve = VersionEdit::new();
+            ve.set_comparator_name("leveldb.BytewiseComparator");
+            ve.set_log_num(10);
+            ve.set_next_file(20);
+            ve.set_last_seq(30);
+            let manifest = manifest_file_name("db", 19);
+            let mffile = opt.env.open_writable_file(Path::new(&manifest)).unwrap();
+            let mut lw = LogWriter::new(mffile);
+            lw.add_record(&ve.encode()).unwrap();
+            lw.flush().unwrap();
+            set_current_file(&opt.env.as_ref(), "db", 19).unwrap();
+        }
+        vs.recover().unwrap();
+        assert_eq!(CompactionStyle::Leveled, vs.compaction_style);
+        vs.log_and_apply(VersionEdit::new()).unwrap();
+        assert_eq!(CompactionStyle::Universal, vs.compaction_style);
+
+        opt.compaction_style = CompactionStyle::Leveled;
+        let mut vs = VersionSet::new("db", opt.clone(), share(TableCache::new("db", opt, 100)));
+        vs.recover().unwrap();
+        assert_eq!(CompactionStyle::Universal, vs.compaction_style);
+    }
+
     /// iterator_properties tests that it contains len elements and that they are ordered in
     /// ascending order by cmp.
     fn iterator_properties<It: LdbIterator>(mut it: It, len: usize, cmp: Rc<Box<dyn Cmp>>) {
@@ -1333,6 +1854,7 @@ mod tests {
                 .unwrap();
             assert!(c.is_base_level_for("aaa".as_bytes()));
             assert!(!c.is_base_level_for("hac".as_bytes()));
+            assert!(!c.is_bottommost());
 
             // input/add_input_deletions
             let from = LookupKey::new("000".as_bytes(), 1000);
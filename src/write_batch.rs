@@ -17,8 +17,12 @@ const HEADER_SIZE: usize = 12;
 /// The storage format is (with the respective length in bytes)
 ///
 /// [tag: 1, keylen: ~var, key: keylen, vallen: ~var, val: vallen]
+///
+/// Preconditions added with `expect()` are checked by the database before the batch is applied;
+/// they are not part of the serialized batch.
 pub struct WriteBatch {
     entries: Vec<u8>,
+    preconditions: Vec<(Vec<u8>, Option<Vec<u8>>)>,
 }
 
 impl WriteBatch {
@@ -26,7 +30,10 @@ impl WriteBatch {
         let mut v = Vec::with_capacity(128);
         v.resize(HEADER_SIZE, 0);
 
-        WriteBatch { entries: v }
+        WriteBatch {
+            entries: v,
+            preconditions: vec![],
+        }
     }
 
     /// Initializes a WriteBatch with a serialized WriteBatch.
@@ -61,9 +68,22 @@ impl WriteBatch {
         self.set_count(c + 1);
     }
 
+    /// Adds a precondition to a WriteBatch: The batch is only applied if the current value of `k`
+    /// is `v`, with `None` meaning that `k` must not exist. Otherwise, writing the batch fails with
+    /// `StatusCode::PreconditionFailed`, and none of its entries are applied.
+    pub fn expect(&mut self, k: &[u8], v: Option<&[u8]>) {
+        self.preconditions.push((k.to_vec(), v.map(|v| v.to_vec())));
+    }
+
+    /// Returns the preconditions added by `expect()`.
+    pub fn preconditions(&self) -> &[(Vec<u8>, Option<Vec<u8>>)] {
+        &self.preconditions
+    }
+
     /// Clear the contents of a WriteBatch.
     pub fn clear(&mut self) {
-        self.entries.clear()
+        self.entries.clear();
+        self.preconditions.clear();
     }
 
     fn byte_size(&self) -> usize {
@@ -188,4 +208,24 @@ mod tests {
         assert_eq!(i, 5);
         assert_eq!(b.encode(1).len(), 49);
     }
+
+    #[test]
+    fn test_write_batch_preconditions() {
+        let mut b = WriteBatch::new();
+        b.expect("abc".as_bytes(), Some("def".as_bytes()));
+        b.expect("xyz".as_bytes(), None);
+        b.put("abc".as_bytes(), "ghi".as_bytes());
+
+        assert_eq!(
+            &[
+                (b"abc".to_vec(), Some(b"def".to_vec())),
+                (b"xyz".to_vec(), None)
+            ],
+            b.preconditions()
+        );
+        // Preconditions are not serialized.
+        assert_eq!(b.count(), 1);
+        assert_eq!(b.iter().count(), 1);
+        assert_eq!(b.encode(1).len(), 21);
+    }
 }